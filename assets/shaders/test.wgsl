@@ -44,6 +44,8 @@ struct InstanceInput {
     [[location(7)]] normal_matrix_0: vec3<f32>;
     [[location(8)]] normal_matrix_1: vec3<f32>;
     [[location(9)]] normal_matrix_2: vec3<f32>;
+    [[location(10)]] multiply_color: vec4<f32>; // the alpha is the opacity of the part
+    [[location(11)]] screen_color: vec4<f32>;
 };
 
 struct VertexOutput {
@@ -52,6 +54,8 @@ struct VertexOutput {
     [[location(1)]] world_position: vec4<f32>;
     [[location(2)]] view_position: vec4<f32>;
     [[location(3)]] model_view_matrix: mat3x3<f32>; // 3, 4, 5
+    [[location(6)]] multiply_color: vec4<f32>;
+    [[location(7)]] screen_color: vec3<f32>;
     //[[location(6)]] tbn: mat3x3<f32>; // 6, 7, 8
 };
 
@@ -84,6 +88,8 @@ fn vs_main(
     out.world_position = world_position;
     //out.tbn = mat3x3<f32>(normalize(model.tangent), normalize(model.bitangent), normal);
     out.tex_coords = model.tex_coords;
+    out.multiply_color = instance.multiply_color;
+    out.screen_color = instance.screen_color.rgb;
     //out.model_view_matrix = model_view_matrix;
     out.view_position = view_position;
     out.clip_position = camera.proj_matrix * view_position; // camera.proj_matrix * 
@@ -209,7 +215,23 @@ fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
         //color = color + calculate_light(light, i, in.view_position, in.world_position, tangent_normal, object_color, object_specular);
     //}
 
-    return vec4<f32>(object_color.xyz, object_color.a);
+    // the multiply and screen colors of cubism 4.2
+    let multiplied: vec3<f32> = object_color.rgb * in.multiply_color.rgb;
+    let screened: vec3<f32> = multiplied + in.screen_color - multiplied * in.screen_color;
+
+    return vec4<f32>(screened, object_color.a * in.multiply_color.a);
+}
+
+// only marks the stencil where the mask is opaque enough, nothing is written to the color
+[[stage(fragment)]]
+fn fs_mask(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+
+    if (object_color.a < 0.5) {
+        discard;
+    }
+
+    return object_color;
 }
 
 // The fragment entrypoint used when storage buffers are not available for the lights
@@ -3,25 +3,35 @@ use crate::{
     camera::Projection,
     camera_controller::CameraController,
     camera_uniform::CameraUniform,
+    clipping::{ClipPipelines, Clipping},
+    expression::{ExpressionInput, ExpressionPlayer},
     instance_uniform::InstanceUniform,
     model::*,
+    state_machine::{StateMachineInput, StateMachinePlayer},
     texture::{self},
     type_def::*,
 };
 use dashmap::DashMap;
 use specs::{
-    Component, DenseVecStorage, Entity, FlaggedStorage, Read, ReadStorage, System, Write,
-    WriteStorage,
+    Component, DenseVecStorage, Entities, Entity, FlaggedStorage, Read, ReadExpect, ReadStorage,
+    System, Write, WriteStorage,
 };
-use specs_hierarchy::Parent as HParent; // Hierarchy, HierarchySystem,
+use specs_hierarchy::{Hierarchy, Parent as HParent};
 use std::{sync::Arc, time::Duration};
 use winit::event::{DeviceEvent, ElementState, MouseScrollDelta, VirtualKeyCode};
 
 #[derive(Debug, Component, Clone)]
 pub struct Pipeline(pub Arc<wgpu::RenderPipeline>);
 
-struct Parent {
-    entity: Entity,
+#[derive(Debug, Clone)]
+pub struct Parent {
+    pub entity: Entity,
+}
+
+impl Parent {
+    pub fn new(entity: Entity) -> Self {
+        Self { entity }
+    }
 }
 
 impl Component for Parent {
@@ -34,6 +44,8 @@ impl HParent for Parent {
     }
 }
 
+pub type ParentHierarchy = Hierarchy<Parent>;
+
 // #[derive(Debug, Default)]
 // pub struct InstanceData(pub Vec<InstanceRaw>);
 
@@ -63,6 +75,8 @@ impl Default for SmaaTarget {
     }
 }
 
+// not inserted in the world for now, see the end of State::new
+#[allow(dead_code)]
 #[derive(Debug, Default)]
 pub struct Collections {
     pub render_pipelines: Vec<Arc<wgpu::RenderPipeline>>,
@@ -120,9 +134,26 @@ impl Default for RenderThings {
 pub struct ProcessEvents;
 
 impl<'a> System<'a> for ProcessEvents {
-    type SystemData = (Write<'a, EventInput>, WriteStorage<'a, CameraController>);
+    type SystemData = (
+        Write<'a, EventInput>,
+        WriteStorage<'a, CameraController>,
+        ReadStorage<'a, ExpressionInput>,
+        WriteStorage<'a, ExpressionPlayer>,
+        ReadStorage<'a, StateMachineInput>,
+        WriteStorage<'a, StateMachinePlayer>,
+    );
 
-    fn run(&mut self, (mut event_input, mut camera_controllers): Self::SystemData) {
+    fn run(
+        &mut self,
+        (
+            mut event_input,
+            mut camera_controllers,
+            expression_inputs,
+            mut expression_players,
+            state_machine_inputs,
+            mut state_machine_players,
+        ): Self::SystemData,
+    ) {
         use specs::Join;
 
         for camera_controller in (&mut camera_controllers).join() {
@@ -138,6 +169,36 @@ impl<'a> System<'a> for ProcessEvents {
                 }
             }
         }
+
+        for (expression_input, expression_player) in
+            (&expression_inputs, &mut expression_players).join()
+        {
+            let mut i = 0;
+            while i < event_input.events.len() {
+                if expression_input.process_event(
+                    expression_player,
+                    &event_input.events[i],
+                    &event_input.info,
+                ) {
+                    event_input.events.remove(i);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        for (state_machine_input, state_machine_player) in
+            (&state_machine_inputs, &mut state_machine_players).join()
+        {
+            let mut i = 0;
+            while i < event_input.events.len() {
+                if state_machine_input.process_event(state_machine_player, &event_input.events[i]) {
+                    event_input.events.remove(i);
+                } else {
+                    i += 1;
+                }
+            }
+        }
     }
 }
 
@@ -200,10 +261,26 @@ impl<'a> System<'a> for UpdateSize {
 
 pub struct Rendering;
 
+impl Rendering {
+    // like in cubism the draw orders of the parts are compared whatever groups, deformers or bones
+    // they hang from, the parts with the same draw order keep the order they were created in
+    fn draw_key(draw_orders: &ReadStorage<'_, DrawOrder>, entity: Entity) -> (i32, u32) {
+        let draw_order = draw_orders
+            .get(entity)
+            .map_or(DrawOrder::default().0, |draw_order| draw_order.0);
+
+        (draw_order, entity.id())
+    }
+}
+
 impl<'a> System<'a> for Rendering {
     type SystemData = (
+        Entities<'a>,
         ReadStorage<'a, Model>,
         ReadStorage<'a, Pipeline>,
+        ReadStorage<'a, Clipping>,
+        ReadExpect<'a, ClipPipelines>, // inserted with the render pipelines, it has nothing to default to
+        ReadStorage<'a, DrawOrder>,
         //
         //ReadStorage<'a, LightUniform>,
         //ReadStorage<'a, TextureView>,
@@ -233,8 +310,12 @@ impl<'a> System<'a> for Rendering {
     fn run(
         &mut self,
         (
+            entities,
             materials,
             pipelines,
+            clippings,
+            clip_pipelines,
+            draw_orders,
             //
             //_lights,
             //_texture_views,
@@ -378,7 +459,10 @@ impl<'a> System<'a> for Rendering {
                                 load: wgpu::LoadOp::Clear(1.0),
                                 store: true,
                             }),
-                            stencil_ops: None,
+                            stencil_ops: Some(wgpu::Operations {
+                                load: wgpu::LoadOp::Clear(0),
+                                store: true,
+                            }),
                         }),
                     });
 
@@ -393,20 +477,79 @@ impl<'a> System<'a> for Rendering {
                     render_pass.set_bind_group(1, &render_things.camera_bind_group, &[]);
                     //render_pass.set_bind_group(2, &render_things.light_bind_group, &[]);
 
-                    for (material, pipeline, index_index, vertex_index, instance_index) in (
+                    // each clipped part gets its own stencil value, so the masks of the others never leak into it,
+                    // past 255 clipped parts in a frame the values are reused
+                    let mut stencil_reference = 0;
+
+                    // drawn back to front since every part is translucent
+                    let mut draws = (
+                        &entities,
                         &materials,
                         &pipelines,
+                        clippings.maybe(),
                         &indices_indices,
                         &vertices_indices,
                         &instances_indices,
                     )
                         .join()
+                        .map(|(entity, material, pipeline, clipping, i, j, k)| {
+                            (
+                                Self::draw_key(&draw_orders, entity),
+                                (material, pipeline, clipping, i, j, k),
+                            )
+                        })
+                        .collect::<Vec<_>>();
+
+                    draws.sort_by_key(|(key, _)| *key);
+
+                    for (
+                        _,
+                        (material, pipeline, clipping, index_index, vertex_index, instance_index),
+                    ) in draws.into_iter()
                     {
                         let i = index_index.get_range::<u32>(&indices_data, true);
                         let j = vertex_index.get_array_index::<i32>(&vertices_data, true);
                         let k = instance_index.get_range::<u32>(&instances_data, true);
 
-                        render_pass.set_pipeline(&pipeline.0);
+                        match clipping {
+                            Some(clipping) if !clipping.masks.is_empty() => {
+                                stencil_reference = stencil_reference % 255 + 1;
+                                render_pass.set_stencil_reference(stencil_reference);
+
+                                render_pass.set_pipeline(&clip_pipelines.mask);
+
+                                for mask in clipping.masks.iter() {
+                                    if let (
+                                        Some(material),
+                                        Some(index_index),
+                                        Some(vertex_index),
+                                        Some(instance_index),
+                                    ) = (
+                                        materials.get(*mask),
+                                        indices_indices.get(*mask),
+                                        vertices_indices.get(*mask),
+                                        instances_indices.get(*mask),
+                                    ) {
+                                        render_pass.set_bind_group(0, &material.0.bind_group, &[]);
+
+                                        render_pass.draw_indexed(
+                                            index_index.get_range::<u32>(&indices_data, true),
+                                            vertex_index
+                                                .get_array_index::<i32>(&vertices_data, true),
+                                            instance_index.get_range::<u32>(&instances_data, true),
+                                        );
+                                    }
+                                }
+
+                                render_pass.set_pipeline(if clipping.inverted {
+                                    &clip_pipelines.inverted
+                                } else {
+                                    &clip_pipelines.clipped
+                                });
+                            }
+                            _ => render_pass.set_pipeline(&pipeline.0),
+                        }
+
                         render_pass.set_bind_group(0, &material.0.bind_group, &[]);
 
                         render_pass.draw_indexed(i, j, k);
@@ -429,3 +572,48 @@ impl<'a> System<'a> for Rendering {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use specs::{Builder, World, WorldExt};
+
+    #[test]
+    fn parts_are_sorted_by_their_own_draw_order_whatever_their_depth() {
+        let mut world = World::new();
+        world.register::<DrawOrder>();
+        world.register::<Parent>();
+
+        // a part in a group without a draw order, like the psd groups, deformers and bones
+        let root = world.create_entity().build();
+        let group = world.create_entity().with(Parent::new(root)).build();
+        let in_group = world
+            .create_entity()
+            .with(Parent::new(group))
+            .with(DrawOrder(600))
+            .build();
+        let beside_group = world
+            .create_entity()
+            .with(Parent::new(root))
+            .with(DrawOrder::default())
+            .build();
+        let over_beside_group = world
+            .create_entity()
+            .with(Parent::new(root))
+            .with(DrawOrder::default())
+            .build();
+
+        let draw_orders = world.read_storage::<DrawOrder>();
+        let key = |entity| Rendering::draw_key(&draw_orders, entity);
+
+        // the deeper part isn't drawn under its shallower siblings
+        assert!(key(beside_group) < key(in_group));
+        assert!(key(over_beside_group) < key(in_group));
+
+        // the same draw order keeps the order of creation
+        assert!(key(beside_group) < key(over_beside_group));
+
+        // what has no draw order is at the default one
+        assert_eq!(key(group).0, DrawOrder::default().0);
+    }
+}
@@ -0,0 +1,902 @@
+use crate::{
+    actor::{Parent, ParentHierarchy, Time},
+    parameter::Parameters,
+    type_def::*,
+};
+use anyhow::Result;
+use serde::Deserialize;
+use specs::{
+    Component, DenseVecStorage, Entities, Entity, Read, ReadExpect, ReadStorage, System,
+    WriteStorage,
+};
+use std::{collections::HashMap, f32::consts::PI, path::Path, sync::Arc};
+
+// how a keyframe goes to the next one
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    Step, // holds the value until the next keyframe
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    CubicBezier([Real; 4]), // x1, y1, x2, y2 like in css
+}
+
+impl Easing {
+    pub fn apply(&self, t: Real) -> Real {
+        let t = t.clamp(0.0, 1.0);
+
+        match self {
+            Self::Linear => t,
+            Self::Step => 0.0,
+            Self::EaseIn => 1.0 - (t * PI / 2.0).cos(),
+            Self::EaseOut => (t * PI / 2.0).sin(),
+            Self::EaseInOut => 0.5 - 0.5 * (t * PI).cos(),
+            Self::CubicBezier([x_1, y_1, x_2, y_2]) => {
+                let bezier = |s: Real, a: Real, b: Real| {
+                    let u = 1.0 - s;
+                    3.0 * u * u * s * a + 3.0 * u * s * s * b + s * s * s
+                };
+
+                // the x of the curve is the time, so the s giving t has to be found first
+                let (mut low, mut high) = (0.0, 1.0);
+                for _ in 0..24 {
+                    let middle = (low + high) / 2.0;
+                    if bezier(middle, *x_1, *x_2) < t {
+                        low = middle;
+                    } else {
+                        high = middle;
+                    }
+                }
+
+                bezier((low + high) / 2.0, *y_1, *y_2)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackTarget {
+    Position,          // x, y
+    Rotation,          // angle in radians
+    Scale,             // x, y
+    Color,             // r, g, b, a
+    Opacity,           // alpha of the part, 0 hides it
+    Parameter(String), // value, on the Parameters of the puppet the entity belongs to
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Keyframe {
+    pub time: Real,       // seconds from the start of the clip
+    pub value: Vec<Real>, // as many values as the target has
+    #[serde(default)]
+    pub easing: Easing, // toward the next keyframe
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Track {
+    #[serde(default)]
+    pub entity: Option<String>, // Name of an entity below the player, the player itself when none
+    pub target: TrackTarget,
+    pub keyframes: Vec<Keyframe>, // sorted by time
+}
+
+impl Track {
+    pub fn sample(&self, time: Real) -> Option<Vec<Real>> {
+        let first = self.keyframes.first()?;
+        let last = self.keyframes.last()?;
+
+        if time <= first.time {
+            return Some(first.value.clone());
+        }
+
+        if time >= last.time {
+            return Some(last.value.clone());
+        }
+
+        let next = self.keyframes.iter().position(|key| key.time > time)?;
+        let (a, b) = (&self.keyframes[next - 1], &self.keyframes[next]);
+        let t = a.easing.apply((time - a.time) / (b.time - a.time));
+
+        Some(
+            a.value
+                .iter()
+                .zip(b.value.iter())
+                .map(|(a, b)| a + (b - a) * t)
+                .collect(),
+        )
+    }
+}
+
+// a named animation, loaded from a json file or built in code
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnimationClip {
+    pub name: String,
+    pub duration: Real,
+    #[serde(default)]
+    pub looped: bool,
+    pub tracks: Vec<Track>,
+}
+
+impl AnimationClip {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let mut clip: Self = serde_json::from_str(json)?;
+
+        for track in clip.tracks.iter_mut() {
+            track
+                .keyframes
+                .sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        }
+
+        Ok(clip)
+    }
+}
+
+// a track by the name of the entity it moves, and the entity found with it
+type TrackKey = (Option<String>, TrackTarget);
+type TargetKey = (Entity, TrackTarget);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerBlend {
+    Override, // moves the targets toward the values of the clip by the weight of the layer
+    Additive, // adds how far the clip is from its first keyframe, ex: breathing over a gesture
+}
+
+// what a layer is allowed to move, everything when a layer has no mask
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AnimationMask {
+    pub parameters: Vec<String>,
+    pub entities: Vec<String>, // Name of the entities targeted by the tracks
+}
+
+impl AnimationMask {
+    pub fn allows(&self, track: &Track) -> bool {
+        match (&track.target, &track.entity) {
+            (TrackTarget::Parameter(id), _) => self.parameters.contains(id),
+            (_, Some(name)) => self.entities.contains(name),
+            (_, None) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClipState {
+    pub clip: Arc<AnimationClip>,
+    pub time: Real,
+}
+
+impl ClipState {
+    fn new(clip: Arc<AnimationClip>) -> Self {
+        Self { clip, time: 0.0 }
+    }
+
+    // the time inside of the clip
+    fn at(&self) -> Real {
+        if self.clip.looped && self.clip.duration > 0.0 {
+            self.time.rem_euclid(self.clip.duration)
+        } else {
+            self.time.clamp(0.0, self.clip.duration)
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        !self.clip.looped && self.time >= self.clip.duration
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationLayer {
+    pub name: String,
+    pub weight: Real,
+    pub blend: LayerBlend,
+    pub mask: Option<AnimationMask>,
+    pub speed: Real,
+    pub playing: bool,
+    pub current: Option<ClipState>,
+    pub previous: Option<ClipState>, // the clip fading out during a crossfade
+    pub fade: Real,                  // duration of the crossfade
+    pub fade_time: Real,
+}
+
+impl AnimationLayer {
+    pub fn new(name: &str, blend: LayerBlend, weight: Real) -> Self {
+        Self {
+            name: name.to_string(),
+            weight,
+            blend,
+            mask: None,
+            speed: 1.0,
+            playing: false,
+            current: None,
+            previous: None,
+            fade: 0.0,
+            fade_time: 0.0,
+        }
+    }
+
+    pub fn with_mask(mut self, mask: AnimationMask) -> Self {
+        self.mask = Some(mask);
+        self
+    }
+
+    pub fn play(&mut self, clip: Arc<AnimationClip>) {
+        self.crossfade(clip, 0.0);
+    }
+
+    // the clip playing fades out while the new one fades in
+    pub fn crossfade(&mut self, clip: Arc<AnimationClip>, duration: Real) {
+        self.previous = match duration > 0.0 {
+            true => self.current.take(),
+            false => None,
+        };
+        self.current = Some(ClipState::new(clip));
+        self.fade = duration;
+        self.fade_time = 0.0;
+        self.playing = true;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.current.as_ref().is_none_or(ClipState::is_finished)
+    }
+
+    // how far the crossfade is, 1 once the previous clip is gone
+    fn fade_factor(&self) -> Real {
+        if self.previous.is_some() && self.fade > 0.0 {
+            (self.fade_time / self.fade).clamp(0.0, 1.0)
+        } else {
+            1.0
+        }
+    }
+
+    fn advance(&mut self, delta: Real) {
+        if !self.playing {
+            return;
+        }
+
+        let delta = delta * self.speed;
+
+        for state in self.current.iter_mut().chain(self.previous.iter_mut()) {
+            state.time += delta;
+        }
+
+        self.fade_time += delta.abs();
+        if self.fade_time >= self.fade {
+            self.previous = None;
+        }
+    }
+
+    // the value of every track of the layer with its weight, the previous and current clips mixed
+    fn sample(&self) -> Vec<(TrackKey, Vec<Real>, Real)> {
+        let factor = self.fade_factor();
+        let mut samples: Vec<(TrackKey, Vec<Real>, Real)> = Vec::new();
+
+        let states = self
+            .previous
+            .iter()
+            .map(|state| (state, 1.0 - factor))
+            .chain(self.current.iter().map(|state| (state, factor)));
+
+        for (state, factor) in states {
+            for track in state.clip.tracks.iter() {
+                if !self.mask.as_ref().is_none_or(|mask| mask.allows(track)) {
+                    continue;
+                }
+
+                let (value, reference) = match (track.sample(state.at()), track.keyframes.first()) {
+                    (Some(value), Some(first)) => (value, first.value.clone()),
+                    _ => continue,
+                };
+
+                // additive tracks only keep how far they moved
+                let value = match self.blend {
+                    LayerBlend::Override => value,
+                    LayerBlend::Additive => value
+                        .iter()
+                        .zip(reference.iter())
+                        .map(|(value, reference)| value - reference)
+                        .collect(),
+                };
+
+                let key = (track.entity.clone(), track.target.clone());
+
+                // a track in both clips crossfades, a track in only one fades with its clip
+                match samples.iter_mut().find(|(other, ..)| *other == key) {
+                    Some((_, old, weight)) => {
+                        *old = old
+                            .iter()
+                            .zip(value.iter())
+                            .map(|(old, value)| old + (value - old) * factor)
+                            .collect();
+                        *weight = self.weight;
+                    }
+                    None => samples.push((key, value, self.weight * factor)),
+                }
+            }
+        }
+
+        samples
+    }
+}
+
+// layers are applied in order, each one over the result of the ones before it
+#[derive(Debug, Clone)]
+pub struct AnimationPlayer {
+    pub layers: Vec<AnimationLayer>,
+    blended: HashMap<TargetKey, (Vec<Real>, Vec<Real>)>, // base value and value read back after the write of each target
+}
+
+impl Default for AnimationPlayer {
+    fn default() -> Self {
+        Self::new(vec![AnimationLayer::new("base", LayerBlend::Override, 1.0)])
+    }
+}
+
+impl Component for AnimationPlayer {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl AnimationPlayer {
+    pub fn new(layers: Vec<AnimationLayer>) -> Self {
+        Self {
+            layers,
+            blended: HashMap::new(),
+        }
+    }
+
+    // replaces the layer with the same name if there is already one
+    pub fn add_layer(&mut self, layer: AnimationLayer) {
+        match self.layer_mut(&layer.name) {
+            Some(old) => *old = layer,
+            None => self.layers.push(layer),
+        }
+    }
+
+    pub fn layer(&self, name: &str) -> Option<&AnimationLayer> {
+        self.layers.iter().find(|layer| layer.name == name)
+    }
+
+    pub fn layer_mut(&mut self, name: &str) -> Option<&mut AnimationLayer> {
+        self.layers.iter_mut().find(|layer| layer.name == name)
+    }
+
+    // plays on the first layer
+    pub fn play(&mut self, clip: Arc<AnimationClip>) {
+        if let Some(layer) = self.layers.first_mut() {
+            layer.play(clip);
+        }
+    }
+}
+
+// writes the blended values through the flagged storages,
+// so the transforms, instances and keyforms are updated like for any other change
+pub struct AnimationUpdate;
+
+type AnimatedStorages<'a, 'b> = (
+    &'b mut WriteStorage<'a, Position>,
+    &'b mut WriteStorage<'a, Rotation>,
+    &'b mut WriteStorage<'a, Scale>,
+    &'b mut WriteStorage<'a, Color>,
+    &'b mut WriteStorage<'a, Opacity>,
+    &'b mut WriteStorage<'a, Parameters>,
+);
+
+impl<'a> System<'a> for AnimationUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time>,
+        ReadExpect<'a, ParentHierarchy>,
+        WriteStorage<'a, AnimationPlayer>,
+        WriteStorage<'a, Position>,
+        WriteStorage<'a, Rotation>,
+        WriteStorage<'a, Scale>,
+        WriteStorage<'a, Color>,
+        WriteStorage<'a, Opacity>,
+        WriteStorage<'a, Parameters>,
+        ReadStorage<'a, Parent>,
+        ReadStorage<'a, Name>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            hierarchy,
+            mut players,
+            mut positions,
+            mut rotations,
+            mut scales,
+            mut colors,
+            mut opacities,
+            mut parameters,
+            parents,
+            names,
+        ): Self::SystemData,
+    ) {
+        use specs::Join;
+
+        let delta = time.delta.as_secs_f32();
+
+        for (entity, player) in (&entities, &mut players).join() {
+            if player.layers.iter().all(|layer| layer.current.is_none()) {
+                player.blended.clear();
+                continue;
+            }
+
+            let find = |name: &str| {
+                (&entities, &names, &hierarchy.all_children(entity))
+                    .join()
+                    .find(|(_, other, _)| other.0 == name)
+                    .map(|(entity, _, _)| entity)
+            };
+
+            // the parameters are on the root of the puppet the entity belongs to
+            let owner = |target: Entity, track_target: &TrackTarget| match track_target {
+                TrackTarget::Parameter(_) => {
+                    let mut target = target;
+                    while !parameters.contains(target) {
+                        target = parents.get(target)?.entity;
+                    }
+                    Some(target)
+                }
+                _ => Some(target),
+            };
+
+            // every layer in order, resolved to the entity holding what they move
+            let mut contributions: Vec<(TargetKey, LayerBlend, Vec<Real>, Real)> = Vec::new();
+
+            for layer in player.layers.iter_mut() {
+                layer.advance(delta);
+
+                for ((name, target), value, weight) in layer.sample() {
+                    let found = match &name {
+                        Some(name) => find(name),
+                        None => Some(entity),
+                    };
+
+                    if let Some(found) = found.and_then(|found| owner(found, &target)) {
+                        contributions.push(((found, target), layer.blend, value, weight));
+                    }
+                }
+
+                if layer.is_finished() && layer.previous.is_none() {
+                    layer.playing = false;
+                }
+            }
+
+            let mut blended = HashMap::new();
+            let mut storages = (
+                &mut positions,
+                &mut rotations,
+                &mut scales,
+                &mut colors,
+                &mut opacities,
+                &mut parameters,
+            );
+
+            for (key, _, _, _) in contributions.iter() {
+                if blended.contains_key(key) {
+                    continue;
+                }
+
+                let current = match Self::read(key, &storages) {
+                    Some(current) => current,
+                    None => continue,
+                };
+
+                // something else moved the target since the last frame, it becomes the new base
+                let base = match player.blended.get(key) {
+                    Some((base, written)) if *written == current => base.clone(),
+                    _ => current,
+                };
+
+                let value = Self::blend(
+                    &base,
+                    contributions
+                        .iter()
+                        .filter(|(other, ..)| other == key)
+                        .map(|(_, blend, sample, weight)| (*blend, sample.as_slice(), *weight)),
+                );
+
+                Self::write(key, &value, &mut storages);
+
+                // what the storage kept, an angle doesn't come back the same and a parameter is clamped
+                let written = Self::read(key, &storages).unwrap_or(value);
+                blended.insert(key.clone(), (base, written));
+            }
+
+            player.blended = blended;
+        }
+    }
+}
+
+impl AnimationUpdate {
+    // the layers in order over the value the target has without them
+    fn blend<'b>(
+        base: &[Real],
+        contributions: impl Iterator<Item = (LayerBlend, &'b [Real], Real)>,
+    ) -> Vec<Real> {
+        let mut value = base.to_vec();
+
+        for (blend, sample, weight) in contributions {
+            for (value, sample) in value.iter_mut().zip(sample.iter()) {
+                match blend {
+                    LayerBlend::Override => *value += (sample - *value) * weight,
+                    LayerBlend::Additive => *value += sample * weight,
+                }
+            }
+        }
+
+        value
+    }
+
+    fn read(
+        (entity, target): &TargetKey,
+        (positions, rotations, scales, colors, opacities, parameters): &AnimatedStorages<'_, '_>,
+    ) -> Option<Vec<Real>> {
+        match target {
+            TrackTarget::Position => positions
+                .get(*entity)
+                .map(|position| vec![position.0.x, position.0.y]),
+            TrackTarget::Rotation => rotations
+                .get(*entity)
+                .map(|rotation| vec![rotation.0.angle()]),
+            TrackTarget::Scale => scales.get(*entity).map(|scale| vec![scale.0.x, scale.0.y]),
+            // a part without a color is drawn in white
+            TrackTarget::Color => Some(
+                colors
+                    .get(*entity)
+                    .map(|color| Color::to_uniform_rgba(&color.0).to_vec())
+                    .unwrap_or_else(|| vec![1.0, 1.0, 1.0, 1.0]),
+            ),
+            TrackTarget::Opacity => Some(vec![opacities
+                .get(*entity)
+                .map_or(1.0, |opacity| opacity.0)]),
+            TrackTarget::Parameter(id) => parameters
+                .get(*entity)
+                .and_then(|parameters| parameters.value(id))
+                .map(|value| vec![value]),
+        }
+    }
+
+    fn write(
+        (entity, target): &TargetKey,
+        value: &[Real],
+        (positions, rotations, scales, colors, opacities, parameters): &mut AnimatedStorages<
+            '_,
+            '_,
+        >,
+    ) {
+        let entity = *entity;
+        let at = |i: usize, default: Real| value.get(i).copied().unwrap_or(default);
+
+        match target {
+            TrackTarget::Position => {
+                if let Some(position) = positions.get_mut(entity) {
+                    *position = Position::new(at(0, 0.0), at(1, 0.0));
+                }
+            }
+            TrackTarget::Rotation => {
+                if let Some(rotation) = rotations.get_mut(entity) {
+                    *rotation = Rotation::new(at(0, 0.0));
+                }
+            }
+            TrackTarget::Scale => {
+                if let Some(scale) = scales.get_mut(entity) {
+                    *scale = Scale::new(at(0, 1.0), at(1, 1.0));
+                }
+            }
+            TrackTarget::Color => {
+                let color = Color::new_rgba(at(0, 1.0), at(1, 1.0), at(2, 1.0), at(3, 1.0));
+
+                match colors.get_mut(entity) {
+                    Some(old) => *old = color,
+                    None => {
+                        colors.insert(entity, color).unwrap();
+                    }
+                }
+            }
+            TrackTarget::Opacity => match opacities.get_mut(entity) {
+                Some(opacity) => opacity.0 = at(0, 1.0),
+                None => {
+                    opacities.insert(entity, Opacity(at(0, 1.0))).unwrap();
+                }
+            },
+            TrackTarget::Parameter(id) => {
+                if let Some(parameters) = parameters.get_mut(entity) {
+                    parameters.set(id, at(0, 0.0));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Real, b: Real) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    fn keyframe(time: Real, value: Real, easing: Easing) -> Keyframe {
+        Keyframe {
+            time,
+            value: vec![value],
+            easing,
+        }
+    }
+
+    #[test]
+    fn easings_go_from_0_to_1() {
+        let easings = [
+            Easing::Linear,
+            Easing::EaseIn,
+            Easing::EaseOut,
+            Easing::EaseInOut,
+            Easing::CubicBezier([0.42, 0.0, 0.58, 1.0]),
+        ];
+
+        for easing in easings {
+            assert!(close(easing.apply(0.0), 0.0), "{:?}", easing);
+            assert!(close(easing.apply(1.0), 1.0), "{:?}", easing);
+            assert!(close(easing.apply(2.0), 1.0), "{:?}", easing);
+        }
+
+        assert_eq!(Easing::Step.apply(0.99), 0.0);
+    }
+
+    #[test]
+    fn easings_bend_the_way_they_say() {
+        assert!(close(Easing::Linear.apply(0.25), 0.25));
+        assert!(Easing::EaseIn.apply(0.5) < 0.5);
+        assert!(Easing::EaseOut.apply(0.5) > 0.5);
+        assert!(close(Easing::EaseInOut.apply(0.5), 0.5));
+
+        // a bezier with its handles on the diagonal is linear
+        let linear = Easing::CubicBezier([1.0 / 3.0, 1.0 / 3.0, 2.0 / 3.0, 2.0 / 3.0]);
+        assert!(close(linear.apply(0.3), 0.3));
+
+        let ease_in = Easing::CubicBezier([0.42, 0.0, 1.0, 1.0]);
+        assert!(ease_in.apply(0.5) < 0.5);
+    }
+
+    #[test]
+    fn sample_eases_between_keyframes_and_holds_the_ends() {
+        let track = Track {
+            entity: None,
+            target: TrackTarget::Opacity,
+            keyframes: vec![
+                keyframe(1.0, 0.0, Easing::Linear),
+                keyframe(2.0, 1.0, Easing::Step),
+                keyframe(3.0, 0.5, Easing::Linear),
+            ],
+        };
+
+        assert_eq!(track.sample(0.0), Some(vec![0.0]));
+        assert!(close(track.sample(1.5).unwrap()[0], 0.5));
+        assert_eq!(track.sample(2.5), Some(vec![1.0]));
+        assert_eq!(track.sample(4.0), Some(vec![0.5]));
+    }
+
+    #[test]
+    fn sample_needs_keyframes() {
+        let track = Track {
+            entity: None,
+            target: TrackTarget::Rotation,
+            keyframes: Vec::new(),
+        };
+
+        assert_eq!(track.sample(0.0), None);
+    }
+
+    #[test]
+    fn from_json_sorts_the_keyframes() {
+        let clip = AnimationClip::from_json(
+            r#"{
+                "name": "wave",
+                "duration": 1.0,
+                "tracks": [{
+                    "entity": "ArmL",
+                    "target": "rotation",
+                    "keyframes": [
+                        { "time": 1.0, "value": [0.5] },
+                        { "time": 0.0, "value": [0.0], "easing": { "cubic_bezier": [0.4, 0, 0.6, 1] } }
+                    ]
+                }, {
+                    "target": { "parameter": "ParamAngleX" },
+                    "keyframes": [{ "time": 0.0, "value": [10.0] }]
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let keyframes = &clip.tracks[0].keyframes;
+        assert_eq!(keyframes[0].time, 0.0);
+        assert_eq!(
+            keyframes[0].easing,
+            Easing::CubicBezier([0.4, 0.0, 0.6, 1.0])
+        );
+        assert_eq!(keyframes[1].easing, Easing::Linear);
+        assert!(!clip.looped);
+        assert_eq!(
+            clip.tracks[1].target,
+            TrackTarget::Parameter("ParamAngleX".to_string())
+        );
+    }
+
+    fn clip(name: &str, track: Track) -> Arc<AnimationClip> {
+        Arc::new(AnimationClip {
+            name: name.to_string(),
+            duration: 1.0,
+            looped: true,
+            tracks: vec![track],
+        })
+    }
+
+    // a parameter track going from the first value to the second over the clip
+    fn parameter(id: &str, from: Real, to: Real) -> Track {
+        Track {
+            entity: None,
+            target: TrackTarget::Parameter(id.to_string()),
+            keyframes: vec![
+                keyframe(0.0, from, Easing::Linear),
+                keyframe(1.0, to, Easing::Linear),
+            ],
+        }
+    }
+
+    #[test]
+    fn a_looped_clip_wraps_and_the_others_stop_at_the_end() {
+        let clip = |looped| AnimationClip {
+            name: "idle".to_string(),
+            duration: 2.0,
+            looped,
+            tracks: Vec::new(),
+        };
+
+        let mut looped = ClipState::new(Arc::new(clip(true)));
+        looped.time = 5.0;
+        assert!(close(looped.at(), 1.0));
+        assert!(!looped.is_finished());
+
+        let mut once = ClipState::new(Arc::new(clip(false)));
+        once.time = 5.0;
+        assert_eq!(once.at(), 2.0);
+        assert!(once.is_finished());
+    }
+
+    #[test]
+    fn layers_blend_in_order() {
+        let base = [10.0];
+        let value = AnimationUpdate::blend(
+            &base,
+            [
+                (LayerBlend::Override, &[20.0][..], 0.5),
+                (LayerBlend::Additive, &[4.0][..], 0.5),
+            ]
+            .into_iter(),
+        );
+
+        assert_eq!(value, vec![17.0]);
+    }
+
+    #[test]
+    fn additive_layers_keep_how_far_the_clip_moved() {
+        let mut layer = AnimationLayer::new("breath", LayerBlend::Additive, 1.0);
+        layer.play(clip("breath", parameter("ParamBreath", 5.0, 7.0)));
+        layer.advance(0.5);
+
+        let samples = layer.sample();
+        assert_eq!(samples.len(), 1);
+        assert!(close(samples[0].1[0], 1.0));
+    }
+
+    #[test]
+    fn masks_keep_the_tracks_they_allow() {
+        let mut layer =
+            AnimationLayer::new("face", LayerBlend::Override, 1.0).with_mask(AnimationMask {
+                parameters: vec!["ParamEyeLOpen".to_string()],
+                entities: Vec::new(),
+            });
+
+        layer.play(clip("look", parameter("ParamAngleX", 0.0, 30.0)));
+        assert!(layer.sample().is_empty());
+
+        layer.play(clip("blink", parameter("ParamEyeLOpen", 1.0, 0.0)));
+        assert_eq!(layer.sample().len(), 1);
+    }
+
+    #[test]
+    fn a_crossfade_mixes_the_clips() {
+        let mut layer = AnimationLayer::new("base", LayerBlend::Override, 1.0);
+        layer.play(clip("left", parameter("ParamAngleX", -30.0, -30.0)));
+        layer.crossfade(clip("right", parameter("ParamAngleX", 30.0, 30.0)), 1.0);
+        layer.advance(0.25);
+
+        let samples = layer.sample();
+        assert_eq!(samples.len(), 1);
+        assert!(close(samples[0].1[0], -15.0));
+        assert_eq!(samples[0].2, 1.0);
+
+        layer.advance(1.0);
+        assert!(layer.previous.is_none());
+        assert!(close(layer.sample()[0].1[0], 30.0));
+    }
+
+    #[test]
+    fn the_base_stays_when_the_storage_changes_what_is_written() {
+        use specs::{Builder, DispatcherBuilder, World, WorldExt};
+        use specs_hierarchy::HierarchySystem;
+        use std::time::Duration;
+
+        let mut world = World::new();
+        let hierarchy = HierarchySystem::<Parent>::new(&mut world);
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(hierarchy, "HierarchySystem", &[])
+            .with(AnimationUpdate, "AnimationUpdate", &["HierarchySystem"])
+            .build();
+        dispatcher.setup(&mut world);
+
+        world.insert(Time {
+            delta: Duration::from_millis(100),
+            speed: 1.0,
+            on: true,
+        });
+
+        // half of an angle over pi, and a parameter pushed past its maximum
+        let turn = Track {
+            entity: None,
+            target: TrackTarget::Rotation,
+            keyframes: vec![keyframe(0.0, 4.0, Easing::Linear)],
+        };
+        let mut layers = AnimationLayer::new("base", LayerBlend::Override, 0.5);
+        layers.play(clip("turn", turn));
+        let mut push = AnimationLayer::new("push", LayerBlend::Override, 0.5);
+        push.play(clip("push", parameter("ParamMouthOpenY", 4.0, 4.0)));
+
+        let puppet = world
+            .create_entity()
+            .with(Rotation::new(0.0))
+            .with(Parameters::standard())
+            .with(AnimationPlayer::new(vec![layers, push]))
+            .build();
+
+        for _ in 0..3 {
+            dispatcher.dispatch(&world);
+            world.maintain();
+        }
+
+        let angle = world
+            .read_storage::<Rotation>()
+            .get(puppet)
+            .unwrap()
+            .0
+            .angle();
+        assert!(close(angle, 2.0), "{}", angle);
+
+        // the clip going back to 0 brings the parameter back to where it started
+        world
+            .write_storage::<AnimationPlayer>()
+            .get_mut(puppet)
+            .unwrap()
+            .layer_mut("push")
+            .unwrap()
+            .play(clip("rest", parameter("ParamMouthOpenY", 0.0, 0.0)));
+
+        dispatcher.dispatch(&world);
+
+        let parameters = world.read_storage::<Parameters>();
+        let value = parameters.get(puppet).unwrap().value("ParamMouthOpenY");
+        assert_eq!(value, Some(0.0));
+    }
+}
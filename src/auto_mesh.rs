@@ -0,0 +1,497 @@
+use crate::{
+    mesh::{Mesh, MeshFrame},
+    sprite_selector::SpriteSelector,
+    type_def::*,
+};
+use ahash::AHashMap;
+use image::{imageops, DynamicImage, GenericImageView, RgbaImage};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::collections::HashMap;
+
+// settings used to build a mesh from the alpha channel of a texture
+#[derive(Debug, Clone)]
+pub struct AutoMesh {
+    // pixels between two vertices, inside the outline and along it
+    pub density: f32,
+    // pixels with this alpha or less are considered empty
+    pub alpha_threshold: u8,
+    // pixels added around the opaque ones so the outline never cuts into them
+    pub margin: u32,
+    // how far in pixels the simplified outline can stray from the traced one
+    pub tolerance: f32,
+}
+
+impl Default for AutoMesh {
+    fn default() -> Self {
+        Self {
+            density: 48.0,
+            alpha_threshold: 8,
+            margin: 2,
+            tolerance: 1.5,
+        }
+    }
+}
+
+impl AutoMesh {
+    pub fn from_mat(unknown_param: &AHashMap<String, String>) -> Self {
+        let default = String::new();
+        let auto_mesh = Self::default();
+
+        let density = unknown_param
+            .get("meshDensity")
+            .unwrap_or(&default)
+            .parse::<f32>()
+            .unwrap_or(auto_mesh.density)
+            .max(1.0);
+
+        let alpha_threshold = unknown_param
+            .get("meshAlpha")
+            .unwrap_or(&default)
+            .parse::<u8>()
+            .unwrap_or(auto_mesh.alpha_threshold);
+
+        let margin = unknown_param
+            .get("meshMargin")
+            .unwrap_or(&default)
+            .parse::<u32>()
+            .unwrap_or(auto_mesh.margin);
+
+        let tolerance = unknown_param
+            .get("meshTolerance")
+            .unwrap_or(&default)
+            .parse::<f32>()
+            .unwrap_or(auto_mesh.tolerance);
+
+        Self {
+            density,
+            alpha_threshold,
+            margin,
+            tolerance,
+        }
+    }
+
+    // one frame per sprite cell so every frame of an animated part gets an outline that fits it
+    pub fn generate(&self, img: &DynamicImage, sprite_selector: &SpriteSelector) -> Mesh {
+        let (width, height) = img.dimensions();
+        let rgba = img.to_rgba8();
+
+        let frames = (0..sprite_selector.cells())
+            .into_par_iter()
+            .map(|at| {
+                let [w_0, h_0, w_1, h_1] = sprite_selector.get_cell(at);
+
+                let x_0 = (w_0 * width as f32).round() as u32;
+                let y_0 = (h_0 * height as f32).round() as u32;
+                let x_1 = ((w_1 * width as f32).round() as u32).clamp(x_0, width);
+                let y_1 = ((h_1 * height as f32).round() as u32).clamp(y_0, height);
+
+                let cell = imageops::crop_imm(&rgba, x_0, y_0, x_1 - x_0, y_1 - y_0).to_image();
+
+                self.frame(&cell)
+            })
+            .collect::<Vec<_>>();
+
+        Mesh::from_frames(frames)
+    }
+
+    fn frame(&self, cell: &RgbaImage) -> MeshFrame {
+        let (width, height) = cell.dimensions();
+
+        if width == 0 || height == 0 {
+            return MeshFrame::default();
+        }
+
+        let mask = self.mask(cell);
+
+        let outlines = Self::trace(&mask, width, height)
+            .into_iter()
+            .map(|outline| self.resample(&Self::simplify(&outline, self.tolerance)))
+            .filter(|outline| outline.len() >= 3)
+            .collect::<Vec<_>>();
+
+        let mut points = outlines.concat();
+
+        // interior vertices on a grid, kept away from the outline to avoid thin triangles
+        let mut y = self.density / 2.0;
+        while y < height as f32 {
+            let mut x = self.density / 2.0;
+            while x < width as f32 {
+                let point = [x, y];
+
+                if Self::inside(&outlines, point)
+                    && Self::distance_to_outlines(&outlines, point) > self.density / 2.0
+                {
+                    points.push(point);
+                }
+
+                x += self.density;
+            }
+            y += self.density;
+        }
+
+        let triangulation = delaunator::triangulate(
+            &points
+                .iter()
+                .map(|p| delaunator::Point {
+                    x: p[0] as f64,
+                    y: p[1] as f64,
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        let mut remap: HashMap<usize, Indices> = HashMap::new();
+        let mut uvs = Vec::new();
+        let mut indices = Vec::new();
+
+        for triangle in triangulation.triangles.chunks(3) {
+            let [a, b, c] = [
+                points[triangle[0]],
+                points[triangle[1]],
+                points[triangle[2]],
+            ];
+            let centroid = [(a[0] + b[0] + c[0]) / 3.0, (a[1] + b[1] + c[1]) / 3.0];
+
+            // the triangulation fills the convex hull, only keep what is inside the outlines
+            if !Self::inside(&outlines, centroid) {
+                continue;
+            }
+
+            // the parts are drawn mirrored, so the triangles have to turn the same way as the quad's
+            let area = (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0]);
+            let triangle = if area > 0.0 {
+                [triangle[0], triangle[2], triangle[1]]
+            } else {
+                [triangle[0], triangle[1], triangle[2]]
+            };
+
+            for i in triangle {
+                let index = *remap.entry(i).or_insert_with(|| {
+                    uvs.push([points[i][0] / width as f32, points[i][1] / height as f32]);
+                    (uvs.len() - 1) as Indices
+                });
+
+                indices.push(index);
+            }
+        }
+
+        MeshFrame { uvs, indices }
+    }
+
+    // opaque pixels grown by the margin
+    fn mask(&self, cell: &RgbaImage) -> Vec<bool> {
+        let (width, height) = (cell.width() as usize, cell.height() as usize);
+        let margin = self.margin as usize;
+
+        let opaque = cell
+            .pixels()
+            .map(|pixel| pixel[3] > self.alpha_threshold)
+            .collect::<Vec<_>>();
+
+        let mut horizontal = vec![false; opaque.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let from = x.saturating_sub(margin);
+                let to = (x + margin).min(width - 1);
+                horizontal[y * width + x] = (from..=to).any(|x| opaque[y * width + x]);
+            }
+        }
+
+        let mut mask = vec![false; opaque.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let from = y.saturating_sub(margin);
+                let to = (y + margin).min(height - 1);
+                mask[y * width + x] = (from..=to).any(|y| horizontal[y * width + x]);
+            }
+        }
+
+        mask
+    }
+
+    // follows the borders between filled and empty pixels, giving closed loops of pixel corners
+    fn trace(mask: &[bool], width: u32, height: u32) -> Vec<Vec<[f32; 2]>> {
+        let (width, height) = (width as i64, height as i64);
+        let filled = |x: i64, y: i64| {
+            x >= 0 && y >= 0 && x < width && y < height && mask[(y * width + x) as usize]
+        };
+
+        // every border goes clockwise around the filled pixel it belongs to
+        let mut edges: HashMap<(i64, i64), Vec<(i64, i64)>> = HashMap::new();
+
+        for y in 0..height {
+            for x in 0..width {
+                if !filled(x, y) {
+                    continue;
+                }
+
+                if !filled(x, y - 1) {
+                    edges.entry((x, y)).or_default().push((x + 1, y));
+                }
+                if !filled(x + 1, y) {
+                    edges.entry((x + 1, y)).or_default().push((x + 1, y + 1));
+                }
+                if !filled(x, y + 1) {
+                    edges.entry((x + 1, y + 1)).or_default().push((x, y + 1));
+                }
+                if !filled(x - 1, y) {
+                    edges.entry((x, y + 1)).or_default().push((x, y));
+                }
+            }
+        }
+
+        let mut outlines = Vec::new();
+
+        // from the smallest corner left, the order of a hashmap would give a different mesh every run
+        while let Some(&start) = edges.keys().min() {
+            let mut outline = Vec::new();
+            let mut at = start;
+
+            while let Some(next) = edges.get_mut(&at).and_then(|ends| ends.pop()) {
+                if edges[&at].is_empty() {
+                    edges.remove(&at);
+                }
+
+                outline.push([at.0 as f32, at.1 as f32]);
+                at = next;
+            }
+
+            edges.remove(&at);
+            outlines.push(outline);
+        }
+
+        outlines
+    }
+
+    // douglas-peucker on a closed loop, split in two at the point the farthest from the first one
+    fn simplify(outline: &[[f32; 2]], tolerance: f32) -> Vec<[f32; 2]> {
+        if outline.len() < 4 {
+            return outline.to_vec();
+        }
+
+        let far = (1..outline.len())
+            .max_by(|a, b| {
+                Self::length(outline[0], outline[*a])
+                    .partial_cmp(&Self::length(outline[0], outline[*b]))
+                    .unwrap()
+            })
+            .unwrap();
+
+        let mut first = outline[..=far].to_vec();
+        let mut second = outline[far..].to_vec();
+        second.push(outline[0]);
+
+        first = Self::simplify_line(&first, tolerance);
+        second = Self::simplify_line(&second, tolerance);
+
+        first.pop();
+        second.pop();
+        first.append(&mut second);
+
+        first
+    }
+
+    fn simplify_line(line: &[[f32; 2]], tolerance: f32) -> Vec<[f32; 2]> {
+        let mut keep = vec![false; line.len()];
+        keep[0] = true;
+        keep[line.len() - 1] = true;
+
+        let mut ranges = vec![(0, line.len() - 1)];
+
+        while let Some((from, to)) = ranges.pop() {
+            let farthest = (from + 1..to)
+                .map(|i| (i, Self::distance_to_segment(line[i], line[from], line[to])))
+                .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
+
+            if let Some((i, distance)) = farthest {
+                if distance > tolerance {
+                    keep[i] = true;
+                    ranges.push((from, i));
+                    ranges.push((i, to));
+                }
+            }
+        }
+
+        line.iter()
+            .zip(keep.iter())
+            .filter(|(_, keep)| **keep)
+            .map(|(point, _)| *point)
+            .collect()
+    }
+
+    // adds vertices along the long sides so the triangles near the outline stay small
+    fn resample(&self, outline: &[[f32; 2]]) -> Vec<[f32; 2]> {
+        let mut points = Vec::new();
+
+        for (i, a) in outline.iter().enumerate() {
+            let b = outline[(i + 1) % outline.len()];
+            let steps = (Self::length(*a, b) / self.density).ceil().max(1.0) as usize;
+
+            for step in 0..steps {
+                let t = step as f32 / steps as f32;
+                points.push([a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t]);
+            }
+        }
+
+        points
+    }
+
+    // even-odd rule so the holes of a part stay empty
+    fn inside(outlines: &[Vec<[f32; 2]>], point: [f32; 2]) -> bool {
+        let mut inside = false;
+
+        for outline in outlines.iter() {
+            for (i, a) in outline.iter().enumerate() {
+                let b = outline[(i + 1) % outline.len()];
+
+                if (a[1] > point[1]) != (b[1] > point[1])
+                    && point[0] < a[0] + (point[1] - a[1]) / (b[1] - a[1]) * (b[0] - a[0])
+                {
+                    inside = !inside;
+                }
+            }
+        }
+
+        inside
+    }
+
+    fn distance_to_outlines(outlines: &[Vec<[f32; 2]>], point: [f32; 2]) -> f32 {
+        outlines
+            .iter()
+            .flat_map(|outline| {
+                outline.iter().enumerate().map(move |(i, a)| {
+                    Self::distance_to_segment(point, *a, outline[(i + 1) % outline.len()])
+                })
+            })
+            .fold(f32::MAX, f32::min)
+    }
+
+    fn distance_to_segment(point: [f32; 2], a: [f32; 2], b: [f32; 2]) -> f32 {
+        let ab = [b[0] - a[0], b[1] - a[1]];
+        let length = ab[0] * ab[0] + ab[1] * ab[1];
+
+        let t = if length > 0.0 {
+            (((point[0] - a[0]) * ab[0] + (point[1] - a[1]) * ab[1]) / length).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+
+        Self::length(point, [a[0] + ab[0] * t, a[1] + ab[1] * t])
+    }
+
+    fn length(a: [f32; 2], b: [f32; 2]) -> f32 {
+        ((b[0] - a[0]).powi(2) + (b[1] - a[1]).powi(2)).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn image(width: u32, height: u32, opaque: impl Fn(u32, u32) -> bool) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| match opaque(x, y) {
+            true => Rgba([255, 255, 255, 255]),
+            false => Rgba([0, 0, 0, 0]),
+        })
+    }
+
+    fn area(frame: &MeshFrame, width: f32, height: f32) -> f32 {
+        frame
+            .indices
+            .chunks(3)
+            .map(|triangle| {
+                let [a, b, c] = [0, 1, 2].map(|i| frame.uvs[triangle[i] as usize]);
+                ((b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])).abs() / 2.0
+                    * width
+                    * height
+            })
+            .sum()
+    }
+
+    #[test]
+    fn a_pixel_is_traced_by_its_corners() {
+        let outlines = AutoMesh::trace(&[true], 1, 1);
+
+        assert_eq!(outlines.len(), 1);
+        assert_eq!(outlines[0].len(), 4);
+    }
+
+    #[test]
+    fn simplify_keeps_the_corners_of_a_square() {
+        let mut square = Vec::new();
+        square.extend((0..10).map(|x| [x as f32, 0.0]));
+        square.extend((0..10).map(|y| [10.0, y as f32]));
+        square.extend((0..10).map(|x| [10.0 - x as f32, 10.0]));
+        square.extend((0..10).map(|y| [0.0, 10.0 - y as f32]));
+
+        let simplified = AutoMesh::simplify(&square, 0.5);
+
+        assert_eq!(simplified.len(), 4);
+        for corner in [[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0]] {
+            assert!(simplified.contains(&corner));
+        }
+    }
+
+    #[test]
+    fn holes_are_outside() {
+        let outer = vec![[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0]];
+        let hole = vec![[3.0, 3.0], [7.0, 3.0], [7.0, 7.0], [3.0, 7.0]];
+        let outlines = vec![outer, hole];
+
+        assert!(AutoMesh::inside(&outlines, [1.0, 1.0]));
+        assert!(!AutoMesh::inside(&outlines, [5.0, 5.0]));
+        assert!(!AutoMesh::inside(&outlines, [11.0, 5.0]));
+    }
+
+    #[test]
+    fn an_opaque_cell_is_covered() {
+        let auto_mesh = AutoMesh {
+            margin: 0,
+            ..AutoMesh::default()
+        };
+        let frame = auto_mesh.frame(&image(64, 32, |_, _| true));
+
+        assert!(!frame.indices.is_empty());
+        assert!((area(&frame, 64.0, 32.0) - 64.0 * 32.0).abs() < 1.0);
+        assert!(frame
+            .uvs
+            .iter()
+            .all(|uv| (0.0..=1.0).contains(&uv[0]) && (0.0..=1.0).contains(&uv[1])));
+    }
+
+    #[test]
+    fn a_transparent_cell_has_no_triangles() {
+        let frame = AutoMesh::default().frame(&image(16, 16, |_, _| false));
+
+        assert!(frame.indices.is_empty());
+    }
+
+    #[test]
+    fn the_mesh_follows_the_opaque_pixels() {
+        let auto_mesh = AutoMesh {
+            margin: 0,
+            density: 8.0,
+            ..AutoMesh::default()
+        };
+
+        // a ring, its middle has to stay empty
+        let frame = auto_mesh.frame(&image(40, 40, |x, y| {
+            !(10..30).contains(&x) || !(10..30).contains(&y)
+        }));
+
+        assert!((area(&frame, 40.0, 40.0) - (40.0 * 40.0 - 20.0 * 20.0)).abs() < 1.0);
+    }
+
+    #[test]
+    fn settings_come_from_the_material() {
+        let mut unknown_param = AHashMap::new();
+        unknown_param.insert("meshDensity".to_string(), "0".to_string());
+        unknown_param.insert("meshAlpha".to_string(), "100".to_string());
+
+        let auto_mesh = AutoMesh::from_mat(&unknown_param);
+
+        assert_eq!(auto_mesh.density, 1.0);
+        assert_eq!(auto_mesh.alpha_threshold, 100);
+        assert_eq!(auto_mesh.margin, AutoMesh::default().margin);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::{
+    buffer_update::{ArcDataIndex, DataManager},
+    keyform::Pose,
+    mesh::Mesh,
+    model::{Model, ModelVertex},
+    sprite_selector::SpriteSelector,
+    transform::GlobalTransform,
+    type_def::*,
+};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use specs::{
+    prelude::ComponentEvent, shred::DynamicSystemData, shrev::EventIterator, BitSet, Component,
+    DenseVecStorage, Entities, Entity, FlaggedStorage, ReadStorage, ReaderId, System, VecStorage,
+    World, WorldExt, Write, WriteStorage,
+};
+
+// a bone is moved like any other entity, with its Position, Rotation and Scale in the hierarchy
+#[derive(Debug, Clone, Default)]
+pub struct Bone;
+
+impl Component for Bone {
+    type Storage = DenseVecStorage<Self>;
+}
+
+// the four heaviest bones of a vertex, indices in the Skin of its part
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VertexWeights {
+    pub bones: [u32; 4],
+    pub weights: [f32; 4], // adding up to 1, all 0 when the vertex isn't weighted
+}
+
+impl VertexWeights {
+    pub fn new(weights: &[(u32, Real)]) -> Self {
+        let mut weights = weights
+            .iter()
+            .filter(|(_, weight)| *weight > 0.0)
+            .copied()
+            .collect::<Vec<_>>();
+        weights.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap());
+        weights.truncate(4);
+
+        let total = weights.iter().map(|(_, weight)| weight).sum::<Real>();
+        let mut vertex = Self::default();
+
+        for (i, (bone, weight)) in weights.into_iter().enumerate() {
+            vertex.bones[i] = bone;
+            vertex.weights[i] = weight / total;
+        }
+
+        vertex
+    }
+}
+
+// the bones a part is weighted to, kept on the cpu next to the vertices they move
+#[derive(Debug, Clone, Default)]
+pub struct Skin {
+    pub bones: Vec<Entity>,
+    pub binds: Vec<Transform>, // from the local space of the part to the one of each bone in the setup pose
+    pub weights: Vec<VertexWeights>, // one per vertex of the part
+}
+
+impl Component for Skin {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+impl Skin {
+    pub fn with_binds(
+        bones: Vec<Entity>,
+        binds: Vec<Transform>,
+        weights: Vec<VertexWeights>,
+    ) -> Self {
+        debug_assert_eq!(bones.len(), binds.len());
+
+        Self {
+            bones,
+            binds,
+            weights,
+        }
+    }
+}
+
+fn transform(transform: &Transform, point: [f32; 2]) -> [f32; 2] {
+    let point = transform.transform_point(&Point::new(point[0], point[1]));
+    [point.x, point.y]
+}
+
+// moves the vertices of the skinned parts with their bones,
+// a skinned part ignores the deformers above it
+#[derive(Default)]
+pub struct SkinUpdate {
+    pub dirty: BitSet,
+    pub reader_id_skin: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_pose: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_transform: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_sprite_selector: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_mesh: Option<ReaderId<ComponentEvent>>,
+}
+
+impl SkinUpdate {
+    fn event_update(&mut self, events: EventIterator<'_, ComponentEvent>) {
+        for event in events.into_iter() {
+            match event {
+                ComponentEvent::Modified(id) | ComponentEvent::Inserted(id) => {
+                    self.dirty.add(*id);
+                }
+                ComponentEvent::Removed(_id) => (),
+            }
+        }
+    }
+}
+
+impl<'a> System<'a> for SkinUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, DataManager<ModelVertex>>,
+        WriteStorage<'a, ArcDataIndex<ModelVertex>>, // mutable to flag a modification in the flagstorage in order to update its buffer
+        WriteStorage<'a, Skin>,
+        ReadStorage<'a, Pose>,
+        ReadStorage<'a, GlobalTransform>,
+        ReadStorage<'a, SpriteSelector>,
+        ReadStorage<'a, Mesh>,
+        ReadStorage<'a, Model>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut vertices_data,
+            mut vertices_indices,
+            mut skins,
+            poses,
+            transforms,
+            sprite_selectors,
+            meshes,
+            materials,
+        ): Self::SystemData,
+    ) {
+        use specs::Join;
+
+        self.dirty.clear();
+
+        let events_skin = skins.channel().read(self.reader_id_skin.as_mut().unwrap());
+        let events_pose = poses.channel().read(self.reader_id_pose.as_mut().unwrap());
+        let events_transform = transforms
+            .channel()
+            .read(self.reader_id_transform.as_mut().unwrap());
+        let events_sprite_selector = sprite_selectors
+            .channel()
+            .read(self.reader_id_sprite_selector.as_mut().unwrap());
+        let events_mesh = meshes.channel().read(self.reader_id_mesh.as_mut().unwrap());
+
+        self.event_update(events_skin);
+        self.event_update(events_pose);
+        self.event_update(events_transform);
+        self.event_update(events_sprite_selector);
+        self.event_update(events_mesh);
+
+        let global = |entity| {
+            transforms
+                .get(entity)
+                .map(|global| global.0)
+                .unwrap_or_else(Transform::identity)
+        };
+
+        // a bone in the local space of the part it moves
+        let relative = |inverse: &Transform, bone| inverse * global(bone);
+
+        // a part follows its own transform and the ones of its bones
+        let dirty = &self.dirty;
+        let parts = (&entities, &vertices_indices, &skins)
+            .join()
+            .filter(|(entity, _, skin)| {
+                dirty.contains(entity.id())
+                    || skin.bones.iter().any(|bone| dirty.contains(bone.id()))
+            })
+            .map(|(entity, _, _)| entity)
+            .collect::<Vec<_>>();
+
+        for entity in parts.iter() {
+            let skin = skins.get(*entity).unwrap();
+
+            // the setup pose is taken from the bones when the skin was made without it
+            if skin.binds.len() != skin.bones.len() {
+                let inverse = global(*entity)
+                    .try_inverse()
+                    .unwrap_or_else(Transform::identity);
+                let binds = skin
+                    .bones
+                    .iter()
+                    .map(|bone| {
+                        relative(&inverse, *bone)
+                            .try_inverse()
+                            .unwrap_or_else(Transform::identity)
+                    })
+                    .collect();
+
+                skins.get_mut(*entity).unwrap().binds = binds;
+            }
+        }
+
+        let skinned = parts
+            .into_par_iter()
+            .filter_map(|entity| {
+                let skin = skins.get(entity)?;
+                let mesh = meshes.get(entity)?;
+                let sprite_selector = sprite_selectors.get(entity)?;
+                let material = materials.get(entity)?;
+
+                let inverse = global(entity)
+                    .try_inverse()
+                    .unwrap_or_else(Transform::identity);
+
+                // how each bone moved away from the setup pose, in the local space of the part
+                let moves = skin
+                    .bones
+                    .iter()
+                    .zip(skin.binds.iter())
+                    .map(|(bone, bind)| relative(&inverse, *bone) * bind)
+                    .collect::<Vec<_>>();
+
+                // the keyforms give the unskinned positions, without them the mesh is at rest
+                let positions = match poses.get(entity) {
+                    Some(pose) => pose.vertices.clone(),
+                    None => ModelVertex::new(mesh, sprite_selector, &material.0)
+                        .into_iter()
+                        .map(|vertex| vertex.position)
+                        .collect(),
+                };
+
+                Some((entity, positions, moves, &skin.weights))
+            })
+            .collect::<Vec<_>>();
+
+        for (entity, positions, moves, weights) in skinned.into_iter() {
+            let vertex_index = vertices_indices.get_mut(entity).unwrap();
+            let vertex_index = vertex_index.0.lock().unwrap();
+            let vertices = vertices_data.get_mut_range(&vertex_index);
+
+            vertices
+                .iter_mut()
+                .zip(positions.iter())
+                .zip(weights.iter())
+                .for_each(|((vertex, position), vertex_weights)| {
+                    let mut skinned = [0.0, 0.0];
+                    let mut total = 0.0;
+
+                    for (bone, weight) in vertex_weights
+                        .bones
+                        .iter()
+                        .zip(vertex_weights.weights.iter())
+                    {
+                        if let (Some(bone_move), true) = (moves.get(*bone as usize), *weight > 0.0)
+                        {
+                            let moved = transform(bone_move, *position);
+                            skinned[0] += moved[0] * weight;
+                            skinned[1] += moved[1] * weight;
+                            total += weight;
+                        }
+                    }
+
+                    // a vertex weighted to no bone stays where it is
+                    vertex.position = if total > 0.0 {
+                        [skinned[0] / total, skinned[1] / total]
+                    } else {
+                        *position
+                    };
+                });
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(&self.accessor(), world);
+        self.reader_id_skin = Some(world.write_component::<Skin>().register_reader());
+        self.reader_id_pose = Some(world.write_component::<Pose>().register_reader());
+        self.reader_id_transform =
+            Some(world.write_component::<GlobalTransform>().register_reader());
+        self.reader_id_sprite_selector =
+            Some(world.write_component::<SpriteSelector>().register_reader());
+        self.reader_id_mesh = Some(world.write_component::<Mesh>().register_reader());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_four_heaviest_bones_are_kept() {
+        let vertex =
+            VertexWeights::new(&[(0, 0.1), (1, 0.4), (2, 0.0), (3, 0.2), (4, 0.1), (5, 0.2)]);
+
+        assert_eq!(vertex.bones[0], 1);
+        assert!(!vertex.bones.contains(&2));
+        assert!((vertex.weights.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        assert!((vertex.weights[0] - 0.4 / 0.9).abs() < 1e-5);
+    }
+
+    #[test]
+    fn a_vertex_without_weights_has_none() {
+        assert_eq!(VertexWeights::new(&[]), VertexWeights::default());
+        assert_eq!(VertexWeights::new(&[(3, 0.0)]), VertexWeights::default());
+    }
+
+    #[test]
+    fn weights_add_up_to_one() {
+        let vertex = VertexWeights::new(&[(0, 2.0), (1, 2.0)]);
+
+        assert_eq!(vertex.bones[..2], [0, 1]);
+        assert_eq!(vertex.weights, [0.5, 0.5, 0.0, 0.0]);
+    }
+}
@@ -170,6 +170,24 @@ impl<T: Pod + Send + Sync + Default + Sized> DataManager<T> {
         }
     }
 
+    // appends a new idle range, only meant to be used while building the data before its buffer is created
+    pub fn push(&mut self, mut data: Vec<T>, waiting_time: Duration) -> ArcDataIndex<T> {
+        debug_assert!(self.active_data.is_empty());
+
+        let index = ArcDataIndex::new(
+            self.idle_size,
+            self.idle_data.len(),
+            data.len(),
+            waiting_time,
+        );
+
+        self.idle_size += 1;
+        self.idle_data.append(&mut data);
+        self.indices.push(Arc::downgrade(&index.0));
+
+        index
+    }
+
     pub fn get_mut_range(&mut self, index: &DataIndex<T>) -> &mut [T] {
         let range = index.get_range::<usize>(self, false);
 
@@ -226,7 +244,7 @@ impl<T: Pod + Send + Sync + Default + Sized> DataBufferUpdater<T> {
         {
             let index = data.indices[index_buf].upgrade()?;
             let mut index = index.lock().unwrap();
-            last_range = index.get_range::<usize>(&data, false);
+            last_range = index.get_range::<usize>(data, false);
             amount = index.amount;
 
             index.active = false;
@@ -300,7 +318,7 @@ impl<'a, T: Pod + Send + Sync + Default + Sized> System<'a> for DataBufferUpdate
                 })
                 .collect::<Vec<_>>();
 
-            updates.sort_by(|(a, _), (b, _)| b.cmp(&a));
+            updates.sort_by(|(a, _), (b, _)| b.cmp(a));
 
             //let mut to_fix = Vec::new();
 
@@ -401,7 +419,7 @@ impl<'a, T: Pod + Send + Sync + Default + Sized> System<'a> for DataBufferUpdate
                     queue.0.write_buffer(
                         &buffer.buffer,
                         at as u64 * std::mem::size_of::<T>() as u64,
-                        bytemuck::cast_slice(&content),
+                        bytemuck::cast_slice(content),
                     );
                 }
             }
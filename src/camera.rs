@@ -130,36 +130,32 @@ impl<'a> System<'a> for ActorUpdate {
     type SystemData = (
         ReadStorage<'a, Model>,
         WriteStorage<'a, Rotation>,
-        WriteStorage<'a, SpriteSelector>,
+        ReadStorage<'a, SpriteSelector>,
         ReadStorage<'a, Translation>,
         Read<'a, Time>,
     );
 
+    // the sprites play their clips on their own, see SpriteSelector::play_clip
     fn run(
         &mut self,
-        (materials, mut rotations, mut sprite_selectors, offsets, time): Self::SystemData,
+        (materials, mut rotations, sprite_selectors, offsets, time): Self::SystemData,
     ) {
         use specs::Join;
 
         let time_delta = time.delta.as_secs_f32();
 
-        sprite_selectors.set_event_emission(false);
-        for (_, mut rotation, sprite_selector, _) in (
+        for (_, mut rotation, _, _) in (
             &materials,
             &mut rotations.restrict_mut(),
-            &mut sprite_selectors,
+            &sprite_selectors,
             &offsets,
         )
             .join()
         {
             if time.on {
                 rotation.get_mut_unchecked().0 *= Rotator::new(time_delta);
-                sprite_selector.play();
-            } else {
-                sprite_selector.stop();
             }
         }
-        sprite_selectors.set_event_emission(true);
     }
 }
 
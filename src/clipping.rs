@@ -0,0 +1,50 @@
+use specs::{Component, Entity, VecStorage};
+use std::sync::Arc;
+
+// a part only drawn where its masks are (or where they aren't when inverted),
+// ex: the irises clipped by the white of the eyes
+#[derive(Debug, Clone)]
+pub struct Clipping {
+    pub masks: Vec<Entity>,
+    pub inverted: bool,
+}
+
+impl Component for Clipping {
+    type Storage = VecStorage<Self>;
+}
+
+impl Clipping {
+    pub fn new(masks: Vec<Entity>, inverted: bool) -> Self {
+        Self { masks, inverted }
+    }
+}
+
+// the pipelines used to draw the masks into the stencil and the parts clipped by them
+#[derive(Debug)]
+pub struct ClipPipelines {
+    pub mask: Arc<wgpu::RenderPipeline>,
+    pub clipped: Arc<wgpu::RenderPipeline>,
+    pub inverted: Arc<wgpu::RenderPipeline>,
+}
+
+impl ClipPipelines {
+    // the stencil reference is the one given to the render pass before drawing
+    pub fn stencil(
+        compare: wgpu::CompareFunction,
+        pass_op: wgpu::StencilOperation,
+    ) -> wgpu::StencilState {
+        let face = wgpu::StencilFaceState {
+            compare,
+            fail_op: wgpu::StencilOperation::Keep,
+            depth_fail_op: wgpu::StencilOperation::Keep,
+            pass_op,
+        };
+
+        wgpu::StencilState {
+            front: face,
+            back: face,
+            read_mask: 0xff,
+            write_mask: 0xff,
+        }
+    }
+}
@@ -1,4 +1,3 @@
-use rapier::prelude::ColliderBuilder;
 use specs::{Component, DenseVecStorage, FlaggedStorage};
 
 use crate::RapierColliderHandle;
@@ -0,0 +1,608 @@
+use crate::{
+    actor::Parent,
+    bone::Skin,
+    buffer_update::{ArcDataIndex, DataManager},
+    keyform::Pose,
+    mesh::Mesh,
+    model::{Model, ModelVertex},
+    parameter::Parameters,
+    sprite_selector::SpriteSelector,
+    transform::GlobalTransform,
+    type_def::*,
+};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::Deserialize;
+use specs::{
+    prelude::ComponentEvent, shred::DynamicSystemData, shrev::EventIterator, BitSet, Component,
+    Entities, Entity, FlaggedStorage, ReadStorage, ReaderId, System, VecStorage, World, WorldExt,
+    Write, WriteStorage,
+};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarpInterpolation {
+    Bilinear, // each cell of the grid on its own, like live2d
+    Bezier,   // the whole grid as a single smooth bezier patch
+}
+
+// a grid of control points bending everything below it in the hierarchy,
+// the control points are given by the Pose of the entity (its keyforms) or sit at rest on the grid
+#[derive(Debug, Clone)]
+pub struct WarpDeformer {
+    pub origin: [f32; 2], // corner of the grid at rest in the local space of the deformer
+    pub size: [f32; 2],
+    pub columns: usize,
+    pub rows: usize,
+    pub interpolation: WarpInterpolation,
+}
+
+impl Component for WarpDeformer {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+impl WarpDeformer {
+    pub fn new(
+        origin: [f32; 2],
+        size: [f32; 2],
+        columns: usize,
+        rows: usize,
+        interpolation: WarpInterpolation,
+    ) -> Self {
+        Self {
+            origin,
+            size,
+            columns: columns.max(1),
+            rows: rows.max(1),
+            interpolation,
+        }
+    }
+
+    // (columns + 1) * (rows + 1) points, x changing the fastest
+    pub fn rest(&self) -> Vec<[f32; 2]> {
+        (0..=self.rows)
+            .flat_map(|row| {
+                (0..=self.columns).map(move |column| {
+                    [
+                        self.origin[0] + self.size[0] * column as f32 / self.columns as f32,
+                        self.origin[1] + self.size[1] * row as f32 / self.rows as f32,
+                    ]
+                })
+            })
+            .collect()
+    }
+
+    // where a point of the local space of the deformer at rest ends up on the control points
+    fn sample(&self, points: &[[f32; 2]], local: [f32; 2]) -> [f32; 2] {
+        let u = (local[0] - self.origin[0]) / self.size[0];
+        let v = (local[1] - self.origin[1]) / self.size[1];
+
+        match self.interpolation {
+            // the cells on the border stretch linearly outside of the grid
+            WarpInterpolation::Bilinear => {
+                let x = u * self.columns as f32;
+                let y = v * self.rows as f32;
+
+                let column = (x.floor() as i64).clamp(0, self.columns as i64 - 1) as usize;
+                let row = (y.floor() as i64).clamp(0, self.rows as i64 - 1) as usize;
+
+                let (s, t) = (x - column as f32, y - row as f32);
+                let at = |column: usize, row: usize| points[row * (self.columns + 1) + column];
+
+                let (a, b) = (at(column, row), at(column + 1, row));
+                let (c, d) = (at(column, row + 1), at(column + 1, row + 1));
+
+                [
+                    (a[0] * (1.0 - s) + b[0] * s) * (1.0 - t) + (c[0] * (1.0 - s) + d[0] * s) * t,
+                    (a[1] * (1.0 - s) + b[1] * s) * (1.0 - t) + (c[1] * (1.0 - s) + d[1] * s) * t,
+                ]
+            }
+            // outside of the grid the points go on straight from the closest edge of the patch,
+            // the way it goes there is taken from the control points so it is in world space too
+            WarpInterpolation::Bezier => {
+                let (u_in, v_in) = (u.clamp(0.0, 1.0), v.clamp(0.0, 1.0));
+                let (u_out, v_out) = (u - u_in, v - v_in);
+                let mut point = [0.0, 0.0];
+
+                for row in 0..=self.rows {
+                    let weight_v = Self::bernstein(self.rows, row, v_in);
+                    let slope_v = Self::bernstein_slope(self.rows, row, v_in);
+
+                    for column in 0..=self.columns {
+                        let weight_u = Self::bernstein(self.columns, column, u_in);
+                        let slope_u = Self::bernstein_slope(self.columns, column, u_in);
+
+                        let weight = weight_u * weight_v
+                            + u_out * slope_u * weight_v
+                            + v_out * weight_u * slope_v;
+                        let control = points[row * (self.columns + 1) + column];
+
+                        point[0] += control[0] * weight;
+                        point[1] += control[1] * weight;
+                    }
+                }
+
+                point
+            }
+        }
+    }
+
+    fn bernstein(n: usize, i: usize, t: f32) -> f32 {
+        let binomial = (0..i).fold(1.0, |binomial, k| {
+            binomial * (n - k) as f32 / (k + 1) as f32
+        });
+
+        binomial * t.powi(i as i32) * (1.0 - t).powi((n - i) as i32)
+    }
+
+    // derivative of the bernstein polynomial
+    fn bernstein_slope(n: usize, i: usize, t: f32) -> f32 {
+        let before = match i {
+            0 => 0.0,
+            _ => Self::bernstein(n - 1, i - 1, t),
+        };
+        let after = match i == n {
+            true => 0.0,
+            false => Self::bernstein(n - 1, i, t),
+        };
+
+        n as f32 * (before - after)
+    }
+}
+
+// turns and scales everything below it in the hierarchy around its origin,
+// the Pose of the entity (its keyforms) replaces the origin, angle and scale when there is one
+#[derive(Debug, Clone)]
+pub struct RotationDeformer {
+    pub origin: [f32; 2], // in the local space of the deformer, the children are placed around it at rest
+    pub angle: Real,
+    pub scale: Real,
+}
+
+impl Component for RotationDeformer {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+impl RotationDeformer {
+    pub fn new(origin: [f32; 2], angle: Real, scale: Real) -> Self {
+        Self {
+            origin,
+            angle,
+            scale,
+        }
+    }
+}
+
+// what a deformer does to the points given to it, everything in world space
+// so that deformers and parts with different transforms can be chained
+enum Deformation {
+    Warp {
+        warp: WarpDeformer,
+        inverse: Transform,    // world to the local space of the deformer
+        points: Vec<[f32; 2]>, // control points in world space, already moved by the parent deformers
+    },
+    Rotation {
+        rest: [f32; 2],   // origin in world space before anything moves it
+        origin: [f32; 2], // origin in world space, already moved by the parent deformers
+        angle: Real,
+        scale: Real,
+    },
+}
+
+impl Deformation {
+    // a rotation deformer placed in the world by its transform then by the deformer above it,
+    // it turns and scales along with the way its parent bends and stretches
+    fn rotation(
+        parent: Option<&Deformation>,
+        global: &Transform,
+        rest: [f32; 2],
+        origin: [f32; 2],
+        angle: Real,
+        scale: Real,
+    ) -> Self {
+        let moved = transform(global, origin);
+        let (turn, stretch) = parent
+            .map(|parent| parent.turn(moved))
+            .unwrap_or((0.0, 1.0));
+
+        Self::Rotation {
+            rest: transform(global, rest),
+            origin: match parent {
+                Some(parent) => parent.apply(moved),
+                None => moved,
+            },
+            angle: angle + turn,
+            scale: scale * stretch,
+        }
+    }
+
+    fn apply(&self, point: [f32; 2]) -> [f32; 2] {
+        match self {
+            Self::Warp {
+                warp,
+                inverse,
+                points,
+            } => {
+                let local = inverse.transform_point(&Point::new(point[0], point[1]));
+                warp.sample(points, [local.x, local.y])
+            }
+            Self::Rotation {
+                rest,
+                origin,
+                angle,
+                scale,
+            } => {
+                let (sin, cos) = angle.sin_cos();
+                let offset = [(point[0] - rest[0]) * scale, (point[1] - rest[1]) * scale];
+
+                [
+                    origin[0] + offset[0] * cos - offset[1] * sin,
+                    origin[1] + offset[0] * sin + offset[1] * cos,
+                ]
+            }
+        }
+    }
+
+    // how much a small step up from a point gets turned and stretched, so a rotation deformer placed
+    // inside a warp (or another rotation) follows the way its parent bends and scales
+    fn turn(&self, point: [f32; 2]) -> (Real, Real) {
+        const STEP: f32 = 0.001;
+
+        let from = self.apply(point);
+        let to = self.apply([point[0], point[1] + STEP]);
+        let step = [to[0] - from[0], to[1] - from[1]];
+
+        (
+            step[1].atan2(step[0]) - (STEP).atan2(0.0),
+            (step[0] * step[0] + step[1] * step[1]).sqrt() / STEP,
+        )
+    }
+}
+
+fn transform(transform: &Transform, point: [f32; 2]) -> [f32; 2] {
+    let point = transform.transform_point(&Point::new(point[0], point[1]));
+    [point.x, point.y]
+}
+
+// moves the vertices of the parts placed below deformers in the hierarchy
+#[derive(Default)]
+pub struct DeformerUpdate {
+    pub dirty: BitSet,
+    pub reader_id_warp: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_rotation: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_pose: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_parameters: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_transform: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_sprite_selector: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_mesh: Option<ReaderId<ComponentEvent>>,
+}
+
+impl DeformerUpdate {
+    fn event_update(&mut self, events: EventIterator<'_, ComponentEvent>) {
+        for event in events.into_iter() {
+            match event {
+                ComponentEvent::Modified(id) | ComponentEvent::Inserted(id) => {
+                    self.dirty.add(*id);
+                }
+                ComponentEvent::Removed(_id) => (),
+            }
+        }
+    }
+}
+
+impl<'a> System<'a> for DeformerUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, DataManager<ModelVertex>>,
+        WriteStorage<'a, ArcDataIndex<ModelVertex>>, // mutable to flag a modification in the flagstorage in order to update its buffer
+        ReadStorage<'a, WarpDeformer>,
+        ReadStorage<'a, RotationDeformer>,
+        ReadStorage<'a, Pose>,
+        ReadStorage<'a, Parameters>,
+        ReadStorage<'a, GlobalTransform>,
+        ReadStorage<'a, Parent>,
+        ReadStorage<'a, SpriteSelector>,
+        ReadStorage<'a, Mesh>,
+        ReadStorage<'a, Model>,
+        ReadStorage<'a, Skin>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut vertices_data,
+            mut vertices_indices,
+            warps,
+            rotations,
+            poses,
+            parameters,
+            transforms,
+            parents,
+            sprite_selectors,
+            meshes,
+            materials,
+            skins,
+        ): Self::SystemData,
+    ) {
+        use specs::{hibitset::BitSetLike, Join};
+
+        self.dirty.clear();
+
+        let events_warp = warps.channel().read(self.reader_id_warp.as_mut().unwrap());
+        let events_rotation = rotations
+            .channel()
+            .read(self.reader_id_rotation.as_mut().unwrap());
+        let events_pose = poses.channel().read(self.reader_id_pose.as_mut().unwrap());
+        let events_parameters = parameters
+            .channel()
+            .read(self.reader_id_parameters.as_mut().unwrap());
+        let events_transform = transforms
+            .channel()
+            .read(self.reader_id_transform.as_mut().unwrap());
+        let events_sprite_selector = sprite_selectors
+            .channel()
+            .read(self.reader_id_sprite_selector.as_mut().unwrap());
+        let events_mesh = meshes.channel().read(self.reader_id_mesh.as_mut().unwrap());
+
+        self.event_update(events_warp);
+        self.event_update(events_rotation);
+        self.event_update(events_pose);
+        self.event_update(events_parameters);
+        self.event_update(events_transform);
+        self.event_update(events_sprite_selector);
+        self.event_update(events_mesh);
+
+        // a deformer moves everything below it, so any change redoes the whole chain
+        if self.dirty.is_empty() || (warps.is_empty() && rotations.is_empty()) {
+            return;
+        }
+
+        let is_deformer = |entity| warps.contains(entity) || rotations.contains(entity);
+
+        // the closest deformer above an entity
+        let deformer_of = |mut entity: Entity| loop {
+            entity = parents.get(entity)?.entity;
+
+            if is_deformer(entity) {
+                return Some(entity);
+            }
+        };
+
+        let depth = |mut entity: Entity| {
+            let mut depth = 0;
+            while let Some(parent) = parents.get(entity) {
+                entity = parent.entity;
+                depth += 1;
+            }
+            depth
+        };
+
+        // parents first so that each deformer can be moved by the ones above it
+        let mut deformers = (&entities, warps.mask() | rotations.mask())
+            .join()
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        deformers.sort_by_key(|entity| depth(*entity));
+
+        let mut deformations: HashMap<Entity, Deformation> = HashMap::new();
+
+        for entity in deformers.into_iter() {
+            let global = transforms
+                .get(entity)
+                .map(|global| global.0)
+                .unwrap_or_else(Transform::identity);
+
+            let parent = deformer_of(entity).and_then(|parent| deformations.get(&parent));
+            let to_world = |point| {
+                let point = transform(&global, point);
+
+                match parent {
+                    Some(parent) => parent.apply(point),
+                    None => point,
+                }
+            };
+
+            // an entity is only ever one kind of deformer, a warp wins if it has both
+            let deformation = if let Some(warp) = warps.get(entity) {
+                let rest = warp.rest();
+                let points = match poses.get(entity) {
+                    Some(pose) if pose.vertices.len() == rest.len() => &pose.vertices,
+                    _ => &rest,
+                };
+
+                Deformation::Warp {
+                    warp: warp.clone(),
+                    inverse: global.try_inverse().unwrap_or_else(Transform::identity),
+                    points: points.iter().map(|point| to_world(*point)).collect(),
+                }
+            } else {
+                let rotation = rotations.get(entity).unwrap();
+                let (origin, angle, scale) = match poses.get(entity) {
+                    Some(pose) if pose.vertices.len() == 1 => {
+                        (pose.vertices[0], pose.angle, pose.scale)
+                    }
+                    _ => (rotation.origin, rotation.angle, rotation.scale),
+                };
+
+                Deformation::rotation(parent, &global, rotation.origin, origin, angle, scale)
+            };
+
+            deformations.insert(entity, deformation);
+        }
+
+        let parts = (
+            &entities,
+            &vertices_indices,
+            &meshes,
+            &sprite_selectors,
+            &materials,
+            !&skins, // moved by their bones instead
+        )
+            .join()
+            .filter_map(|(entity, _, mesh, sprite_selector, material, _)| {
+                Some((
+                    entity,
+                    deformer_of(entity)?,
+                    mesh,
+                    sprite_selector,
+                    material,
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        let deformed = parts
+            .into_par_iter()
+            .map(|(entity, deformer, mesh, sprite_selector, material)| {
+                let global = transforms
+                    .get(entity)
+                    .map(|global| global.0)
+                    .unwrap_or_else(Transform::identity);
+                let inverse = global.try_inverse().unwrap_or_else(Transform::identity);
+                let deformation = &deformations[&deformer];
+
+                // the keyforms give the undeformed positions, without them the mesh is at rest
+                let positions = match poses.get(entity) {
+                    Some(pose) => pose.vertices.clone(),
+                    None => ModelVertex::new(mesh, sprite_selector, &material.0)
+                        .into_iter()
+                        .map(|vertex| vertex.position)
+                        .collect(),
+                };
+
+                let positions = positions
+                    .into_iter()
+                    .map(|position| {
+                        transform(&inverse, deformation.apply(transform(&global, position)))
+                    })
+                    .collect::<Vec<_>>();
+
+                (entity, positions)
+            })
+            .collect::<Vec<_>>();
+
+        for (entity, positions) in deformed.into_iter() {
+            let vertex_index = vertices_indices.get_mut(entity).unwrap();
+            let vertex_index = vertex_index.0.lock().unwrap();
+            let vertices = vertices_data.get_mut_range(&vertex_index);
+
+            vertices
+                .iter_mut()
+                .zip(positions.iter())
+                .for_each(|(vertex, position)| vertex.position = *position);
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(&self.accessor(), world);
+        self.reader_id_warp = Some(world.write_component::<WarpDeformer>().register_reader());
+        self.reader_id_rotation = Some(
+            world
+                .write_component::<RotationDeformer>()
+                .register_reader(),
+        );
+        self.reader_id_pose = Some(world.write_component::<Pose>().register_reader());
+        self.reader_id_parameters = Some(world.write_component::<Parameters>().register_reader());
+        self.reader_id_transform =
+            Some(world.write_component::<GlobalTransform>().register_reader());
+        self.reader_id_sprite_selector =
+            Some(world.write_component::<SpriteSelector>().register_reader());
+        self.reader_id_mesh = Some(world.write_component::<Mesh>().register_reader());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: [f32; 2], b: [f32; 2]) -> bool {
+        (a[0] - b[0]).abs() < 1e-4 && (a[1] - b[1]).abs() < 1e-4
+    }
+
+    #[test]
+    fn a_warp_at_rest_moves_nothing() {
+        for interpolation in [WarpInterpolation::Bilinear, WarpInterpolation::Bezier] {
+            let warp = WarpDeformer::new([-1.0, -2.0], [2.0, 4.0], 3, 2, interpolation);
+            let rest = warp.rest();
+
+            for point in [
+                [0.0, 0.0],
+                [-1.0, -2.0],
+                [0.5, 1.5],
+                [3.0, -5.0],
+                [-4.0, 6.0],
+            ] {
+                assert!(close(warp.sample(&rest, point), point), "{:?}", point);
+            }
+        }
+    }
+
+    #[test]
+    fn outside_of_the_grid_follows_the_control_points() {
+        // the control points in a world where the deformer is twice as big and turned a quarter
+        let to_world = |[x, y]: [f32; 2]| [-2.0 * y, 2.0 * x];
+
+        for interpolation in [WarpInterpolation::Bilinear, WarpInterpolation::Bezier] {
+            let warp = WarpDeformer::new([0.0, 0.0], [1.0, 1.0], 2, 2, interpolation);
+            let points = warp.rest().into_iter().map(to_world).collect::<Vec<_>>();
+
+            for point in [[0.5, 0.5], [2.0, 0.5], [-1.0, 3.0], [0.25, -2.0]] {
+                assert!(
+                    close(warp.sample(&points, point), to_world(point)),
+                    "{:?} {:?}",
+                    interpolation,
+                    point
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bilinear_cells_move_on_their_own() {
+        let warp = WarpDeformer::new([0.0, 0.0], [2.0, 1.0], 2, 1, WarpInterpolation::Bilinear);
+        let mut points = warp.rest();
+        points[1][1] += 1.0; // the middle of the top edge
+
+        assert!(close(warp.sample(&points, [1.0, 0.0]), [1.0, 1.0]));
+        assert!(close(warp.sample(&points, [0.5, 0.0]), [0.5, 0.5]));
+        assert!(close(warp.sample(&points, [1.5, 1.0]), [1.5, 1.0]));
+    }
+
+    #[test]
+    fn a_rotation_in_a_rotation_is_scaled_by_its_parent() {
+        let identity = Transform::identity();
+
+        // the parent doubles everything around the origin, the child sits at (1, 0)
+        let parent = Deformation::rotation(None, &identity, [0.0, 0.0], [0.0, 0.0], 0.0, 2.0);
+        let child =
+            Deformation::rotation(Some(&parent), &identity, [1.0, 0.0], [1.0, 0.0], 0.0, 1.0);
+
+        assert!(close(child.apply([1.0, 0.0]), [2.0, 0.0]));
+        assert!(close(child.apply([2.0, 0.0]), [4.0, 0.0]));
+
+        // a quarter turn of the parent turns the child along with it
+        let parent = Deformation::rotation(
+            None,
+            &identity,
+            [0.0, 0.0],
+            [0.0, 0.0],
+            std::f32::consts::FRAC_PI_2,
+            2.0,
+        );
+        let child =
+            Deformation::rotation(Some(&parent), &identity, [1.0, 0.0], [1.0, 0.0], 0.0, 1.0);
+
+        assert!(close(child.apply([2.0, 0.0]), [0.0, 4.0]));
+    }
+
+    #[test]
+    fn bernstein_slopes_add_up_to_nothing() {
+        for t in [0.0, 0.3, 1.0] {
+            let total = (0..=3)
+                .map(|i| WarpDeformer::bernstein_slope(3, i, t))
+                .sum::<f32>();
+
+            assert!(total.abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use crate::{
+    actor::{DeviceInfo, Time},
+    parameter::Parameters,
+    type_def::*,
+};
+use anyhow::Result;
+use serde::Deserialize;
+use specs::{Component, DenseVecStorage, Entities, Read, System, WriteStorage};
+use std::{f32::consts::PI, path::Path, sync::Arc};
+use winit::event::{DeviceEvent, ElementState, KeyboardInput, VirtualKeyCode};
+
+// the layout of a cubism exp3.json file
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Exp3 {
+    fade_in_time: Option<Real>,
+    fade_out_time: Option<Real>,
+    #[serde(default)]
+    parameters: Vec<Exp3Parameter>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Exp3Parameter {
+    id: String,
+    value: Real,
+    #[serde(default)]
+    blend: ExpressionBlend,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum ExpressionBlend {
+    #[default]
+    Add, // added to the value of the motion
+    Multiply,
+    Overwrite,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExpressionParameter {
+    pub id: String,
+    pub value: Real,
+    pub blend: ExpressionBlend,
+}
+
+impl ExpressionParameter {
+    // the value of the parameter once this is applied with a weight
+    pub fn apply(&self, value: Real, weight: Real) -> Real {
+        match self.blend {
+            ExpressionBlend::Add => value + self.value * weight,
+            ExpressionBlend::Multiply => value * (1.0 + (self.value - 1.0) * weight),
+            ExpressionBlend::Overwrite => value + (self.value - value) * weight,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Expression {
+    pub name: String,
+    pub fade_in: Real,
+    pub fade_out: Real,
+    pub parameters: Vec<ExpressionParameter>,
+}
+
+impl Expression {
+    // named after the file, smile.exp3.json is smile
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+
+        Self::from_json(&name, &std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(name: &str, json: &str) -> Result<Self> {
+        let expression: Exp3 = serde_json::from_str(json)?;
+
+        // cubism fades for a second when the expression doesn't say otherwise
+        Ok(Self {
+            name: name.to_string(),
+            fade_in: expression.fade_in_time.unwrap_or(1.0).max(0.0),
+            fade_out: expression.fade_out_time.unwrap_or(1.0).max(0.0),
+            parameters: expression
+                .parameters
+                .into_iter()
+                .map(|parameter| ExpressionParameter {
+                    id: parameter.id,
+                    value: parameter.value,
+                    blend: parameter.blend,
+                })
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayingExpression {
+    pub expression: Arc<Expression>,
+    pub time: Real,
+    pub fade_out_at: Option<Real>, // time at which it was asked to stop
+}
+
+impl PlayingExpression {
+    fn weight(&self) -> Real {
+        let ease = |t: Real| 0.5 - 0.5 * (t.clamp(0.0, 1.0) * PI).cos();
+
+        let fade_in = match self.expression.fade_in > 0.0 {
+            true => ease(self.time / self.expression.fade_in),
+            false => 1.0,
+        };
+
+        let fade_out = match (self.fade_out_at, self.expression.fade_out > 0.0) {
+            (Some(at), true) => 1.0 - ease((self.time - at) / self.expression.fade_out),
+            (Some(_), false) => 0.0,
+            (None, _) => 1.0,
+        };
+
+        fade_in * fade_out
+    }
+
+    fn is_gone(&self) -> bool {
+        self.fade_out_at
+            .is_some_and(|at| self.time >= at + self.expression.fade_out)
+    }
+}
+
+// the expressions of a puppet, put next to its Parameters and applied over the motions
+#[derive(Debug, Clone, Default)]
+pub struct ExpressionPlayer {
+    pub expressions: Vec<Arc<Expression>>,
+    pub playing: Vec<PlayingExpression>, // applied in order, the last one over the others
+}
+
+impl Component for ExpressionPlayer {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl ExpressionPlayer {
+    pub fn new(expressions: Vec<Expression>) -> Self {
+        Self {
+            expressions: expressions.into_iter().map(Arc::new).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn expression(&self, name: &str) -> Option<Arc<Expression>> {
+        self.expressions
+            .iter()
+            .find(|expression| expression.name == name)
+            .cloned()
+    }
+
+    pub fn is_playing(&self, name: &str) -> bool {
+        self.playing
+            .iter()
+            .any(|playing| playing.fade_out_at.is_none() && playing.expression.name == name)
+    }
+
+    // stacks the expression over the ones already playing, false if there is none with this name
+    pub fn start(&mut self, name: &str) -> bool {
+        let expression = match self.expression(name) {
+            Some(expression) => expression,
+            None => return false,
+        };
+
+        if !self.is_playing(name) {
+            self.playing.push(PlayingExpression {
+                expression,
+                time: 0.0,
+                fade_out_at: None,
+            });
+        }
+
+        true
+    }
+
+    // the other expressions fade out while this one fades in
+    pub fn switch(&mut self, name: &str) -> bool {
+        if self.expression(name).is_none() {
+            return false;
+        }
+
+        for playing in self.playing.iter_mut() {
+            if playing.expression.name != name {
+                playing.fade_out_at.get_or_insert(playing.time);
+            }
+        }
+
+        self.start(name)
+    }
+
+    pub fn toggle(&mut self, name: &str) -> bool {
+        match self.is_playing(name) {
+            true => {
+                self.stop(name);
+                true
+            }
+            false => self.start(name),
+        }
+    }
+
+    pub fn stop(&mut self, name: &str) {
+        for playing in self.playing.iter_mut() {
+            if playing.expression.name == name {
+                playing.fade_out_at.get_or_insert(playing.time);
+            }
+        }
+    }
+
+    pub fn stop_all(&mut self) {
+        for playing in self.playing.iter_mut() {
+            playing.fade_out_at.get_or_insert(playing.time);
+        }
+    }
+}
+
+// keys switching the expressions of the ExpressionPlayer on the same entity,
+// a key pressed while holding control stacks its expression instead
+#[derive(Debug, Clone, Default)]
+pub struct ExpressionInput {
+    pub bindings: Vec<(VirtualKeyCode, String)>,
+    pub clear: Option<VirtualKeyCode>,
+}
+
+impl Component for ExpressionInput {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl ExpressionInput {
+    // the number keys from 1 to 9 in the order of the expressions, 0 to clear them
+    pub fn numbered(player: &ExpressionPlayer) -> Self {
+        let keys = [
+            VirtualKeyCode::Key1,
+            VirtualKeyCode::Key2,
+            VirtualKeyCode::Key3,
+            VirtualKeyCode::Key4,
+            VirtualKeyCode::Key5,
+            VirtualKeyCode::Key6,
+            VirtualKeyCode::Key7,
+            VirtualKeyCode::Key8,
+            VirtualKeyCode::Key9,
+        ];
+
+        Self {
+            bindings: keys
+                .into_iter()
+                .zip(player.expressions.iter())
+                .map(|(key, expression)| (key, expression.name.clone()))
+                .collect(),
+            clear: Some(VirtualKeyCode::Key0),
+        }
+    }
+
+    pub fn process_event(
+        &self,
+        player: &mut ExpressionPlayer,
+        event: &DeviceEvent,
+        info: &DeviceInfo,
+    ) -> bool {
+        let key = match event {
+            DeviceEvent::Key(KeyboardInput {
+                virtual_keycode: Some(key),
+                state: ElementState::Pressed,
+                ..
+            }) => *key,
+            _ => return false,
+        };
+
+        if self.clear == Some(key) {
+            player.stop_all();
+            return true;
+        }
+
+        let stack = [VirtualKeyCode::LControl, VirtualKeyCode::RControl]
+            .iter()
+            .any(|control| {
+                info.key_map
+                    .get(control)
+                    .is_some_and(|state| *state.value() == ElementState::Pressed)
+            });
+
+        match self.bindings.iter().find(|(other, _)| *other == key) {
+            Some((_, name)) if stack => player.toggle(name),
+            Some((_, name)) => player.switch(name),
+            None => false,
+        }
+    }
+}
+
+// applies the expressions over what the motions and animations wrote this frame
+pub struct ExpressionUpdate;
+
+impl<'a> System<'a> for ExpressionUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time>,
+        WriteStorage<'a, ExpressionPlayer>,
+        WriteStorage<'a, Parameters>,
+    );
+
+    fn run(&mut self, (entities, time, mut players, mut parameters): Self::SystemData) {
+        use specs::Join;
+
+        let delta = time.delta.as_secs_f32();
+
+        for (entity, player) in (&entities, &mut players).join() {
+            if player.playing.is_empty() {
+                continue;
+            }
+
+            let parameters = match parameters.get_mut(entity) {
+                Some(parameters) => parameters,
+                None => continue,
+            };
+
+            for playing in player.playing.iter_mut() {
+                playing.time += delta;
+            }
+
+            player.playing.retain(|playing| !playing.is_gone());
+
+            for playing in player.playing.iter() {
+                let weight = playing.weight();
+
+                for parameter in playing.expression.parameters.iter() {
+                    if let Some(value) = parameters.value(&parameter.id) {
+                        parameters.set(&parameter.id, parameter.apply(value, weight));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameter(value: Real, blend: ExpressionBlend) -> ExpressionParameter {
+        ExpressionParameter {
+            id: "ParamMouthForm".to_string(),
+            value,
+            blend,
+        }
+    }
+
+    #[test]
+    fn apply_leaves_the_value_without_weight() {
+        for blend in [
+            ExpressionBlend::Add,
+            ExpressionBlend::Multiply,
+            ExpressionBlend::Overwrite,
+        ] {
+            assert_eq!(parameter(2.0, blend).apply(0.5, 0.0), 0.5);
+        }
+    }
+
+    #[test]
+    fn apply_blends_with_the_weight() {
+        let add = parameter(0.4, ExpressionBlend::Add);
+        assert!((add.apply(0.5, 1.0) - 0.9).abs() < 1e-6);
+        assert!((add.apply(0.5, 0.5) - 0.7).abs() < 1e-6);
+
+        let multiply = parameter(2.0, ExpressionBlend::Multiply);
+        assert!((multiply.apply(0.5, 1.0) - 1.0).abs() < 1e-6);
+        assert!((multiply.apply(0.5, 0.5) - 0.75).abs() < 1e-6);
+
+        let overwrite = parameter(-1.0, ExpressionBlend::Overwrite);
+        assert!((overwrite.apply(0.5, 1.0) + 1.0).abs() < 1e-6);
+        assert!((overwrite.apply(0.5, 0.5) + 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn from_json_fades_for_a_second_and_adds_by_default() {
+        let expression = Expression::from_json(
+            "smile",
+            r#"{
+                "Type": "Live2D Expression",
+                "FadeOutTime": 0.5,
+                "Parameters": [
+                    { "Id": "ParamEyeLSmile", "Value": 1 },
+                    { "Id": "ParamMouthForm", "Value": 1, "Blend": "Overwrite" }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(expression.fade_in, 1.0);
+        assert_eq!(expression.fade_out, 0.5);
+        assert_eq!(expression.parameters[0].blend, ExpressionBlend::Add);
+        assert_eq!(expression.parameters[1].blend, ExpressionBlend::Overwrite);
+    }
+
+    #[test]
+    fn switching_fades_the_others_out() {
+        let expression = |name: &str| Expression {
+            name: name.to_string(),
+            fade_in: 1.0,
+            fade_out: 1.0,
+            parameters: Vec::new(),
+        };
+        let mut player = ExpressionPlayer::new(vec![expression("smile"), expression("angry")]);
+
+        assert!(player.switch("smile"));
+        player.playing[0].time = 2.0;
+        assert!(player.switch("angry"));
+        assert!(!player.switch("sad"));
+
+        assert!(!player.is_playing("smile"));
+        assert!(player.is_playing("angry"));
+        assert_eq!(player.playing[0].weight(), 1.0);
+
+        player.playing[0].time = 2.5;
+        assert!((player.playing[0].weight() - 0.5).abs() < 1e-6);
+        player.playing[0].time = 3.0;
+        assert!(player.playing[0].is_gone());
+    }
+}
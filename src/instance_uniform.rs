@@ -1,14 +1,14 @@
 pub use crate::type_def::*;
 use crate::{
+    actor::{Parent, ParentHierarchy},
     buffer_update::{ArcDataIndex, DataManager},
-    camera::{Position, Rotation},
     model::{self},
+    transform::GlobalTransform,
 };
-use rapier::na::Matrix3;
-use rapier2d::na::{self as nalgebra, vector};
+use rapier::na::{Matrix3, Matrix4};
 use specs::{
-    prelude::ComponentEvent, shred::DynamicSystemData, shrev::EventIterator, BitSet, ReadStorage,
-    ReaderId, System, World, WorldExt, Write, WriteStorage,
+    prelude::ComponentEvent, shred::DynamicSystemData, shrev::EventIterator, BitSet, Entities,
+    ReadExpect, ReadStorage, ReaderId, System, World, WorldExt, Write, WriteStorage,
 };
 use std::mem;
 
@@ -22,10 +22,12 @@ pub struct InstanceUniform {
     pub normal_0: [f32; 3],
     pub normal_1: [f32; 3],
     pub normal_2: [f32; 3],
+    pub multiply: [f32; 4], // the multiply color with the opacity as its alpha
+    pub screen: [f32; 4],
 }
 
 impl model::Vertex for InstanceUniform {
-    const ATTRIBUTES: &'static [wgpu::VertexAttribute] = &wgpu::vertex_attr_array![3 => Float32x4, 4 => Float32x4, 5 => Float32x4, 6 => Float32x4, 7 => Float32x3, 8 => Float32x3, 9 => Float32x3];
+    const ATTRIBUTES: &'static [wgpu::VertexAttribute] = &wgpu::vertex_attr_array![3 => Float32x4, 4 => Float32x4, 5 => Float32x4, 6 => Float32x4, 7 => Float32x3, 8 => Float32x3, 9 => Float32x3, 10 => Float32x4, 11 => Float32x4];
 
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         wgpu::VertexBufferLayout {
@@ -40,21 +42,42 @@ impl model::Vertex for InstanceUniform {
 }
 
 impl InstanceUniform {
-    fn get_raw(rotation: &Rotator, position: &Point) -> ([[f32; 4]; 4], [[f32; 3]; 3]) {
-        let model = Isometry::new(
-            vector![position.coords[0], position.coords[1], 1.0],
-            vector![0.0, 0.0, rotation.angle()],
-        )
-        .to_matrix();
+    fn get_raw(transform: &Transform) -> ([[f32; 4]; 4], [[f32; 3]; 3]) {
+        let t = transform;
+
+        // the 2d transform placed on the z = 1.0 plane
+        #[rustfmt::skip]
+        let model = Matrix4::new(
+            t[(0, 0)], t[(0, 1)], 0.0, t[(0, 2)],
+            t[(1, 0)], t[(1, 1)], 0.0, t[(1, 2)],
+            0.0,       0.0,       1.0, 1.0,
+            0.0,       0.0,       0.0, 1.0,
+        );
+
+        let linear = Matrix3::new(
+            t[(0, 0)],
+            t[(0, 1)],
+            0.0,
+            t[(1, 0)],
+            t[(1, 1)],
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+        let normal = linear
+            .try_inverse()
+            .unwrap_or_else(Matrix3::identity)
+            .transpose();
 
         let model: [[f32; 4]; 4] = *(model).as_ref();
-        let normal: [[f32; 3]; 3] = Matrix3::from(*rotation).into();
+        let normal: [[f32; 3]; 3] = normal.into();
 
         (model, normal)
     }
 
-    pub fn new(rotation: &Rotator, position: &Point) -> Self {
-        let (model, normal) = Self::get_raw(rotation, position);
+    pub fn new(transform: &Transform) -> Self {
+        let (model, normal) = Self::get_raw(transform);
 
         Self {
             model_0: model[0],
@@ -64,11 +87,13 @@ impl InstanceUniform {
             normal_0: normal[0],
             normal_1: normal[1],
             normal_2: normal[2],
+            multiply: [1.0, 1.0, 1.0, 1.0],
+            screen: [0.0, 0.0, 0.0, 0.0],
         }
     }
 
-    fn update(&mut self, rotation: &Rotator, position: &Point) {
-        let (model, normal) = Self::get_raw(rotation, position);
+    fn update(&mut self, transform: &Transform) {
+        let (model, normal) = Self::get_raw(transform);
 
         self.model_0 = model[0];
         self.model_1 = model[1];
@@ -78,21 +103,44 @@ impl InstanceUniform {
         self.normal_1 = normal[1];
         self.normal_2 = normal[2];
     }
+
+    fn update_color(
+        &mut self,
+        opacity: Real,
+        multiply: Option<&Color>,
+        screen: Option<&ScreenColor>,
+    ) {
+        let [r, g, b, a] = multiply
+            .map(|color| Color::to_uniform_rgba(&color.0))
+            .unwrap_or([1.0, 1.0, 1.0, 1.0]);
+
+        self.multiply = [r, g, b, a * opacity];
+        self.screen = match screen {
+            Some(screen) => {
+                let [r, g, b] = Color::to_uniform_rgb(&screen.0);
+                [r, g, b, 0.0]
+            }
+            None => [0.0, 0.0, 0.0, 0.0],
+        };
+    }
 }
 
 #[derive(Default)]
 pub struct InstanceUniformUpdate {
     pub dirty: BitSet,
-    pub reader_id_pos: Option<ReaderId<ComponentEvent>>,
-    pub reader_id_rot: Option<ReaderId<ComponentEvent>>,
+    pub dirty_color: BitSet,
+    pub reader_id_transform: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_opacity: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_color: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_screen_color: Option<ReaderId<ComponentEvent>>,
 }
 
 impl InstanceUniformUpdate {
-    fn event_update(&mut self, events: EventIterator<'_, ComponentEvent>) {
+    fn event_update(dirty: &mut BitSet, events: EventIterator<'_, ComponentEvent>) {
         for event in events.into_iter() {
             match event {
                 ComponentEvent::Modified(id) | ComponentEvent::Inserted(id) => {
-                    self.dirty.add(*id);
+                    dirty.add(*id);
                 }
                 ComponentEvent::Removed(_id) => (),
             }
@@ -102,39 +150,105 @@ impl InstanceUniformUpdate {
 
 impl<'a> System<'a> for InstanceUniformUpdate {
     type SystemData = (
+        Entities<'a>,
         Write<'a, DataManager<InstanceUniform>>,
         WriteStorage<'a, ArcDataIndex<InstanceUniform>>,
-        ReadStorage<'a, Position>,
-        ReadStorage<'a, Rotation>,
+        ReadStorage<'a, GlobalTransform>,
+        ReadStorage<'a, Opacity>,
+        ReadStorage<'a, Color>,
+        ReadStorage<'a, ScreenColor>,
+        ReadStorage<'a, Parent>,
+        ReadExpect<'a, ParentHierarchy>,
     );
 
-    fn run(&mut self, (mut data, mut indices, positions, rotations): Self::SystemData) {
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut data,
+            mut indices,
+            transforms,
+            opacities,
+            colors,
+            screen_colors,
+            parents,
+            hierarchy,
+        ): Self::SystemData,
+    ) {
         use specs::Join;
 
         self.dirty.clear();
+        self.dirty_color.clear();
 
-        let events_pos = positions
+        let events_transform = transforms
+            .channel()
+            .read(self.reader_id_transform.as_mut().unwrap());
+        let events_opacity = opacities
             .channel()
-            .read(self.reader_id_pos.as_mut().unwrap());
-        let events_rot = rotations
+            .read(self.reader_id_opacity.as_mut().unwrap());
+        let events_color = colors
             .channel()
-            .read(self.reader_id_rot.as_mut().unwrap());
+            .read(self.reader_id_color.as_mut().unwrap());
+        let events_screen_color = screen_colors
+            .channel()
+            .read(self.reader_id_screen_color.as_mut().unwrap());
+
+        Self::event_update(&mut self.dirty, events_transform);
+        Self::event_update(&mut self.dirty_color, events_opacity);
+        Self::event_update(&mut self.dirty_color, events_color);
+        Self::event_update(&mut self.dirty_color, events_screen_color);
+
+        (&mut indices, &transforms, &self.dirty)
+            .join()
+            .for_each(|(index, transform, _)| {
+                let index = index.0.lock().unwrap();
+                let instance_uniform = data.get_mut_index(&index);
+                instance_uniform.update(&transform.0);
+            });
+
+        // a group fading out fades everything it holds
+        for (entity, _) in (&entities, &self.dirty_color.clone()).join() {
+            self.dirty_color |= &hierarchy.all_children(entity);
+        }
 
-        self.event_update(events_pos);
-        self.event_update(events_rot);
+        let opacity = |mut entity| {
+            let mut opacity = 1.0;
 
-        (&mut indices, &positions, &rotations, &self.dirty)
+            loop {
+                opacity *= opacities
+                    .get(entity)
+                    .map(|opacity| opacity.0)
+                    .unwrap_or(1.0);
+
+                match parents.get(entity) {
+                    Some(parent) => entity = parent.entity,
+                    None => return opacity,
+                }
+            }
+        };
+
+        (
+            &entities,
+            &mut indices,
+            colors.maybe(),
+            screen_colors.maybe(),
+            &self.dirty_color,
+        )
             .join()
-            .for_each(|(index, position, rotation, _)| {
+            .for_each(|(entity, index, color, screen_color, _)| {
                 let index = index.0.lock().unwrap();
                 let instance_uniform = data.get_mut_index(&index);
-                instance_uniform.update(&rotation.0, &position.0);
+                instance_uniform.update_color(opacity(entity), color, screen_color);
             });
     }
 
     fn setup(&mut self, world: &mut World) {
         Self::SystemData::setup(&self.accessor(), world);
-        self.reader_id_pos = Some(world.write_component::<Position>().register_reader());
-        self.reader_id_rot = Some(world.write_component::<Rotation>().register_reader());
+        self.reader_id_transform =
+            Some(world.write_component::<GlobalTransform>().register_reader());
+        self.reader_id_opacity = Some(world.write_component::<Opacity>().register_reader());
+        self.reader_id_color = Some(world.write_component::<Color>().register_reader());
+        self.reader_id_screen_color =
+            Some(world.write_component::<ScreenColor>().register_reader());
     }
 }
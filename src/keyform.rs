@@ -0,0 +1,607 @@
+use crate::{
+    actor::Parent,
+    buffer_update::{ArcDataIndex, DataManager},
+    mesh::Mesh,
+    model::ModelVertex,
+    parameter::Parameters,
+    sprite_selector::SpriteSelector,
+    type_def::*,
+};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator,
+};
+use specs::{
+    prelude::ComponentEvent, shred::DynamicSystemData, shrev::EventIterator, BitSet, Component,
+    Entities, FlaggedStorage, ReadStorage, ReaderId, System, VecStorage, World, WorldExt, Write,
+    WriteStorage,
+};
+
+// the state of a part at one point of its keyform grid
+#[derive(Debug, Clone, Default)]
+pub struct Keyform {
+    pub vertices: Vec<[f32; 2]>, // same space as ModelVertex::position, empty to leave the mesh as it is
+    pub opacity: Real,
+    pub draw_order: i32,
+    pub angle: Real, // only used by rotation deformers, like the scale
+    pub scale: Real,
+    pub multiply: [Real; 3], // colors of the part, white and black leave it as it is
+    pub screen: [Real; 3],
+}
+
+impl Keyform {
+    pub fn new(vertices: Vec<[f32; 2]>, opacity: Real, draw_order: i32) -> Self {
+        Self {
+            vertices,
+            opacity,
+            draw_order,
+            angle: 0.0,
+            scale: 1.0,
+            multiply: [1.0, 1.0, 1.0],
+            screen: [0.0, 0.0, 0.0],
+        }
+    }
+
+    // ex: a blush fading in with a parameter
+    pub fn colored(mut self, multiply: [Real; 3], screen: [Real; 3]) -> Self {
+        self.multiply = multiply;
+        self.screen = screen;
+        self
+    }
+
+    // the origin of a rotation deformer is its only vertex so the deformers above can move it
+    pub fn rotation(origin: [f32; 2], angle: Real, scale: Real, opacity: Real) -> Self {
+        Self {
+            vertices: vec![origin],
+            opacity,
+            draw_order: DrawOrder::default().0,
+            angle,
+            scale,
+            multiply: [1.0, 1.0, 1.0],
+            screen: [0.0, 0.0, 0.0],
+        }
+    }
+}
+
+// what the keyforms give before any deformer moves it,
+// the vertices of a part, the control points of a warp deformer or the origin of a rotation deformer
+#[derive(Debug, Clone, Default)]
+pub struct Pose {
+    pub vertices: Vec<[f32; 2]>,
+    pub angle: Real,
+    pub scale: Real,
+}
+
+impl Component for Pose {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+// one axis of a keyform grid
+#[derive(Debug, Clone)]
+pub struct KeyformAxis {
+    pub parameter: String,
+    pub keys: Vec<Real>, // values of the parameter where the forms are reached, sorted
+}
+
+impl KeyformAxis {
+    pub fn new(parameter: &str, mut keys: Vec<Real>) -> Self {
+        keys.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        Self {
+            parameter: parameter.to_string(),
+            keys,
+        }
+    }
+
+    // the key just before the value and how far the value is toward the next one,
+    // clamped to the first and last keys
+    fn locate(&self, value: Real) -> (usize, Real) {
+        let last = self.keys.len() - 1;
+
+        if self.keys.len() < 2 || value <= self.keys[0] {
+            return (0, 0.0);
+        }
+
+        if value >= self.keys[last] {
+            return (last - 1, 1.0);
+        }
+
+        let next = self.keys.iter().position(|key| *key > value).unwrap();
+        let (a, b) = (self.keys[next - 1], self.keys[next]);
+
+        (next - 1, (value - a) / (b - a))
+    }
+}
+
+// keyforms of a part on a grid with one axis per parameter it depends on,
+// blended linearly along every axis (bilinear with two parameters, trilinear with three, ...)
+#[derive(Debug, Clone)]
+pub struct Keyforms {
+    pub axes: Vec<KeyformAxis>,
+    pub forms: Vec<Keyform>, // every combination of keys, the first axis changing the fastest
+}
+
+impl Component for Keyforms {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+// what the keyforms of a part move, the rest is left to the motions, animations and poses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyformChannels {
+    pub vertices: bool,
+    pub opacity: bool,
+    pub draw_order: bool,
+    pub multiply: bool,
+    pub screen: bool,
+}
+
+impl Keyforms {
+    pub fn new(axes: Vec<KeyformAxis>, forms: Vec<Keyform>) -> Self {
+        debug_assert_eq!(
+            axes.iter().map(|axis| axis.keys.len()).product::<usize>(),
+            forms.len()
+        );
+
+        Self { axes, forms }
+    }
+
+    // a channel is only moved when one of the forms takes it away from what a part has without keyforms
+    pub fn channels(&self) -> KeyformChannels {
+        let neutral = Keyform::new(Vec::new(), 1.0, DrawOrder::default().0);
+
+        self.forms
+            .iter()
+            .fold(KeyformChannels::default(), |channels, form| {
+                KeyformChannels {
+                    vertices: channels.vertices || !form.vertices.is_empty(),
+                    opacity: channels.opacity || form.opacity != neutral.opacity,
+                    draw_order: channels.draw_order || form.draw_order != neutral.draw_order,
+                    multiply: channels.multiply || form.multiply != neutral.multiply,
+                    screen: channels.screen || form.screen != neutral.screen,
+                }
+            })
+    }
+
+    // values has one entry per axis
+    pub fn evaluate(&self, values: &[Real]) -> Option<Keyform> {
+        if self.forms.is_empty() || self.axes.iter().any(|axis| axis.keys.is_empty()) {
+            return None;
+        }
+
+        let cells = self
+            .axes
+            .iter()
+            .zip(values.iter())
+            .map(|(axis, value)| axis.locate(*value))
+            .collect::<Vec<_>>();
+
+        let vertices_len = self.forms[0].vertices.len();
+        let mut vertices = vec![[0.0, 0.0]; vertices_len];
+        let mut opacity = 0.0;
+        let mut draw_order = 0.0;
+        let mut angle = 0.0;
+        let mut scale = 0.0;
+        let mut multiply = [0.0; 3];
+        let mut screen = [0.0; 3];
+
+        // every corner of the cell around the values, weighted by how close it is
+        for corner in 0..(1usize << cells.len()) {
+            let mut index = 0;
+            let mut stride = 1;
+            let mut weight = 1.0;
+
+            for (axis, (key, t)) in cells.iter().enumerate() {
+                let next = (corner >> axis) & 1 == 1;
+
+                weight *= if next { *t } else { 1.0 - *t };
+                index += (key + next as usize) * stride;
+                stride *= self.axes[axis].keys.len();
+            }
+
+            if weight <= 0.0 {
+                continue;
+            }
+
+            let form = &self.forms[index];
+
+            if form.vertices.len() == vertices_len {
+                vertices
+                    .iter_mut()
+                    .zip(form.vertices.iter())
+                    .for_each(|(vertex, other)| {
+                        vertex[0] += other[0] * weight;
+                        vertex[1] += other[1] * weight;
+                    });
+            }
+
+            opacity += form.opacity * weight;
+            draw_order += form.draw_order as Real * weight;
+            angle += form.angle * weight;
+            scale += form.scale * weight;
+
+            for c in 0..3 {
+                multiply[c] += form.multiply[c] * weight;
+                screen[c] += form.screen[c] * weight;
+            }
+        }
+
+        Some(Keyform {
+            vertices,
+            opacity,
+            draw_order: draw_order.round() as i32,
+            angle,
+            scale,
+            multiply,
+            screen,
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct KeyformUpdate {
+    pub dirty: BitSet,
+    pub reader_id_keyforms: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_parameters: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_sprite_selector: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_mesh: Option<ReaderId<ComponentEvent>>,
+}
+
+impl KeyformUpdate {
+    fn event_update(&mut self, events: EventIterator<'_, ComponentEvent>) {
+        for event in events.into_iter() {
+            match event {
+                ComponentEvent::Modified(id) | ComponentEvent::Inserted(id) => {
+                    self.dirty.add(*id);
+                }
+                ComponentEvent::Removed(_id) => (),
+            }
+        }
+    }
+}
+
+impl<'a> System<'a> for KeyformUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, DataManager<ModelVertex>>,
+        WriteStorage<'a, ArcDataIndex<ModelVertex>>, // mutable to flag a modification in the flagstorage in order to update its buffer
+        WriteStorage<'a, Opacity>,
+        WriteStorage<'a, DrawOrder>,
+        WriteStorage<'a, Color>,
+        WriteStorage<'a, ScreenColor>,
+        WriteStorage<'a, Pose>,
+        ReadStorage<'a, Keyforms>,
+        ReadStorage<'a, Parameters>,
+        ReadStorage<'a, Parent>,
+        ReadStorage<'a, SpriteSelector>,
+        ReadStorage<'a, Mesh>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut vertices_data,
+            mut vertices_indices,
+            mut opacities,
+            mut draw_orders,
+            mut colors,
+            mut screen_colors,
+            mut poses,
+            keyforms,
+            parameters,
+            parents,
+            sprite_selectors,
+            meshes,
+        ): Self::SystemData,
+    ) {
+        use specs::ParJoin;
+
+        self.dirty.clear();
+
+        let events_keyforms = keyforms
+            .channel()
+            .read(self.reader_id_keyforms.as_mut().unwrap());
+        let events_sprite_selector = sprite_selectors
+            .channel()
+            .read(self.reader_id_sprite_selector.as_mut().unwrap());
+        let events_mesh = meshes.channel().read(self.reader_id_mesh.as_mut().unwrap());
+
+        // ModelVertexUpdate puts the vertices back at rest when the sprite or mesh changes
+        self.event_update(events_keyforms);
+        self.event_update(events_sprite_selector);
+        self.event_update(events_mesh);
+
+        // any parameter can move any part of the puppet
+        let parameters_changed = parameters
+            .channel()
+            .read(self.reader_id_parameters.as_mut().unwrap())
+            .next()
+            .is_some();
+
+        if parameters_changed {
+            self.dirty |= keyforms.mask();
+        }
+
+        // the parameters are on the root of the puppet the part belongs to
+        let find_parameters = |mut entity| loop {
+            if let Some(parameters) = parameters.get(entity) {
+                return Some(parameters);
+            }
+
+            entity = parents.get(entity)?.entity;
+        };
+
+        // a part missing one of its parameters is left as it is
+        let forms = (&entities, &keyforms, &self.dirty)
+            .par_join()
+            .filter_map(|(entity, keyforms, _)| {
+                let parameters = find_parameters(entity)?;
+                let values = keyforms
+                    .axes
+                    .iter()
+                    .map(|axis| parameters.value(&axis.parameter))
+                    .collect::<Option<Vec<_>>>()?;
+
+                Some((entity, keyforms.channels(), keyforms.evaluate(&values)?))
+            })
+            .collect::<Vec<_>>();
+
+        for (entity, channels, form) in forms.into_iter() {
+            if channels.vertices && !form.vertices.is_empty() {
+                if let Some(vertex_index) = vertices_indices.get_mut(entity) {
+                    let vertex_index = vertex_index.0.lock().unwrap();
+                    let vertices = vertices_data.get_mut_range(&vertex_index);
+
+                    debug_assert_eq!(vertices.len(), form.vertices.len());
+
+                    vertices
+                        .par_iter_mut()
+                        .zip(form.vertices.par_iter())
+                        .for_each(|(vertex, position)| vertex.position = *position);
+                }
+
+                // kept so the deformers always start from the undeformed positions
+                let pose = Pose {
+                    vertices: form.vertices,
+                    angle: form.angle,
+                    scale: form.scale,
+                };
+
+                match poses.get_mut(entity) {
+                    Some(old) => *old = pose,
+                    None => {
+                        poses.insert(entity, pose).unwrap();
+                    }
+                }
+            }
+
+            // only flagged when they change, the instance uniforms are rebuilt for every flag
+            if channels.opacity {
+                match opacities.get(entity) {
+                    Some(opacity) if opacity.0 == form.opacity => (),
+                    Some(_) => opacities.get_mut(entity).unwrap().0 = form.opacity,
+                    None => {
+                        opacities.insert(entity, Opacity(form.opacity)).unwrap();
+                    }
+                }
+            }
+
+            if channels.multiply {
+                let [r, g, b] = form.multiply;
+
+                match colors.get(entity) {
+                    Some(color) if Color::to_uniform_rgb(&color.0) == form.multiply => (),
+                    Some(color) => {
+                        let alpha = color.0.alpha;
+                        *colors.get_mut(entity).unwrap() = Color::new_rgba(r, g, b, alpha);
+                    }
+                    None => {
+                        colors.insert(entity, Color::new_rgb(r, g, b)).unwrap();
+                    }
+                }
+            }
+
+            if channels.screen {
+                let [r, g, b] = form.screen;
+
+                match screen_colors.get(entity) {
+                    Some(screen_color) if Color::to_uniform_rgb(&screen_color.0) == form.screen => {
+                    }
+                    Some(_) => {
+                        *screen_colors.get_mut(entity).unwrap() = ScreenColor::new_rgb(r, g, b)
+                    }
+                    None => {
+                        screen_colors
+                            .insert(entity, ScreenColor::new_rgb(r, g, b))
+                            .unwrap();
+                    }
+                }
+            }
+
+            // only the entities created with a draw order are sorted by it, a deformer has none
+            if channels.draw_order {
+                if let Some(draw_order) = draw_orders.get(entity) {
+                    if draw_order.0 != form.draw_order {
+                        draw_orders.get_mut(entity).unwrap().0 = form.draw_order;
+                    }
+                }
+            }
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(&self.accessor(), world);
+        self.reader_id_keyforms = Some(world.write_component::<Keyforms>().register_reader());
+        self.reader_id_parameters = Some(world.write_component::<Parameters>().register_reader());
+        self.reader_id_sprite_selector =
+            Some(world.write_component::<SpriteSelector>().register_reader());
+        self.reader_id_mesh = Some(world.write_component::<Mesh>().register_reader());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Real, b: Real) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    // keyforms along a single parameter
+    fn single(parameter: &str, forms: Vec<(Real, Keyform)>) -> Keyforms {
+        let mut forms = forms;
+        forms.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
+
+        let (keys, forms) = forms.into_iter().unzip();
+
+        Keyforms::new(vec![KeyformAxis::new(parameter, keys)], forms)
+    }
+
+    #[test]
+    fn locate_finds_the_key_before_and_the_way_to_the_next() {
+        let axis = KeyformAxis::new("ParamAngleX", vec![30.0, -30.0, 0.0]);
+
+        assert_eq!(axis.keys, vec![-30.0, 0.0, 30.0]);
+        assert_eq!(axis.locate(-30.0), (0, 0.0));
+        assert_eq!(axis.locate(0.0), (1, 0.0));
+        assert_eq!(axis.locate(15.0), (1, 0.5));
+        assert_eq!(axis.locate(30.0), (1, 1.0));
+    }
+
+    #[test]
+    fn locate_clamps_outside_of_the_keys() {
+        let axis = KeyformAxis::new("ParamAngleX", vec![-30.0, 30.0]);
+
+        assert_eq!(axis.locate(-100.0), (0, 0.0));
+        assert_eq!(axis.locate(100.0), (0, 1.0));
+
+        let single = KeyformAxis::new("ParamAngleX", vec![10.0]);
+        assert_eq!(single.locate(50.0), (0, 0.0));
+    }
+
+    #[test]
+    fn evaluate_reaches_every_form_on_its_key() {
+        let keyforms = single(
+            "ParamEyeLOpen",
+            vec![
+                (1.0, Keyform::new(vec![[0.0, 1.0]], 1.0, 500)),
+                (0.0, Keyform::new(vec![[0.0, 0.0]], 0.0, 300)),
+            ],
+        );
+
+        let closed = keyforms.evaluate(&[0.0]).unwrap();
+        assert_eq!(closed.vertices, vec![[0.0, 0.0]]);
+        assert_eq!(closed.opacity, 0.0);
+        assert_eq!(closed.draw_order, 300);
+
+        let half = keyforms.evaluate(&[0.5]).unwrap();
+        assert!(close(half.vertices[0][1], 0.5));
+        assert!(close(half.opacity, 0.5));
+        assert_eq!(half.draw_order, 400);
+
+        let clamped = keyforms.evaluate(&[2.0]).unwrap();
+        assert_eq!(clamped.vertices, vec![[0.0, 1.0]]);
+    }
+
+    #[test]
+    fn evaluate_blends_two_axes() {
+        // x is given by the first axis and y by the second, the first one changing the fastest
+        let form = |x: f32, y: f32| Keyform::new(vec![[x, y]], 1.0, 500);
+        let keyforms = Keyforms::new(
+            vec![
+                KeyformAxis::new("ParamAngleX", vec![-30.0, 0.0, 30.0]),
+                KeyformAxis::new("ParamAngleY", vec![-30.0, 30.0]),
+            ],
+            vec![
+                form(-1.0, -1.0),
+                form(0.0, -1.0),
+                form(1.0, -1.0),
+                form(-1.0, 1.0),
+                form(0.0, 1.0),
+                form(1.0, 1.0),
+            ],
+        );
+
+        let corner = keyforms.evaluate(&[30.0, -30.0]).unwrap();
+        assert_eq!(corner.vertices, vec![[1.0, -1.0]]);
+
+        let middle = keyforms.evaluate(&[15.0, 0.0]).unwrap();
+        assert!(close(middle.vertices[0][0], 0.5));
+        assert!(close(middle.vertices[0][1], 0.0));
+
+        let outside = keyforms.evaluate(&[-90.0, 90.0]).unwrap();
+        assert_eq!(outside.vertices, vec![[-1.0, 1.0]]);
+    }
+
+    #[test]
+    fn evaluate_blends_three_axes() {
+        // the iris of the rig, looking around with the eye open and hidden with it closed
+        let form = |x: f32, y: f32, opacity: Real| Keyform::new(vec![[x, y]], opacity, 500);
+        let keyforms = Keyforms::new(
+            vec![
+                KeyformAxis::new("ParamEyeBallX", vec![-1.0, 1.0]),
+                KeyformAxis::new("ParamEyeBallY", vec![-1.0, 1.0]),
+                KeyformAxis::new("ParamEyeLOpen", vec![0.0, 1.0]),
+            ],
+            vec![
+                form(-1.0, -1.0, 0.0),
+                form(1.0, -1.0, 0.0),
+                form(-1.0, 1.0, 0.0),
+                form(1.0, 1.0, 0.0),
+                form(-1.0, -1.0, 1.0),
+                form(1.0, -1.0, 1.0),
+                form(-1.0, 1.0, 1.0),
+                form(1.0, 1.0, 1.0),
+            ],
+        );
+
+        let corner = keyforms.evaluate(&[1.0, -1.0, 1.0]).unwrap();
+        assert_eq!(corner.vertices, vec![[1.0, -1.0]]);
+        assert_eq!(corner.opacity, 1.0);
+
+        let center = keyforms.evaluate(&[0.0, 0.0, 0.5]).unwrap();
+        assert!(close(center.vertices[0][0], 0.0));
+        assert!(close(center.vertices[0][1], 0.0));
+        assert!(close(center.opacity, 0.5));
+
+        let edge = keyforms.evaluate(&[0.5, 1.0, 0.25]).unwrap();
+        assert!(close(edge.vertices[0][0], 0.5));
+        assert!(close(edge.vertices[0][1], 1.0));
+        assert!(close(edge.opacity, 0.25));
+
+        let outside = keyforms.evaluate(&[-5.0, 5.0, -5.0]).unwrap();
+        assert_eq!(outside.vertices, vec![[-1.0, 1.0]]);
+        assert_eq!(outside.opacity, 0.0);
+    }
+
+    #[test]
+    fn evaluate_needs_forms() {
+        let keyforms = Keyforms {
+            axes: vec![KeyformAxis::new("ParamAngleX", Vec::new())],
+            forms: Vec::new(),
+        };
+
+        assert!(keyforms.evaluate(&[0.0]).is_none());
+    }
+
+    #[test]
+    fn channels_are_the_ones_the_forms_move() {
+        let keyforms = single(
+            "ParamCheek",
+            vec![
+                (0.0, Keyform::new(Vec::new(), 1.0, 500)),
+                (
+                    1.0,
+                    Keyform::new(Vec::new(), 1.0, 500).colored([1.0, 0.8, 0.8], [0.0; 3]),
+                ),
+            ],
+        );
+
+        assert_eq!(
+            keyforms.channels(),
+            KeyformChannels {
+                multiply: true,
+                ..KeyformChannels::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,557 @@
+use crate::{actor::Time, animation::Easing, parameter::Parameters, type_def::*};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use specs::{Component, DenseVecStorage, Entities, Read, System, WriteStorage};
+use std::{f32::consts::PI, path::Path, sync::Arc};
+
+// the samples of a pcm wav file mixed down to one channel, from -1 to 1
+#[derive(Debug, Clone)]
+pub struct Wav {
+    pub sample_rate: u32,
+    pub samples: Vec<Real>,
+}
+
+impl Wav {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            bail!("not a wav file");
+        }
+
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+
+        let mut format = None;
+        let mut data = None;
+        let mut at = 12;
+
+        // the chunks are padded to an even size
+        while at + 8 <= bytes.len() {
+            let size = u32_at(at + 4) as usize;
+            let start = at + 8;
+            let end = (start + size).min(bytes.len());
+
+            match &bytes[at..at + 4] {
+                b"fmt " if size >= 16 => {
+                    if start + size > bytes.len() {
+                        bail!("wav file with a truncated fmt chunk");
+                    }
+
+                    let mut kind = u16_at(start);
+
+                    // WAVE_FORMAT_EXTENSIBLE keeps the real format in its sub format
+                    if kind == 0xfffe && size >= 26 {
+                        kind = u16_at(start + 24);
+                    }
+
+                    format = Some((
+                        kind,
+                        u16_at(start + 2),
+                        u32_at(start + 4),
+                        u16_at(start + 14),
+                    ));
+                }
+                b"data" => data = Some(&bytes[start..end]),
+                _ => (),
+            }
+
+            at = start + size + size % 2;
+        }
+
+        let (kind, channels, sample_rate, bits) = match format {
+            Some(format) => format,
+            None => bail!("wav file without a fmt chunk"),
+        };
+
+        let data = match data {
+            Some(data) => data,
+            None => bail!("wav file without a data chunk"),
+        };
+
+        let sample = |bytes: &[u8]| -> Real {
+            match (kind, bits) {
+                (1, 8) => (bytes[0] as Real - 128.0) / 128.0,
+                (1, 16) => i16::from_le_bytes([bytes[0], bytes[1]]) as Real / 32768.0,
+                (1, 24) => {
+                    i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as Real / 2147483648.0
+                }
+                (1, 32) => {
+                    i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as Real
+                        / 2147483648.0
+                }
+                (3, 32) => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                _ => 0.0,
+            }
+        };
+
+        if !matches!((kind, bits), (1, 8) | (1, 16) | (1, 24) | (1, 32) | (3, 32)) {
+            bail!("unsupported wav format {} with {} bits", kind, bits);
+        }
+
+        if channels == 0 {
+            bail!("wav file without channels");
+        }
+
+        let width = bits as usize / 8;
+        let frame = width * channels as usize;
+
+        let samples = data
+            .chunks_exact(frame)
+            .map(|frame| frame.chunks_exact(width).map(sample).sum::<Real>() / channels as Real)
+            .collect();
+
+        Ok(Self {
+            sample_rate,
+            samples,
+        })
+    }
+
+    pub fn duration(&self) -> Real {
+        self.samples.len() as Real / self.sample_rate as Real
+    }
+
+    // the samples of a window centered on a time, cut at the ends of the file
+    pub fn window(&self, time: Real, length: Real) -> &[Real] {
+        let center = (time * self.sample_rate as Real) as i64;
+        let half = (length * self.sample_rate as Real / 2.0) as i64;
+        let start = (center - half).clamp(0, self.samples.len() as i64) as usize;
+        let end = (center + half).clamp(0, self.samples.len() as i64) as usize;
+
+        &self.samples[start..end]
+    }
+
+    pub fn rms(&self, time: Real, length: Real) -> Real {
+        let window = self.window(time, length);
+
+        if window.is_empty() {
+            return 0.0;
+        }
+
+        (window.iter().map(|sample| sample * sample).sum::<Real>() / window.len() as Real).sqrt()
+    }
+
+    // the strongest frequency between two bounds, with a hann window over the samples
+    fn peak(&self, window: &[Real], low: Real, high: Real) -> Option<Real> {
+        let length = window.len() as Real;
+        let step = self.sample_rate as Real / length;
+        let first = (low / step).ceil() as usize;
+        let last = (high / step).floor() as usize;
+
+        (first.max(1)..=last)
+            .map(|bin| {
+                let (mut real, mut imaginary) = (0.0, 0.0);
+
+                for (i, sample) in window.iter().enumerate() {
+                    let hann = 0.5 - 0.5 * (2.0 * PI * i as Real / length).cos();
+                    let angle = 2.0 * PI * bin as Real * i as Real / length;
+                    real += sample * hann * angle.cos();
+                    imaginary -= sample * hann * angle.sin();
+                }
+
+                (bin as Real * step, real * real + imaginary * imaginary)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .filter(|(_, power)| *power > 0.0)
+            .map(|(frequency, _)| frequency)
+    }
+
+    // how much the sound at a time looks like each of a, i, u, e and o, from its two first formants
+    pub fn vowels(&self, time: Real, length: Real) -> [Real; 5] {
+        let window = self.window(time, length);
+
+        let first = match self.peak(window, 250.0, 1000.0) {
+            Some(first) => first,
+            None => return [0.0; 5],
+        };
+
+        // the second formant is looked for above the first one, or it could be found again
+        let second = match self.peak(window, (first + 300.0).max(700.0), 3000.0) {
+            Some(second) => second,
+            None => return [0.0; 5],
+        };
+
+        // the formants of the japanese vowels, in hz
+        const FORMANTS: [[Real; 2]; 5] = [
+            [800.0, 1200.0],
+            [300.0, 2300.0],
+            [350.0, 1300.0],
+            [500.0, 1900.0],
+            [500.0, 800.0],
+        ];
+
+        let closeness = FORMANTS.map(|[f_1, f_2]| {
+            let distance = ((first - f_1) / 200.0).powi(2) + ((second - f_2) / 500.0).powi(2);
+            (-distance).exp()
+        });
+
+        let total: Real = closeness.iter().sum();
+
+        match total > 0.0 {
+            true => closeness.map(|closeness| closeness / total),
+            false => [0.0; 5],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LipSyncMode {
+    Volume, // opens the mouth with the volume
+    #[default]
+    Vowels, // also shapes it like the vowel being said
+}
+
+// the mouth for a vowel said at full volume
+#[derive(Debug, Clone, Copy)]
+pub struct MouthShape {
+    pub open: Real,
+    pub form: Real,
+}
+
+impl MouthShape {
+    pub fn new(open: Real, form: Real) -> Self {
+        Self { open, form }
+    }
+}
+
+// moves the mouth with a wav file played on Time::delta, put next to the Parameters of a puppet
+#[derive(Debug, Clone)]
+pub struct LipSync {
+    pub audio: Arc<Wav>,
+    pub mode: LipSyncMode,
+    pub open_parameter: String,
+    pub form_parameter: String,
+    pub gain: Real,              // multiplies the rms volume before the curve
+    pub curve: Easing,           // from the volume to how open the mouth is
+    pub smoothing: Real,         // time in seconds the mouth takes to follow the volume
+    pub window: Real,            // length in seconds of the samples heard at a time
+    pub shapes: [MouthShape; 5], // a, i, u, e, o
+    pub looped: bool,
+    pub playing: bool,
+    pub time: Real,
+    level: Real,
+    vowels: [Real; 5],
+}
+
+impl Component for LipSync {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl LipSync {
+    pub fn new(audio: Arc<Wav>, mode: LipSyncMode) -> Self {
+        Self {
+            audio,
+            mode,
+            open_parameter: "ParamMouthOpenY".to_string(),
+            form_parameter: "ParamMouthForm".to_string(),
+            gain: 5.0,
+            curve: Easing::EaseOut,
+            smoothing: 0.05,
+            window: 1.0 / 30.0,
+            shapes: [
+                MouthShape::new(1.0, 0.0),
+                MouthShape::new(0.4, 1.0),
+                MouthShape::new(0.3, -1.0),
+                MouthShape::new(0.6, 0.6),
+                MouthShape::new(0.8, -0.6),
+            ],
+            looped: false,
+            playing: true,
+            time: 0.0,
+            level: 0.0,
+            vowels: [0.0; 5],
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !self.looped && self.time >= self.audio.duration()
+    }
+
+    // moves the time forward and follows the sound, the same deltas always give the same mouth
+    pub fn advance(&mut self, delta: Real) {
+        if !self.playing {
+            return;
+        }
+
+        self.time += delta;
+
+        let duration = self.audio.duration();
+        if self.looped && duration > 0.0 {
+            self.time %= duration;
+        }
+
+        let (level, vowels) = match self.is_finished() {
+            true => (0.0, [0.0; 5]),
+            false => {
+                let volume = (self.audio.rms(self.time, self.window) * self.gain).clamp(0.0, 1.0);
+                let vowels = match self.mode {
+                    LipSyncMode::Volume => [0.0; 5],
+                    LipSyncMode::Vowels if volume > 0.0 => {
+                        self.audio.vowels(self.time, self.window)
+                    }
+                    LipSyncMode::Vowels => self.vowels,
+                };
+
+                (self.curve.apply(volume), vowels)
+            }
+        };
+
+        let follow = match self.smoothing > 0.0 {
+            true => 1.0 - (-delta / self.smoothing).exp(),
+            false => 1.0,
+        };
+
+        self.level += (level - self.level) * follow;
+        for (old, new) in self.vowels.iter_mut().zip(vowels.iter()) {
+            *old += (new - *old) * follow;
+        }
+
+        if self.is_finished() {
+            self.playing = false;
+        }
+    }
+
+    // how open the mouth is and its form, no form in the volume mode
+    pub fn mouth(&self) -> (Real, Option<Real>) {
+        match self.mode {
+            LipSyncMode::Volume => (self.level, None),
+            LipSyncMode::Vowels => {
+                let total: Real = self.vowels.iter().sum();
+
+                if total <= 0.0 {
+                    return (self.level, None);
+                }
+
+                let (open, form) = self.vowels.iter().zip(self.shapes.iter()).fold(
+                    (0.0, 0.0),
+                    |(open, form), (weight, shape)| {
+                        (open + shape.open * weight, form + shape.form * weight)
+                    },
+                );
+
+                (self.level * open / total, Some(form / total))
+            }
+        }
+    }
+}
+
+pub struct LipSyncUpdate;
+
+impl<'a> System<'a> for LipSyncUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time>,
+        WriteStorage<'a, LipSync>,
+        WriteStorage<'a, Parameters>,
+    );
+
+    fn run(&mut self, (entities, time, mut lip_syncs, mut parameters): Self::SystemData) {
+        use specs::Join;
+
+        let delta = time.delta.as_secs_f32();
+
+        for (entity, lip_sync) in (&entities, &mut lip_syncs).join() {
+            if !lip_sync.playing {
+                continue;
+            }
+
+            lip_sync.advance(delta);
+
+            if let Some(parameters) = parameters.get_mut(entity) {
+                let (open, form) = lip_sync.mouth();
+
+                parameters.set(&lip_sync.open_parameter, open);
+
+                if let Some(form) = form {
+                    parameters.set(&lip_sync.form_parameter, form);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a wav file with the frames given as the bytes of their samples
+    fn wav(kind: u16, channels: u16, bits: u16, frames: &[Vec<u8>]) -> Vec<u8> {
+        let data = frames.concat();
+        let rate = 16000u32;
+        let block = channels * bits / 8;
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&kind.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&rate.to_le_bytes());
+        bytes.extend_from_slice(&(rate * block as u32).to_le_bytes());
+        bytes.extend_from_slice(&block.to_le_bytes());
+        bytes.extend_from_slice(&bits.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&data);
+        bytes
+    }
+
+    // a second of sines at 16khz, each one a frequency and an amplitude
+    fn sines(waves: &[(Real, Real)]) -> Wav {
+        Wav {
+            sample_rate: 16000,
+            samples: (0..16000)
+                .map(|i| {
+                    let time = i as Real / 16000.0;
+                    waves
+                        .iter()
+                        .map(|(frequency, amplitude)| {
+                            amplitude * (2.0 * PI * frequency * time).sin()
+                        })
+                        .sum()
+                })
+                .collect(),
+        }
+    }
+
+    fn close(a: Real, b: Real) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn from_bytes_reads_every_format() {
+        let formats: [(u16, u16, Vec<u8>); 5] = [
+            (1, 8, vec![192]),
+            (1, 16, 16384i16.to_le_bytes().to_vec()),
+            (1, 24, 4194304i32.to_le_bytes()[..3].to_vec()),
+            (1, 32, 1073741824i32.to_le_bytes().to_vec()),
+            (3, 32, 0.5f32.to_le_bytes().to_vec()),
+        ];
+
+        for (kind, bits, sample) in formats {
+            let wav = Wav::from_bytes(&wav(kind, 1, bits, &[sample.clone(), sample])).unwrap();
+
+            assert_eq!(wav.sample_rate, 16000);
+            assert_eq!(wav.samples.len(), 2);
+            assert!(close(wav.samples[0], 0.5), "{} bits", bits);
+        }
+    }
+
+    #[test]
+    fn from_bytes_mixes_the_channels_down() {
+        let frame = [16384i16.to_le_bytes(), (-8192i16).to_le_bytes()].concat();
+        let wav = Wav::from_bytes(&wav(1, 2, 16, &[frame])).unwrap();
+
+        assert_eq!(wav.samples.len(), 1);
+        assert!(close(wav.samples[0], 0.125));
+    }
+
+    #[test]
+    fn from_bytes_refuses_what_it_cant_read() {
+        assert!(Wav::from_bytes(b"RIFF").is_err());
+        assert!(Wav::from_bytes(&wav(1, 1, 12, &[vec![0, 0]])).is_err());
+        assert!(Wav::from_bytes(&wav(1, 0, 16, &[])).is_err());
+        assert!(Wav::from_bytes(&wav(1, 1, 16, &[])).is_ok());
+    }
+
+    #[test]
+    fn from_bytes_refuses_a_truncated_fmt_chunk() {
+        let bytes = wav(1, 1, 16, &[vec![0, 0], vec![0, 0]]);
+
+        // cut in the middle of the fmt chunk, after the header and the chunk header
+        assert!(Wav::from_bytes(&bytes[..12 + 8 + 10]).is_err());
+
+        // wherever the file ends it is refused or read, never out of its bounds
+        for end in 0..bytes.len() {
+            let _ = Wav::from_bytes(&bytes[..end]);
+        }
+    }
+
+    #[test]
+    fn rms_of_silence_and_of_a_sine() {
+        assert_eq!(sines(&[]).rms(0.5, 0.1), 0.0);
+
+        // the rms of a sine is its amplitude over the square root of 2
+        let sine = sines(&[(440.0, 0.5)]);
+        assert!((sine.rms(0.5, 0.1) - 0.5 / 2.0f32.sqrt()).abs() < 1e-2);
+
+        // half of the window is out of the file
+        assert_eq!(sine.window(0.0, 0.1).len(), 800);
+        assert_eq!(sine.rms(2.0, 0.1), 0.0);
+    }
+
+    #[test]
+    fn vowels_follow_the_formants() {
+        assert_eq!(sines(&[]).vowels(0.5, 1.0 / 30.0), [0.0; 5]);
+
+        let a = sines(&[(800.0, 0.5), (1200.0, 0.3)]).vowels(0.5, 1.0 / 30.0);
+        let i = sines(&[(300.0, 0.5), (2300.0, 0.3)]).vowels(0.5, 1.0 / 30.0);
+
+        let strongest = |vowels: [Real; 5]| {
+            (0..5)
+                .max_by(|x, y| vowels[*x].partial_cmp(&vowels[*y]).unwrap())
+                .unwrap()
+        };
+
+        assert_eq!(strongest(a), 0);
+        assert_eq!(strongest(i), 1);
+        assert!(close(a.iter().sum(), 1.0));
+    }
+
+    #[test]
+    fn advance_opens_the_mouth_with_the_volume_and_stops_at_the_end() {
+        let mut lip_sync = LipSync::new(Arc::new(sines(&[(440.0, 0.5)])), LipSyncMode::Volume);
+        lip_sync.smoothing = 0.0;
+
+        lip_sync.advance(0.5);
+        let (open, form) = lip_sync.mouth();
+        assert!(close(open, 1.0));
+        assert!(form.is_none());
+
+        lip_sync.advance(0.6);
+        assert_eq!(lip_sync.mouth().0, 0.0);
+        assert!(!lip_sync.playing);
+
+        let mut quiet = LipSync::new(Arc::new(sines(&[])), LipSyncMode::Vowels);
+        quiet.advance(0.5);
+        assert_eq!(quiet.mouth(), (0.0, None));
+    }
+
+    #[test]
+    fn advance_is_the_same_for_the_same_deltas() {
+        let audio = Arc::new(sines(&[(800.0, 0.1), (1200.0, 0.05)]));
+        let run = || {
+            let mut lip_sync = LipSync::new(audio.clone(), LipSyncMode::Vowels);
+            lip_sync.looped = true;
+            (0..90)
+                .map(|_| {
+                    lip_sync.advance(1.0 / 60.0);
+                    lip_sync.mouth()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mouths = run();
+        assert_eq!(mouths, run());
+
+        // the mouth eases toward the volume and takes the form of an a
+        assert!(mouths[0].0 < mouths[10].0);
+        assert!(mouths[89].1.unwrap() < 0.3);
+    }
+
+    #[test]
+    fn a_looped_voice_starts_over() {
+        let mut lip_sync = LipSync::new(Arc::new(sines(&[(440.0, 0.5)])), LipSyncMode::Volume);
+        lip_sync.looped = true;
+        lip_sync.advance(1.5);
+
+        assert!(lip_sync.playing);
+        assert!(close(lip_sync.time, 0.5));
+    }
+}
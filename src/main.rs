@@ -1,22 +1,54 @@
+// the allows below keep the style some modules were written in before clippy ran on them
+#[allow(clippy::single_match)]
 mod actor;
+mod animation;
+mod auto_mesh;
+mod bone;
+#[allow(clippy::init_numbered_fields)]
 mod camera;
+#[allow(clippy::needless_return)]
 mod camera_controller;
 mod camera_uniform;
+mod clipping;
+mod deformer;
+mod expression;
 mod fs;
 mod instance_uniform;
+mod keyform;
 //mod light;
+mod lip_sync;
+mod mesh;
 mod model;
+mod model_settings;
+mod motion;
+mod parameter;
+mod part_pose;
+mod phoneme;
+mod physics;
+mod procedural;
+mod psd_import;
+mod rig;
+mod spine_atlas;
+mod spine_import;
+#[allow(clippy::init_numbered_fields)]
 mod state;
+mod state_machine;
 mod texture;
+mod transform;
+#[allow(clippy::init_numbered_fields, clippy::needless_return)]
 mod type_def;
+#[allow(clippy::init_numbered_fields, clippy::let_and_return)]
 mod buffer_update;
 mod sprite_selector;
+mod sprite_sheet;
+// kept for when the rapier bodies get attached to entities
+#[allow(dead_code, clippy::init_numbered_fields)]
 mod collider;
+#[allow(dead_code, clippy::init_numbered_fields)]
 mod rigid_body;
 
 extern crate rapier2d as rapier;
 
-use rapier::{na::Matrix4};
 use specs::{WorldExt};
 use state::*;
 use winit::{
@@ -40,6 +72,7 @@ pub fn to_deg(rad: Real) -> Real {
     rad * RAD_TO_DEG
 }
 
+#[allow(clippy::single_match)]
 fn main() {
     env_logger::init();
 
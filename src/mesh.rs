@@ -0,0 +1,289 @@
+use crate::{
+    buffer_update::{ArcDataIndex, DataManager},
+    model::ModelVertex,
+    sprite_selector::SpriteSelector,
+    type_def::*,
+};
+use anyhow::{anyhow, Result};
+use specs::{
+    prelude::ComponentEvent, shred::DynamicSystemData, shrev::EventIterator, BitSet, Component,
+    Entities, FlaggedStorage, ReadStorage, ReaderId, System, VecStorage, World, WorldExt, Write,
+    WriteStorage,
+};
+use std::path::Path;
+
+// the triangles used while a given sprite cell is shown
+#[derive(Debug, Clone, Default)]
+pub struct MeshFrame {
+    pub uvs: Vec<[f32; 2]>, // 0.0 to 1.0 inside a single sprite cell, v going down like the texture
+    pub indices: Vec<Indices>,
+}
+
+// triangles of a part, every vertex is placed by where it sits on the current sprite cell
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    pub frames: Vec<MeshFrame>, // one for every sprite cell, or a single one shared by all of them
+}
+
+impl Component for Mesh {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+impl Default for Mesh {
+    fn default() -> Self {
+        Self::quad()
+    }
+}
+
+impl Mesh {
+    pub fn new(uvs: Vec<[f32; 2]>, indices: Vec<Indices>) -> Self {
+        Self {
+            frames: vec![MeshFrame { uvs, indices }],
+        }
+    }
+
+    // the buffers can't change size once created, so every frame is padded to the biggest one
+    // with unused vertices and degenerate triangles
+    pub fn from_frames(mut frames: Vec<MeshFrame>) -> Self {
+        let uvs_len = frames
+            .iter()
+            .map(|frame| frame.uvs.len())
+            .max()
+            .unwrap_or(0);
+        let indices_len = frames
+            .iter()
+            .map(|frame| frame.indices.len())
+            .max()
+            .unwrap_or(0);
+
+        if uvs_len == 0 || indices_len == 0 {
+            return Self::quad();
+        }
+
+        for frame in frames.iter_mut() {
+            let last = frame.uvs.last().copied().unwrap_or([0.0, 0.0]);
+            frame.uvs.resize(uvs_len, last);
+            frame.indices.resize(indices_len, 0);
+        }
+
+        Self { frames }
+    }
+
+    // the two triangles covering the whole cell
+    pub fn quad() -> Self {
+        Self::new(
+            vec![[1.0, 1.0], [1.0, 0.0], [0.0, 0.0], [0.0, 1.0]],
+            vec![0, 1, 2, 0, 2, 3],
+        )
+    }
+
+    pub fn frame(&self, at: u32) -> &MeshFrame {
+        &self.frames[at as usize % self.frames.len()]
+    }
+
+    // the vertices and indices of a part are given their room in the buffers once,
+    // a mesh with another count of either can't replace the one the part was created with
+    pub fn fits(&self, at: u32, vertices: usize, indices: usize) -> Result<()> {
+        let frame = self.frame(at);
+
+        match frame.uvs.len() == vertices && frame.indices.len() == indices {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "a mesh of {} vertices and {} indices can't replace one of {} vertices and {} indices",
+                frame.uvs.len(),
+                frame.indices.len(),
+                vertices,
+                indices
+            )),
+        }
+    }
+
+    // loads the first object of a wavefront file, its texture coordinates place the vertices,
+    // the positions are only used (stretched over the cell) when it doesn't have any
+    pub fn load_obj<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<Self> {
+        let (models, _) = tobj::load_obj(&path, &tobj::GPU_LOAD_OPTIONS)?;
+        let mesh = models
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("no object inside {:?}", path))?
+            .mesh;
+
+        let uvs = if mesh.texcoords.is_empty() {
+            let positions = mesh.positions.chunks(3).collect::<Vec<_>>();
+
+            let (min_x, max_x, min_y, max_y) = positions.iter().fold(
+                (f32::MAX, f32::MIN, f32::MAX, f32::MIN),
+                |(min_x, max_x, min_y, max_y), p| {
+                    (
+                        min_x.min(p[0]),
+                        max_x.max(p[0]),
+                        min_y.min(p[1]),
+                        max_y.max(p[1]),
+                    )
+                },
+            );
+
+            let width = (max_x - min_x).max(f32::EPSILON);
+            let height = (max_y - min_y).max(f32::EPSILON);
+
+            positions
+                .iter()
+                .map(|p| [(p[0] - min_x) / width, 1.0 - (p[1] - min_y) / height])
+                .collect()
+        } else {
+            // obj texture coordinates go up, the textures go down
+            mesh.texcoords
+                .chunks(2)
+                .map(|t| [t[0], 1.0 - t[1]])
+                .collect()
+        };
+
+        Ok(Self::new(uvs, mesh.indices))
+    }
+}
+
+// swaps the triangles of the meshes that have one frame per sprite cell when the cell changes
+#[derive(Default)]
+pub struct MeshIndicesUpdate {
+    pub dirty: BitSet,
+    pub reader_id_sprite_selector: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_mesh: Option<ReaderId<ComponentEvent>>,
+}
+
+impl MeshIndicesUpdate {
+    fn event_update(&mut self, events: EventIterator<'_, ComponentEvent>) {
+        for event in events.into_iter() {
+            match event {
+                ComponentEvent::Modified(id) | ComponentEvent::Inserted(id) => {
+                    self.dirty.add(*id);
+                }
+                ComponentEvent::Removed(_id) => (),
+            }
+        }
+    }
+}
+
+impl<'a> System<'a> for MeshIndicesUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, DataManager<Indices>>,
+        WriteStorage<'a, ArcDataIndex<Indices>>, // mutable to flag a modification in the flagstorage in order to update its buffer
+        ReadStorage<'a, ArcDataIndex<ModelVertex>>,
+        ReadStorage<'a, SpriteSelector>,
+        ReadStorage<'a, Mesh>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut indices_data,
+            mut indices_indices,
+            vertices_indices,
+            sprite_selectors,
+            meshes,
+        ): Self::SystemData,
+    ) {
+        use specs::Join;
+
+        self.dirty.clear();
+
+        let events_sprite_selector = sprite_selectors
+            .channel()
+            .read(self.reader_id_sprite_selector.as_mut().unwrap());
+        let events_mesh = meshes.channel().read(self.reader_id_mesh.as_mut().unwrap());
+
+        self.event_update(events_sprite_selector);
+
+        // a mesh with a single frame never has to change its triangles with the sprite cell
+        for (entity, mesh) in (&entities, &meshes).join() {
+            if mesh.frames.len() < 2 {
+                self.dirty.remove(entity.id());
+            }
+        }
+
+        // but a new mesh always brings its own
+        self.event_update(events_mesh);
+
+        (
+            &entities,
+            &mut indices_indices,
+            &vertices_indices,
+            &sprite_selectors,
+            &meshes,
+            &self.dirty,
+        )
+            .join()
+            .for_each(
+                |(entity, index_index, vertex_index, sprite_selector, mesh, _)| {
+                    let vertices = vertex_index.0.lock().unwrap().amount;
+                    let index_index = index_index.0.lock().unwrap();
+
+                    // the part keeps its old triangles, ModelVertexUpdate refuses the mesh the same way
+                    if let Err(e) = mesh.fits(sprite_selector.at, vertices, index_index.amount) {
+                        eprintln!("{:?}: {:?}", entity, e);
+                        return;
+                    }
+
+                    let old_indices = indices_data.get_mut_range(&index_index);
+                    old_indices.copy_from_slice(&mesh.frame(sprite_selector.at).indices);
+                },
+            );
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(&self.accessor(), world);
+        self.reader_id_sprite_selector =
+            Some(world.write_component::<SpriteSelector>().register_reader());
+        self.reader_id_mesh = Some(world.write_component::<Mesh>().register_reader());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_padded_to_the_biggest() {
+        let mesh = Mesh::from_frames(vec![
+            MeshFrame {
+                uvs: vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0]],
+                indices: vec![0, 1, 2],
+            },
+            Mesh::quad().frames.remove(0),
+        ]);
+
+        for frame in mesh.frames.iter() {
+            assert_eq!(frame.uvs.len(), 4);
+            assert_eq!(frame.indices.len(), 6);
+        }
+
+        // the padding repeats the last vertex and only adds degenerate triangles
+        assert_eq!(mesh.frames[0].uvs[3], [1.0, 1.0]);
+        assert_eq!(&mesh.frames[0].indices[3..], &[0, 0, 0]);
+    }
+
+    #[test]
+    fn empty_frames_give_a_quad() {
+        let mesh = Mesh::from_frames(Vec::new());
+
+        assert_eq!(mesh.frames.len(), 1);
+        assert_eq!(mesh.frame(0).indices, vec![0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn frames_wrap_around() {
+        let mesh = Mesh::from_frames(vec![MeshFrame::default(), Mesh::quad().frames.remove(0)]);
+
+        assert_eq!(mesh.frame(3).uvs, mesh.frame(1).uvs);
+    }
+
+    #[test]
+    fn only_a_mesh_of_the_same_size_fits() {
+        let mesh = Mesh::quad();
+
+        assert!(mesh.fits(0, 4, 6).is_ok());
+        assert!(mesh.fits(0, 3, 6).is_err());
+        assert!(mesh.fits(0, 4, 3).is_err());
+    }
+}
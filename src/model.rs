@@ -1,7 +1,9 @@
 use crate::{
+    auto_mesh::AutoMesh,
     buffer_update::{ArcDataIndex, DataManager},
-    camera::Scale,
+    mesh::Mesh,
     sprite_selector::SpriteSelector,
+    sprite_sheet::SpriteSheet,
     type_def::*,
 };
 use anyhow::Result;
@@ -23,13 +25,16 @@ use std::{
 
 use crate::texture::{self};
 
-pub const DEFAULT_MATERIAL: &str = "assets/default/materials/default.mtl";
-
 pub const DEFAULT_DIFFUSE: &str = "assets/default/textures/default_diffuse.png";
 pub const DEFAULT_NORMAL: &str = "assets/default/textures/default_normal.png";
 pub const DEFAULT_SPECULAR: &str = "assets/default/textures/default_specular.png";
 pub const DEFAULT_AMBIENT: &str = "assets/default/textures/default_ambient.png";
 
+// how many texture pixels fit in one world unit when scale is 1.0
+pub const PIXELS_PER_UNIT: Real = 250.0;
+
+pub type ModelData = (Model, Mesh, Vec<ModelVertex>, SpriteSelector);
+
 pub trait Vertex {
     const ATTRIBUTES: &'static [wgpu::VertexAttribute];
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a>;
@@ -112,19 +117,20 @@ impl Vertex for ModelVertex {
 }
 
 impl ModelVertex {
-    fn new(
-        scale: &Vector,
+    pub fn new(
+        mesh: &Mesh,
         sprite_selector: &SpriteSelector,
         material: &Material,
         //indices: &[Indices],
     ) -> Vec<Self> {
         let dimensions = material.diffuse_texture.img.as_ref().unwrap().dimensions();
 
-        let scale_x = scale.x / 250.0;
-        let scale_y = scale.y / 250.0;
+        // the entity scale is applied by its transform, not here
+        let scale_x = 1.0 / PIXELS_PER_UNIT;
+        let scale_y = 1.0 / PIXELS_PER_UNIT;
 
-        let mut size_x = dimensions.0 as f32 * sprite_selector.width;
-        let mut size_y = dimensions.1 as f32 * sprite_selector.height;
+        // a trimmed frame of a sheet is put back where it was in its untrimmed frame so it doesn't jitter
+        let (offset, size, source) = sprite_selector.frame_rect(sprite_selector.at, dimensions);
 
         let mid_w = material
             .mat
@@ -133,7 +139,7 @@ impl ModelVertex {
             .unwrap_or(&String::new())
             .parse::<f32>()
             .unwrap_or(0.0)
-            .clamp(0.0, size_x)
+            .clamp(0.0, source[0])
             * scale_x;
 
         let mid_h = material
@@ -143,33 +149,21 @@ impl ModelVertex {
             .unwrap_or(&String::new())
             .parse::<f32>()
             .unwrap_or(0.0)
-            .clamp(0.0, size_y)
+            .clamp(0.0, source[1])
             * scale_y;
 
-        size_x *= scale_x;
-        size_y *= scale_y;
-
-        let n_half_x = mid_w - size_x;
-        let n_half_y = mid_h - size_y;
-
-        let half_x = size_x + n_half_x;
-        let half_y = size_y + n_half_y;
-
-        let positions: [[f32; 2]; 4] = [
-            [n_half_x, n_half_y],
-            [n_half_x, half_y],
-            [half_x, half_y],
-            [half_x, n_half_y],
-        ];
-        let tex_coords: [[f32; 2]; 4] = sprite_selector.get_current();
-
-        let vertices = positions
+        // the right of the texture goes toward -x and its bottom toward -y
+        let vertices = mesh
+            .frame(sprite_selector.at)
+            .uvs
             .par_iter()
-            .zip(tex_coords.par_iter())
-            .map(|(p, t)| {
+            .map(|uv| {
                 ModelVertex {
-                    position: *p,
-                    tex_coords: *t,
+                    position: [
+                        mid_w - (offset[0] + uv[0] * size[0]) * scale_x,
+                        mid_h - (offset[1] + uv[1] * size[1]) * scale_y,
+                    ],
+                    tex_coords: sprite_selector.get_current_uv(*uv),
                     normal: [0.0, 0.0, 1.0],
                     // We'll calculate these later
                     //tangent: [0.0; 2],
@@ -254,8 +248,8 @@ impl ModelVertex {
 #[derive(Default)]
 pub struct ModelVertexUpdate {
     pub dirty: BitSet,
-    pub reader_id_scale: Option<ReaderId<ComponentEvent>>,
     pub reader_id_sprite_selector: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_mesh: Option<ReaderId<ComponentEvent>>,
 }
 
 impl ModelVertexUpdate {
@@ -275,8 +269,9 @@ impl<'a> System<'a> for ModelVertexUpdate {
     type SystemData = (
         Write<'a, DataManager<ModelVertex>>,
         WriteStorage<'a, ArcDataIndex<ModelVertex>>, // mutable to flag a modification in the flagstorage in order to update its buffer
-        ReadStorage<'a, Scale>,
+        ReadStorage<'a, ArcDataIndex<Indices>>,
         ReadStorage<'a, SpriteSelector>,
+        ReadStorage<'a, Mesh>,
         ReadStorage<'a, Model>,
     );
 
@@ -285,8 +280,9 @@ impl<'a> System<'a> for ModelVertexUpdate {
         (
             mut vertices_data,
             mut vertices_indices, // mutable to flag a modification in the flagstorage in order to update its buffer
-            scales,
+            indices_indices,
             sprite_selectors,
+            meshes,
             materials,
         ): Self::SystemData,
     ) {
@@ -294,29 +290,35 @@ impl<'a> System<'a> for ModelVertexUpdate {
 
         self.dirty.clear();
 
-        let events_scales = scales
-            .channel()
-            .read(self.reader_id_scale.as_mut().unwrap());
         let events_sprite_selector = sprite_selectors
             .channel()
             .read(self.reader_id_sprite_selector.as_mut().unwrap());
 
-        self.event_update(events_scales);
+        let events_mesh = meshes.channel().read(self.reader_id_mesh.as_mut().unwrap());
+
         self.event_update(events_sprite_selector);
+        self.event_update(events_mesh);
 
         (
             &mut vertices_indices,
-            &scales,
+            &indices_indices,
             &sprite_selectors,
+            &meshes,
             &materials,
             &self.dirty,
         )
             .join()
-            .for_each(|(vertex_index, scale, sprite_selector, material, _)| {
+            .for_each(|(vertex_index, index_index, sprite_selector, mesh, material, _)| {
+                let indices = index_index.0.lock().unwrap().amount;
                 let vertex_index = vertex_index.0.lock().unwrap();
-                let old_vertices = vertices_data.get_mut_range(&vertex_index);
 
-                let new_vertices = ModelVertex::new(&scale.0, sprite_selector, &material.0);
+                // the range was sized by the mesh the entity was created with, MeshIndicesUpdate says why
+                if mesh.fits(sprite_selector.at, vertex_index.amount, indices).is_err() {
+                    return;
+                }
+
+                let old_vertices = vertices_data.get_mut_range(&vertex_index);
+                let new_vertices = ModelVertex::new(mesh, sprite_selector, &material.0);
 
                 old_vertices
                     .par_iter_mut()
@@ -327,9 +329,9 @@ impl<'a> System<'a> for ModelVertexUpdate {
 
     fn setup(&mut self, world: &mut World) {
         Self::SystemData::setup(&self.accessor(), world);
-        self.reader_id_scale = Some(world.write_component::<Scale>().register_reader());
         self.reader_id_sprite_selector =
             Some(world.write_component::<SpriteSelector>().register_reader());
+        self.reader_id_mesh = Some(world.write_component::<Mesh>().register_reader());
     }
 }
 
@@ -342,17 +344,13 @@ impl Model {
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         layout: &wgpu::BindGroupLayout,
-        path_mtl: Option<P>,
+        path_mtl: P,
         path_assets: P,
-        textures_map: &mut DashMap<OsString, Arc<texture::Texture>>,
-        models_map: &mut DashMap<OsString, (Self, Vec<Indices>, Vec<ModelVertex>, SpriteSelector)>,
-    ) -> Result<(Self, Vec<Indices>, Vec<ModelVertex>, SpriteSelector)> {
+        textures_map: &DashMap<OsString, Arc<texture::Texture>>,
+        models_map: &DashMap<OsString, ModelData>,
+    ) -> Result<ModelData> {
         let path_assets = path_assets.as_ref();
-
-        let path_mtl = match path_mtl {
-            Some(path_mtl) => path_mtl.as_ref().as_os_str().to_os_string(),
-            None => OsString::from(DEFAULT_MATERIAL),
-        };
+        let path_mtl = path_mtl.as_ref().as_os_str().to_os_string();
 
         match models_map.get(&path_mtl) {
             Some(model) => Ok(model.clone()),
@@ -369,36 +367,38 @@ impl Model {
                     ]
                     .par_iter()
                     .map(|(texture_path, default, is_normal_map)| {
-                        let entry = if texture_path.len() == 0 {
+                        let entry = if texture_path.is_empty() {
                             OsString::from(default)
                         } else {
                             path_assets.join(texture_path).as_os_str().to_os_string()
                         };
 
-                        match textures_map.get(&entry) {
-                            Some(texture) => texture.clone(),
-                            None => {
-                                let texture =
-                                    texture::Texture::load(device, queue, &entry, *is_normal_map);
-
-                                match texture {
-                                    Ok(texture) => {
-                                        textures_map.insert(entry, texture.clone());
-
-                                        texture
-                                    }
-                                    Err(e) => {
-                                        eprintln!(
-                                        "Error : {}. fall back on default texture. |{}|, |{:?}| ",
-                                        e, texture_path, entry
-                                    );
-                                        textures_map.get(OsStr::new(default)).unwrap().clone()
-                                    }
-                                }
+                        let texture = Self::cached_texture(
+                            device,
+                            queue,
+                            &entry,
+                            *is_normal_map,
+                            textures_map,
+                        );
+
+                        match texture {
+                            Ok(texture) => Ok(texture),
+                            Err(e) => {
+                                eprintln!(
+                                    "Error : {}. fall back on default texture. |{}|, |{:?}| ",
+                                    e, texture_path, entry
+                                );
+                                Self::cached_texture(
+                                    device,
+                                    queue,
+                                    OsStr::new(default),
+                                    *is_normal_map,
+                                    textures_map,
+                                )
                             }
                         }
                     })
-                    .collect::<Vec<_>>();
+                    .collect::<Result<Vec<_>>>()?;
 
                     let ambient_texture = textures.pop().unwrap();
                     let specular_texture = textures.pop().unwrap();
@@ -416,20 +416,172 @@ impl Model {
                     ))
                 };
 
-                let sprite_selector = SpriteSelector::from_mat(&material.mat.unknown_param);
+                let mut sprite_selector = SpriteSelector::from_mat(&material.mat.unknown_param);
+
+                // "sheet file.json" in the mtl takes the frames from an aseprite or texturepacker export
+                if let Some(sheet_path) = material.mat.unknown_param.get("sheet") {
+                    let sheet = SpriteSheet::load(path_assets.join(sheet_path))?;
+                    for warning in sheet.warnings.iter() {
+                        eprintln!("Warning : {}. |{}|", warning, sheet_path);
+                    }
+                    sprite_selector.use_sheet(&sheet);
+                }
+
+                // "mesh file.obj" in the mtl gives the part its own triangles instead of a quad,
+                // "mesh auto" builds them from the alpha of the diffuse texture
+                let mesh = match material
+                    .mat
+                    .unknown_param
+                    .get("mesh")
+                    .map(|mesh| mesh.as_str())
+                {
+                    Some("auto") => AutoMesh::from_mat(&material.mat.unknown_param).generate(
+                        material.diffuse_texture.img.as_ref().unwrap(),
+                        &sprite_selector,
+                    ),
+                    Some(mesh_path) => Mesh::load_obj(path_assets.join(mesh_path))?,
+                    None => Mesh::quad(),
+                };
 
-                let indices = vec![0, 1, 2, 0, 2, 3]; // 0, 1, 2, 0, 2, 3 // 0, 2, 1, 0, 3, 2
-                                                      //let indices = vec![0, 2, 1, 0, 3, 2];
-                let vertices =
-                    ModelVertex::new(&Vector::new(1.0, 1.0), &sprite_selector, &material);
+                let vertices = ModelVertex::new(&mesh, &sprite_selector, &material);
 
-                let result = (Self(material), indices, vertices, sprite_selector);
+                let result = (Self(material), mesh, vertices, sprite_selector);
                 models_map.insert(path_mtl, result.clone());
 
                 Ok(result)
             }
         }
     }
+
+    // the texture at a path, loaded only the first time it is asked for
+    pub fn cached_texture(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: &OsStr,
+        is_normal_map: bool,
+        textures_map: &DashMap<OsString, Arc<texture::Texture>>,
+    ) -> Result<Arc<texture::Texture>> {
+        match textures_map.get(path) {
+            Some(texture) => Ok(texture.clone()),
+            None => {
+                let texture = texture::Texture::load(device, queue, path, is_normal_map)?;
+                textures_map.insert(path.to_os_string(), texture.clone());
+
+                Ok(texture)
+            }
+        }
+    }
+
+    // builds a model around an already created diffuse texture (ex: a psd layer),
+    // the other maps fall back on the default textures
+    pub fn from_texture(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        name: &str,
+        mesh: Mesh,
+        diffuse_texture: Arc<texture::Texture>,
+        textures_map: &DashMap<OsString, Arc<texture::Texture>>,
+    ) -> Result<ModelData> {
+        let (width, height) = diffuse_texture.img.as_ref().unwrap().dimensions();
+
+        // centers the mesh on its position so rotations happen around the middle of the texture
+        Self::from_material(
+            device,
+            queue,
+            layout,
+            name,
+            [width as f32 / 2.0, height as f32 / 2.0],
+            mesh,
+            diffuse_texture,
+            textures_map,
+        )
+    }
+
+    // a region of a packed texture, like the ones of a spine atlas, centered on its untrimmed frame
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_sheet(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        name: &str,
+        mesh: Mesh,
+        diffuse_texture: Arc<texture::Texture>,
+        sheet: &SpriteSheet,
+        textures_map: &DashMap<OsString, Arc<texture::Texture>>,
+    ) -> Result<ModelData> {
+        let source = sheet.frames.first().map_or([0, 0], |frame| frame.source);
+
+        let (model, mesh, _, mut sprite_selector) = Self::from_material(
+            device,
+            queue,
+            layout,
+            name,
+            [source[0] as f32 / 2.0, source[1] as f32 / 2.0],
+            mesh,
+            diffuse_texture,
+            textures_map,
+        )?;
+
+        sprite_selector.use_sheet(sheet);
+
+        let vertices = ModelVertex::new(&mesh, &sprite_selector, &model.0);
+
+        Ok((model, mesh, vertices, sprite_selector))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn from_material(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        name: &str,
+        mid: [f32; 2],
+        mesh: Mesh,
+        diffuse_texture: Arc<texture::Texture>,
+        textures_map: &DashMap<OsString, Arc<texture::Texture>>,
+    ) -> Result<ModelData> {
+        let mut mat = tobj::Material {
+            name: name.to_string(),
+            ..Default::default()
+        };
+
+        mat.unknown_param
+            .insert("midW".to_string(), mid[0].to_string());
+        mat.unknown_param
+            .insert("midH".to_string(), mid[1].to_string());
+
+        let mut textures = [
+            (DEFAULT_NORMAL, true),
+            (DEFAULT_SPECULAR, true),
+            (DEFAULT_AMBIENT, true),
+        ]
+        .iter()
+        .map(|(default, is_normal_map)| {
+            Self::cached_texture(device, queue, OsStr::new(default), *is_normal_map, textures_map)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+        let ambient_texture = textures.pop().unwrap();
+        let specular_texture = textures.pop().unwrap();
+        let normal_texture = textures.pop().unwrap();
+
+        let material = Arc::new(Material::new(
+            device,
+            mat,
+            diffuse_texture,
+            normal_texture,
+            specular_texture,
+            ambient_texture,
+            layout,
+        ));
+
+        let sprite_selector = SpriteSelector::from_mat(&material.mat.unknown_param);
+
+        let vertices = ModelVertex::new(&mesh, &sprite_selector, &material);
+
+        Ok((Self(material), mesh, vertices, sprite_selector))
+    }
 }
 
 #[derive(Component, Debug)]
@@ -437,8 +589,12 @@ impl Model {
 pub struct Material {
     pub mat: tobj::Material,
     pub diffuse_texture: Arc<texture::Texture>,
+    // only the bind group reads the other maps, they are held so they live as long as it does
+    #[allow(dead_code)]
     pub normal_texture: Arc<texture::Texture>,
+    #[allow(dead_code)]
     pub specular_texture: Arc<texture::Texture>,
+    #[allow(dead_code)]
     pub ambient_texture: Arc<texture::Texture>,
     pub bind_group: wgpu::BindGroup,
 }
@@ -0,0 +1,235 @@
+use crate::{
+    expression::{Expression, ExpressionInput, ExpressionPlayer},
+    lip_sync::LipSync,
+    model::Model,
+    motion::{Motion, MotionGroups},
+    parameter::{Parameter, Parameters},
+    part_pose::PartPose,
+    physics::Physics,
+    procedural::AutoBlink,
+    texture,
+    type_def::*,
+};
+use anyhow::Result;
+use dashmap::DashMap;
+use serde::Deserialize;
+use specs::{Entity, World, WorldExt};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+// the layout of a cubism model3.json file, the moc3 it points to is left alone
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Model3 {
+    file_references: Model3Files,
+    #[serde(default)]
+    groups: Vec<Model3Group>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Model3Files {
+    #[serde(default)]
+    textures: Vec<String>,
+    physics: Option<String>,
+    pose: Option<String>,
+    #[serde(default)]
+    expressions: Vec<Model3Expression>,
+    #[serde(default)]
+    motions: HashMap<String, Vec<Model3Motion>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Model3Expression {
+    name: String,
+    file: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Model3Motion {
+    file: String,
+    fade_in_time: Option<Real>,
+    fade_out_time: Option<Real>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Model3Group {
+    name: String,
+    #[serde(default)]
+    ids: Vec<String>,
+}
+
+// what a cubism model3.json says about a puppet, every file loaded relative to it
+#[derive(Debug, Clone)]
+pub struct ModelSettings {
+    pub textures: Vec<PathBuf>,
+    pub motions: MotionGroups,
+    pub expressions: Vec<Expression>,
+    pub physics: Option<Physics>,
+    pub pose: Option<PartPose>,
+    pub eye_blink: Vec<String>, // parameters closing the eyes
+    pub lip_sync: Vec<String>,  // parameters opening the mouth
+}
+
+impl ModelSettings {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        let model: Model3 = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let files = model.file_references;
+
+        let mut motions = MotionGroups::default();
+
+        for (group, entries) in files.motions.into_iter() {
+            for entry in entries.into_iter() {
+                let mut motion = Motion::load(directory.join(&entry.file))?;
+
+                // the model can fade a motion differently than its file
+                if let Some(fade_in) = entry.fade_in_time.filter(|time| *time >= 0.0) {
+                    motion.fade_in = fade_in;
+                }
+                if let Some(fade_out) = entry.fade_out_time.filter(|time| *time >= 0.0) {
+                    motion.fade_out = fade_out;
+                }
+
+                motions.add(&group, motion);
+            }
+        }
+
+        let expressions = files
+            .expressions
+            .iter()
+            .map(|expression| {
+                let json = std::fs::read_to_string(directory.join(&expression.file))?;
+                Expression::from_json(&expression.name, &json)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let physics = files
+            .physics
+            .map(|physics| Physics::load(directory.join(physics)))
+            .transpose()?;
+
+        let pose = files
+            .pose
+            .map(|pose| PartPose::load(directory.join(pose)))
+            .transpose()?;
+
+        let ids = |name: &str| {
+            model
+                .groups
+                .iter()
+                .filter(|group| group.name == name)
+                .flat_map(|group| group.ids.iter().cloned())
+                .collect::<Vec<_>>()
+        };
+
+        Ok(Self {
+            textures: files
+                .textures
+                .iter()
+                .map(|texture| directory.join(texture))
+                .collect(),
+            motions,
+            expressions,
+            physics,
+            pose,
+            eye_blink: ids("EyeBlink"),
+            lip_sync: ids("LipSync"),
+        })
+    }
+
+    // the textures of the model in their order, shared with the other models through the map
+    pub fn load_textures(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        textures_map: &DashMap<OsString, Arc<texture::Texture>>,
+    ) -> Result<Vec<Arc<texture::Texture>>> {
+        self.textures
+            .iter()
+            .map(|path| Model::cached_texture(device, queue, path.as_os_str(), false, textures_map))
+            .collect()
+    }
+
+    // puts the motions, expressions, physics, pose and blinking of the model on the root of a puppet,
+    // MotionUpdate goes through the Idle motions when nothing else plays
+    pub fn insert(self, world: &World, root: Entity) {
+        let mut parameters = world.write_storage::<Parameters>();
+
+        // the ranges are in the moc3, the parameters only the physics knows of go from -1 to 1
+        if let Some(parameters) = parameters.get_mut(root) {
+            let outputs = self.physics.iter().flat_map(|physics| {
+                physics
+                    .settings
+                    .iter()
+                    .flat_map(|setting| setting.outputs.iter())
+            });
+
+            for output in outputs {
+                if parameters.get(&output.parameter).is_none() {
+                    parameters.add(Parameter::new(&output.parameter, -1.0, 0.0, 1.0));
+                }
+            }
+        }
+
+        world
+            .write_storage::<MotionGroups>()
+            .insert(root, self.motions)
+            .unwrap();
+
+        if !self.expressions.is_empty() {
+            let player = ExpressionPlayer::new(self.expressions);
+
+            world
+                .write_storage::<ExpressionInput>()
+                .insert(root, ExpressionInput::numbered(&player))
+                .unwrap();
+            world
+                .write_storage::<ExpressionPlayer>()
+                .insert(root, player)
+                .unwrap();
+        }
+
+        if let Some(physics) = self.physics {
+            world
+                .write_storage::<Physics>()
+                .insert(root, physics)
+                .unwrap();
+        }
+
+        if let Some(pose) = self.pose {
+            world
+                .write_storage::<PartPose>()
+                .insert(root, pose)
+                .unwrap();
+        }
+
+        if let (Some(open_parameter), Some(lip_sync)) = (
+            self.lip_sync.first(),
+            world.write_storage::<LipSync>().get_mut(root),
+        ) {
+            lip_sync.open_parameter = open_parameter.clone();
+        }
+
+        if !self.eye_blink.is_empty() {
+            let mut auto_blinks = world.write_storage::<AutoBlink>();
+
+            match auto_blinks.get_mut(root) {
+                Some(auto_blink) => auto_blink.parameters = self.eye_blink,
+                None => {
+                    let mut auto_blink = AutoBlink::default();
+                    auto_blink.parameters = self.eye_blink;
+                    auto_blinks.insert(root, auto_blink).unwrap();
+                }
+            }
+        }
+    }
+}
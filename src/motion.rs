@@ -0,0 +1,657 @@
+use crate::{
+    actor::{ParentHierarchy, Time},
+    parameter::Parameters,
+    type_def::*,
+};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use specs::{
+    Component, DenseVecStorage, Entities, Read, ReadExpect, ReadStorage, System, WriteStorage,
+};
+use std::{collections::HashMap, f32::consts::PI, path::Path, sync::Arc};
+
+// the layout of a cubism motion3.json file, only what is needed to play it
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Motion3 {
+    meta: Motion3Meta,
+    curves: Vec<Motion3Curve>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Motion3Meta {
+    duration: Real,
+    #[serde(default)]
+    r#loop: bool,
+    #[serde(default)]
+    are_beziers_restricted: bool,
+    fade_in_time: Option<Real>,
+    fade_out_time: Option<Real>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Motion3Curve {
+    target: String,
+    id: String,
+    fade_in_time: Option<Real>,
+    fade_out_time: Option<Real>,
+    segments: Vec<Real>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotionTarget {
+    Model, // EyeBlink and LipSync, weights cubism gives to its own effects
+    Parameter,
+    PartOpacity,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    Linear,
+    Bezier,
+    Stepped,        // keeps the value of its first point until the next one
+    InverseStepped, // jumps to the value of its last point right away
+}
+
+#[derive(Debug, Clone)]
+pub struct MotionCurve {
+    pub target: MotionTarget,
+    pub id: String,
+    pub fade_in: Option<Real>, // replaces the ones of the motion when given
+    pub fade_out: Option<Real>,
+    pub points: Vec<[Real; 2]>,              // time and value
+    pub segments: Vec<(SegmentKind, usize)>, // index of the first point of each segment
+}
+
+impl MotionCurve {
+    // the value at a time, held before the first point and after the last one
+    pub fn evaluate(&self, time: Real, restricted: bool) -> Real {
+        let last = self.points.len() - 1;
+
+        if time <= self.points[0][0] || self.segments.is_empty() {
+            return self.points[0][1];
+        }
+
+        if time >= self.points[last][0] {
+            return self.points[last][1];
+        }
+
+        let (kind, start) = *self
+            .segments
+            .iter()
+            .find(|(kind, start)| time <= self.points[start + Self::length(*kind)][0])
+            .unwrap();
+
+        let points = &self.points[start..=start + Self::length(kind)];
+        let [a, b] = [points[0], points[points.len() - 1]];
+
+        match kind {
+            SegmentKind::Linear => {
+                let t = ((time - a[0]) / (b[0] - a[0])).clamp(0.0, 1.0);
+                a[1] + (b[1] - a[1]) * t
+            }
+            SegmentKind::Stepped => a[1],
+            SegmentKind::InverseStepped => b[1],
+            SegmentKind::Bezier => {
+                let bezier = |t: Real, axis: usize| {
+                    let u = 1.0 - t;
+                    u * u * u * points[0][axis]
+                        + 3.0 * u * u * t * points[1][axis]
+                        + 3.0 * u * t * t * points[2][axis]
+                        + t * t * t * points[3][axis]
+                };
+
+                // older motions place the bezier by the time alone, the others by its curve
+                let t = if restricted {
+                    (time - a[0]) / (b[0] - a[0])
+                } else {
+                    let (mut low, mut high) = (0.0, 1.0);
+                    for _ in 0..24 {
+                        let middle = (low + high) / 2.0;
+                        if bezier(middle, 0) < time {
+                            low = middle;
+                        } else {
+                            high = middle;
+                        }
+                    }
+                    (low + high) / 2.0
+                };
+
+                bezier(t.clamp(0.0, 1.0), 1)
+            }
+        }
+    }
+
+    // points added by a segment after the one it starts from
+    fn length(kind: SegmentKind) -> usize {
+        match kind {
+            SegmentKind::Bezier => 3,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Motion {
+    pub duration: Real,
+    pub looped: bool,
+    pub restricted_beziers: bool,
+    pub fade_in: Real,
+    pub fade_out: Real,
+    pub curves: Vec<MotionCurve>,
+}
+
+impl Motion {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let motion: Motion3 = serde_json::from_str(json)?;
+
+        let curves = motion
+            .curves
+            .into_iter()
+            .map(|curve| {
+                let target = match curve.target.as_str() {
+                    "Model" => MotionTarget::Model,
+                    "Parameter" => MotionTarget::Parameter,
+                    "PartOpacity" => MotionTarget::PartOpacity,
+                    target => bail!("unknown motion target {} for {}", target, curve.id),
+                };
+
+                let (points, segments) = Self::segments(&curve.segments)
+                    .ok_or_else(|| anyhow::anyhow!("broken segments in the curve {}", curve.id))?;
+
+                Ok(MotionCurve {
+                    target,
+                    id: curve.id,
+                    fade_in: curve.fade_in_time.filter(|time| *time >= 0.0),
+                    fade_out: curve.fade_out_time.filter(|time| *time >= 0.0),
+                    points,
+                    segments,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // cubism fades for a second when the motion doesn't say otherwise
+        Ok(Self {
+            duration: motion.meta.duration,
+            looped: motion.meta.r#loop,
+            restricted_beziers: motion.meta.are_beziers_restricted,
+            fade_in: motion.meta.fade_in_time.unwrap_or(1.0).max(0.0),
+            fade_out: motion.meta.fade_out_time.unwrap_or(1.0).max(0.0),
+            curves,
+        })
+    }
+
+    // the first point, then the kind of each segment followed by the points it adds
+    #[allow(clippy::type_complexity)]
+    fn segments(flat: &[Real]) -> Option<(Vec<[Real; 2]>, Vec<(SegmentKind, usize)>)> {
+        let mut points = vec![[*flat.first()?, *flat.get(1)?]];
+        let mut segments = Vec::new();
+        let mut at = 2;
+
+        while at < flat.len() {
+            let kind = match flat[at] as i32 {
+                0 => SegmentKind::Linear,
+                1 => SegmentKind::Bezier,
+                2 => SegmentKind::Stepped,
+                3 => SegmentKind::InverseStepped,
+                _ => return None,
+            };
+            segments.push((kind, points.len() - 1));
+            at += 1;
+
+            for _ in 0..MotionCurve::length(kind) {
+                points.push([*flat.get(at)?, *flat.get(at + 1)?]);
+                at += 2;
+            }
+        }
+
+        Some((points, segments))
+    }
+}
+
+// smooth start and end of the fades, like cubism
+fn ease(t: Real) -> Real {
+    if t <= 0.0 {
+        0.0
+    } else if t >= 1.0 {
+        1.0
+    } else {
+        0.5 - 0.5 * (t * PI).cos()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayingMotion {
+    pub motion: Arc<Motion>,
+    pub priority: i32,
+    pub looped: bool,
+    pub time: Real,
+    pub fade_out_at: Option<Real>, // time at which it was asked to stop
+}
+
+impl PlayingMotion {
+    // the time at which the motion is gone
+    fn end(&self) -> Option<Real> {
+        let stopped = self
+            .fade_out_at
+            .map(|fade_out_at| fade_out_at + self.motion.fade_out);
+
+        match (stopped, self.looped) {
+            (Some(stopped), true) => Some(stopped),
+            (Some(stopped), false) => Some(stopped.min(self.motion.duration)),
+            (None, true) => None,
+            (None, false) => Some(self.motion.duration),
+        }
+    }
+
+    fn weight(&self, fade_in: Real, fade_out: Real) -> Real {
+        let fade_in = if fade_in > 0.0 {
+            ease(self.time / fade_in)
+        } else {
+            1.0
+        };
+
+        let fade_out = match (self.end(), fade_out > 0.0) {
+            (Some(end), true) => ease((end - self.time) / fade_out),
+            _ => 1.0,
+        };
+
+        fade_in * fade_out
+    }
+}
+
+// plays motions on the puppet whose root holds it, writing into its Parameters
+#[derive(Debug, Clone, Default)]
+pub struct MotionPlayer {
+    pub playing: Vec<PlayingMotion>, // the last one is the one started the latest
+}
+
+impl Component for MotionPlayer {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl MotionPlayer {
+    // the priority of the motion that isn't fading out, 0 when nothing plays
+    pub fn priority(&self) -> i32 {
+        self.playing
+            .iter()
+            .filter(|playing| playing.fade_out_at.is_none())
+            .map(|playing| playing.priority)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // the motions already playing fade out while this one fades in,
+    // nothing happens if one of them has a higher priority
+    pub fn start(&mut self, motion: Arc<Motion>, priority: i32) -> bool {
+        if priority < self.priority() {
+            return false;
+        }
+
+        self.stop();
+
+        self.playing.push(PlayingMotion {
+            looped: motion.looped,
+            motion,
+            priority,
+            time: 0.0,
+            fade_out_at: None,
+        });
+
+        true
+    }
+
+    pub fn stop(&mut self) {
+        for playing in self.playing.iter_mut() {
+            playing.fade_out_at.get_or_insert(playing.time);
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.playing.is_empty()
+    }
+}
+
+// the motions of a puppet by group, like the Idle and TapBody groups of a cubism model3.json
+#[derive(Debug, Clone, Default)]
+pub struct MotionGroups {
+    pub groups: HashMap<String, Vec<Arc<Motion>>>,
+    idle: usize, // the next motion of the Idle group
+}
+
+impl Component for MotionGroups {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl MotionGroups {
+    pub fn add(&mut self, group: &str, motion: Motion) {
+        self.groups
+            .entry(group.to_string())
+            .or_default()
+            .push(Arc::new(motion));
+    }
+
+    pub fn get(&self, group: &str, index: usize) -> Option<Arc<Motion>> {
+        self.groups.get(group)?.get(index).cloned()
+    }
+
+    // false when there is no such motion or one with a higher priority plays
+    pub fn start(
+        &self,
+        player: &mut MotionPlayer,
+        group: &str,
+        index: usize,
+        priority: i32,
+    ) -> bool {
+        match self.get(group, index) {
+            Some(motion) => player.start(motion, priority),
+            None => false,
+        }
+    }
+
+    // the motions of the Idle group one after the other, under any other motion
+    pub fn start_idle(&mut self, player: &mut MotionPlayer) -> bool {
+        let count = self.groups.get("Idle").map_or(0, Vec::len);
+
+        if count == 0 {
+            return false;
+        }
+
+        let index = self.idle % count;
+        self.idle = index + 1;
+
+        self.start(player, "Idle", index, 0)
+    }
+}
+
+pub struct MotionUpdate;
+
+impl<'a> System<'a> for MotionUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time>,
+        ReadExpect<'a, ParentHierarchy>,
+        WriteStorage<'a, MotionPlayer>,
+        WriteStorage<'a, MotionGroups>,
+        WriteStorage<'a, Parameters>,
+        WriteStorage<'a, Opacity>,
+        ReadStorage<'a, Name>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            hierarchy,
+            mut players,
+            mut groups,
+            mut parameters,
+            mut opacities,
+            names,
+        ): Self::SystemData,
+    ) {
+        use specs::Join;
+
+        let delta = time.delta.as_secs_f32();
+
+        for (root, player) in (&entities, &mut players).join() {
+            if let (true, Some(groups)) = (player.is_finished(), groups.get_mut(root)) {
+                groups.start_idle(player);
+            }
+
+            if player.playing.is_empty() {
+                continue;
+            }
+
+            for playing in player.playing.iter_mut() {
+                playing.time += delta;
+            }
+
+            for playing in player.playing.iter() {
+                let motion = &playing.motion;
+                let time = if playing.looped && motion.duration > 0.0 {
+                    playing.time % motion.duration
+                } else {
+                    playing.time.min(motion.duration)
+                };
+
+                for curve in motion.curves.iter() {
+                    let value = curve.evaluate(time, motion.restricted_beziers);
+                    let weight = playing.weight(
+                        curve.fade_in.unwrap_or(motion.fade_in),
+                        curve.fade_out.unwrap_or(motion.fade_out),
+                    );
+
+                    match curve.target {
+                        MotionTarget::Parameter => {
+                            if let Some(parameters) = parameters.get_mut(root) {
+                                if let Some(current) = parameters.value(&curve.id) {
+                                    parameters.set(&curve.id, current + (value - current) * weight);
+                                }
+                            }
+                        }
+                        // the parts of cubism are the groups and layers of the puppet, found by name
+                        MotionTarget::PartOpacity => {
+                            for (entity, name, _) in
+                                (&entities, &names, &hierarchy.all_children(root)).join()
+                            {
+                                if name.0 == curve.id {
+                                    match opacities.get_mut(entity) {
+                                        Some(opacity) => {
+                                            opacity.0 += (value - opacity.0) * weight;
+                                        }
+                                        None => {
+                                            opacities.insert(entity, Opacity(value)).unwrap();
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        MotionTarget::Model => (),
+                    }
+                }
+            }
+
+            player
+                .playing
+                .retain(|playing| playing.end().is_none_or(|end| playing.time < end));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Real, b: Real) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    fn curve(flat: &[Real]) -> MotionCurve {
+        let (points, segments) = Motion::segments(flat).unwrap();
+
+        MotionCurve {
+            target: MotionTarget::Parameter,
+            id: "ParamAngleX".to_string(),
+            fade_in: None,
+            fade_out: None,
+            points,
+            segments,
+        }
+    }
+
+    #[test]
+    fn segments_add_their_points_after_the_first_one() {
+        let (points, segments) = Motion::segments(&[
+            0.0, 0.0, // first point
+            0.0, 1.0, 10.0, // linear
+            1.0, 1.5, 10.0, 2.5, 0.0, 3.0, 0.0, // bezier
+            2.0, 4.0, 5.0, // stepped
+            3.0, 5.0, 1.0, // inverse stepped
+        ])
+        .unwrap();
+
+        assert_eq!(points.len(), 7);
+        assert_eq!(
+            segments,
+            vec![
+                (SegmentKind::Linear, 0),
+                (SegmentKind::Bezier, 1),
+                (SegmentKind::Stepped, 4),
+                (SegmentKind::InverseStepped, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_segments_are_refused() {
+        assert!(Motion::segments(&[]).is_none());
+        assert!(Motion::segments(&[0.0, 0.0, 4.0, 1.0, 1.0]).is_none());
+        assert!(Motion::segments(&[0.0, 0.0, 1.0, 1.0, 1.0]).is_none());
+    }
+
+    #[test]
+    fn evaluate_holds_the_ends() {
+        let curve = curve(&[1.0, 5.0, 0.0, 2.0, 15.0]);
+
+        assert_eq!(curve.evaluate(0.0, false), 5.0);
+        assert!(close(curve.evaluate(1.5, false), 10.0));
+        assert_eq!(curve.evaluate(3.0, false), 15.0);
+    }
+
+    #[test]
+    fn steps_keep_one_end_of_their_segment() {
+        let stepped = curve(&[0.0, 0.0, 2.0, 1.0, 10.0]);
+        let inverse = curve(&[0.0, 0.0, 3.0, 1.0, 10.0]);
+
+        assert_eq!(stepped.evaluate(0.9, false), 0.0);
+        assert_eq!(inverse.evaluate(0.1, false), 10.0);
+    }
+
+    #[test]
+    fn a_bezier_with_its_handles_on_the_line_is_linear() {
+        let curve = curve(&[0.0, 0.0, 1.0, 1.0, 1.0, 2.0, 2.0, 3.0, 3.0]);
+
+        for time in [0.5, 1.5, 2.25] {
+            assert!(close(curve.evaluate(time, false), time));
+            assert!(close(curve.evaluate(time, true), time));
+        }
+    }
+
+    #[test]
+    fn restricted_beziers_are_placed_by_the_time_alone() {
+        // the handles bunch up at the start, so half the time isn't half the curve
+        let curve = curve(&[0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 1.0, 1.0, 1.0]);
+
+        assert!(close(curve.evaluate(0.5, true), 0.875));
+        assert!(curve.evaluate(0.5, false) > 0.875);
+    }
+
+    #[test]
+    fn from_json_reads_the_targets_and_the_fades() {
+        let motion = Motion::from_json(
+            r#"{
+                "Meta": { "Duration": 2.0, "Loop": true, "FadeInTime": 0.5 },
+                "Curves": [
+                    { "Target": "Parameter", "Id": "ParamAngleX", "Segments": [0, 0, 0, 2, 30] },
+                    { "Target": "PartOpacity", "Id": "PartArm", "FadeOutTime": -1, "Segments": [0, 1] }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(motion.looped);
+        assert_eq!(motion.fade_in, 0.5);
+        assert_eq!(motion.fade_out, 1.0);
+        assert_eq!(motion.curves[1].target, MotionTarget::PartOpacity);
+        assert_eq!(motion.curves[1].fade_out, None);
+
+        let unknown = r#"{
+            "Meta": { "Duration": 1.0 },
+            "Curves": [{ "Target": "Bone", "Id": "Arm", "Segments": [0, 0] }]
+        }"#;
+        assert!(Motion::from_json(unknown).is_err());
+    }
+
+    fn motion(duration: Real) -> Arc<Motion> {
+        Arc::new(Motion {
+            duration,
+            looped: false,
+            restricted_beziers: false,
+            fade_in: 1.0,
+            fade_out: 1.0,
+            curves: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn a_lower_priority_waits_for_the_playing_motion() {
+        let mut player = MotionPlayer::default();
+
+        assert!(player.start(motion(2.0), 2));
+        assert!(!player.start(motion(2.0), 1));
+        assert!(player.start(motion(2.0), 3));
+
+        // the first one fades out under the new one
+        assert_eq!(player.playing.len(), 2);
+        assert_eq!(player.playing[0].fade_out_at, Some(0.0));
+        assert_eq!(player.priority(), 3);
+    }
+
+    #[test]
+    fn the_weight_fades_in_and_out() {
+        let mut playing = PlayingMotion {
+            motion: motion(4.0),
+            priority: 1,
+            looped: false,
+            time: 0.0,
+            fade_out_at: None,
+        };
+
+        assert_eq!(playing.weight(1.0, 1.0), 0.0);
+
+        playing.time = 0.5;
+        assert!(close(playing.weight(1.0, 1.0), 0.5));
+
+        playing.time = 2.0;
+        assert_eq!(playing.weight(1.0, 1.0), 1.0);
+
+        playing.time = 3.5;
+        assert!(close(playing.weight(1.0, 1.0), 0.5));
+
+        playing.fade_out_at = Some(2.0);
+        playing.time = 2.5;
+        assert!(close(playing.weight(0.0, 1.0), 0.5));
+    }
+
+    #[test]
+    fn the_idle_motions_play_one_after_the_other() {
+        let mut groups = MotionGroups::default();
+        let mut player = MotionPlayer::default();
+        assert!(!groups.start_idle(&mut player));
+
+        groups.add("Idle", (*motion(1.0)).clone());
+        groups.add("Idle", (*motion(2.0)).clone());
+        groups.add("TapBody", (*motion(3.0)).clone());
+
+        let durations = (0..3)
+            .map(|_| {
+                player.playing.clear();
+                groups.start_idle(&mut player);
+                player.playing[0].motion.duration
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(durations, vec![1.0, 2.0, 1.0]);
+
+        // anything started over an idle motion wins, an idle motion doesn't cut into it
+        assert!(groups.start(&mut player, "TapBody", 0, 2));
+        assert!(!groups.start_idle(&mut player));
+    }
+}
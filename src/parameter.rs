@@ -0,0 +1,221 @@
+use crate::type_def::*;
+use specs::{
+    Component, DenseVecStorage, Entities, FlaggedStorage, ReadStorage, System, WriteStorage,
+};
+use std::collections::HashMap;
+
+// a named value driving the deformation of a puppet, ex: ParamAngleX going from -30 to 30
+#[derive(Debug, Clone)]
+pub struct Parameter {
+    pub id: String,
+    pub min: Real,
+    pub default: Real,
+    pub max: Real,
+    pub value: Real,
+}
+
+impl Parameter {
+    pub fn new(id: &str, min: Real, default: Real, max: Real) -> Self {
+        Self {
+            id: id.to_string(),
+            min,
+            default,
+            max,
+            value: default,
+        }
+    }
+
+    pub fn set(&mut self, value: Real) {
+        self.value = value.clamp(self.min, self.max);
+    }
+}
+
+// every parameter of a puppet, put on the entity at the root of its hierarchy
+#[derive(Debug, Clone, Default)]
+pub struct Parameters {
+    pub list: Vec<Parameter>,
+    indices: HashMap<String, usize>,
+}
+
+impl Component for Parameters {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl Parameters {
+    pub fn new(list: Vec<Parameter>) -> Self {
+        let mut parameters = Self::default();
+
+        for parameter in list.into_iter() {
+            parameters.add(parameter);
+        }
+
+        parameters
+    }
+
+    // the parameters every live2d puppet is expected to have
+    pub fn standard() -> Self {
+        Self::new(vec![
+            Parameter::new("ParamAngleX", -30.0, 0.0, 30.0),
+            Parameter::new("ParamAngleY", -30.0, 0.0, 30.0),
+            Parameter::new("ParamAngleZ", -30.0, 0.0, 30.0),
+            Parameter::new("ParamEyeLOpen", 0.0, 1.0, 1.0),
+            Parameter::new("ParamEyeROpen", 0.0, 1.0, 1.0),
+            Parameter::new("ParamEyeBallX", -1.0, 0.0, 1.0),
+            Parameter::new("ParamEyeBallY", -1.0, 0.0, 1.0),
+            Parameter::new("ParamBrowLY", -1.0, 0.0, 1.0),
+            Parameter::new("ParamBrowRY", -1.0, 0.0, 1.0),
+            Parameter::new("ParamMouthForm", -1.0, 0.0, 1.0),
+            Parameter::new("ParamMouthOpenY", 0.0, 0.0, 1.0),
+            Parameter::new("ParamBodyAngleX", -10.0, 0.0, 10.0),
+            Parameter::new("ParamBodyAngleY", -10.0, 0.0, 10.0),
+            Parameter::new("ParamBodyAngleZ", -10.0, 0.0, 10.0),
+            Parameter::new("ParamBreath", 0.0, 0.0, 1.0),
+        ])
+    }
+
+    // replaces the parameter with the same id if there is already one
+    pub fn add(&mut self, parameter: Parameter) {
+        match self.indices.get(&parameter.id) {
+            Some(index) => self.list[*index] = parameter,
+            None => {
+                self.indices.insert(parameter.id.clone(), self.list.len());
+                self.list.push(parameter);
+            }
+        }
+    }
+
+    pub fn index(&self, id: &str) -> Option<usize> {
+        self.indices.get(id).copied()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Parameter> {
+        self.index(id).map(|index| &self.list[index])
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Parameter> {
+        self.index(id).map(move |index| &mut self.list[index])
+    }
+
+    pub fn value(&self, id: &str) -> Option<Real> {
+        self.get(id).map(|parameter| parameter.value)
+    }
+
+    // returns false if the puppet doesn't have this parameter
+    pub fn set(&mut self, id: &str, value: Real) -> bool {
+        match self.get_mut(id) {
+            Some(parameter) => {
+                parameter.set(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // back to the rest pose, nothing calls it while the systems drive the puppet
+    #[allow(dead_code)]
+    pub fn reset(&mut self) {
+        for parameter in self.list.iter_mut() {
+            parameter.value = parameter.default;
+        }
+    }
+}
+
+// the parameters as the motions and animations left them, the expressions, blinking and breathing
+// are applied over them and undone at the start of the next frame like in cubism
+#[derive(Debug, Clone, Default)]
+pub struct SavedParameters(pub Vec<Real>);
+
+impl Component for SavedParameters {
+    type Storage = DenseVecStorage<Self>;
+}
+
+pub struct ParameterRestore;
+
+impl<'a> System<'a> for ParameterRestore {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, SavedParameters>,
+        WriteStorage<'a, Parameters>,
+    );
+
+    fn run(&mut self, (entities, saved, mut parameters): Self::SystemData) {
+        use specs::Join;
+
+        // only flags the parameters something changed after they were saved
+        let changed = (&entities, &saved, &parameters)
+            .join()
+            .filter(|(_, saved, parameters)| {
+                saved.0.len() == parameters.list.len()
+                    && saved
+                        .0
+                        .iter()
+                        .zip(parameters.list.iter())
+                        .any(|(saved, parameter)| *saved != parameter.value)
+            })
+            .map(|(entity, _, _)| entity)
+            .collect::<Vec<_>>();
+
+        for entity in changed.into_iter() {
+            let parameters = parameters.get_mut(entity).unwrap();
+
+            for (parameter, value) in parameters
+                .list
+                .iter_mut()
+                .zip(saved.get(entity).unwrap().0.iter())
+            {
+                parameter.value = *value;
+            }
+        }
+    }
+}
+
+pub struct ParameterSave;
+
+impl<'a> System<'a> for ParameterSave {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, SavedParameters>,
+        ReadStorage<'a, Parameters>,
+    );
+
+    fn run(&mut self, (entities, mut saved, parameters): Self::SystemData) {
+        use specs::Join;
+
+        for (entity, parameters) in (&entities, &parameters).join() {
+            let values = parameters.list.iter().map(|parameter| parameter.value);
+
+            match saved.get_mut(entity) {
+                Some(saved) => {
+                    saved.0.clear();
+                    saved.0.extend(values);
+                }
+                None => {
+                    saved
+                        .insert(entity, SavedParameters(values.collect()))
+                        .unwrap();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_parameter_starts_at_its_default_and_resets_to_it() {
+        let mut parameters = Parameters::new(vec![Parameter::new("ParamEyeLOpen", 0.0, 1.0, 1.0)]);
+
+        let parameter = parameters.get("ParamEyeLOpen").unwrap();
+        assert_eq!(parameter.default, 1.0);
+        assert_eq!(parameter.value, 1.0);
+
+        parameters.set("ParamEyeLOpen", 0.25);
+        assert_eq!(parameters.value("ParamEyeLOpen"), Some(0.25));
+
+        parameters.reset();
+        assert_eq!(parameters.value("ParamEyeLOpen"), Some(1.0));
+        assert_eq!(parameters.get("ParamEyeLOpen").unwrap().default, 1.0);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{
+    actor::{ParentHierarchy, Time},
+    parameter::Parameters,
+    type_def::*,
+};
+use anyhow::Result;
+use serde::Deserialize;
+use specs::{
+    Component, DenseVecStorage, Entities, Entity, Read, ReadExpect, ReadStorage, System,
+    WriteStorage,
+};
+use std::path::Path;
+
+// the layout of a cubism pose3.json file
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Pose3 {
+    fade_in_time: Option<Real>,
+    groups: Vec<Vec<Pose3Part>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Pose3Part {
+    id: String,
+    #[serde(default)]
+    link: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PosePart {
+    pub id: String,
+    pub links: Vec<String>, // parts taking the opacity of this one
+}
+
+// groups of parts of which only one is seen at a time, like the arms of a puppet in two positions,
+// the part shown is the one whose parameter or opacity the motions set, the others fade out
+#[derive(Debug, Clone)]
+pub struct PartPose {
+    pub fade_in: Real,
+    pub groups: Vec<Vec<PosePart>>,
+    started: bool,
+}
+
+impl Component for PartPose {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl PartPose {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let pose: Pose3 = serde_json::from_str(json)?;
+
+        // cubism fades for half a second when the pose doesn't say otherwise
+        Ok(Self {
+            fade_in: pose.fade_in_time.unwrap_or(0.5).max(0.0),
+            groups: pose
+                .groups
+                .into_iter()
+                .map(|group| {
+                    group
+                        .into_iter()
+                        .map(|part| PosePart {
+                            id: part.id,
+                            links: part.link,
+                        })
+                        .collect()
+                })
+                .collect(),
+            started: false,
+        })
+    }
+
+    // the opacity of a part that isn't the one shown, it fades out as the shown one fades in
+    // but never lets more than a bit of the background through
+    fn hidden_opacity(opacity: Real, shown: Real) -> Real {
+        const PHI: Real = 0.5;
+        const BACK_OPACITY_THRESHOLD: Real = 0.15;
+
+        let mut limit = match shown < PHI {
+            true => shown * (PHI - 1.0) / PHI + 1.0,
+            false => (1.0 - shown) * PHI / (1.0 - PHI),
+        };
+
+        let back_opacity = (1.0 - limit) * (1.0 - shown);
+        if back_opacity > BACK_OPACITY_THRESHOLD {
+            limit = 1.0 - BACK_OPACITY_THRESHOLD / (1.0 - shown);
+        }
+
+        opacity.min(limit)
+    }
+}
+
+fn set_opacity(opacities: &mut WriteStorage<Opacity>, entity: Entity, value: Real) {
+    match opacities.get_mut(entity) {
+        Some(opacity) if opacity.0 != value => opacity.0 = value,
+        Some(_) => (),
+        None => {
+            opacities.insert(entity, Opacity(value)).unwrap();
+        }
+    }
+}
+
+// after the motions, the parts of the puppet are found by name like the ones of the motions
+pub struct PartPoseUpdate;
+
+impl<'a> System<'a> for PartPoseUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time>,
+        ReadExpect<'a, ParentHierarchy>,
+        WriteStorage<'a, PartPose>,
+        ReadStorage<'a, Parameters>,
+        WriteStorage<'a, Opacity>,
+        ReadStorage<'a, Name>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, time, hierarchy, mut poses, parameters, mut opacities, names): Self::SystemData,
+    ) {
+        use specs::Join;
+
+        let delta = time.delta.as_secs_f32();
+
+        for (root, pose) in (&entities, &mut poses).join() {
+            let parts = (&entities, &names, &hierarchy.all_children(root))
+                .join()
+                .map(|(entity, name, _)| (name.0.clone(), entity))
+                .collect::<Vec<_>>();
+
+            let find = |id: &str| -> Vec<Entity> {
+                parts
+                    .iter()
+                    .filter(|(name, _)| name == id)
+                    .map(|(_, entity)| *entity)
+                    .collect()
+            };
+
+            let parameters = parameters.get(root);
+
+            for group in pose.groups.iter() {
+                let opacity_of = |part: &PosePart, opacities: &WriteStorage<Opacity>| {
+                    find(&part.id)
+                        .into_iter()
+                        .find_map(|entity| opacities.get(entity))
+                        .map_or(1.0, |opacity| opacity.0)
+                };
+
+                // the first part of each group starts shown and the others hidden
+                if !pose.started {
+                    for (i, part) in group.iter().enumerate() {
+                        for entity in find(&part.id) {
+                            set_opacity(&mut opacities, entity, if i == 0 { 1.0 } else { 0.0 });
+                        }
+                    }
+                }
+
+                // the part to show has its parameter set, or its opacity when there is no parameter
+                let wanted = group.iter().position(|part| {
+                    match parameters.and_then(|parameters| parameters.value(&part.id)) {
+                        Some(value) => value > Real::EPSILON,
+                        None => opacity_of(part, &opacities) > Real::EPSILON,
+                    }
+                });
+                let shown_at = wanted.unwrap_or(0);
+
+                let shown = match (wanted, fade(pose.fade_in, delta)) {
+                    (Some(_), Some(step)) => {
+                        (opacity_of(&group[shown_at], &opacities) + step).min(1.0)
+                    }
+                    _ => 1.0,
+                };
+
+                for (i, part) in group.iter().enumerate() {
+                    let value = match i == shown_at {
+                        true => shown,
+                        false => PartPose::hidden_opacity(opacity_of(part, &opacities), shown),
+                    };
+
+                    for entity in find(&part.id) {
+                        set_opacity(&mut opacities, entity, value);
+                    }
+
+                    for link in part.links.iter() {
+                        for entity in find(link) {
+                            set_opacity(&mut opacities, entity, value);
+                        }
+                    }
+                }
+            }
+
+            pose.started = true;
+        }
+    }
+}
+
+// how much the shown part fades in this frame, none when it shows up at once
+fn fade(fade_in: Real, delta: Real) -> Option<Real> {
+    match fade_in > 0.0 {
+        true => Some(delta / fade_in),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Real, b: Real) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn hidden_parts_fade_as_the_shown_one_comes() {
+        assert!(close(PartPose::hidden_opacity(1.0, 0.0), 1.0));
+        assert!(close(PartPose::hidden_opacity(1.0, 1.0), 0.0));
+        assert!(close(PartPose::hidden_opacity(0.3, 0.0), 0.3));
+
+        // the limit falls as the shown part goes up
+        let limits = [0.1, 0.3, 0.5, 0.7, 0.9].map(|shown| PartPose::hidden_opacity(1.0, shown));
+        assert!(limits.windows(2).all(|pair| pair[1] <= pair[0]));
+    }
+
+    #[test]
+    fn hidden_parts_dont_let_much_of_the_background_through() {
+        // halfway, 1 - 0.5 would let a quarter of the background through
+        assert!(close(PartPose::hidden_opacity(1.0, 0.5), 0.7));
+
+        for shown in [0.2, 0.4, 0.6, 0.8] {
+            let hidden = PartPose::hidden_opacity(1.0, shown);
+            assert!((1.0 - hidden) * (1.0 - shown) <= 0.15 + 1e-5);
+        }
+    }
+
+    #[test]
+    fn from_json_reads_the_groups_and_their_links() {
+        let pose = PartPose::from_json(
+            r#"{
+                "Type": "Live2D Pose",
+                "Groups": [
+                    [
+                        { "Id": "PartArmA", "Link": ["PartHandA"] },
+                        { "Id": "PartArmB", "Link": [] }
+                    ],
+                    [{ "Id": "PartHat" }]
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(pose.fade_in, 0.5);
+        assert_eq!(pose.groups.len(), 2);
+        assert_eq!(pose.groups[0][0].links, vec!["PartHandA".to_string()]);
+        assert!(pose.groups[1][0].links.is_empty());
+        assert!(!pose.started);
+
+        let pose = PartPose::from_json(r#"{ "FadeInTime": -1, "Groups": [] }"#).unwrap();
+        assert_eq!(pose.fade_in, 0.0);
+    }
+}
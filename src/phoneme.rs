@@ -0,0 +1,578 @@
+use crate::{
+    actor::{Parent, Time},
+    parameter::Parameters,
+    sprite_selector::SpriteSelector,
+    type_def::*,
+};
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use specs::{Component, DenseVecStorage, Entities, Read, ReadStorage, System, WriteStorage};
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+// the layout of the json export of rhubarb lip sync
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RhubarbJson {
+    mouth_cues: Vec<RhubarbCue>,
+}
+
+#[derive(Deserialize)]
+struct RhubarbCue {
+    start: Real,
+    end: Real,
+    value: String,
+}
+
+// a mouth shape of rhubarb (A to H and X) or a phoneme of a TextGrid, from start to end in seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct MouthCue {
+    pub start: Real,
+    pub end: Real,
+    pub label: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MouthTrack {
+    pub cues: Vec<MouthCue>, // sorted by start
+}
+
+impl MouthTrack {
+    // .tsv and .txt are the tsv of rhubarb, .json its json and .TextGrid the ones of praat
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        let extension = path
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase();
+
+        match extension.as_str() {
+            "tsv" | "txt" => Self::from_rhubarb_tsv(&text),
+            "json" => Self::from_rhubarb_json(&text),
+            "textgrid" => Self::from_textgrid(&text, None),
+            _ => bail!("unknown mouth cue file {}", path.display()),
+        }
+    }
+
+    // a start time and a shape per line, each shape lasts until the next one
+    pub fn from_rhubarb_tsv(text: &str) -> Result<Self> {
+        let mut starts = Vec::new();
+
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let mut columns = line.split_whitespace();
+
+            let start = columns
+                .next()
+                .and_then(|start| start.parse::<Real>().ok())
+                .ok_or_else(|| anyhow!("broken rhubarb line {}", line))?;
+            let label = columns
+                .next()
+                .ok_or_else(|| anyhow!("broken rhubarb line {}", line))?;
+
+            starts.push((start, label.to_string()));
+        }
+
+        starts.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        let ends = starts
+            .iter()
+            .skip(1)
+            .map(|(start, _)| *start)
+            .chain(starts.last().map(|(start, _)| *start));
+
+        Ok(Self {
+            cues: starts
+                .iter()
+                .zip(ends)
+                .map(|((start, label), end)| MouthCue {
+                    start: *start,
+                    end,
+                    label: label.clone(),
+                })
+                .collect(),
+        })
+    }
+
+    pub fn from_rhubarb_json(json: &str) -> Result<Self> {
+        let rhubarb: RhubarbJson = serde_json::from_str(json)?;
+
+        let mut cues = rhubarb
+            .mouth_cues
+            .into_iter()
+            .map(|cue| MouthCue {
+                start: cue.start,
+                end: cue.end,
+                label: cue.value,
+            })
+            .collect::<Vec<_>>();
+
+        cues.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+
+        Ok(Self { cues })
+    }
+
+    // the intervals of a tier of a TextGrid in the long format of praat,
+    // the tier named phones or phonemes when none is given, or else the first one
+    pub fn from_textgrid(text: &str, tier: Option<&str>) -> Result<Self> {
+        let mut tiers: Vec<(String, Vec<MouthCue>)> = Vec::new();
+        let mut interval: Option<(Option<Real>, Option<Real>)> = None;
+
+        let value = |line: &str| {
+            line.split_once('=')
+                .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        };
+
+        for line in text.lines().map(str::trim) {
+            if line.starts_with("item [") {
+                tiers.push((String::new(), Vec::new()));
+                interval = None;
+            } else if line.starts_with("name =") {
+                if let (Some(tier), Some(name)) = (tiers.last_mut(), value(line)) {
+                    tier.0 = name;
+                }
+            } else if line.starts_with("intervals [") {
+                interval = Some((None, None));
+            } else if let Some((start, end)) = interval.as_mut() {
+                let number = || value(line).and_then(|value| value.parse::<Real>().ok());
+
+                if line.starts_with("xmin =") {
+                    *start = number();
+                } else if line.starts_with("xmax =") {
+                    *end = number();
+                } else if line.starts_with("text =") {
+                    if let (Some(start), Some(end), Some(tier)) = (*start, *end, tiers.last_mut()) {
+                        tier.1.push(MouthCue {
+                            start,
+                            end,
+                            label: value(line).unwrap_or_default(),
+                        });
+                    }
+                    interval = None;
+                }
+            }
+        }
+
+        let found = match tier {
+            Some(tier) => tiers.into_iter().find(|(name, _)| name == tier),
+            None => {
+                let named = tiers.iter().position(|(name, _)| {
+                    matches!(name.to_lowercase().as_str(), "phones" | "phonemes")
+                });
+                tiers.into_iter().nth(named.unwrap_or(0))
+            }
+        };
+
+        match found {
+            Some((_, mut cues)) => {
+                cues.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+                Ok(Self { cues })
+            }
+            None => bail!("no interval tier {} in the TextGrid", tier.unwrap_or("")),
+        }
+    }
+
+    pub fn duration(&self) -> Real {
+        self.cues.last().map(|cue| cue.end).unwrap_or(0.0)
+    }
+
+    // the cue at a time with its index
+    pub fn at(&self, time: Real) -> Option<(usize, &MouthCue)> {
+        let index = self.cues.iter().rposition(|cue| cue.start <= time)?;
+        let cue = &self.cues[index];
+
+        // the last cue of rhubarb has no length, it holds until the end
+        match time < cue.end || index == self.cues.len() - 1 {
+            true => Some((index, cue)),
+            false => None,
+        }
+    }
+}
+
+// what a mouth shape looks like on a puppet
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MouthPose {
+    #[serde(default)]
+    pub frame: Option<u32>, // cell of the SpriteSelector of the mouth, from its min
+    #[serde(default)]
+    pub parameters: HashMap<String, Real>,
+}
+
+impl MouthPose {
+    fn new(frame: u32, open: Real, form: Real) -> Self {
+        Self {
+            frame: Some(frame),
+            parameters: HashMap::from([
+                ("ParamMouthOpenY".to_string(), open),
+                ("ParamMouthForm".to_string(), form),
+            ]),
+        }
+    }
+}
+
+// from the labels of the cues to the poses of a puppet, loaded from its settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MouthMapping {
+    pub shapes: HashMap<String, MouthPose>, // by the shapes of rhubarb
+    pub phonemes: HashMap<String, String>, // from a phoneme to one of the shapes, without its stress
+    pub rest: String,                      // shape between the cues and for unknown labels
+}
+
+impl MouthMapping {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn pose(&self, label: Option<&str>) -> Option<&MouthPose> {
+        let label = label.map(|label| label.trim_end_matches(|c: char| c.is_ascii_digit()));
+
+        let shape = match label {
+            Some(label) if self.shapes.contains_key(label) => label,
+            Some(label) => self
+                .phonemes
+                .get(label)
+                .or_else(|| self.phonemes.get(&label.to_uppercase()))
+                .map(String::as_str)
+                .unwrap_or(&self.rest),
+            None => &self.rest,
+        };
+
+        self.shapes
+            .get(shape)
+            .or_else(|| self.shapes.get(&self.rest))
+    }
+}
+
+// the shapes of rhubarb in the order of their frames, and the arpabet phonemes they are made with
+impl Default for MouthMapping {
+    fn default() -> Self {
+        let shapes = [
+            ("A", 0.0, 0.0),
+            ("B", 0.2, 0.3),
+            ("C", 0.5, 0.3),
+            ("D", 1.0, 0.0),
+            ("E", 0.6, -0.5),
+            ("F", 0.3, -1.0),
+            ("G", 0.1, 0.2),
+            ("H", 0.4, 0.2),
+            ("X", 0.0, 0.0),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(frame, (shape, open, form))| {
+            (shape.to_string(), MouthPose::new(frame as u32, open, form))
+        })
+        .collect();
+
+        let phonemes = [
+            ("A", &["P", "B", "M"][..]),
+            (
+                "B",
+                &[
+                    "K", "S", "T", "D", "G", "N", "NG", "Z", "IY", "IH", "Y", "HH", "CH", "JH",
+                    "SH", "ZH", "TH", "DH",
+                ][..],
+            ),
+            ("C", &["EH", "AE", "EY", "AH", "AY"][..]),
+            ("D", &["AA", "AW"][..]),
+            ("E", &["AO", "ER", "R", "OY"][..]),
+            ("F", &["UW", "OW", "W", "UH"][..]),
+            ("G", &["F", "V"][..]),
+            ("H", &["L"][..]),
+            ("X", &["SIL", "SP", ""][..]),
+        ]
+        .into_iter()
+        .flat_map(|(shape, phonemes)| {
+            phonemes
+                .iter()
+                .map(move |phoneme| (phoneme.to_string(), shape.to_string()))
+        })
+        .collect();
+
+        Self {
+            shapes,
+            phonemes,
+            rest: "X".to_string(),
+        }
+    }
+}
+
+// plays a mouth track on Time::delta, swapping the cell of the SpriteSelector of the entity
+// and moving the mouth parameters of the puppet it belongs to
+#[derive(Debug, Clone)]
+pub struct MouthSync {
+    pub track: Arc<MouthTrack>,
+    pub mapping: Arc<MouthMapping>,
+    pub blend: Real, // time the parameters take to go from a pose to the next
+    pub looped: bool,
+    pub playing: bool,
+    pub time: Real,
+}
+
+impl Component for MouthSync {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl MouthSync {
+    pub fn new(track: Arc<MouthTrack>, mapping: Arc<MouthMapping>) -> Self {
+        Self {
+            track,
+            mapping,
+            blend: 0.05,
+            looped: false,
+            playing: true,
+            time: 0.0,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !self.looped && self.time >= self.track.duration()
+    }
+
+    // the pose at the time of the track and the one it comes from, with how far it is
+    pub fn poses(&self) -> (Option<&MouthPose>, Option<&MouthPose>, Real) {
+        let cue = self.track.at(self.time);
+        let pose = self.mapping.pose(cue.map(|(_, cue)| cue.label.as_str()));
+
+        let (previous, start) = match cue {
+            Some((index, cue)) => {
+                let previous = index
+                    .checked_sub(1)
+                    .map(|index| &self.track.cues[index])
+                    .filter(|previous| previous.end >= cue.start);
+
+                (
+                    self.mapping
+                        .pose(previous.map(|previous| previous.label.as_str())),
+                    cue.start,
+                )
+            }
+            None => (pose, self.time),
+        };
+
+        let t = match self.blend > 0.0 {
+            true => ((self.time - start) / self.blend).clamp(0.0, 1.0),
+            false => 1.0,
+        };
+
+        (previous, pose, t)
+    }
+}
+
+pub struct MouthSyncUpdate;
+
+impl<'a> System<'a> for MouthSyncUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time>,
+        WriteStorage<'a, MouthSync>,
+        WriteStorage<'a, SpriteSelector>,
+        WriteStorage<'a, Parameters>,
+        ReadStorage<'a, Parent>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, time, mut mouth_syncs, mut sprite_selectors, mut parameters, parents): Self::SystemData,
+    ) {
+        use specs::Join;
+
+        let delta = time.delta.as_secs_f32();
+
+        for (entity, mouth_sync) in (&entities, &mut mouth_syncs).join() {
+            if !mouth_sync.playing {
+                continue;
+            }
+
+            mouth_sync.time += delta;
+
+            let duration = mouth_sync.track.duration();
+            if mouth_sync.looped && duration > 0.0 {
+                mouth_sync.time %= duration;
+            }
+
+            if mouth_sync.is_finished() {
+                mouth_sync.playing = false;
+            }
+
+            let (previous, pose, t) = mouth_sync.poses();
+
+            // a sprite swaps to its frame right away, only written when it changes to not rebuild the vertices
+            if let Some(frame) = pose.and_then(|pose| pose.frame) {
+                let changed = sprite_selectors.get(entity).is_some_and(|sprite_selector| {
+                    sprite_selector.at != frame + sprite_selector.min()
+                });
+
+                if changed {
+                    let _ = sprite_selectors.get_mut(entity).unwrap().set(frame);
+                }
+            }
+
+            // the parameters are on the root of the puppet the entity belongs to
+            let mut owner = Some(entity);
+            while let Some(target) = owner.filter(|target| !parameters.contains(*target)) {
+                owner = parents.get(target).map(|parent| parent.entity);
+            }
+
+            let parameters = match owner.and_then(|owner| parameters.get_mut(owner)) {
+                Some(parameters) => parameters,
+                None => continue,
+            };
+
+            if let Some(pose) = pose {
+                for (id, value) in pose.parameters.iter() {
+                    let from = previous
+                        .and_then(|previous| previous.parameters.get(id))
+                        .unwrap_or(value);
+
+                    parameters.set(id, from + (value - from) * t);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(track: &MouthTrack) -> Vec<&str> {
+        track.cues.iter().map(|cue| cue.label.as_str()).collect()
+    }
+
+    const TEXTGRID: &str = r#"File type = "ooTextFile"
+Object class = "TextGrid"
+
+xmin = 0
+xmax = 0.6
+tiers? <exists>
+size = 2
+item []:
+    item [1]:
+        class = "IntervalTier"
+        name = "words"
+        xmin = 0
+        xmax = 0.6
+        intervals: size = 1
+        intervals [1]:
+            xmin = 0
+            xmax = 0.6
+            text = "hello"
+    item [2]:
+        class = "IntervalTier"
+        name = "phones"
+        xmin = 0
+        xmax = 0.6
+        intervals: size = 4
+        intervals [1]:
+            xmin = 0
+            xmax = 0.1
+            text = ""
+        intervals [2]:
+            xmin = 0.1
+            xmax = 0.2
+            text = "HH"
+        intervals [3]:
+            xmin = 0.2
+            xmax = 0.4
+            text = "AH0"
+        intervals [4]:
+            xmin = 0.4
+            xmax = 0.6
+            text = "OW1"
+"#;
+
+    #[test]
+    fn rhubarb_tsv_shapes_last_until_the_next_one() {
+        let track = MouthTrack::from_rhubarb_tsv("0.00\tX\n0.05\tB\n\n0.27\tD\n0.40\tX\n").unwrap();
+
+        assert_eq!(labels(&track), vec!["X", "B", "D", "X"]);
+        assert_eq!(track.cues[1].start, 0.05);
+        assert_eq!(track.cues[1].end, 0.27);
+        assert_eq!(track.duration(), 0.40);
+
+        // the last shape holds after the end
+        assert_eq!(track.at(0.3).unwrap().1.label, "D");
+        assert_eq!(track.at(1.0).unwrap().0, 3);
+
+        assert!(MouthTrack::from_rhubarb_tsv("0.00\n").is_err());
+        assert!(MouthTrack::from_rhubarb_tsv("start\tX\n").is_err());
+    }
+
+    #[test]
+    fn rhubarb_json_is_sorted() {
+        let track = MouthTrack::from_rhubarb_json(
+            r#"{
+                "metadata": { "soundFile": "voice.wav", "duration": 0.5 },
+                "mouthCues": [
+                    { "start": 0.2, "end": 0.5, "value": "X" },
+                    { "start": 0.0, "end": 0.2, "value": "C" }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(labels(&track), vec!["C", "X"]);
+        assert_eq!(track.duration(), 0.5);
+        assert!(track.at(0.1).is_some());
+    }
+
+    #[test]
+    fn textgrid_reads_the_phones_tier() {
+        let track = MouthTrack::from_textgrid(TEXTGRID, None).unwrap();
+
+        assert_eq!(labels(&track), vec!["", "HH", "AH0", "OW1"]);
+        assert_eq!(track.cues[2].start, 0.2);
+        assert_eq!(track.cues[2].end, 0.4);
+        assert_eq!(track.duration(), 0.6);
+
+        let words = MouthTrack::from_textgrid(TEXTGRID, Some("words")).unwrap();
+        assert_eq!(labels(&words), vec!["hello"]);
+
+        assert!(MouthTrack::from_textgrid(TEXTGRID, Some("syllables")).is_err());
+    }
+
+    #[test]
+    fn phonemes_map_to_shapes_without_their_stress() {
+        let mapping = MouthMapping::default();
+        let frame = |label: Option<&str>| mapping.pose(label).and_then(|pose| pose.frame);
+
+        assert_eq!(frame(Some("D")), Some(3));
+        assert_eq!(frame(Some("AA1")), Some(3));
+        assert_eq!(frame(Some("ow")), Some(5));
+
+        // empty labels and unknown ones are the rest shape
+        assert_eq!(frame(Some("")), Some(8));
+        assert_eq!(frame(Some("?")), Some(8));
+        assert_eq!(frame(None), Some(8));
+    }
+
+    #[test]
+    fn a_mapping_only_needs_what_it_changes() {
+        let mapping: MouthMapping = serde_json::from_str(
+            r#"{ "shapes": { "X": { "frame": 0 }, "A": { "parameters": { "ParamMouthOpenY": 0.1 } } } }"#,
+        )
+        .unwrap();
+
+        assert_eq!(mapping.rest, "X");
+        assert_eq!(
+            mapping.pose(Some("M")).unwrap().parameters["ParamMouthOpenY"],
+            0.1
+        );
+        assert_eq!(mapping.pose(Some("AA")).unwrap().frame, Some(0));
+    }
+
+    #[test]
+    fn poses_blend_from_the_previous_cue() {
+        let track = MouthTrack::from_textgrid(TEXTGRID, None).unwrap();
+        let mut mouth_sync = MouthSync::new(Arc::new(track), Arc::new(MouthMapping::default()));
+        mouth_sync.blend = 0.1;
+        mouth_sync.time = 0.25;
+
+        let (previous, pose, t) = mouth_sync.poses();
+        assert_eq!(previous.unwrap().frame, Some(1));
+        assert_eq!(pose.unwrap().frame, Some(2));
+        assert!((t - 0.5).abs() < 1e-4);
+    }
+}
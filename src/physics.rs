@@ -0,0 +1,654 @@
+use crate::{
+    actor::Time,
+    parameter::{Parameter, Parameters},
+    type_def::*,
+};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use specs::{Component, DenseVecStorage, Entities, Read, System, WriteStorage};
+use std::{f32::consts::PI, path::Path};
+
+// the layout of a cubism physics3.json file
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Physics3 {
+    #[serde(default)]
+    meta: Physics3Meta,
+    physics_settings: Vec<Physics3Setting>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct Physics3Meta {
+    effective_forces: Option<Physics3Forces>,
+    fps: Option<Real>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Physics3Forces {
+    gravity: Physics3Vector,
+    wind: Physics3Vector,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "PascalCase")]
+struct Physics3Vector {
+    x: Real,
+    y: Real,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Physics3Setting {
+    input: Vec<Physics3Input>,
+    output: Vec<Physics3Output>,
+    vertices: Vec<Physics3Vertex>,
+    normalization: Physics3Normalization,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Physics3Target {
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Physics3Input {
+    source: Physics3Target,
+    weight: Real,
+    r#type: String,
+    #[serde(default)]
+    reflect: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Physics3Output {
+    destination: Physics3Target,
+    vertex_index: usize,
+    scale: Real,
+    weight: Real,
+    r#type: String,
+    #[serde(default)]
+    reflect: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Physics3Vertex {
+    mobility: Real,
+    delay: Real,
+    acceleration: Real,
+    radius: Real,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Physics3Normalization {
+    position: Physics3Range,
+    angle: Physics3Range,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "PascalCase")]
+struct Physics3Range {
+    minimum: Real,
+    default: Real,
+    maximum: Real,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhysicsAxis {
+    X,
+    Y,
+    Angle,
+}
+
+impl PhysicsAxis {
+    fn parse(kind: &str) -> Result<Self> {
+        match kind {
+            "X" => Ok(PhysicsAxis::X),
+            "Y" => Ok(PhysicsAxis::Y),
+            "Angle" => Ok(PhysicsAxis::Angle),
+            kind => bail!("unknown physics type {}", kind),
+        }
+    }
+}
+
+// the range a parameter is brought to before moving the pendulum
+#[derive(Debug, Clone, Copy)]
+pub struct PhysicsRange {
+    pub min: Real,
+    pub default: Real,
+    pub max: Real,
+}
+
+#[derive(Debug, Clone)]
+pub struct PhysicsInput {
+    pub parameter: String,
+    pub axis: PhysicsAxis,
+    pub weight: Real, // from 0 to 100
+    pub reflect: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PhysicsOutput {
+    pub parameter: String,
+    pub axis: PhysicsAxis,
+    pub vertex: usize, // the particle whose move is read, from 1
+    pub scale: Real,
+    pub weight: Real, // from 0 to 100
+    pub reflect: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Particle {
+    pub mobility: Real,
+    pub delay: Real,
+    pub acceleration: Real,
+    pub radius: Real,
+    pub position: [Real; 2],
+    last_position: [Real; 2],
+    last_gravity: [Real; 2],
+    velocity: [Real; 2],
+}
+
+// a pendulum, a chain of particles hanging from the first one moved by the inputs
+#[derive(Debug, Clone)]
+pub struct PhysicsSetting {
+    pub inputs: Vec<PhysicsInput>,
+    pub outputs: Vec<PhysicsOutput>,
+    pub particles: Vec<Particle>,
+    pub position: PhysicsRange,
+    pub angle: PhysicsRange,
+}
+
+impl PhysicsSetting {
+    // every particle hangs straight down from the previous one
+    pub fn reset(&mut self) {
+        let mut position = [0.0, 0.0];
+
+        for (i, particle) in self.particles.iter_mut().enumerate() {
+            if i > 0 {
+                position[1] += particle.radius;
+            }
+
+            particle.position = position;
+            particle.last_position = position;
+            particle.last_gravity = [0.0, 1.0];
+            particle.velocity = [0.0, 0.0];
+        }
+    }
+
+    fn update(&mut self, translation: [Real; 2], angle: Real, wind: [Real; 2], delta: Real) {
+        const AIR_RESISTANCE: Real = 5.0;
+        const THRESHOLD: Real = 0.001;
+
+        self.particles[0].position = translation;
+
+        let radian = angle.to_radians();
+        let gravity = [radian.sin(), radian.cos()];
+
+        for i in 1..self.particles.len() {
+            let parent = self.particles[i - 1].position;
+            let particle = &mut self.particles[i];
+
+            let force = [
+                gravity[0] * particle.acceleration + wind[0],
+                gravity[1] * particle.acceleration + wind[1],
+            ];
+
+            particle.last_position = particle.position;
+
+            let delay = particle.delay * delta * 30.0;
+
+            // the chain turns a bit with the gravity, slowed by the air
+            let turn = direction_to_radian(particle.last_gravity, gravity) / AIR_RESISTANCE;
+            let direction = rotate(
+                [
+                    particle.position[0] - parent[0],
+                    particle.position[1] - parent[1],
+                ],
+                turn,
+            );
+
+            particle.position = [
+                parent[0] + direction[0] + particle.velocity[0] * delay + force[0] * delay * delay,
+                parent[1] + direction[1] + particle.velocity[1] * delay + force[1] * delay * delay,
+            ];
+
+            // the particle stays at its radius from its parent
+            let direction = [
+                particle.position[0] - parent[0],
+                particle.position[1] - parent[1],
+            ];
+            let length = (direction[0] * direction[0] + direction[1] * direction[1]).sqrt();
+
+            if length > 0.0 {
+                particle.position = [
+                    parent[0] + direction[0] / length * particle.radius,
+                    parent[1] + direction[1] / length * particle.radius,
+                ];
+            }
+
+            if particle.position[0].abs() < THRESHOLD {
+                particle.position[0] = 0.0;
+            }
+
+            if delay != 0.0 {
+                particle.velocity = [
+                    (particle.position[0] - particle.last_position[0]) / delay * particle.mobility,
+                    (particle.position[1] - particle.last_position[1]) / delay * particle.mobility,
+                ];
+            }
+
+            particle.last_gravity = gravity;
+        }
+    }
+}
+
+// the pendulums of a cubism puppet moving its hair and clothes from the other parameters,
+// put next to the Parameters of the puppet
+#[derive(Debug, Clone)]
+pub struct Physics {
+    pub settings: Vec<PhysicsSetting>,
+    pub gravity: [Real; 2],
+    pub wind: [Real; 2],
+    pub fps: Option<Real>, // the pendulums move by steps of this rate when given
+    time: Real,            // not yet simulated when stepping at a rate
+}
+
+impl Component for Physics {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl Physics {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let physics: Physics3 = serde_json::from_str(json)?;
+
+        let range = |range: Physics3Range| PhysicsRange {
+            min: range.minimum,
+            default: range.default,
+            max: range.maximum,
+        };
+
+        let settings = physics
+            .physics_settings
+            .into_iter()
+            .map(|setting| {
+                let inputs = setting
+                    .input
+                    .into_iter()
+                    .map(|input| {
+                        Ok(PhysicsInput {
+                            axis: PhysicsAxis::parse(&input.r#type)?,
+                            parameter: input.source.id,
+                            weight: input.weight,
+                            reflect: input.reflect,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                let outputs = setting
+                    .output
+                    .into_iter()
+                    .map(|output| {
+                        Ok(PhysicsOutput {
+                            axis: PhysicsAxis::parse(&output.r#type)?,
+                            parameter: output.destination.id,
+                            vertex: output.vertex_index,
+                            scale: output.scale,
+                            weight: output.weight,
+                            reflect: output.reflect,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                if setting.vertices.is_empty() {
+                    bail!("physics setting without vertices");
+                }
+
+                let particles = setting
+                    .vertices
+                    .into_iter()
+                    .map(|vertex| Particle {
+                        mobility: vertex.mobility,
+                        delay: vertex.delay,
+                        acceleration: vertex.acceleration,
+                        radius: vertex.radius,
+                        position: [0.0, 0.0],
+                        last_position: [0.0, 0.0],
+                        last_gravity: [0.0, 1.0],
+                        velocity: [0.0, 0.0],
+                    })
+                    .collect();
+
+                let mut setting = PhysicsSetting {
+                    inputs,
+                    outputs,
+                    particles,
+                    position: range(setting.normalization.position),
+                    angle: range(setting.normalization.angle),
+                };
+                setting.reset();
+
+                Ok(setting)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let forces = physics.meta.effective_forces;
+
+        Ok(Self {
+            settings,
+            gravity: forces
+                .as_ref()
+                .map_or([0.0, -1.0], |forces| [forces.gravity.x, forces.gravity.y]),
+            wind: forces.map_or([0.0, 0.0], |forces| [forces.wind.x, forces.wind.y]),
+            fps: physics.meta.fps.filter(|fps| *fps > 0.0),
+            time: 0.0,
+        })
+    }
+
+    // moves the pendulums from the inputs
+    pub fn step(&mut self, parameters: &Parameters, delta: Real) {
+        if delta <= 0.0 {
+            return;
+        }
+
+        for setting in self.settings.iter_mut() {
+            let mut translation = [0.0, 0.0];
+            let mut angle = 0.0;
+
+            for input in setting.inputs.iter() {
+                let parameter = match parameters.get(&input.parameter) {
+                    Some(parameter) => parameter,
+                    None => continue,
+                };
+
+                let weight = input.weight / 100.0;
+
+                match input.axis {
+                    PhysicsAxis::X => {
+                        translation[0] +=
+                            normalize(parameter, setting.position, input.reflect) * weight
+                    }
+                    PhysicsAxis::Y => {
+                        translation[1] +=
+                            normalize(parameter, setting.position, input.reflect) * weight
+                    }
+                    PhysicsAxis::Angle => {
+                        angle += normalize(parameter, setting.angle, input.reflect) * weight
+                    }
+                }
+            }
+
+            let translation = rotate(translation, (-angle).to_radians());
+
+            setting.update(translation, angle, self.wind, delta);
+        }
+    }
+
+    // writes where the pendulums are into the outputs
+    pub fn apply(&self, parameters: &mut Parameters) {
+        for setting in self.settings.iter() {
+            for output in setting.outputs.iter() {
+                let at = output.vertex;
+
+                if at < 1 || at >= setting.particles.len() {
+                    continue;
+                }
+
+                let position = setting.particles[at].position;
+                let parent = setting.particles[at - 1].position;
+                let move_ = [position[0] - parent[0], position[1] - parent[1]];
+
+                let mut value = match output.axis {
+                    PhysicsAxis::X => move_[0],
+                    PhysicsAxis::Y => move_[1],
+                    PhysicsAxis::Angle => {
+                        let parent_gravity = match at >= 2 {
+                            true => {
+                                let grand_parent = setting.particles[at - 2].position;
+                                [parent[0] - grand_parent[0], parent[1] - grand_parent[1]]
+                            }
+                            false => [-self.gravity[0], -self.gravity[1]],
+                        };
+
+                        direction_to_radian(parent_gravity, move_)
+                    }
+                };
+
+                if output.reflect {
+                    value = -value;
+                }
+
+                let current = match parameters.value(&output.parameter) {
+                    Some(current) => current,
+                    None => continue,
+                };
+
+                let value = value * output.scale;
+                let weight = output.weight / 100.0;
+
+                match weight >= 1.0 {
+                    true => parameters.set(&output.parameter, value),
+                    false => {
+                        parameters.set(&output.parameter, current + (value - current) * weight)
+                    }
+                };
+            }
+        }
+    }
+}
+
+// the value of a parameter brought into a range, the middle of the parameter goes to the default of the range
+fn normalize(parameter: &Parameter, range: PhysicsRange, reflect: bool) -> Real {
+    let (min, max) = (
+        parameter.min.min(parameter.max),
+        parameter.min.max(parameter.max),
+    );
+    let value = parameter.value.clamp(min, max);
+    let middle = min + (max - min) / 2.0;
+    let offset = value - middle;
+
+    let result = if offset > 0.0 && max != middle {
+        offset * (range.max.max(range.min) - range.default) / (max - middle) + range.default
+    } else if offset < 0.0 && min != middle {
+        offset * (range.min.min(range.max) - range.default) / (min - middle) + range.default
+    } else {
+        range.default
+    };
+
+    // cubism turns the inputs around unless they are reflected
+    match reflect {
+        true => result,
+        false => -result,
+    }
+}
+
+// the angle from one direction to another, between -pi and pi
+fn direction_to_radian(from: [Real; 2], to: [Real; 2]) -> Real {
+    let mut radian = to[1].atan2(to[0]) - from[1].atan2(from[0]);
+
+    while radian < -PI {
+        radian += 2.0 * PI;
+    }
+
+    while radian > PI {
+        radian -= 2.0 * PI;
+    }
+
+    radian
+}
+
+fn rotate(vector: [Real; 2], radian: Real) -> [Real; 2] {
+    let (sin, cos) = radian.sin_cos();
+
+    [
+        vector[0] * cos - vector[1] * sin,
+        vector[0] * sin + vector[1] * cos,
+    ]
+}
+
+// after the expressions and the procedural effects, the pendulums follow what the puppet ends up doing
+pub struct PhysicsUpdate;
+
+impl<'a> System<'a> for PhysicsUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time>,
+        WriteStorage<'a, Physics>,
+        WriteStorage<'a, Parameters>,
+    );
+
+    fn run(&mut self, (entities, time, mut physics, mut parameters): Self::SystemData) {
+        use specs::Join;
+
+        let delta = time.delta.as_secs_f32();
+
+        if delta == 0.0 {
+            return;
+        }
+
+        for (entity, physics) in (&entities, &mut physics).join() {
+            let parameters = match parameters.get_mut(entity) {
+                Some(parameters) => parameters,
+                None => continue,
+            };
+
+            match physics.fps {
+                Some(fps) => {
+                    // a long frame doesn't throw the pendulums away, the steps it would take are dropped
+                    physics.time = (physics.time + delta).min(0.25);
+
+                    let step = 1.0 / fps;
+                    while physics.time >= step {
+                        physics.time -= step;
+                        physics.step(parameters, step);
+                    }
+                }
+                None => physics.step(parameters, delta.min(0.25)),
+            }
+
+            // the outputs are undone at the start of every frame, even the ones without a step
+            physics.apply(parameters);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a hair strand of three particles swung by the angle of the head
+    const HAIR: &str = r#"{
+        "Version": 3,
+        "Meta": { "Fps": 30 },
+        "PhysicsSettings": [{
+            "Id": "PhysicsSetting1",
+            "Input": [
+                { "Source": { "Target": "Parameter", "Id": "ParamAngleX" }, "Weight": 60, "Type": "X", "Reflect": false },
+                { "Source": { "Target": "Parameter", "Id": "ParamAngleZ" }, "Weight": 60, "Type": "Angle", "Reflect": false }
+            ],
+            "Output": [
+                { "Destination": { "Target": "Parameter", "Id": "ParamHairFront" }, "VertexIndex": 1,
+                  "Scale": 1.5, "Weight": 100, "Type": "Angle", "Reflect": false }
+            ],
+            "Vertices": [
+                { "Position": { "X": 0, "Y": 0 }, "Mobility": 1, "Delay": 1, "Acceleration": 1, "Radius": 0 },
+                { "Position": { "X": 0, "Y": 3 }, "Mobility": 0.95, "Delay": 0.9, "Acceleration": 1.5, "Radius": 3 }
+            ],
+            "Normalization": {
+                "Position": { "Minimum": -10, "Default": 0, "Maximum": 10 },
+                "Angle": { "Minimum": -10, "Default": 0, "Maximum": 10 }
+            }
+        }]
+    }"#;
+
+    fn puppet() -> Parameters {
+        let mut parameters = Parameters::standard();
+        parameters.add(Parameter::new("ParamHairFront", -1.0, 0.0, 1.0));
+        parameters
+    }
+
+    #[test]
+    fn from_json_reads_the_settings() {
+        let physics = Physics::from_json(HAIR).unwrap();
+
+        assert_eq!(physics.gravity, [0.0, -1.0]);
+        assert_eq!(physics.wind, [0.0, 0.0]);
+        assert_eq!(physics.fps, Some(30.0));
+
+        let setting = &physics.settings[0];
+        assert_eq!(setting.inputs[0].axis, PhysicsAxis::X);
+        assert_eq!(setting.inputs[1].axis, PhysicsAxis::Angle);
+        assert_eq!(setting.outputs[0].parameter, "ParamHairFront");
+        assert_eq!(setting.outputs[0].vertex, 1);
+        assert_eq!(setting.particles.len(), 2);
+        assert_eq!(setting.particles[1].position, [0.0, 3.0]);
+    }
+
+    #[test]
+    fn from_json_refuses_what_it_cant_simulate() {
+        assert!(Physics::from_json(&HAIR.replace(r#""Type": "X""#, r#""Type": "Z""#)).is_err());
+
+        let start = HAIR.find(r#""Vertices": ["#).unwrap();
+        let end = HAIR.find(r#""Normalization""#).unwrap();
+        let without_vertices = format!("{}\"Vertices\": [],\n{}", &HAIR[..start], &HAIR[end..]);
+        assert!(Physics::from_json(&without_vertices).is_err());
+    }
+
+    #[test]
+    fn normalize_brings_the_middle_to_the_default() {
+        let range = PhysicsRange {
+            min: -10.0,
+            default: 0.0,
+            max: 10.0,
+        };
+        let mut parameter = Parameter::new("ParamAngleX", -30.0, 0.0, 30.0);
+
+        assert_eq!(normalize(&parameter, range, true), 0.0);
+
+        parameter.value = 15.0;
+        assert!((normalize(&parameter, range, true) - 5.0).abs() < 1e-5);
+        assert!((normalize(&parameter, range, false) + 5.0).abs() < 1e-5);
+
+        parameter.value = -60.0;
+        assert!((normalize(&parameter, range, true) + 10.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn the_hair_stays_still_at_rest_and_swings_with_the_head() {
+        let mut physics = Physics::from_json(HAIR).unwrap();
+        let mut parameters = puppet();
+
+        for _ in 0..30 {
+            physics.step(&parameters, 1.0 / 30.0);
+        }
+        physics.apply(&mut parameters);
+        assert!(parameters.value("ParamHairFront").unwrap().abs() < 1e-3);
+
+        parameters.set("ParamAngleX", 30.0);
+        physics.step(&parameters, 1.0 / 30.0);
+        physics.apply(&mut parameters);
+        let swing = parameters.value("ParamHairFront").unwrap();
+        assert!(swing.abs() > 1e-3);
+
+        // the same steps move the hair the same way
+        let mut again = Physics::from_json(HAIR).unwrap();
+        let mut other = puppet();
+        for _ in 0..30 {
+            again.step(&other, 1.0 / 30.0);
+        }
+        other.set("ParamAngleX", 30.0);
+        again.step(&other, 1.0 / 30.0);
+        again.apply(&mut other);
+        assert_eq!(other.value("ParamHairFront"), Some(swing));
+    }
+}
@@ -0,0 +1,340 @@
+use crate::{
+    actor::Time, animation::Easing, lip_sync::LipSyncMode, parameter::Parameters, type_def::*,
+};
+use anyhow::Result;
+use serde::Deserialize;
+use specs::{Component, DenseVecStorage, Entities, Read, System, WriteStorage};
+use std::{f32::consts::PI, path::Path};
+
+// closes the eyes every few seconds, the open value of the eyes is multiplied by how open the blink is
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AutoBlink {
+    pub parameters: Vec<String>,
+    pub interval: [Real; 2], // shortest and longest time between two blinks
+    pub closing: Real,
+    pub closed: Real,
+    pub opening: Real,
+    pub curve: Easing, // of the closing and the opening
+    pub seed: u64,     // the same seed blinks at the same times
+    #[serde(skip)]
+    time: Real, // since the start of the current phase
+    #[serde(skip)]
+    wait: Option<Real>, // until the next blink, blinking when none
+}
+
+impl Default for AutoBlink {
+    fn default() -> Self {
+        Self {
+            parameters: vec!["ParamEyeLOpen".to_string(), "ParamEyeROpen".to_string()],
+            interval: [2.0, 6.0],
+            closing: 0.1,
+            closed: 0.05,
+            opening: 0.15,
+            curve: Easing::EaseInOut,
+            seed: 0x2545_f491_4f6c_dd1d,
+            time: 0.0,
+            wait: None,
+        }
+    }
+}
+
+impl Component for AutoBlink {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl AutoBlink {
+    // xorshift, enough to not blink like a clock
+    fn random(&mut self) -> Real {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        (self.seed >> 40) as Real / (1u64 << 24) as Real
+    }
+
+    fn advance(&mut self, delta: Real) {
+        self.time += delta;
+
+        let wait = match self.wait {
+            Some(wait) => wait,
+            None if self.time >= self.closing + self.closed + self.opening => {
+                self.time -= self.closing + self.closed + self.opening;
+                self.next()
+            }
+            None => return,
+        };
+
+        if self.time >= wait {
+            self.time -= wait;
+            self.wait = None;
+        }
+    }
+
+    fn next(&mut self) -> Real {
+        let [shortest, longest] = self.interval;
+        let wait = shortest + (longest - shortest).max(0.0) * self.random();
+        self.wait = Some(wait);
+        wait
+    }
+
+    // 1 when the eyes are open, 0 when they are closed
+    pub fn openness(&self) -> Real {
+        if self.wait.is_some() {
+            return 1.0;
+        }
+
+        if self.time < self.closing {
+            1.0 - self.curve.apply(self.time / self.closing)
+        } else if self.time < self.closing + self.closed {
+            0.0
+        } else if self.opening > 0.0 {
+            self.curve
+                .apply((self.time - self.closing - self.closed) / self.opening)
+        } else {
+            1.0
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BreathParameter {
+    pub id: String,
+    #[serde(default)]
+    pub offset: Real,
+    pub peak: Real,  // amplitude of the wave
+    pub cycle: Real, // period of the wave in seconds
+    #[serde(default = "BreathParameter::full_weight")]
+    pub weight: Real,
+}
+
+impl BreathParameter {
+    pub fn new(id: &str, offset: Real, peak: Real, cycle: Real, weight: Real) -> Self {
+        Self {
+            id: id.to_string(),
+            offset,
+            peak,
+            cycle,
+            weight,
+        }
+    }
+
+    fn full_weight() -> Real {
+        1.0
+    }
+
+    pub fn value(&self, time: Real) -> Real {
+        match self.cycle > 0.0 {
+            true => self.offset + self.peak * (2.0 * PI * time / self.cycle).sin(),
+            false => self.offset,
+        }
+    }
+}
+
+// sine waves added to the parameters, the body keeps moving a bit even when nothing plays
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Breath {
+    pub parameters: Vec<BreathParameter>,
+    #[serde(skip)]
+    time: Real,
+}
+
+// the breath of the cubism samples
+impl Default for Breath {
+    fn default() -> Self {
+        Self {
+            parameters: vec![
+                BreathParameter::new("ParamAngleX", 0.0, 15.0, 6.5345, 0.5),
+                BreathParameter::new("ParamAngleY", 0.0, 8.0, 3.5345, 0.5),
+                BreathParameter::new("ParamAngleZ", 0.0, 10.0, 5.5345, 0.5),
+                BreathParameter::new("ParamBodyAngleX", 0.0, 4.0, 15.5345, 0.5),
+                BreathParameter::new("ParamBreath", 0.5, 0.5, 3.2345, 0.5),
+            ],
+            time: 0.0,
+        }
+    }
+}
+
+impl Component for Breath {
+    type Storage = DenseVecStorage<Self>;
+}
+
+// the generators of a puppet, each one is left out when its settings don't have it
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProceduralSettings {
+    pub auto_blink: Option<AutoBlink>,
+    pub breath: Option<Breath>,
+    #[serde(default)]
+    pub lip_sync: LipSyncMode, // how a voice next to the puppet moves its mouth
+}
+
+impl ProceduralSettings {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+// runs on Time::delta, already scaled by Time::speed
+pub struct ProceduralUpdate;
+
+impl<'a> System<'a> for ProceduralUpdate {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time>,
+        WriteStorage<'a, AutoBlink>,
+        WriteStorage<'a, Breath>,
+        WriteStorage<'a, Parameters>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, time, mut auto_blinks, mut breaths, mut parameters): Self::SystemData,
+    ) {
+        use specs::Join;
+
+        let delta = time.delta.as_secs_f32();
+
+        if delta == 0.0 {
+            return;
+        }
+
+        for (entity, breath) in (&entities, &mut breaths).join() {
+            let parameters = match parameters.get_mut(entity) {
+                Some(parameters) => parameters,
+                None => continue,
+            };
+
+            breath.time += delta;
+
+            for parameter in breath.parameters.iter() {
+                if let Some(value) = parameters.value(&parameter.id) {
+                    let value = value + parameter.value(breath.time) * parameter.weight;
+                    parameters.set(&parameter.id, value);
+                }
+            }
+        }
+
+        for (entity, auto_blink) in (&entities, &mut auto_blinks).join() {
+            let parameters = match parameters.get_mut(entity) {
+                Some(parameters) => parameters,
+                None => continue,
+            };
+
+            // the first blink waits like the others
+            if auto_blink.wait.is_none() && auto_blink.time == 0.0 {
+                auto_blink.next();
+            }
+
+            auto_blink.advance(delta);
+            let openness = auto_blink.openness();
+
+            for id in auto_blink.parameters.iter() {
+                if let Some(value) = parameters.value(id) {
+                    parameters.set(id, value * openness);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the times at which the eyes close during a minute, in steps of 10ms
+    fn blinks(auto_blink: &mut AutoBlink) -> Vec<usize> {
+        auto_blink.next();
+
+        let mut closed = Vec::new();
+        let mut was_closed = false;
+        for step in 0..6000 {
+            auto_blink.advance(0.01);
+            let is_closed = auto_blink.openness() == 0.0;
+            if is_closed && !was_closed {
+                closed.push(step);
+            }
+            was_closed = is_closed;
+        }
+
+        closed
+    }
+
+    #[test]
+    fn random_stays_between_0_and_1() {
+        let mut auto_blink = AutoBlink::default();
+
+        for _ in 0..1000 {
+            let value = auto_blink.random();
+            assert!((0.0..1.0).contains(&value));
+        }
+    }
+
+    #[test]
+    fn the_eyes_close_and_open_again() {
+        let mut auto_blink = AutoBlink {
+            interval: [1.0, 1.0],
+            curve: Easing::Linear,
+            ..Default::default()
+        };
+
+        auto_blink.next();
+        auto_blink.advance(0.99);
+        assert_eq!(auto_blink.openness(), 1.0);
+
+        auto_blink.advance(0.06);
+        assert!((auto_blink.openness() - 0.5).abs() < 1e-3);
+
+        auto_blink.advance(0.1);
+        assert_eq!(auto_blink.openness(), 0.0);
+
+        auto_blink.advance(0.1);
+        assert!(auto_blink.openness() > 0.0 && auto_blink.openness() < 1.0);
+
+        auto_blink.advance(0.1);
+        assert_eq!(auto_blink.openness(), 1.0);
+        assert!(auto_blink.wait.is_some());
+    }
+
+    #[test]
+    fn the_same_seed_blinks_at_the_same_times() {
+        let first = blinks(&mut AutoBlink::default());
+        let second = blinks(&mut AutoBlink::default());
+        let other = blinks(&mut AutoBlink {
+            seed: 42,
+            ..Default::default()
+        });
+
+        assert!(first.len() >= 60 / 6);
+        assert!(first.windows(2).all(|pair| pair[1] - pair[0] >= 200));
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn breath_waves_around_its_offset() {
+        let parameter = BreathParameter::new("ParamBreath", 0.5, 0.5, 4.0, 1.0);
+
+        assert!((parameter.value(0.0) - 0.5).abs() < 1e-6);
+        assert!((parameter.value(1.0) - 1.0).abs() < 1e-6);
+        assert!((parameter.value(3.0) - 0.0).abs() < 1e-6);
+
+        let still = BreathParameter::new("ParamBreath", 0.5, 0.5, 0.0, 1.0);
+        assert_eq!(still.value(1.0), 0.5);
+    }
+
+    #[test]
+    fn settings_leave_out_what_they_dont_have() {
+        let settings: ProceduralSettings =
+            serde_json::from_str(r#"{ "auto_blink": { "interval": [1, 3] } }"#).unwrap();
+
+        let auto_blink = settings.auto_blink.unwrap();
+        assert_eq!(auto_blink.interval, [1.0, 3.0]);
+        assert_eq!(auto_blink.parameters.len(), 2);
+        assert!(settings.breath.is_none());
+        assert_eq!(settings.lip_sync, LipSyncMode::Vowels);
+
+        let settings: ProceduralSettings =
+            serde_json::from_str(r#"{ "lip_sync": "volume" }"#).unwrap();
+        assert_eq!(settings.lip_sync, LipSyncMode::Volume);
+    }
+}
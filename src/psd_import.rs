@@ -0,0 +1,231 @@
+use crate::{
+    auto_mesh::AutoMesh,
+    model::{Model, ModelData, PIXELS_PER_UNIT},
+    sprite_selector::SpriteSelector,
+    texture::{self},
+    type_def::*,
+};
+use anyhow::Result;
+use dashmap::DashMap;
+use image::{imageops, DynamicImage, RgbaImage};
+use psd::{Psd, PsdLayer};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::{collections::HashMap, ffi::OsString, path::Path, sync::Arc};
+
+// one visible layer of a psd, ready to be turned into an entity
+pub struct PsdPart {
+    pub name: String,
+    pub data: ModelData,
+    pub position: Position, // relative to its group, or to the middle of the canvas
+    pub rect: [u32; 4],     // left, top, right and bottom in canvas pixels
+    pub parent: Option<u32>, // id of the group holding this layer
+}
+
+// a visible group (folder) of a psd, positioned at the center of what it contains
+pub struct PsdGroupPart {
+    pub id: u32,
+    pub name: String,
+    pub position: Position, // relative to its parent group, or to the middle of the canvas
+    pub parent: Option<u32>,
+}
+
+pub struct PsdImport {
+    pub groups: Vec<PsdGroupPart>, // in the order the psd lists them, children before their parent
+    pub parts: Vec<PsdPart>,       // from the bottom layer to the top one
+}
+
+impl PsdImport {
+    pub fn load<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        path: P,
+        auto_mesh: &AutoMesh,
+        textures_map: &DashMap<OsString, Arc<texture::Texture>>,
+    ) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let psd = Psd::from_bytes(&bytes)?;
+
+        let width = psd.width();
+        let height = psd.height();
+
+        let mut parts = Self::shown_layers(&psd)
+            .par_iter()
+            .filter_map(|layer| {
+                Self::load_layer(
+                    device,
+                    queue,
+                    layout,
+                    layer,
+                    (width, height),
+                    auto_mesh,
+                    textures_map,
+                )
+                .transpose()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // pixel bounds of every group, grown by the parts they contain
+        let mut bounds: HashMap<u32, [u32; 4]> = HashMap::new();
+
+        for part in parts.iter() {
+            let mut parent = part.parent;
+
+            while let Some(id) = parent {
+                let bound = bounds.entry(id).or_insert(part.rect);
+                *bound = [
+                    bound[0].min(part.rect[0]),
+                    bound[1].min(part.rect[1]),
+                    bound[2].max(part.rect[2]),
+                    bound[3].max(part.rect[3]),
+                ];
+
+                parent = psd.groups()[&id].parent_id();
+            }
+        }
+
+        let centers = bounds
+            .iter()
+            .map(|(id, bound)| (*id, Self::center(bound, (width, height))))
+            .collect::<HashMap<_, _>>();
+
+        // positions are kept local to the parent group so that moving a group moves what it holds
+        let local = |center: Point, parent: Option<u32>| {
+            let origin = parent
+                .and_then(|id| centers.get(&id))
+                .copied()
+                .unwrap_or_else(Point::origin);
+
+            Position(Point::from(center - origin))
+        };
+
+        for part in parts.iter_mut() {
+            part.position = local(Self::center(&part.rect, (width, height)), part.parent);
+        }
+
+        let groups = psd
+            .group_ids_in_order()
+            .iter()
+            .filter_map(|id| {
+                let group = &psd.groups()[id];
+                let center = centers.get(id)?; // hidden or empty groups have no bounds
+
+                Some(PsdGroupPart {
+                    id: *id,
+                    name: group.name().to_string(),
+                    position: local(*center, group.parent_id()),
+                    parent: group.parent_id(),
+                })
+            })
+            .collect();
+
+        Ok(Self { groups, parts })
+    }
+
+    // the layers that can be seen, from the bottom one to the top one,
+    // the psd crate lists them from the top like the layer panel of photoshop
+    fn shown_layers(psd: &Psd) -> Vec<&PsdLayer> {
+        // a layer is only shown if itself and every group above it are visible,
+        // the psd crate reads the second flag bit as "visible" but photoshop sets it when the layer is hidden
+        let shown = |layer: &PsdLayer| {
+            let mut visible = !layer.visible();
+            let mut parent = layer.parent_id();
+
+            while let (true, Some(id)) = (visible, parent) {
+                let group = &psd.groups()[&id];
+                visible = !group.visible();
+                parent = group.parent_id();
+            }
+
+            visible
+        };
+
+        psd.layers()
+            .iter()
+            .rev()
+            .filter(|layer| shown(layer))
+            .collect()
+    }
+
+    fn load_layer(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        layer: &PsdLayer,
+        canvas: (u32, u32),
+        auto_mesh: &AutoMesh,
+        textures_map: &DashMap<OsString, Arc<texture::Texture>>,
+    ) -> Result<Option<PsdPart>> {
+        // the layer bounds can go outside of the canvas, only keep what can be seen
+        let left = layer.layer_left().clamp(0, canvas.0 as i32) as u32;
+        let top = layer.layer_top().clamp(0, canvas.1 as i32) as u32;
+        let right = (layer.layer_left() + layer.width() as i32).clamp(0, canvas.0 as i32) as u32;
+        let bottom = (layer.layer_top() + layer.height() as i32).clamp(0, canvas.1 as i32) as u32;
+
+        if right <= left || bottom <= top {
+            return Ok(None);
+        }
+
+        // rgba() gives the layer drawn on a canvas sized image
+        let canvas_img = RgbaImage::from_raw(canvas.0, canvas.1, layer.rgba()).unwrap();
+        let img = imageops::crop_imm(&canvas_img, left, top, right - left, bottom - top).to_image();
+        let img = DynamicImage::ImageRgba8(img);
+
+        // a layer is a single sprite cell
+        let mesh = auto_mesh.generate(&img, &SpriteSelector::new(0, 0, 1, 1, 1, None));
+
+        let diffuse_texture =
+            texture::Texture::from_image(device, queue, img, Some(layer.name()), false)?;
+
+        let data = Model::from_texture(
+            device,
+            queue,
+            layout,
+            layer.name(),
+            mesh,
+            diffuse_texture,
+            textures_map,
+        )?;
+
+        Ok(Some(PsdPart {
+            name: layer.name().to_string(),
+            data,
+            position: Position::default(),
+            rect: [left, top, right, bottom],
+            parent: layer.parent_id(),
+        }))
+    }
+
+    // model space position of the middle of a pixel rectangle, the canvas being centered on the origin
+    fn center(rect: &[u32; 4], canvas: (u32, u32)) -> Point {
+        let center_x = (rect[0] + rect[2]) as Real / 2.0 - canvas.0 as Real / 2.0;
+        let center_y = (rect[1] + rect[3]) as Real / 2.0 - canvas.1 as Real / 2.0;
+
+        // world x goes toward the left of the screen and world y goes up
+        Point::new(-center_x, -center_y) / PIXELS_PER_UNIT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shown_layers_go_from_the_bottom_to_the_top() {
+        let bytes = std::fs::read("assets/psd/mark_free_Import.psd").unwrap();
+        let psd = Psd::from_bytes(&bytes).unwrap();
+
+        let names = PsdImport::shown_layers(&psd)
+            .iter()
+            .map(|layer| layer.name().to_string())
+            .collect::<Vec<_>>();
+        let at = |name: &str| names.iter().position(|other| other == name).unwrap();
+
+        // what photoshop draws over is after it, the eyes go over the face and the iris over its white
+        assert!(at("Torso") < at("Face"));
+        assert!(at("Face") < at("WhiteEyeL"));
+        assert!(at("WhiteEyeL") < at("IrisL"));
+        assert!(at("IrisL") < at("UpEyelidL"));
+        assert!(at("Face") < at("Mouth"));
+    }
+}
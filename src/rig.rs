@@ -0,0 +1,857 @@
+use crate::{
+    actor::Parent,
+    clipping::Clipping,
+    deformer::{RotationDeformer, WarpDeformer, WarpInterpolation},
+    deg,
+    keyform::{Keyform, KeyformAxis, Keyforms},
+    mesh::Mesh,
+    model::{Model, ModelVertex, PIXELS_PER_UNIT},
+    parameter::{Parameter, Parameters},
+    sprite_selector::SpriteSelector,
+    transform::GlobalTransform,
+    type_def::*,
+};
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use specs::{Entity, World, WorldExt};
+use std::path::Path;
+
+// the layout of a rig file, written by hand next to a psd to say what the parameters do to its layers
+#[derive(Deserialize)]
+struct RigFile {
+    #[serde(default)]
+    parameters: Vec<RigFileParameter>,
+    #[serde(default)]
+    warps: Vec<RigFileWarp>,
+    #[serde(default)]
+    rotations: Vec<RigFileRotation>,
+    #[serde(default)]
+    keyforms: Vec<RigFileKeyforms>,
+    #[serde(default)]
+    clippings: Vec<RigClipping>,
+}
+
+#[derive(Deserialize)]
+struct RigFileParameter {
+    id: String,
+    min: Real,
+    default: Real,
+    max: Real,
+}
+
+#[derive(Deserialize)]
+struct RigFileKeyforms {
+    part: String,
+    axes: Vec<RigFileAxis>,
+    forms: Vec<RigFileForm>,
+}
+
+// a warp deformer put between a group and its children, the grid covers them at rest
+#[derive(Deserialize)]
+struct RigFileWarp {
+    name: String,
+    parent: String,
+    #[serde(default)]
+    children: Vec<String>, // every child of the parent when empty
+    columns: usize,
+    rows: usize,
+    interpolation: WarpInterpolation,
+    axes: Vec<RigFileAxis>,
+    forms: Vec<RigFileWarpForm>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct RigFileWarpForm {
+    offset: Option<[Real; 2]>, // in psd pixels like the keyforms, moves the whole grid
+    points: Vec<[Real; 2]>,    // one offset per control point, x changing the fastest
+}
+
+// a rotation deformer put between a group and its children like a warp
+#[derive(Deserialize)]
+struct RigFileRotation {
+    name: String,
+    parent: String,
+    #[serde(default)]
+    children: Vec<String>,
+    #[serde(default = "RigFileRotation::center")]
+    origin: [Real; 2], // where it turns in what the children cover, 0 to 1 going right and down
+    axes: Vec<RigFileAxis>,
+    forms: Vec<RigFileRotationForm>,
+}
+
+impl RigFileRotation {
+    fn center() -> [Real; 2] {
+        [0.5, 0.5]
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RigFileRotationForm {
+    offset: Option<[Real; 2]>, // moves the origin, in psd pixels
+    angle: Real,               // in degrees
+    scale: Real,
+}
+
+impl Default for RigFileRotationForm {
+    fn default() -> Self {
+        Self {
+            offset: None,
+            angle: 0.0,
+            scale: 1.0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RigFileAxis {
+    parameter: String,
+    keys: Vec<Real>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RigFileForm {
+    offset: Option<[Real; 2]>, // in psd pixels, x going right and y going down
+    opacity: Real,
+    draw_order: i32,
+    multiply: [Real; 3],
+    screen: [Real; 3],
+}
+
+impl Default for RigFileForm {
+    fn default() -> Self {
+        Self {
+            offset: None,
+            opacity: 1.0,
+            draw_order: DrawOrder::default().0,
+            multiply: [1.0, 1.0, 1.0],
+            screen: [0.0, 0.0, 0.0],
+        }
+    }
+}
+
+// the parts with that name only drawn where the masks are, or where they aren't when inverted
+#[derive(Debug, Clone, Deserialize)]
+pub struct RigClipping {
+    pub part: String,
+    pub masks: Vec<String>,
+    #[serde(default)]
+    pub inverted: bool,
+}
+
+// a form of a rig, the offset moves the whole part from where it is at rest
+#[derive(Debug, Clone)]
+pub struct RigForm {
+    pub keyform: Keyform,
+    pub offset: Option<[f32; 2]>, // in the space of ModelVertex::position
+}
+
+// control points moved from where they are at rest
+#[derive(Debug, Clone)]
+pub struct RigWarpForm {
+    pub offset: [f32; 2],
+    pub points: Vec<[f32; 2]>, // empty or one per control point
+}
+
+#[derive(Debug, Clone)]
+pub struct RigWarp {
+    pub name: String,
+    pub parent: String,
+    pub children: Vec<String>,
+    pub columns: usize,
+    pub rows: usize,
+    pub interpolation: WarpInterpolation,
+    pub axes: Vec<KeyformAxis>,
+    pub forms: Vec<RigWarpForm>,
+}
+
+// the origin moved from where it is at rest, the angle in radians
+#[derive(Debug, Clone)]
+pub struct RigRotationForm {
+    pub offset: [f32; 2],
+    pub angle: Real,
+    pub scale: Real,
+}
+
+#[derive(Debug, Clone)]
+pub struct RigRotation {
+    pub name: String,
+    pub parent: String,
+    pub children: Vec<String>,
+    pub origin: [Real; 2],
+    pub axes: Vec<KeyformAxis>,
+    pub forms: Vec<RigRotationForm>,
+}
+
+// the keyforms of every part with a given name
+#[derive(Debug, Clone)]
+pub struct RigKeyforms {
+    pub part: String,
+    pub axes: Vec<KeyformAxis>,
+    pub forms: Vec<RigForm>,
+}
+
+// what the parameters of a puppet do to its parts, the parts are found by name under its root
+#[derive(Debug, Clone, Default)]
+pub struct Rig {
+    pub parameters: Vec<Parameter>,  // added to the ones of the puppet
+    pub warps: Vec<RigWarp>,         // created in order, a warp can be the parent of the next ones
+    pub rotations: Vec<RigRotation>, // created after the warps, they can be above or below them
+    pub keyforms: Vec<RigKeyforms>,
+    pub clippings: Vec<RigClipping>,
+}
+
+// psd pixels go right and down, the world goes left and up
+fn offset([x, y]: [Real; 2]) -> [f32; 2] {
+    [-x / PIXELS_PER_UNIT, -y / PIXELS_PER_UNIT]
+}
+
+impl Rig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    // one form for every combination of keys, the first axis changing the fastest
+    fn axes(name: &str, axes: Vec<RigFileAxis>, forms: usize) -> Result<Vec<KeyformAxis>> {
+        for axis in axes.iter() {
+            if axis.keys.is_empty() || axis.keys.windows(2).any(|keys| keys[0] >= keys[1]) {
+                bail!("the keys of {} on {} have to go up", axis.parameter, name);
+            }
+        }
+
+        let count = axes.iter().map(|axis| axis.keys.len()).product::<usize>();
+
+        if count != forms {
+            bail!(
+                "{} has {} forms for {} combinations of keys",
+                name,
+                forms,
+                count
+            );
+        }
+
+        Ok(axes
+            .into_iter()
+            .map(|axis| KeyformAxis::new(&axis.parameter, axis.keys))
+            .collect())
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let file: RigFile = serde_json::from_str(json)?;
+
+        let warps = file
+            .warps
+            .into_iter()
+            .map(|warp| {
+                let (columns, rows) = (warp.columns.max(1), warp.rows.max(1));
+                let points = (columns + 1) * (rows + 1);
+
+                if let Some(form) = warp
+                    .forms
+                    .iter()
+                    .find(|form| !form.points.is_empty() && form.points.len() != points)
+                {
+                    bail!(
+                        "{} has forms of {} points for {} control points",
+                        warp.name,
+                        form.points.len(),
+                        points
+                    );
+                }
+
+                Ok(RigWarp {
+                    axes: Self::axes(&warp.name, warp.axes, warp.forms.len())?,
+                    forms: warp
+                        .forms
+                        .into_iter()
+                        .map(|form| RigWarpForm {
+                            offset: offset(form.offset.unwrap_or([0.0, 0.0])),
+                            points: form.points.into_iter().map(offset).collect(),
+                        })
+                        .collect(),
+                    name: warp.name,
+                    parent: warp.parent,
+                    children: warp.children,
+                    columns,
+                    rows,
+                    interpolation: warp.interpolation,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let rotations = file
+            .rotations
+            .into_iter()
+            .map(|rotation| {
+                Ok(RigRotation {
+                    axes: Self::axes(&rotation.name, rotation.axes, rotation.forms.len())?,
+                    forms: rotation
+                        .forms
+                        .into_iter()
+                        .map(|form| RigRotationForm {
+                            offset: offset(form.offset.unwrap_or([0.0, 0.0])),
+                            angle: deg(form.angle),
+                            scale: form.scale,
+                        })
+                        .collect(),
+                    name: rotation.name,
+                    parent: rotation.parent,
+                    children: rotation.children,
+                    origin: rotation.origin,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let keyforms = file
+            .keyforms
+            .into_iter()
+            .map(|keyforms| {
+                Ok(RigKeyforms {
+                    axes: Self::axes(&keyforms.part, keyforms.axes, keyforms.forms.len())?,
+                    part: keyforms.part,
+                    forms: keyforms
+                        .forms
+                        .into_iter()
+                        .map(|form| RigForm {
+                            keyform: Keyform::new(Vec::new(), form.opacity, form.draw_order)
+                                .colored(form.multiply, form.screen),
+                            offset: form.offset.map(offset),
+                        })
+                        .collect(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            parameters: file
+                .parameters
+                .into_iter()
+                .map(|parameter| {
+                    Parameter::new(
+                        &parameter.id,
+                        parameter.min,
+                        parameter.default,
+                        parameter.max,
+                    )
+                })
+                .collect(),
+            warps,
+            rotations,
+            keyforms,
+            clippings: file.clippings,
+        })
+    }
+
+    // the entities with that name somewhere below the root
+    fn find(world: &World, root: Entity, name: &str) -> Vec<Entity> {
+        use specs::Join;
+
+        let entities = world.entities();
+        let names = world.read_storage::<Name>();
+        let parents = world.read_storage::<Parent>();
+
+        let below_root = |mut entity: Entity| loop {
+            match parents.get(entity) {
+                Some(parent) if parent.entity == root => return true,
+                Some(parent) => entity = parent.entity,
+                None => return false,
+            }
+        };
+
+        (&entities, &names)
+            .join()
+            .filter(|(entity, entity_name)| entity_name.0 == name && below_root(*entity))
+            .map(|(entity, _)| entity)
+            .collect()
+    }
+
+    // where the vertices of a part are without keyforms
+    fn rest(world: &World, entity: Entity) -> Option<Vec<[f32; 2]>> {
+        let meshes = world.read_storage::<Mesh>();
+        let sprite_selectors = world.read_storage::<SpriteSelector>();
+        let models = world.read_storage::<Model>();
+
+        Some(
+            ModelVertex::new(
+                meshes.get(entity)?,
+                sprite_selectors.get(entity)?,
+                &models.get(entity)?.0,
+            )
+            .into_iter()
+            .map(|vertex| vertex.position)
+            .collect(),
+        )
+    }
+
+    // a group can have the name of one of its layers, only the layers are parts
+    fn parts(world: &World, root: Entity, name: &str) -> Result<Vec<Entity>> {
+        let models = world.read_storage::<Model>();
+
+        let parts = Self::find(world, root, name)
+            .into_iter()
+            .filter(|entity| models.contains(*entity))
+            .collect::<Vec<_>>();
+
+        match parts.is_empty() {
+            true => Err(anyhow!("no part named {}", name)),
+            false => Ok(parts),
+        }
+    }
+
+    // the group a deformer goes below, a layer can't hold anything
+    fn group(world: &World, root: Entity, name: &str) -> Result<Entity> {
+        let models = world.read_storage::<Model>();
+
+        let mut groups = Self::find(world, root, name)
+            .into_iter()
+            .filter(|entity| !models.contains(*entity));
+
+        match (groups.next(), groups.next()) {
+            (Some(group), None) => Ok(group),
+            (Some(_), Some(_)) => Err(anyhow!("more than one group is named {}", name)),
+            (None, _) => Err(anyhow!("no group named {}", name)),
+        }
+    }
+
+    // the children of a group with one of the names, every child when no names are given
+    fn children(world: &World, parent: Entity, names: &[String]) -> Vec<Entity> {
+        use specs::Join;
+
+        let entities = world.entities();
+        let parents = world.read_storage::<Parent>();
+        let entity_names = world.read_storage::<Name>();
+
+        (&entities, &parents)
+            .join()
+            .filter(|(_, entity_parent)| entity_parent.entity == parent)
+            .filter(|(entity, _)| {
+                names.is_empty()
+                    || entity_names
+                        .get(*entity)
+                        .is_some_and(|name| names.contains(&name.0))
+            })
+            .map(|(entity, _)| entity)
+            .collect()
+    }
+
+    // the corners of what the children and everything below them cover at rest, in the space of their parent
+    fn bounds(world: &World, children: &[Entity]) -> Option<([f32; 2], [f32; 2])> {
+        use specs::Join;
+
+        let entities = world.entities();
+        let parents = world.read_storage::<Parent>();
+        let models = world.read_storage::<Model>();
+        let positions = world.read_storage::<Position>();
+        let rotations = world.read_storage::<Rotation>();
+        let scales = world.read_storage::<Scale>();
+
+        let local = |entity: Entity| {
+            GlobalTransform::local(
+                &positions
+                    .get(entity)
+                    .map_or_else(Point::origin, |position| position.0),
+                rotations.get(entity).map(|rotation| &rotation.0),
+                scales.get(entity).map(|scale| &scale.0),
+            )
+        };
+
+        // from a part up to one of the children, none when it isn't below them
+        let to_parent = |mut entity: Entity| {
+            let mut transform = Transform::identity();
+
+            loop {
+                transform = local(entity) * transform;
+
+                if children.contains(&entity) {
+                    return Some(transform);
+                }
+
+                entity = parents.get(entity)?.entity;
+            }
+        };
+
+        (&entities, &models)
+            .join()
+            .filter_map(|(entity, _)| {
+                let transform = to_parent(entity)?;
+                let rest = Self::rest(world, entity)?;
+
+                Some(
+                    rest.into_iter()
+                        .map(|vertex| {
+                            let point =
+                                transform.transform_point(&Point::new(vertex[0], vertex[1]));
+                            [point.x, point.y]
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .flatten()
+            .fold(None, |bounds, point| match bounds {
+                Some(([min_x, min_y], [max_x, max_y])) => Some((
+                    [min_x.min(point[0]), min_y.min(point[1])],
+                    [max_x.max(point[0]), max_y.max(point[1])],
+                )),
+                None => Some((point, point)),
+            })
+    }
+
+    // the deformer takes the place of the children below their parent, with no transform of its own
+    // they stay where they are until it moves them
+    fn deformer(world: &World, name: &str, parent: Entity, children: &[Entity]) -> Result<Entity> {
+        let deformer = world.entities().create();
+
+        world
+            .write_storage::<Name>()
+            .insert(deformer, Name::new(name))?;
+        world
+            .write_storage::<Position>()
+            .insert(deformer, Position::new(0.0, 0.0))?;
+        world
+            .write_storage::<Rotation>()
+            .insert(deformer, Rotation::default())?;
+        world
+            .write_storage::<Scale>()
+            .insert(deformer, Scale::new(1.0, 1.0))?;
+
+        let mut parents = world.write_storage::<Parent>();
+        parents.insert(deformer, Parent::new(parent))?;
+
+        for child in children.iter() {
+            parents.insert(*child, Parent::new(deformer))?;
+        }
+
+        Ok(deformer)
+    }
+
+    fn insert_warp(world: &World, root: Entity, warp: &RigWarp) -> Result<()> {
+        let parent = Self::group(world, root, &warp.parent)?;
+        let children = Self::children(world, parent, &warp.children);
+
+        let (min, max) = Self::bounds(world, &children)
+            .ok_or_else(|| anyhow!("{} has nothing to deform", warp.name))?;
+
+        let deformer = WarpDeformer::new(
+            min,
+            [max[0] - min[0], max[1] - min[1]],
+            warp.columns,
+            warp.rows,
+            warp.interpolation,
+        );
+        let rest = deformer.rest();
+
+        let forms = warp
+            .forms
+            .iter()
+            .map(|form| {
+                let vertices = rest
+                    .iter()
+                    .enumerate()
+                    .map(|(i, point)| {
+                        let [x, y] = form.points.get(i).copied().unwrap_or([0.0, 0.0]);
+                        [point[0] + form.offset[0] + x, point[1] + form.offset[1] + y]
+                    })
+                    .collect();
+
+                Keyform::new(vertices, 1.0, DrawOrder::default().0)
+            })
+            .collect();
+
+        let entity = Self::deformer(world, &warp.name, parent, &children)?;
+        world
+            .write_storage::<WarpDeformer>()
+            .insert(entity, deformer)?;
+        world
+            .write_storage::<Keyforms>()
+            .insert(entity, Keyforms::new(warp.axes.clone(), forms))?;
+
+        Ok(())
+    }
+
+    fn insert_rotation(world: &World, root: Entity, rotation: &RigRotation) -> Result<()> {
+        let parent = Self::group(world, root, &rotation.parent)?;
+        let children = Self::children(world, parent, &rotation.children);
+
+        // psd pixels go right and down, the world goes left and up
+        let (min, max) = Self::bounds(world, &children)
+            .ok_or_else(|| anyhow!("{} has nothing to turn", rotation.name))?;
+        let origin = [
+            max[0] - (max[0] - min[0]) * rotation.origin[0],
+            max[1] - (max[1] - min[1]) * rotation.origin[1],
+        ];
+
+        let forms = rotation
+            .forms
+            .iter()
+            .map(|form| {
+                Keyform::rotation(
+                    [origin[0] + form.offset[0], origin[1] + form.offset[1]],
+                    form.angle,
+                    form.scale,
+                    1.0,
+                )
+            })
+            .collect();
+
+        let entity = Self::deformer(world, &rotation.name, parent, &children)?;
+        world
+            .write_storage::<RotationDeformer>()
+            .insert(entity, RotationDeformer::new(origin, 0.0, 1.0))?;
+        world
+            .write_storage::<Keyforms>()
+            .insert(entity, Keyforms::new(rotation.axes.clone(), forms))?;
+
+        Ok(())
+    }
+
+    pub fn insert(&self, world: &World, root: Entity) -> Result<()> {
+        if let Some(parameters) = world.write_storage::<Parameters>().get_mut(root) {
+            for parameter in self.parameters.iter() {
+                parameters.add(parameter.clone());
+            }
+        }
+
+        for warp in self.warps.iter() {
+            Self::insert_warp(world, root, warp)?;
+        }
+
+        for rotation in self.rotations.iter() {
+            Self::insert_rotation(world, root, rotation)?;
+        }
+
+        for keyforms in self.keyforms.iter() {
+            let parts = Self::parts(world, root, &keyforms.part)?;
+
+            let moved = keyforms.forms.iter().any(|form| form.offset.is_some());
+
+            for part in parts.into_iter() {
+                // the offsets are turned into vertices, every form moves all of them then
+                let rest = match moved {
+                    true => Self::rest(world, part)
+                        .ok_or_else(|| anyhow!("{} has no mesh to move", keyforms.part))?,
+                    false => Vec::new(),
+                };
+
+                let forms = keyforms
+                    .forms
+                    .iter()
+                    .map(|form| {
+                        let [x, y] = form.offset.unwrap_or([0.0, 0.0]);
+                        let mut keyform = form.keyform.clone();
+                        keyform.vertices = rest
+                            .iter()
+                            .map(|vertex| [vertex[0] + x, vertex[1] + y])
+                            .collect();
+
+                        keyform
+                    })
+                    .collect();
+
+                world
+                    .write_storage::<Keyforms>()
+                    .insert(part, Keyforms::new(keyforms.axes.clone(), forms))?;
+            }
+        }
+
+        for clipping in self.clippings.iter() {
+            let masks = clipping
+                .masks
+                .iter()
+                .map(|mask| Self::parts(world, root, mask))
+                .collect::<Result<Vec<_>>>()?
+                .concat();
+
+            for part in Self::parts(world, root, &clipping.part)? {
+                world
+                    .write_storage::<Clipping>()
+                    .insert(part, Clipping::new(masks.clone(), clipping.inverted))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forms_default_to_the_part_as_it_is() {
+        let rig = Rig::from_json(
+            r#"{
+                "parameters": [{ "id": "ParamWave", "min": 0, "default": 0, "max": 1 }],
+                "keyforms": [{
+                    "part": "Mouth",
+                    "axes": [{ "parameter": "ParamMouthOpenY", "keys": [0, 1] }],
+                    "forms": [{ "opacity": 0 }, {}]
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(rig.parameters.len(), 1);
+        assert_eq!(rig.parameters[0].id, "ParamWave");
+
+        let forms = &rig.keyforms[0].forms;
+        assert_eq!(forms[0].keyform.opacity, 0.0);
+        assert_eq!(forms[1].keyform.opacity, 1.0);
+        assert_eq!(forms[1].keyform.draw_order, DrawOrder::default().0);
+        assert_eq!(forms[1].keyform.multiply, [1.0, 1.0, 1.0]);
+        assert_eq!(forms[1].keyform.screen, [0.0, 0.0, 0.0]);
+        assert!(forms.iter().all(|form| form.offset.is_none()));
+    }
+
+    #[test]
+    fn offsets_go_from_psd_pixels_to_the_world() {
+        let rig = Rig::from_json(
+            r#"{
+                "keyforms": [{
+                    "part": "LowerMouth",
+                    "axes": [{ "parameter": "ParamMouthOpenY", "keys": [0, 1] }],
+                    "forms": [{}, { "offset": [50, 100] }]
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            rig.keyforms[0].forms[1].offset,
+            Some([-50.0 / PIXELS_PER_UNIT, -100.0 / PIXELS_PER_UNIT])
+        );
+    }
+
+    #[test]
+    fn every_combination_of_keys_needs_a_form() {
+        let json = |forms: &str| {
+            format!(
+                r#"{{
+                    "keyforms": [{{
+                        "part": "IrisL",
+                        "axes": [
+                            {{ "parameter": "ParamEyeBallX", "keys": [-1, 0, 1] }},
+                            {{ "parameter": "ParamEyeLOpen", "keys": [0, 1] }}
+                        ],
+                        "forms": [{}]
+                    }}]
+                }}"#,
+                forms
+            )
+        };
+
+        assert!(Rig::from_json(&json(&["{}"; 6].join(","))).is_ok());
+        assert!(Rig::from_json(&json(&["{}"; 5].join(","))).is_err());
+        assert!(Rig::from_json(&json(&["{}"; 7].join(","))).is_err());
+    }
+
+    #[test]
+    fn keys_have_to_go_up() {
+        let json = |keys: &str| {
+            format!(
+                r#"{{
+                    "keyforms": [{{
+                        "part": "Mouth",
+                        "axes": [{{ "parameter": "ParamMouthOpenY", "keys": [{}] }}],
+                        "forms": [{{}}, {{}}]
+                    }}]
+                }}"#,
+                keys
+            )
+        };
+
+        assert!(Rig::from_json(&json("0, 1")).is_ok());
+        assert!(Rig::from_json(&json("1, 0")).is_err());
+        assert!(Rig::from_json(&json("0, 0")).is_err());
+    }
+
+    #[test]
+    fn warps_move_their_control_points() {
+        let json = |points: &str| {
+            format!(
+                r#"{{
+                    "warps": [{{
+                        "name": "HeadWarp",
+                        "parent": "Details",
+                        "columns": 1,
+                        "rows": 1,
+                        "interpolation": "bezier",
+                        "axes": [{{ "parameter": "ParamAngleX", "keys": [-30, 30] }}],
+                        "forms": [{{ "offset": [0, 25] }}, {{ "points": [{}] }}]
+                    }}]
+                }}"#,
+                points
+            )
+        };
+
+        let rig = Rig::from_json(&json("[0, 0], [10, 0], [0, 0], [10, 0]")).unwrap();
+        let warp = &rig.warps[0];
+
+        assert_eq!(warp.interpolation, WarpInterpolation::Bezier);
+        assert!(warp.children.is_empty());
+        assert_eq!(warp.forms[0].offset, [0.0, -25.0 / PIXELS_PER_UNIT]);
+        assert!(warp.forms[0].points.is_empty());
+        assert_eq!(warp.forms[1].points[1], [-10.0 / PIXELS_PER_UNIT, 0.0]);
+
+        // a 1 by 1 grid has 4 control points
+        assert!(Rig::from_json(&json("[0, 0], [10, 0], [0, 0]")).is_err());
+    }
+
+    #[test]
+    fn rotations_are_keyed_in_degrees() {
+        let rig = Rig::from_json(
+            r#"{
+                "rotations": [{
+                    "name": "HeadRotation",
+                    "parent": "Details",
+                    "children": ["HeadWarp"],
+                    "axes": [{ "parameter": "ParamAngleZ", "keys": [-30, 30] }],
+                    "forms": [{ "angle": -90, "offset": [0, 10] }, { "angle": 90, "scale": 2 }]
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let rotation = &rig.rotations[0];
+        assert_eq!(rotation.origin, [0.5, 0.5]);
+        assert_eq!(rotation.children, vec!["HeadWarp".to_string()]);
+        assert!((rotation.forms[0].angle + std::f32::consts::FRAC_PI_2).abs() < 1e-5);
+        assert_eq!(rotation.forms[0].offset, [0.0, -10.0 / PIXELS_PER_UNIT]);
+        assert_eq!(rotation.forms[0].scale, 1.0);
+        assert_eq!(rotation.forms[1].scale, 2.0);
+    }
+
+    #[test]
+    fn clippings_are_not_inverted_by_default() {
+        let rig = Rig::from_json(
+            r#"{
+                "clippings": [
+                    { "part": "IrisL", "masks": ["WhiteEyeL"] },
+                    { "part": "Theet", "masks": ["UpperMouth", "LowerMouth"], "inverted": true }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(!rig.clippings[0].inverted);
+        assert!(rig.clippings[1].inverted);
+        assert_eq!(rig.clippings[1].masks.len(), 2);
+    }
+
+    #[test]
+    fn the_rig_next_to_the_puppet_loads() {
+        let rig = Rig::load("assets/psd/mark_free_Import.rig.json").unwrap();
+
+        assert!(rig.keyforms.iter().any(|keyforms| keyforms.part == "IrisL"));
+        assert!(rig.warps.iter().any(|warp| warp.name == "HeadWarp"));
+        assert!(rig
+            .rotations
+            .iter()
+            .any(|rotation| rotation.name == "HeadRotation"));
+        assert!(rig
+            .clippings
+            .iter()
+            .any(|clipping| clipping.part == "IrisL"));
+    }
+}
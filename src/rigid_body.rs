@@ -1,4 +1,3 @@
-use rapier::prelude::RigidBodyBuilder;
 use specs::{Component, DenseVecStorage, FlaggedStorage};
 
 use crate::RapierRigidBodyHandle;
@@ -0,0 +1,318 @@
+use crate::{
+    model::Model,
+    sprite_selector::SpriteFrame,
+    sprite_sheet::SpriteSheet,
+    texture::{self},
+};
+use anyhow::{anyhow, bail, Result};
+use dashmap::DashMap;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+// a texture of a spine atlas
+#[derive(Debug, Clone)]
+pub struct AtlasPage {
+    pub path: PathBuf,
+    pub size: [u32; 2], // 0 when the atlas doesn't say, the texture gives it then
+}
+
+// an image packed in a page, in pixels with y going down
+#[derive(Debug, Clone)]
+pub struct AtlasRegion {
+    pub page: usize,
+    pub rect: [u32; 4],   // x, y, width and height in the page
+    pub offset: [u32; 2], // where the trimmed rect goes in the untrimmed image, from its top left
+    pub source: [u32; 2], // size of the image before it was trimmed
+}
+
+// the .atlas text file written next to a spine export, read by the libgdx rules
+#[derive(Debug, Clone, Default)]
+pub struct SpineAtlas {
+    pub pages: Vec<AtlasPage>,
+    pub regions: HashMap<String, AtlasRegion>,
+}
+
+impl SpineAtlas {
+    // the pages are relative to the atlas
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+        Self::from_text(&std::fs::read_to_string(path)?, directory)
+    }
+
+    pub fn from_text(text: &str, directory: &Path) -> Result<Self> {
+        let mut atlas = Self::default();
+
+        // the fields of a region are only known once all of its lines are read
+        let mut region: Option<(String, HashMap<String, Vec<String>>)> = None;
+        let mut expecting_page = true;
+
+        let finish =
+            |atlas: &mut Self, region: Option<(String, HashMap<String, Vec<String>>)>| {
+                if let Some((name, fields)) = region {
+                    let region = Self::region(atlas.pages.len() - 1, &name, &fields)?;
+                    atlas.regions.insert(name, region);
+                }
+                Ok::<(), anyhow::Error>(())
+            };
+
+        for line in text.lines() {
+            let trimmed = line.trim();
+
+            // a blank line ends a page, the next name is the one of a texture
+            if trimmed.is_empty() {
+                finish(&mut atlas, region.take())?;
+                expecting_page = true;
+                continue;
+            }
+
+            match trimmed.split_once(':') {
+                Some((key, value)) => {
+                    let values = value.split(',').map(|value| value.trim().to_string());
+
+                    match region.as_mut() {
+                        Some((_, fields)) => {
+                            fields.insert(key.trim().to_string(), values.collect());
+                        }
+                        // the size of the page, its format, filters and repeat are all a texture needs to know
+                        None if key.trim() == "size" => {
+                            let size = values
+                                .filter_map(|value| value.parse().ok())
+                                .collect::<Vec<u32>>();
+                            if let (Some(page), [width, height]) =
+                                (atlas.pages.last_mut(), size.as_slice())
+                            {
+                                page.size = [*width, *height];
+                            }
+                        }
+                        None => (),
+                    }
+                }
+                None if expecting_page => {
+                    atlas.pages.push(AtlasPage {
+                        path: directory.join(trimmed),
+                        size: [0, 0],
+                    });
+                    expecting_page = false;
+                }
+                None => {
+                    if atlas.pages.is_empty() {
+                        bail!("region {} before any page of the atlas", trimmed);
+                    }
+
+                    finish(&mut atlas, region.take())?;
+                    region = Some((trimmed.to_string(), HashMap::new()));
+                }
+            }
+        }
+
+        finish(&mut atlas, region.take())?;
+
+        Ok(atlas)
+    }
+
+    // the old atlases write xy, size, orig and offset, the new ones bounds and offsets
+    fn region(
+        page: usize,
+        name: &str,
+        fields: &HashMap<String, Vec<String>>,
+    ) -> Result<AtlasRegion> {
+        let numbers = |key: &str| -> Option<Vec<i64>> {
+            fields
+                .get(key)?
+                .iter()
+                .map(|value| value.parse().ok())
+                .collect()
+        };
+
+        let rotated = match fields.get("rotate").and_then(|value| value.first()) {
+            Some(value) => !matches!(value.as_str(), "false" | "0"),
+            None => false,
+        };
+
+        if rotated {
+            bail!(
+                "region {} is rotated in the atlas, export it without rotation",
+                name
+            );
+        }
+
+        let (x, y, width, height) = match (numbers("bounds"), numbers("xy"), numbers("size")) {
+            (Some(bounds), _, _) if bounds.len() == 4 => {
+                (bounds[0], bounds[1], bounds[2], bounds[3])
+            }
+            (_, Some(xy), Some(size)) if xy.len() == 2 && size.len() == 2 => {
+                (xy[0], xy[1], size[0], size[1])
+            }
+            _ => return Err(anyhow!("region {} without bounds in the atlas", name)),
+        };
+
+        // the offsets go from the bottom left of the untrimmed image
+        let (offset_x, offset_y, source_width, source_height) =
+            match (numbers("offsets"), numbers("offset"), numbers("orig")) {
+                (Some(offsets), _, _) if offsets.len() == 4 => {
+                    (offsets[0], offsets[1], offsets[2], offsets[3])
+                }
+                (_, Some(offset), Some(orig)) if offset.len() == 2 && orig.len() == 2 => {
+                    (offset[0], offset[1], orig[0], orig[1])
+                }
+                _ => (0, 0, width, height),
+            };
+
+        let top = (source_height - height - offset_y).max(0);
+
+        Ok(AtlasRegion {
+            page,
+            rect: [x as u32, y as u32, width as u32, height as u32],
+            offset: [offset_x.max(0) as u32, top as u32],
+            source: [
+                source_width.max(width) as u32,
+                source_height.max(height) as u32,
+            ],
+        })
+    }
+
+    // the textures of the pages in their order, shared with the other models through the map
+    pub fn load_textures(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        textures_map: &DashMap<OsString, Arc<texture::Texture>>,
+    ) -> Result<Vec<Arc<texture::Texture>>> {
+        self.pages
+            .iter()
+            .map(|page| {
+                Model::cached_texture(device, queue, page.path.as_os_str(), false, textures_map)
+            })
+            .collect()
+    }
+
+    // a sheet of the single frame of a region, for Model::from_sheet
+    pub fn sheet(&self, region: &AtlasRegion, page_size: [u32; 2]) -> SpriteSheet {
+        let size = match self.pages[region.page].size {
+            [0, _] | [_, 0] => page_size,
+            size => size,
+        };
+
+        SpriteSheet {
+            size,
+            frames: vec![SpriteFrame {
+                rect: region.rect,
+                offset: region.offset,
+                source: region.source,
+                duration: None,
+            }],
+            clips: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "
+body.png
+size: 256,128
+format: RGBA8888
+filter: Linear,Linear
+repeat: none
+head
+  rotate: false
+  xy: 2, 4
+  size: 16, 24
+  orig: 20, 30
+  offset: 2, 3
+  index: -1
+arm
+  rotate: false
+  xy: 30, 4
+  size: 10, 10
+  orig: 10, 10
+  offset: 0, 0
+  index: -1
+
+face.png
+size: 64,64
+eye
+  rotate: false
+  xy: 0, 0
+  size: 8, 8
+";
+
+    const NEW: &str = "body.png
+size:256,128
+filter:Linear,Linear
+pma:true
+head
+bounds:2,4,16,24
+offsets:2,3,20,30
+arm
+bounds:30,4,10,10
+";
+
+    #[test]
+    fn from_text_reads_the_old_atlases() {
+        let atlas = SpineAtlas::from_text(OLD, Path::new("assets/spine")).unwrap();
+
+        assert_eq!(atlas.pages.len(), 2);
+        assert_eq!(atlas.pages[0].path, Path::new("assets/spine/body.png"));
+        assert_eq!(atlas.pages[0].size, [256, 128]);
+        assert_eq!(atlas.regions.len(), 3);
+
+        // the offset of the atlas goes from the bottom left, the one of the region from the top left
+        let head = &atlas.regions["head"];
+        assert_eq!(head.page, 0);
+        assert_eq!(head.rect, [2, 4, 16, 24]);
+        assert_eq!(head.offset, [2, 3]);
+        assert_eq!(head.source, [20, 30]);
+
+        let eye = &atlas.regions["eye"];
+        assert_eq!(eye.page, 1);
+        assert_eq!(eye.offset, [0, 0]);
+        assert_eq!(eye.source, [8, 8]);
+    }
+
+    #[test]
+    fn from_text_reads_the_new_atlases_the_same_way() {
+        let old = SpineAtlas::from_text(OLD, Path::new("")).unwrap();
+        let new = SpineAtlas::from_text(NEW, Path::new("")).unwrap();
+
+        for name in ["head", "arm"] {
+            assert_eq!(new.regions[name].rect, old.regions[name].rect);
+            assert_eq!(new.regions[name].offset, old.regions[name].offset);
+            assert_eq!(new.regions[name].source, old.regions[name].source);
+        }
+    }
+
+    #[test]
+    fn from_text_refuses_what_it_cant_place() {
+        let rotated = OLD.replacen("rotate: false", "rotate: true", 1);
+        assert!(SpineAtlas::from_text(&rotated, Path::new("")).is_err());
+
+        let without_bounds = NEW.replace("bounds:30,4,10,10\n", "");
+        assert!(SpineAtlas::from_text(&without_bounds, Path::new("")).is_err());
+    }
+
+    #[test]
+    fn sheet_is_the_size_of_the_page() {
+        let mut atlas = SpineAtlas::from_text(OLD, Path::new("")).unwrap();
+        let sheet = atlas.sheet(&atlas.regions["head"], [512, 512]);
+
+        assert_eq!(sheet.size, [256, 128]);
+        assert_eq!(sheet.frames[0].rect, [2, 4, 16, 24]);
+
+        // without a size in the atlas, the one of the texture
+        atlas.pages[0].size = [0, 0];
+        assert_eq!(
+            atlas.sheet(&atlas.regions["head"], [512, 512]).size,
+            [512, 512]
+        );
+    }
+}
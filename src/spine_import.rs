@@ -0,0 +1,958 @@
+use crate::{
+    animation::{AnimationClip, Easing, Keyframe, Track, TrackTarget},
+    bone::VertexWeights,
+    mesh::Mesh,
+    model::{Model, ModelData, PIXELS_PER_UNIT},
+    spine_atlas::SpineAtlas,
+    texture::{self},
+    transform::GlobalTransform,
+    type_def::*,
+};
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use image::GenericImageView;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, ffi::OsString, path::Path, sync::Arc};
+
+// the layout of a spine json export, the timelines are read by hand as they changed between versions
+#[derive(Deserialize)]
+struct SpineFile {
+    #[serde(default)]
+    skeleton: SpineFileSkeleton,
+    #[serde(default)]
+    bones: Vec<SpineFileBone>,
+    #[serde(default)]
+    slots: Vec<SpineFileSlot>,
+    #[serde(default)]
+    skins: SpineFileSkins,
+    #[serde(default)]
+    animations: HashMap<String, SpineFileAnimation>,
+}
+
+#[derive(Deserialize, Default)]
+struct SpineFileSkeleton {
+    spine: Option<String>, // the version of spine that exported the file, like 3.8.99
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SpineFileBone {
+    name: String,
+    parent: Option<String>,
+    #[serde(default)]
+    x: Real,
+    #[serde(default)]
+    y: Real,
+    #[serde(default)]
+    rotation: Real, // in degrees
+    #[serde(default = "one")]
+    scale_x: Real,
+    #[serde(default = "one")]
+    scale_y: Real,
+}
+
+#[derive(Deserialize)]
+struct SpineFileSlot {
+    name: String,
+    bone: String,
+    attachment: Option<String>,
+    color: Option<String>,
+}
+
+// spine 3.8 and later list the skins, the older versions map them by name
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpineFileSkins {
+    List(Vec<SpineFileSkin>),
+    Map(HashMap<String, HashMap<String, HashMap<String, Value>>>),
+}
+
+impl Default for SpineFileSkins {
+    fn default() -> Self {
+        Self::List(Vec::new())
+    }
+}
+
+#[derive(Deserialize)]
+struct SpineFileSkin {
+    name: String,
+    #[serde(default)]
+    attachments: HashMap<String, HashMap<String, Value>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SpineFileAttachment {
+    #[serde(rename = "type", default = "region")]
+    kind: String,
+    path: Option<String>,
+    #[serde(default)]
+    x: Real,
+    #[serde(default)]
+    y: Real,
+    #[serde(default)]
+    rotation: Real,
+    #[serde(default = "one")]
+    scale_x: Real,
+    #[serde(default = "one")]
+    scale_y: Real,
+    width: Option<Real>,
+    height: Option<Real>,
+    #[serde(default)]
+    uvs: Vec<Real>,
+    #[serde(default)]
+    triangles: Vec<Indices>,
+    #[serde(default)]
+    vertices: Vec<Real>,
+}
+
+#[derive(Deserialize, Default)]
+struct SpineFileAnimation {
+    #[serde(default)]
+    bones: HashMap<String, HashMap<String, Vec<Value>>>,
+    #[serde(default)]
+    slots: HashMap<String, HashMap<String, Vec<Value>>>,
+}
+
+fn one() -> Real {
+    1.0
+}
+
+fn region() -> String {
+    "region".to_string()
+}
+
+// a bone of the skeleton, ready to be turned into an entity with a Bone
+pub struct SpineBone {
+    pub name: String,
+    pub parent: Option<usize>, // always before its children, the root of the puppet when none
+    pub position: Position,
+    pub rotation: Rotation,
+    pub scale: Scale,
+}
+
+// what a skinned part needs, the indices are the ones of SpineImport::bones
+pub struct SpineSkin {
+    pub bones: Vec<usize>,
+    pub binds: Vec<Transform>, // from the root of the puppet to each bone in the setup pose
+    pub weights: Vec<VertexWeights>,
+    pub vertices: Vec<[f32; 2]>, // in the setup pose, relative to the root of the puppet
+}
+
+// an attachment of a slot, ready to be turned into an entity
+pub struct SpinePart {
+    pub name: String, // slot/attachment, what the clips move
+    pub bone: usize,  // the bone of its slot, which it hangs from unless it is skinned
+    pub data: ModelData,
+    pub position: Position, // relative to its bone, or to the root of the puppet when skinned
+    pub rotation: Rotation,
+    pub scale: Scale,
+    pub opacity: Real, // 0 for the attachments the slot doesn't show in the setup pose
+    pub color: Option<Color>,
+    pub draw_order: DrawOrder, // the index of its slot
+    pub skin: Option<SpineSkin>,
+}
+
+// a spine skeleton, its .atlas is the file next to it with the same name,
+// shearing, the transform modes of the bones and the constraints aren't imported
+pub struct SpineImport {
+    pub bones: Vec<SpineBone>,
+    pub parts: Vec<SpinePart>, // in the draw order of the slots
+    pub clips: Vec<AnimationClip>,
+    pub warnings: Vec<String>, // the attachments left out
+}
+
+// spine x goes to the right of the screen and world x goes toward the left, y goes up in both
+fn point(x: Real, y: Real) -> [f32; 2] {
+    [-x / PIXELS_PER_UNIT, y / PIXELS_PER_UNIT]
+}
+
+fn local(x: Real, y: Real, rotation: Real, scale_x: Real, scale_y: Real) -> Transform {
+    let [x, y] = point(x, y);
+
+    GlobalTransform::local(
+        &Point::new(x, y),
+        Some(&Rotator::new(-rotation.to_radians())),
+        Some(&Vector::new(scale_x, scale_y)),
+    )
+}
+
+fn transform(transform: &Transform, point: [f32; 2]) -> [f32; 2] {
+    let point = transform.transform_point(&Point::new(point[0], point[1]));
+    [point.x, point.y]
+}
+
+// rrggbbaa, the alpha can be left out
+fn color(hex: &str) -> Option<[Real; 4]> {
+    let channel = |at: usize| {
+        hex.get(at..at + 2).map(|channel| {
+            u8::from_str_radix(channel, 16)
+                .ok()
+                .map(|value| value as Real / 255.0)
+        })
+    };
+
+    Some([
+        channel(0)??,
+        channel(2)??,
+        channel(4)??,
+        channel(6).unwrap_or(Some(1.0))?,
+    ])
+}
+
+impl SpineImport {
+    // the attachments of the default skin, replaced by the ones of another skin when given
+    pub fn load<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        path: P,
+        skin: Option<&str>,
+        textures_map: &DashMap<OsString, Arc<texture::Texture>>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let file: SpineFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+
+        let atlas = SpineAtlas::load(path.with_extension("atlas"))?;
+        let pages = atlas.load_textures(device, queue, textures_map)?;
+
+        let indices: HashMap<&str, usize> = file
+            .bones
+            .iter()
+            .enumerate()
+            .map(|(i, bone)| (bone.name.as_str(), i))
+            .collect();
+
+        // the setup pose of every bone relative to the root of the puppet, the parents come first
+        let mut setup: Vec<Transform> = Vec::with_capacity(file.bones.len());
+        let mut bones = Vec::with_capacity(file.bones.len());
+
+        for bone in file.bones.iter() {
+            let parent = match &bone.parent {
+                Some(parent) => Some(
+                    *indices
+                        .get(parent.as_str())
+                        .filter(|parent| **parent < setup.len())
+                        .ok_or_else(|| anyhow!("bone {} comes before its parent", bone.name))?,
+                ),
+                None => None,
+            };
+
+            let local = local(bone.x, bone.y, bone.rotation, bone.scale_x, bone.scale_y);
+            setup.push(match parent {
+                Some(parent) => setup[parent] * local,
+                None => local,
+            });
+
+            let [x, y] = point(bone.x, bone.y);
+            bones.push(SpineBone {
+                name: bone.name.clone(),
+                parent,
+                position: Position::new(x, y),
+                rotation: Rotation::new(-bone.rotation.to_radians()),
+                scale: Scale::new(bone.scale_x, bone.scale_y),
+            });
+        }
+
+        let mut attachments: HashMap<String, HashMap<String, Value>> = HashMap::new();
+        let skins = match file.skins {
+            SpineFileSkins::List(skins) => skins,
+            SpineFileSkins::Map(skins) => skins
+                .into_iter()
+                .map(|(name, attachments)| SpineFileSkin { name, attachments })
+                .collect(),
+        };
+
+        for name in ["default"].into_iter().chain(skin) {
+            let skin = match skins.iter().find(|skin| skin.name == name) {
+                Some(skin) => skin,
+                None if name == "default" => continue,
+                None => return Err(anyhow!("no skin named {}", name)),
+            };
+
+            for (slot, slot_attachments) in skin.attachments.iter() {
+                attachments
+                    .entry(slot.clone())
+                    .or_default()
+                    .extend(slot_attachments.clone());
+            }
+        }
+
+        let mut parts = Vec::new();
+        let mut warnings = Vec::new();
+
+        for (slot, (bone, draw_order)) in file
+            .slots
+            .iter()
+            .zip(Self::slots(&file.slots, &file.bones)?)
+        {
+            let mut names = attachments
+                .get(&slot.name)
+                .map(|attachments| attachments.keys().cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            names.sort();
+
+            for name in names.into_iter() {
+                let attachment: SpineFileAttachment =
+                    serde_json::from_value(attachments[&slot.name][&name].clone())?;
+
+                let part = Self::load_attachment(
+                    device,
+                    queue,
+                    layout,
+                    &atlas,
+                    &pages,
+                    &setup,
+                    bone,
+                    &format!("{}/{}", slot.name, name),
+                    &name,
+                    attachment,
+                    textures_map,
+                );
+
+                match part {
+                    Ok(Some(mut part)) => {
+                        part.opacity = match slot.attachment.as_deref() == Some(name.as_str()) {
+                            true => 1.0,
+                            false => 0.0,
+                        };
+                        part.color = slot
+                            .color
+                            .as_deref()
+                            .and_then(color)
+                            .map(|[r, g, b, a]| Color::new_rgba(r, g, b, a));
+                        part.draw_order = DrawOrder(draw_order);
+
+                        parts.push(part);
+                    }
+                    Ok(None) => (),
+                    Err(e) => warnings.push(e.to_string()),
+                }
+            }
+        }
+
+        let slots: Vec<(&str, &str)> = file
+            .slots
+            .iter()
+            .flat_map(|slot| {
+                parts
+                    .iter()
+                    .filter(move |part| {
+                        part.name.split_once('/').map(|(name, _)| name) == Some(slot.name.as_str())
+                    })
+                    .map(move |part| (slot.name.as_str(), part.name.as_str()))
+            })
+            .collect();
+
+        // the curves of the keys changed with spine 4, the files without a version are taken as the latest
+        let absolute_curves = file
+            .skeleton
+            .spine
+            .as_deref()
+            .and_then(|version| version.split('.').next()?.parse::<u32>().ok())
+            .is_none_or(|major| major >= 4);
+
+        let mut clips = file
+            .animations
+            .iter()
+            .map(|(name, animation)| {
+                Self::clip(
+                    name,
+                    animation,
+                    &file.bones,
+                    &file.slots,
+                    &slots,
+                    absolute_curves,
+                )
+            })
+            .collect::<Vec<_>>();
+        clips.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Self {
+            bones,
+            parts,
+            clips,
+            warnings,
+        })
+    }
+
+    // the bone of every slot and the draw order of what it shows,
+    // spine draws the slots in the order they are listed whatever the depth of their bones
+    fn slots(slots: &[SpineFileSlot], bones: &[SpineFileBone]) -> Result<Vec<(usize, i32)>> {
+        slots
+            .iter()
+            .enumerate()
+            .map(|(i, slot)| {
+                let bone = bones
+                    .iter()
+                    .position(|bone| bone.name == slot.bone)
+                    .ok_or_else(|| {
+                        anyhow!("slot {} on the unknown bone {}", slot.name, slot.bone)
+                    })?;
+
+                Ok((bone, i as i32))
+            })
+            .collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn load_attachment(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        atlas: &SpineAtlas,
+        pages: &[Arc<texture::Texture>],
+        setup: &[Transform],
+        bone: usize,
+        name: &str,
+        attachment_name: &str,
+        attachment: SpineFileAttachment,
+        textures_map: &DashMap<OsString, Arc<texture::Texture>>,
+    ) -> Result<Option<SpinePart>> {
+        // the bounding boxes, paths, points and clipping attachments don't draw anything
+        if attachment.kind == "linkedmesh" {
+            return Err(anyhow!("linked mesh {} isn't imported", name));
+        }
+
+        if !matches!(attachment.kind.as_str(), "region" | "mesh") {
+            return Ok(None);
+        }
+
+        let image = attachment.path.as_deref().unwrap_or(attachment_name);
+        let region = atlas
+            .regions
+            .get(image)
+            .ok_or_else(|| anyhow!("no region {} in the atlas for {}", image, name))?;
+
+        let page = &pages[region.page];
+        let sheet = atlas.sheet(region, page.img.as_ref().unwrap().dimensions().into());
+        let [source_width, source_height] = region.source.map(|size| size.max(1) as Real);
+
+        if attachment.kind == "region" {
+            let data = Model::from_sheet(
+                device,
+                queue,
+                layout,
+                name,
+                Mesh::quad(),
+                page.clone(),
+                &sheet,
+                textures_map,
+            )?;
+
+            // the attachment can be drawn bigger or smaller than its image
+            let scale_x =
+                attachment.scale_x * attachment.width.unwrap_or(source_width) / source_width;
+            let scale_y =
+                attachment.scale_y * attachment.height.unwrap_or(source_height) / source_height;
+            let [x, y] = point(attachment.x, attachment.y);
+
+            return Ok(Some(SpinePart {
+                name: name.to_string(),
+                bone,
+                data,
+                position: Position::new(x, y),
+                rotation: Rotation::new(-attachment.rotation.to_radians()),
+                scale: Scale::new(scale_x, scale_y),
+                opacity: 1.0,
+                color: None,
+                draw_order: DrawOrder::default(),
+                skin: None,
+            }));
+        }
+
+        let count = attachment.uvs.len() / 2;
+
+        // the uvs of spine go over the untrimmed image, the ones of a mesh over the trimmed rect
+        let [_, _, width, height] = region.rect.map(|size| size.max(1) as Real);
+        let uvs = attachment
+            .uvs
+            .chunks_exact(2)
+            .map(|uv| {
+                [
+                    (uv[0] * source_width - region.offset[0] as Real) / width,
+                    (uv[1] * source_height - region.offset[1] as Real) / height,
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        // a mesh is skinned to the bone of its slot when its vertices aren't weighted
+        let (skin_bones, weights, vertices) = match attachment.vertices.len() == count * 2 {
+            true => (
+                vec![bone],
+                vec![vec![(0, 1.0)]; count],
+                attachment
+                    .vertices
+                    .chunks_exact(2)
+                    .map(|vertex| transform(&setup[bone], point(vertex[0], vertex[1])))
+                    .collect::<Vec<_>>(),
+            ),
+            false => Self::weighted(&attachment.vertices, count, setup)
+                .ok_or_else(|| anyhow!("broken weighted vertices in the mesh {}", name))?,
+        };
+
+        if skin_bones.iter().any(|bone| *bone >= setup.len()) {
+            return Err(anyhow!("mesh {} weighted to an unknown bone", name));
+        }
+
+        let mesh = Mesh::new(uvs, attachment.triangles);
+        let (model, mesh, model_vertices, sprite_selector) = Model::from_sheet(
+            device,
+            queue,
+            layout,
+            name,
+            mesh,
+            page.clone(),
+            &sheet,
+            textures_map,
+        )?;
+
+        let binds = skin_bones
+            .iter()
+            .map(|bone| {
+                setup[*bone]
+                    .try_inverse()
+                    .unwrap_or_else(Transform::identity)
+            })
+            .collect();
+
+        Ok(Some(SpinePart {
+            name: name.to_string(),
+            bone,
+            data: (model, mesh, model_vertices, sprite_selector),
+            position: Position::default(),
+            rotation: Rotation::default(),
+            scale: Scale::new(1.0, 1.0),
+            opacity: 1.0,
+            color: None,
+            draw_order: DrawOrder::default(),
+            skin: Some(SpineSkin {
+                bones: skin_bones,
+                binds,
+                weights: weights
+                    .iter()
+                    .map(|weights| VertexWeights::new(weights))
+                    .collect(),
+                vertices,
+            }),
+        }))
+    }
+
+    // each vertex gives its bone count, then the index, x, y and weight for every bone
+    #[allow(clippy::type_complexity)]
+    fn weighted(
+        flat: &[Real],
+        count: usize,
+        setup: &[Transform],
+    ) -> Option<(Vec<usize>, Vec<Vec<(u32, Real)>>, Vec<[f32; 2]>)> {
+        let mut bones: Vec<usize> = Vec::new();
+        let mut weights = Vec::with_capacity(count);
+        let mut vertices = Vec::with_capacity(count);
+        let mut at = 0;
+
+        for _ in 0..count {
+            let bone_count = *flat.get(at)? as usize;
+            at += 1;
+
+            let mut vertex_weights = Vec::with_capacity(bone_count);
+            let mut position = [0.0, 0.0];
+
+            for _ in 0..bone_count {
+                let values = flat.get(at..at + 4)?;
+                at += 4;
+
+                let bone = values[0] as usize;
+                let weight = values[3];
+                let moved = transform(setup.get(bone)?, point(values[1], values[2]));
+
+                position[0] += moved[0] * weight;
+                position[1] += moved[1] * weight;
+
+                let index = match bones.iter().position(|other| *other == bone) {
+                    Some(index) => index,
+                    None => {
+                        bones.push(bone);
+                        bones.len() - 1
+                    }
+                };
+                vertex_weights.push((index as u32, weight));
+            }
+
+            weights.push(vertex_weights);
+            vertices.push(position);
+        }
+
+        Some((bones, weights, vertices))
+    }
+
+    // the timelines of spine are relative to the setup pose, the tracks hold the values themselves
+    fn clip(
+        name: &str,
+        animation: &SpineFileAnimation,
+        bones: &[SpineFileBone],
+        slots: &[SpineFileSlot],
+        parts: &[(&str, &str)],
+        absolute_curves: bool,
+    ) -> AnimationClip {
+        let mut tracks = Vec::new();
+
+        for (bone_name, timelines) in animation.bones.iter() {
+            let bone = match bones.iter().find(|bone| &bone.name == bone_name) {
+                Some(bone) => bone,
+                None => continue,
+            };
+
+            for (kind, keys) in timelines.iter() {
+                // the keys hold the change from the setup pose, "angle" before spine 4
+                let (target, fields, default): (TrackTarget, &[&str], Real) = match kind.as_str() {
+                    "rotate" if keys.iter().any(|key| key.get("angle").is_some()) => {
+                        (TrackTarget::Rotation, &["angle"], 0.0)
+                    }
+                    "rotate" => (TrackTarget::Rotation, &["value"], 0.0),
+                    "translate" => (TrackTarget::Position, &["x", "y"], 0.0),
+                    "scale" => (TrackTarget::Scale, &["x", "y"], 1.0),
+                    _ => continue,
+                };
+
+                let changes = keys
+                    .iter()
+                    .map(|key| {
+                        fields
+                            .iter()
+                            .map(|field| number(key, field).unwrap_or(default))
+                            .collect()
+                    })
+                    .collect::<Vec<Vec<Real>>>();
+
+                let value = |change: &[Real]| match target {
+                    TrackTarget::Rotation => vec![-(bone.rotation + change[0]).to_radians()],
+                    TrackTarget::Position => point(bone.x + change[0], bone.y + change[1]).to_vec(),
+                    _ => vec![bone.scale_x * change[0], bone.scale_y * change[1]],
+                };
+
+                let keyframes = Self::keyframes(keys, &changes, value, absolute_curves);
+                tracks.push(Track {
+                    entity: Some(bone_name.clone()),
+                    target,
+                    keyframes,
+                });
+            }
+        }
+
+        for (slot_name, timelines) in animation.slots.iter() {
+            let slot_parts = parts
+                .iter()
+                .filter(|(slot, _)| slot == slot_name)
+                .map(|(_, part)| *part)
+                .collect::<Vec<_>>();
+
+            for (kind, keys) in timelines.iter() {
+                match kind.as_str() {
+                    // "color" before spine 4
+                    "rgba" | "color" => {
+                        let values = keys
+                            .iter()
+                            .map(|key| {
+                                key.get("color")
+                                    .and_then(Value::as_str)
+                                    .and_then(color)
+                                    .unwrap_or([1.0; 4])
+                                    .to_vec()
+                            })
+                            .collect::<Vec<_>>();
+
+                        for part in slot_parts.iter() {
+                            tracks.push(Track {
+                                entity: Some(part.to_string()),
+                                target: TrackTarget::Color,
+                                keyframes: Self::keyframes(
+                                    keys,
+                                    &values,
+                                    <[Real]>::to_vec,
+                                    absolute_curves,
+                                ),
+                            });
+                        }
+                    }
+                    // the attachment shown is the only part of the slot that isn't transparent
+                    "attachment" => {
+                        let setup = slots
+                            .iter()
+                            .find(|slot| &slot.name == slot_name)
+                            .and_then(|slot| slot.attachment.as_deref());
+
+                        for part in slot_parts.iter() {
+                            let shown = |attachment: Option<&str>| {
+                                let attachment =
+                                    attachment.map(|name| format!("{}/{}", slot_name, name));
+                                match attachment.as_deref() == Some(*part) {
+                                    true => vec![1.0],
+                                    false => vec![0.0],
+                                }
+                            };
+
+                            let mut keyframes = keys
+                                .iter()
+                                .map(|key| Keyframe {
+                                    time: number(key, "time").unwrap_or(0.0),
+                                    value: shown(key.get("name").and_then(Value::as_str)),
+                                    easing: Easing::Step,
+                                })
+                                .collect::<Vec<_>>();
+
+                            // before its first key the slot shows its setup attachment
+                            if keyframes.first().is_some_and(|key| key.time > 0.0) {
+                                keyframes.insert(
+                                    0,
+                                    Keyframe {
+                                        time: 0.0,
+                                        value: shown(setup),
+                                        easing: Easing::Step,
+                                    },
+                                );
+                            }
+
+                            tracks.push(Track {
+                                entity: Some(part.to_string()),
+                                target: TrackTarget::Opacity,
+                                keyframes,
+                            });
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let duration = tracks
+            .iter()
+            .filter_map(|track: &Track| track.keyframes.last())
+            .map(|key| key.time)
+            .fold(0.0, Real::max);
+
+        // spine leaves looping to the player, the clips loop like most of what a skeleton plays
+        AnimationClip {
+            name: name.to_string(),
+            duration,
+            looped: true,
+            tracks,
+        }
+    }
+
+    // the curves of spine 4 are in the units of the timeline, so the easing is found before
+    // the values are turned into the ones of the tracks
+    fn keyframes(
+        keys: &[Value],
+        changes: &[Vec<Real>],
+        value: impl Fn(&[Real]) -> Vec<Real>,
+        absolute_curves: bool,
+    ) -> Vec<Keyframe> {
+        let times = keys
+            .iter()
+            .map(|key| number(key, "time").unwrap_or(0.0))
+            .collect::<Vec<_>>();
+
+        (0..keys.len())
+            .map(|i| Keyframe {
+                time: times[i],
+                value: value(&changes[i]),
+                easing: match (times.get(i + 1), changes.get(i + 1)) {
+                    (Some(next_time), Some(next)) => easing(
+                        &keys[i],
+                        [times[i], *next_time],
+                        &changes[i],
+                        next,
+                        absolute_curves,
+                    ),
+                    _ => Easing::Linear,
+                },
+            })
+            .collect()
+    }
+}
+
+fn number(key: &Value, field: &str) -> Option<Real> {
+    key.get(field)
+        .and_then(Value::as_f64)
+        .map(|value| value as Real)
+}
+
+// "stepped", the control points from 0 to 1 as an array before spine 3.8, the first one with c2, c3
+// and c4 in spine 3.8, or since spine 4 the two control points of every value in the time and
+// values of the keys
+fn easing(
+    key: &Value,
+    times: [Real; 2],
+    from: &[Real],
+    to: &[Real],
+    absolute_curves: bool,
+) -> Easing {
+    match key.get("curve") {
+        Some(Value::String(curve)) if curve == "stepped" => Easing::Step,
+        Some(Value::Array(curve)) if !absolute_curves => match curve.get(0..4) {
+            Some(points) => {
+                Easing::CubicBezier([0, 1, 2, 3].map(|i| points[i].as_f64().unwrap_or(0.0) as Real))
+            }
+            None => Easing::Linear,
+        },
+        Some(Value::Number(c_1)) => Easing::CubicBezier([
+            c_1.as_f64().unwrap_or(0.0) as Real,
+            number(key, "c2").unwrap_or(0.0),
+            number(key, "c3").unwrap_or(1.0),
+            number(key, "c4").unwrap_or(1.0),
+        ]),
+        Some(Value::Array(curve)) => {
+            let curve = curve
+                .iter()
+                .map(|value| value.as_f64().unwrap_or(0.0) as Real)
+                .collect::<Vec<_>>();
+
+            // one curve is shared by every value, the one of the first value that changes
+            let channel = (0..from.len().min(to.len()))
+                .find(|&i| from[i] != to[i] && curve.len() >= i * 4 + 4);
+            let length = times[1] - times[0];
+
+            match (channel, length > 0.0) {
+                (Some(i), true) => {
+                    let change = to[i] - from[i];
+                    let value = |at: usize| (curve[at] - from[i]) / change;
+                    let time = |at: usize| ((curve[at] - times[0]) / length).clamp(0.0, 1.0);
+
+                    Easing::CubicBezier([
+                        time(i * 4),
+                        value(i * 4 + 1),
+                        time(i * 4 + 2),
+                        value(i * 4 + 3),
+                    ])
+                }
+                _ => Easing::Linear,
+            }
+        }
+        _ => Easing::Linear,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn close(a: [f32; 2], b: [f32; 2]) -> bool {
+        (a[0] - b[0]).abs() < 1e-5 && (a[1] - b[1]).abs() < 1e-5
+    }
+
+    #[test]
+    fn slots_are_drawn_in_their_order_whatever_the_depth_of_their_bones() {
+        // the arm is two bones down but listed before the body on the root
+        let file: SpineFile = serde_json::from_value(json!({
+            "bones": [
+                { "name": "root" },
+                { "name": "hip", "parent": "root" },
+                { "name": "arm", "parent": "hip" }
+            ],
+            "slots": [
+                { "name": "arm", "bone": "arm", "attachment": "arm" },
+                { "name": "body", "bone": "root", "attachment": "body" }
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            SpineImport::slots(&file.slots, &file.bones).unwrap(),
+            vec![(2, 0), (0, 1)]
+        );
+
+        let file: SpineFile = serde_json::from_value(json!({
+            "bones": [{ "name": "root" }],
+            "slots": [{ "name": "body", "bone": "hip" }]
+        }))
+        .unwrap();
+
+        assert!(SpineImport::slots(&file.slots, &file.bones).is_err());
+    }
+
+    #[test]
+    fn weighted_vertices_are_placed_by_their_bones() {
+        // the second bone is 100 pixels to the right of the first one
+        let setup = [Transform::identity(), local(100.0, 0.0, 0.0, 1.0, 1.0)];
+
+        // a vertex on the first bone, then one half on each bone
+        let flat = [
+            1.0, 0.0, 50.0, 0.0, 1.0, //
+            2.0, 1.0, 0.0, 50.0, 0.5, 0.0, 0.0, 50.0, 0.5,
+        ];
+
+        let (bones, weights, vertices) = SpineImport::weighted(&flat, 2, &setup).unwrap();
+
+        // the bones are numbered in the order the vertices use them
+        assert_eq!(bones, vec![0, 1]);
+        assert_eq!(weights, vec![vec![(0, 1.0)], vec![(1, 0.5), (0, 0.5)]]);
+        assert!(close(vertices[0], point(50.0, 0.0)));
+        assert!(close(vertices[1], point(50.0, 50.0)));
+    }
+
+    #[test]
+    fn weighted_refuses_broken_vertices() {
+        let setup = [Transform::identity()];
+
+        assert!(SpineImport::weighted(&[1.0, 0.0, 0.0, 0.0], 1, &setup).is_none());
+        assert!(SpineImport::weighted(&[1.0, 3.0, 0.0, 0.0, 1.0], 1, &setup).is_none());
+        assert!(SpineImport::weighted(&[1.0, 0.0, 0.0, 0.0, 1.0], 2, &setup).is_none());
+    }
+
+    #[test]
+    fn easing_reads_the_curves_of_every_version() {
+        let times = [1.0, 3.0];
+        let (from, to) = ([10.0], [30.0]);
+
+        let stepped = json!({ "time": 1, "curve": "stepped" });
+        assert_eq!(easing(&stepped, times, &from, &to, true), Easing::Step);
+        assert_eq!(
+            easing(&json!({ "time": 1 }), times, &from, &to, true),
+            Easing::Linear
+        );
+
+        // before spine 3.8, the control points already go from 0 to 1
+        let old = json!({ "time": 1, "curve": [0.25, 0, 0.75, 1] });
+        assert_eq!(
+            easing(&old, times, &from, &to, false),
+            Easing::CubicBezier([0.25, 0.0, 0.75, 1.0])
+        );
+
+        let spine_3_8 = json!({ "time": 1, "curve": 0.25, "c3": 0.75 });
+        assert_eq!(
+            easing(&spine_3_8, times, &from, &to, false),
+            Easing::CubicBezier([0.25, 0.0, 0.75, 1.0])
+        );
+
+        // since spine 4, in the time and the values of the keys
+        let spine_4 = json!({ "time": 1, "curve": [1.5, 10, 2.5, 30] });
+        assert_eq!(
+            easing(&spine_4, times, &from, &to, true),
+            Easing::CubicBezier([0.25, 0.0, 0.75, 1.0])
+        );
+    }
+
+    #[test]
+    fn easing_of_spine_4_follows_the_first_value_that_changes() {
+        let curve = json!({ "curve": [0, 0, 1, 0, 0.5, 5, 1.5, 15] });
+
+        assert_eq!(
+            easing(&curve, [0.0, 2.0], &[0.0, 0.0], &[0.0, 20.0], true),
+            Easing::CubicBezier([0.25, 0.25, 0.75, 0.75])
+        );
+        assert_eq!(
+            easing(&curve, [0.0, 2.0], &[0.0, 0.0], &[0.0, 0.0], true),
+            Easing::Linear
+        );
+    }
+
+    #[test]
+    fn color_reads_rgb_and_rgba() {
+        assert_eq!(color("ff000080").unwrap()[3], 128.0 / 255.0);
+        assert_eq!(color("00ff00"), Some([0.0, 1.0, 0.0, 1.0]));
+        assert_eq!(color("00ff0"), None);
+        assert_eq!(color("zz0000ff"), None);
+    }
+}
@@ -2,11 +2,112 @@ use std::time::Duration;
 
 use ahash::AHashMap;
 use specs::{
+    shred::DynamicSystemData,
+    shrev::EventChannel,
     storage::{PairedStorage, SequentialRestriction},
-    BitSet, Component, FlaggedStorage, Read, System, VecStorage, WriteStorage,
+    BitSet, Component, Entities, Entity, FlaggedStorage, Read, ReaderId, System, VecStorage, World,
+    Write, WriteStorage,
 };
 
-use crate::actor::Time;
+use crate::{actor::Time, sprite_sheet::SpriteSheet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpriteMode {
+    Loop,
+    PingPong, // goes back to the first frame the way it came
+    Once,     // stops on the last frame and sends a SpriteEvent::Finished
+    Reverse,  // loops from the last frame to the first one
+}
+
+// a named range of frames of the sheet, ex: idle from 0 to 7
+#[derive(Debug, Clone)]
+pub struct SpriteClip {
+    pub name: String,
+    pub first: u32,
+    pub last: u32, // included
+    pub wait: Duration,
+    pub waits: Vec<(u32, Duration)>, // frames shown longer or shorter than the others
+    pub mode: SpriteMode,
+    pub next: Option<String>, // played when a clip played once reaches its end
+}
+
+impl SpriteClip {
+    pub fn new(name: &str, first: u32, last: u32, wait: Duration, mode: SpriteMode) -> Self {
+        Self {
+            name: name.to_string(),
+            first: first.min(last),
+            last: last.max(first),
+            wait,
+            waits: Vec::new(),
+            mode,
+            next: None,
+        }
+    }
+
+    pub fn wait_at(&self, at: u32) -> Duration {
+        self.waits
+            .iter()
+            .find(|(other, _)| *other == at)
+            .map(|(_, wait)| *wait)
+            .unwrap_or(self.wait)
+    }
+
+    // from a line of the mtl like clip_talk 12..15 wait=80 mode=ping_pong frames=13:160,14:40,
+    // a clip played once can go on with another one, ex: clip_wave 8..11 mode=once next=idle
+    pub fn parse(name: &str, value: &str, wait: Option<Duration>) -> Option<Self> {
+        let mut words = value.split_whitespace();
+        let (first, last) = words.next()?.split_once("..")?;
+
+        let mut clip = Self::new(
+            name,
+            first.parse().ok()?,
+            last.parse().ok()?,
+            wait.unwrap_or(Duration::from_millis(100)),
+            SpriteMode::Loop,
+        );
+
+        for (key, value) in words.filter_map(|word| word.split_once('=')) {
+            match key {
+                "wait" => clip.wait = Duration::from_millis(value.parse().ok()?),
+                "mode" => {
+                    clip.mode = match value {
+                        "loop" => SpriteMode::Loop,
+                        "ping_pong" => SpriteMode::PingPong,
+                        "once" => SpriteMode::Once,
+                        "reverse" => SpriteMode::Reverse,
+                        _ => return None,
+                    }
+                }
+                "frames" => {
+                    for frame in value.split(',') {
+                        let (at, wait) = frame.split_once(':')?;
+                        clip.waits
+                            .push((at.parse().ok()?, Duration::from_millis(wait.parse().ok()?)));
+                    }
+                }
+                "next" => clip.next = Some(value.to_string()),
+                _ => (),
+            }
+        }
+
+        Some(clip)
+    }
+}
+
+// a frame of a sheet packed by aseprite or texturepacker, in pixels
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpriteFrame {
+    pub rect: [u32; 4],   // x, y, width and height in the sheet
+    pub offset: [u32; 2], // where the trimmed rect goes in the untrimmed frame
+    pub source: [u32; 2], // size of the frame before it was trimmed
+    pub duration: Option<Duration>,
+}
+
+// sent when a clip played once reaches its last frame
+#[derive(Debug, Clone)]
+pub enum SpriteEvent {
+    Finished { entity: Entity, clip: String },
+}
 
 #[derive(Debug, Clone)]
 pub struct SpriteSelector {
@@ -16,9 +117,13 @@ pub struct SpriteSelector {
     on: bool,
     pub width: f32,
     pub height: f32,
-    pub wait: Option<Duration>,
     pub time: Duration,
     pub at: u32,
+    pub clips: Vec<SpriteClip>,
+    clip: Option<usize>,
+    forward: bool,            // direction of a ping pong
+    frames: Vec<SpriteFrame>, // replaces the grid of width by height cells when not empty
+    sheet: [u32; 2],          // size of the texture the frames are in
 }
 
 impl Component for SpriteSelector {
@@ -26,6 +131,7 @@ impl Component for SpriteSelector {
 }
 
 impl SpriteSelector {
+    // with a wait, the cells from min to max make a looping clip named default
     pub fn new(
         start: u32,
         min: u32,
@@ -34,6 +140,17 @@ impl SpriteSelector {
         height: u32,
         wait: Option<Duration>,
     ) -> Self {
+        let clips = match wait {
+            Some(wait) if max > min + 1 => vec![SpriteClip::new(
+                "default",
+                min,
+                max - 1,
+                wait,
+                SpriteMode::Loop,
+            )],
+            _ => Vec::new(),
+        };
+
         Self {
             min,
             max,
@@ -41,9 +158,59 @@ impl SpriteSelector {
             on: false,
             width: 1.0 / width as f32,
             height: 1.0 / height as f32,
-            wait,
             time: Duration::from_millis(0),
             at: start.clamp(min, max - 1),
+            clips,
+            clip: None,
+            forward: true,
+            frames: Vec::new(),
+            sheet: [1, 1],
+        }
+    }
+
+    // the frames and the clips of a sheet, the clip_ of the mtl are kept over the ones of the sheet
+    pub fn use_sheet(&mut self, sheet: &SpriteSheet) {
+        if sheet.frames.is_empty() {
+            return;
+        }
+
+        let playing = self.clip().map(|clip| clip.name.clone());
+
+        self.frames = sheet.frames.clone();
+        self.sheet = sheet.size;
+        self.min = 0;
+        self.max = self.frames.len() as u32;
+        self.at = self.at.min(self.max - 1);
+
+        let clips = std::mem::take(&mut self.clips);
+        self.clips = sheet.clips.clone();
+        let max = self.max;
+        for clip in clips.into_iter().filter(|clip| clip.last < max) {
+            self.add_clip(clip);
+        }
+
+        self.clip = None;
+        self.on = false;
+        if !self.play_clip(&playing.unwrap_or_else(|| "default".to_string())) {
+            self.play_clip("default");
+        }
+    }
+
+    // offset and size of the trimmed frame inside of its source, and the size of the source, in pixels
+    pub fn frame_rect(&self, at: u32, dimensions: (u32, u32)) -> ([f32; 2], [f32; 2], [f32; 2]) {
+        match self.frames.get(at as usize) {
+            Some(frame) => (
+                [frame.offset[0] as f32, frame.offset[1] as f32],
+                [frame.rect[2] as f32, frame.rect[3] as f32],
+                [frame.source[0] as f32, frame.source[1] as f32],
+            ),
+            None => {
+                let size = [
+                    dimensions.0 as f32 * self.width,
+                    dimensions.1 as f32 * self.height,
+                ];
+                ([0.0, 0.0], size, size)
+            }
         }
     }
 
@@ -85,43 +252,124 @@ impl SpriteSelector {
             Err(_) => None,
         };
 
-        Self::new(start, min, max, width, height, wait)
+        let mut sprite_selector = Self::new(start, min, max, width, height, wait);
+
+        // every clip_<name> of the material, in the order of their frames
+        let mut clips = unknown_param
+            .iter()
+            .filter_map(|(key, value)| {
+                let name = key.strip_prefix("clip_")?;
+                SpriteClip::parse(name, value, wait)
+            })
+            .filter(|clip| clip.last < max)
+            .collect::<Vec<_>>();
+        clips.sort_by_key(|clip| clip.first);
+
+        for clip in clips.into_iter() {
+            sprite_selector.add_clip(clip);
+        }
+
+        // the clip played from the start, the default one when there is one
+        let playing = unknown_param
+            .get("clip")
+            .cloned()
+            .unwrap_or_else(|| "default".to_string());
+        sprite_selector.play_clip(&playing);
+
+        sprite_selector
     }
 
-    pub fn play(&mut self) {
-        if self.wait.is_some() {
-            self.on = true;
+    // replaces the clip with the same name if there is already one
+    pub fn add_clip(&mut self, clip: SpriteClip) {
+        match self.clips.iter_mut().find(|other| other.name == clip.name) {
+            Some(other) => *other = clip,
+            None => self.clips.push(clip),
         }
     }
 
-    pub fn stop(&mut self) {
-        self.on = false;
+    // starts a clip from its first frame, false if there is none with this name
+    pub fn play_clip(&mut self, name: &str) -> bool {
+        match self.clips.iter().position(|clip| clip.name == name) {
+            Some(index) => {
+                self.clip = Some(index);
+                self.at = match self.clips[index].mode {
+                    SpriteMode::Reverse => self.clips[index].last,
+                    _ => self.clips[index].first,
+                };
+                self.time = Duration::from_millis(0);
+                self.forward = true;
+                self.on = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clip(&self) -> Option<&SpriteClip> {
+        self.clips.get(self.clip?)
+    }
+
+    // the next frame of the clip and if it stops there
+    fn step(clip: &SpriteClip, at: u32, forward: bool) -> (u32, bool, bool) {
+        match clip.mode {
+            SpriteMode::Loop if at >= clip.last => (clip.first, forward, false),
+            SpriteMode::Loop => (at + 1, forward, false),
+            SpriteMode::Once if at >= clip.last => (clip.last, forward, true),
+            SpriteMode::Once => (at + 1, forward, false),
+            SpriteMode::Reverse if at <= clip.first => (clip.last, forward, false),
+            SpriteMode::Reverse => (at - 1, forward, false),
+            SpriteMode::PingPong if clip.first == clip.last => (clip.first, forward, false),
+            SpriteMode::PingPong if forward && at >= clip.last => (at - 1, false, false),
+            SpriteMode::PingPong if !forward && at <= clip.first => (at + 1, true, false),
+            SpriteMode::PingPong if forward => (at + 1, true, false),
+            SpriteMode::PingPong => (at - 1, false, false),
+        }
     }
 
-    pub fn update<'a>(
+    // only flags the sprite selector when its frame changes, returns the clip it finished
+    pub fn update(
         this: &mut PairedStorage<
             Self,
             &mut FlaggedStorage<Self, VecStorage<Self>>,
             &BitSet,
             SequentialRestriction,
         >,
-    ) {
-        if this.get_unchecked().on {
-            match this.get_unchecked().wait {
-                Some(wait) => {
-                    let elapsed = this.get_unchecked().time.as_nanos();
-                    let skip = (elapsed / wait.as_nanos()) as u32;
-
-                    if skip > 0 {
-                        let this = this.get_mut_unchecked();
-
-                        this.at = ((this.at + skip) % this.max).max(this.min);
-                        this.time -= wait * skip;
-                    }
-                }
-                None => {}
-            }
+    ) -> Option<String> {
+        let sprite_selector = this.get_unchecked();
+
+        let clip = match (sprite_selector.on, sprite_selector.clip()) {
+            (true, Some(clip)) => clip,
+            _ => return None,
+        };
+
+        let (mut at, mut time, mut forward) = (
+            sprite_selector.at,
+            sprite_selector.time,
+            sprite_selector.forward,
+        );
+        let mut finished = false;
+
+        // a frame without a wait would never let the next one be shown
+        while !finished && clip.wait_at(at) > Duration::ZERO && time >= clip.wait_at(at) {
+            time -= clip.wait_at(at);
+            (at, forward, finished) = Self::step(clip, at.clamp(clip.first, clip.last), forward);
+        }
+
+        let finished = finished.then(|| clip.name.clone());
+
+        if at != sprite_selector.at || finished.is_some() {
+            let this = this.get_mut_unchecked();
+
+            this.at = at;
+            this.forward = forward;
+            this.on = finished.is_none();
+            this.time = match finished {
+                Some(_) => Duration::from_millis(0),
+                None => time,
+            };
         }
+
+        finished
     }
 
     pub fn add_time(&mut self, delta: Duration) {
@@ -130,7 +378,7 @@ impl SpriteSelector {
         }
     }
 
-    pub fn _set(&mut self, at: u32) -> Result<(), ()> {
+    pub fn set(&mut self, at: u32) -> Result<(), ()> {
         let at = at + self.min;
 
         if at < self.max {
@@ -141,8 +389,28 @@ impl SpriteSelector {
         }
     }
 
-    pub fn get_current(&self) -> [[f32; 2]; 4] {
-        self.calculate(self.at)
+    // first cell of the animation, the ones before it are only shown when set
+    pub fn min(&self) -> u32 {
+        self.min
+    }
+
+    // moves a coordinate inside a single cell (0.0 to 1.0) onto the current cell of the texture
+    pub fn get_current_uv(&self, uv: [f32; 2]) -> [f32; 2] {
+        let [w_0, h_0, w_1, h_1] = self.get_cell(self.at);
+
+        [w_0 + uv[0] * (w_1 - w_0), h_0 + uv[1] * (h_1 - h_0)]
+    }
+
+    // amount of cells that can be shown, including the ones before min
+    pub fn cells(&self) -> u32 {
+        self.max
+    }
+
+    // left, top, right and bottom of a cell in texture coordinates
+    pub fn get_cell(&self, at: u32) -> [f32; 4] {
+        let cell = self.calculate(at);
+
+        [cell[2][0], cell[2][1], cell[0][0], cell[0][1]]
     }
 
     pub fn _get(&self, at: u32) -> Result<[[f32; 2]; 4], ()> {
@@ -156,6 +424,18 @@ impl SpriteSelector {
     }
 
     fn calculate(&self, at: u32) -> [[f32; 2]; 4] {
+        if let Some(frame) = self.frames.get(at as usize) {
+            let [x, y, width, height] = frame.rect;
+            let (w_0, h_0) = (
+                x as f32 / self.sheet[0] as f32,
+                y as f32 / self.sheet[1] as f32,
+            );
+            let w_1 = (x + width) as f32 / self.sheet[0] as f32;
+            let h_1 = (y + height) as f32 / self.sheet[1] as f32;
+
+            return [[w_1, h_1], [w_1, h_0], [w_0, h_0], [w_0, h_1]];
+        }
+
         let w: f32 = (at % self.max_width) as f32;
         let h: f32 = (at / self.max_width) as f32;
 
@@ -172,9 +452,14 @@ impl SpriteSelector {
 pub struct SpriteSelectorUpdate;
 
 impl<'a> System<'a> for SpriteSelectorUpdate {
-    type SystemData = (WriteStorage<'a, SpriteSelector>, Read<'a, Time>);
-
-    fn run(&mut self, (mut sprite_selectors, time): Self::SystemData) {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, SpriteSelector>,
+        Read<'a, Time>,
+        Write<'a, EventChannel<SpriteEvent>>,
+    );
+
+    fn run(&mut self, (entities, mut sprite_selectors, time, mut events): Self::SystemData) {
         use specs::Join;
 
         let time_delta = time.delta.as_nanos();
@@ -186,9 +471,250 @@ impl<'a> System<'a> for SpriteSelectorUpdate {
             }
 
             sprite_selectors.set_event_emission(true);
-            for mut sprite_selector in (&mut sprite_selectors.restrict_mut()).join() {
-                SpriteSelector::update(&mut sprite_selector);
+            for (entity, mut sprite_selector) in
+                (&entities, &mut sprite_selectors.restrict_mut()).join()
+            {
+                if let Some(clip) = SpriteSelector::update(&mut sprite_selector) {
+                    events.single_write(SpriteEvent::Finished { entity, clip });
+                }
             }
         }
     }
 }
+
+// starts the next clip of the clips played once that reached their end
+#[derive(Default)]
+pub struct SpriteClipChain {
+    pub reader_id: Option<ReaderId<SpriteEvent>>,
+}
+
+impl<'a> System<'a> for SpriteClipChain {
+    type SystemData = (
+        WriteStorage<'a, SpriteSelector>,
+        Read<'a, EventChannel<SpriteEvent>>,
+    );
+
+    fn run(&mut self, (mut sprite_selectors, events): Self::SystemData) {
+        for event in events.read(self.reader_id.as_mut().unwrap()) {
+            let SpriteEvent::Finished { entity, clip } = event;
+
+            let sprite_selector = match sprite_selectors.get_mut(*entity) {
+                Some(sprite_selector) => sprite_selector,
+                None => continue,
+            };
+
+            let next = sprite_selector
+                .clips
+                .iter()
+                .find(|other| other.name == *clip)
+                .and_then(|clip| clip.next.clone());
+
+            if let Some(next) = next {
+                sprite_selector.play_clip(&next);
+            }
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(&self.accessor(), world);
+        self.reader_id = Some(
+            world
+                .fetch_mut::<EventChannel<SpriteEvent>>()
+                .register_reader(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use specs::{Builder, RunNow, World, WorldExt};
+
+    // the frames a clip goes through from its first one
+    fn walk(clip: &SpriteClip, steps: usize) -> Vec<u32> {
+        let mut at = match clip.mode {
+            SpriteMode::Reverse => clip.last,
+            _ => clip.first,
+        };
+        let mut forward = true;
+        let mut frames = vec![at];
+
+        for _ in 0..steps {
+            (at, forward, _) = SpriteSelector::step(clip, at, forward);
+            frames.push(at);
+        }
+
+        frames
+    }
+
+    #[test]
+    fn parse_reads_the_whole_line() {
+        let clip = SpriteClip::parse(
+            "talk",
+            "15..12 wait=80 mode=ping_pong frames=13:160,14:40 unknown=1",
+            None,
+        )
+        .unwrap();
+
+        assert_eq!((clip.first, clip.last), (12, 15));
+        assert_eq!(clip.mode, SpriteMode::PingPong);
+        assert_eq!(clip.wait_at(12), Duration::from_millis(80));
+        assert_eq!(clip.wait_at(13), Duration::from_millis(160));
+        assert_eq!(clip.wait_at(14), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn parse_waits_like_the_material() {
+        let clip = SpriteClip::parse("idle", "0..7", Some(Duration::from_millis(120))).unwrap();
+        assert_eq!(clip.wait, Duration::from_millis(120));
+        assert_eq!(clip.mode, SpriteMode::Loop);
+
+        let clip = SpriteClip::parse("idle", "0..7", None).unwrap();
+        assert_eq!(clip.wait, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn parse_refuses_broken_lines() {
+        assert!(SpriteClip::parse("idle", "", None).is_none());
+        assert!(SpriteClip::parse("idle", "7", None).is_none());
+        assert!(SpriteClip::parse("idle", "a..7", None).is_none());
+        assert!(SpriteClip::parse("idle", "0..7 mode=bounce", None).is_none());
+        assert!(SpriteClip::parse("idle", "0..7 wait=fast", None).is_none());
+        assert!(SpriteClip::parse("idle", "0..7 frames=3", None).is_none());
+    }
+
+    #[test]
+    fn step_goes_through_the_frames_of_each_mode() {
+        let clip = |mode| SpriteClip::new("clip", 2, 4, Duration::from_millis(100), mode);
+
+        assert_eq!(walk(&clip(SpriteMode::Loop), 5), vec![2, 3, 4, 2, 3, 4]);
+        assert_eq!(walk(&clip(SpriteMode::Reverse), 5), vec![4, 3, 2, 4, 3, 2]);
+        assert_eq!(
+            walk(&clip(SpriteMode::PingPong), 6),
+            vec![2, 3, 4, 3, 2, 3, 4]
+        );
+        assert_eq!(walk(&clip(SpriteMode::Once), 4), vec![2, 3, 4, 4, 4]);
+
+        let single = SpriteClip::new("still", 3, 3, Duration::ZERO, SpriteMode::PingPong);
+        assert_eq!(walk(&single, 2), vec![3, 3, 3]);
+
+        assert_eq!(
+            SpriteSelector::step(&clip(SpriteMode::Once), 4, true),
+            (4, true, true)
+        );
+    }
+
+    #[test]
+    fn from_mat_keeps_the_clips_inside_the_cells() {
+        let unknown_param = [
+            ("max", "8"),
+            ("width", "4"),
+            ("height", "2"),
+            ("wait", "50"),
+            ("clip_blink", "4..7 mode=once"),
+            ("clip_outside", "6..9"),
+            ("clip", "blink"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<AHashMap<_, _>>();
+
+        let sprite_selector = SpriteSelector::from_mat(&unknown_param);
+        let names = sprite_selector
+            .clips
+            .iter()
+            .map(|clip| clip.name.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(names, vec!["default", "blink"]);
+        assert_eq!(sprite_selector.clip().unwrap().name, "blink");
+        assert_eq!(sprite_selector.at, 4);
+        assert!(sprite_selector.on);
+    }
+
+    #[test]
+    fn a_clip_played_once_sends_an_event_at_its_end() {
+        let mut world = World::new();
+        world.register::<SpriteSelector>();
+        world.insert(Time {
+            delta: Duration::from_millis(250),
+            speed: 1.0,
+            on: true,
+        });
+        world.insert(EventChannel::<SpriteEvent>::new());
+        let mut reader = world
+            .write_resource::<EventChannel<SpriteEvent>>()
+            .register_reader();
+
+        let mut sprite_selector = SpriteSelector::new(0, 0, 4, 4, 1, None);
+        sprite_selector.add_clip(SpriteClip::new(
+            "wave",
+            0,
+            3,
+            Duration::from_millis(100),
+            SpriteMode::Once,
+        ));
+        sprite_selector.play_clip("wave");
+        let entity = world.create_entity().with(sprite_selector).build();
+
+        SpriteSelectorUpdate.run_now(&world);
+        assert_eq!(
+            world
+                .read_storage::<SpriteSelector>()
+                .get(entity)
+                .unwrap()
+                .at,
+            2
+        );
+        assert_eq!(
+            world
+                .read_resource::<EventChannel<SpriteEvent>>()
+                .read(&mut reader)
+                .len(),
+            0
+        );
+
+        SpriteSelectorUpdate.run_now(&world);
+        let sprite_selectors = world.read_storage::<SpriteSelector>();
+        let sprite_selector = sprite_selectors.get(entity).unwrap();
+        assert_eq!(sprite_selector.at, 3);
+        assert!(!sprite_selector.on);
+
+        let events = world.read_resource::<EventChannel<SpriteEvent>>();
+        let finished = events
+            .read(&mut reader)
+            .map(|SpriteEvent::Finished { entity, clip }| (*entity, clip.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(finished, vec![(entity, "wave".to_string())]);
+    }
+
+    #[test]
+    fn a_clip_played_once_goes_on_with_its_next_clip() {
+        let mut world = World::new();
+        world.register::<SpriteSelector>();
+        world.insert(Time {
+            delta: Duration::from_millis(250),
+            speed: 1.0,
+            on: true,
+        });
+
+        let mut chain = SpriteClipChain::default();
+        System::setup(&mut chain, &mut world);
+
+        let mut sprite_selector = SpriteSelector::new(0, 0, 8, 8, 1, None);
+        sprite_selector.add_clip(SpriteClip::parse("idle", "0..3", None).unwrap());
+        sprite_selector
+            .add_clip(SpriteClip::parse("wave", "4..5 mode=once next=idle", None).unwrap());
+        sprite_selector.play_clip("wave");
+        let entity = world.create_entity().with(sprite_selector).build();
+
+        SpriteSelectorUpdate.run_now(&world);
+        chain.run_now(&world);
+
+        let sprite_selectors = world.read_storage::<SpriteSelector>();
+        let sprite_selector = sprite_selectors.get(entity).unwrap();
+        assert_eq!(sprite_selector.clip().unwrap().name, "idle");
+        assert_eq!(sprite_selector.at, 0);
+        assert!(sprite_selector.on);
+    }
+}
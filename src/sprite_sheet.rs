@@ -0,0 +1,387 @@
+use crate::sprite_selector::{SpriteClip, SpriteFrame, SpriteMode};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::{cmp::Ordering, collections::HashMap, path::Path, time::Duration};
+
+// the json exported by aseprite and texturepacker, with the frames as an array or as a hash
+#[derive(Deserialize)]
+struct SheetFile {
+    frames: SheetFrames,
+    #[serde(default)]
+    meta: SheetMeta,
+    #[serde(default)]
+    animations: HashMap<String, Vec<String>>, // texturepacker, frame names by animation
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SheetFrames {
+    Array(Vec<NamedFrame>),
+    Hash(HashMap<String, SheetFrame>),
+}
+
+#[derive(Deserialize)]
+struct NamedFrame {
+    #[serde(default)]
+    filename: String,
+    #[serde(flatten)]
+    frame: SheetFrame,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SheetFrame {
+    frame: SheetRect,
+    #[serde(default)]
+    rotated: bool,
+    sprite_source_size: Option<SheetRect>,
+    source_size: Option<SheetSize>,
+    duration: Option<u64>, // aseprite, in milliseconds
+}
+
+#[derive(Deserialize, Clone, Copy)]
+struct SheetRect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+struct SheetSize {
+    w: u32,
+    h: u32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct SheetMeta {
+    size: Option<SheetSize>,
+    #[serde(default)]
+    frame_tags: Vec<FrameTag>,
+}
+
+// an aseprite tag, from and to are included
+#[derive(Deserialize)]
+struct FrameTag {
+    name: String,
+    from: u32,
+    to: u32,
+    #[serde(default)]
+    direction: String,
+    repeat: Option<String>,
+}
+
+// the frames of a packed texture and the clips playing them
+#[derive(Debug, Clone, Default)]
+pub struct SpriteSheet {
+    pub size: [u32; 2],
+    pub frames: Vec<SpriteFrame>,
+    pub clips: Vec<SpriteClip>,
+    pub warnings: Vec<String>, // what was left out of the file
+}
+
+impl SpriteSheet {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let file: SheetFile = serde_json::from_str(json)?;
+
+        let frames = match file.frames {
+            SheetFrames::Array(frames) => frames
+                .into_iter()
+                .map(|frame| (frame.filename, frame.frame))
+                .collect::<Vec<_>>(),
+            // a hash has no order, the frames are sorted by name with run_2 before run_10
+            SheetFrames::Hash(frames) => {
+                let mut frames: Vec<_> = frames.into_iter().collect();
+                frames.sort_by(|(a, _), (b, _)| natural_order(a, b));
+                frames
+            }
+        };
+
+        if frames.is_empty() {
+            bail!("sprite sheet without frames");
+        }
+
+        if let Some((name, _)) = frames.iter().find(|(_, frame)| frame.rotated) {
+            bail!(
+                "frame {} is rotated in the sheet, export it without rotation",
+                name
+            );
+        }
+
+        let size = match file.meta.size {
+            Some(size) => [size.w, size.h],
+            // without a size the sheet is as big as its frames go
+            None => frames.iter().fold([1, 1], |[w, h], (_, frame)| {
+                [
+                    w.max(frame.frame.x + frame.frame.w),
+                    h.max(frame.frame.y + frame.frame.h),
+                ]
+            }),
+        };
+
+        let names: HashMap<&str, u32> = frames
+            .iter()
+            .enumerate()
+            .map(|(at, (name, _))| (name.as_str(), at as u32))
+            .collect();
+
+        let mut clips = Vec::new();
+        let mut warnings = Vec::new();
+
+        for (name, animation) in file.animations.iter() {
+            let indices: Option<Vec<u32>> = animation
+                .iter()
+                .map(|frame| names.get(frame.as_str()).copied())
+                .collect();
+
+            // a clip is a range of frames, the ones jumping around the sheet are left out
+            match indices {
+                Some(indices)
+                    if !indices.is_empty()
+                        && indices.windows(2).all(|pair| pair[1] == pair[0] + 1) =>
+                {
+                    clips.push(SpriteClip::new(
+                        name,
+                        indices[0],
+                        indices[indices.len() - 1],
+                        Duration::from_millis(100),
+                        SpriteMode::Loop,
+                    ));
+                }
+                _ => warnings.push(format!(
+                    "animation {} isn't a range of frames of the sheet",
+                    name
+                )),
+            }
+        }
+
+        clips.sort_by_key(|clip| clip.first);
+        warnings.sort();
+
+        for tag in file.meta.frame_tags.iter() {
+            let mode = match (tag.direction.as_str(), tag.repeat.as_deref()) {
+                (_, Some("1")) => SpriteMode::Once,
+                ("reverse", _) => SpriteMode::Reverse,
+                ("pingpong" | "pingpong_reverse", _) => SpriteMode::PingPong,
+                _ => SpriteMode::Loop,
+            };
+
+            clips.push(SpriteClip::new(
+                &tag.name,
+                tag.from,
+                tag.to,
+                Duration::from_millis(100),
+                mode,
+            ));
+        }
+
+        let frames: Vec<SpriteFrame> = frames
+            .into_iter()
+            .map(|(_, frame)| {
+                let rect = frame.frame;
+                let trimmed = frame.sprite_source_size.unwrap_or(SheetRect {
+                    x: 0,
+                    y: 0,
+                    w: rect.w,
+                    h: rect.h,
+                });
+                let source = frame.source_size.unwrap_or(SheetSize {
+                    w: trimmed.x + trimmed.w,
+                    h: trimmed.y + trimmed.h,
+                });
+
+                SpriteFrame {
+                    rect: [rect.x, rect.y, rect.w, rect.h],
+                    offset: [trimmed.x, trimmed.y],
+                    source: [source.w, source.h],
+                    duration: frame.duration.map(Duration::from_millis),
+                }
+            })
+            .collect();
+
+        // the durations of aseprite go to every clip showing the frame
+        if frames.iter().any(|frame| frame.duration.is_some()) {
+            clips.insert(
+                0,
+                SpriteClip::new(
+                    "default",
+                    0,
+                    frames.len() as u32 - 1,
+                    Duration::from_millis(100),
+                    SpriteMode::Loop,
+                ),
+            );
+        }
+
+        for clip in clips.iter_mut() {
+            clip.last = clip.last.min(frames.len() as u32 - 1);
+            clip.first = clip.first.min(clip.last);
+
+            for at in clip.first..=clip.last {
+                if let Some(duration) = frames[at as usize].duration {
+                    clip.waits.push((at, duration));
+                }
+            }
+        }
+
+        Ok(Self {
+            size,
+            frames,
+            clips,
+            warnings,
+        })
+    }
+}
+
+// compares the numbers in two names by their value
+fn natural_order(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                        digits.push(digit);
+                    }
+                    digits.trim_start_matches('0').to_string()
+                };
+
+                let (x, y) = (number(&mut a), number(&mut b));
+                let order = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+
+                if order != Ordering::Equal {
+                    return order;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn natural_order_compares_numbers_by_value() {
+        let mut names = vec!["run_10", "run_2", "idle", "run_02b", "run_1", "run_"];
+        names.sort_by(|a, b| natural_order(a, b));
+
+        assert_eq!(
+            names,
+            vec!["idle", "run_", "run_1", "run_2", "run_02b", "run_10"]
+        );
+        assert_eq!(natural_order("frame007", "frame7"), Ordering::Equal);
+        assert_eq!(natural_order("a9", "b1"), Ordering::Less);
+    }
+
+    #[test]
+    fn from_json_reads_an_aseprite_array() {
+        let sheet = SpriteSheet::from_json(
+            r#"{
+                "frames": [
+                    { "filename": "walk 0", "frame": { "x": 0, "y": 0, "w": 30, "h": 40 },
+                      "spriteSourceSize": { "x": 1, "y": 2, "w": 30, "h": 40 },
+                      "sourceSize": { "w": 32, "h": 48 }, "duration": 100 },
+                    { "filename": "walk 1", "frame": { "x": 30, "y": 0, "w": 32, "h": 48 }, "duration": 200 },
+                    { "filename": "walk 2", "frame": { "x": 62, "y": 0, "w": 32, "h": 48 }, "duration": 100 }
+                ],
+                "meta": {
+                    "size": { "w": 128, "h": 64 },
+                    "frameTags": [
+                        { "name": "step", "from": 1, "to": 2, "direction": "pingpong" },
+                        { "name": "wave", "from": 0, "to": 5, "direction": "forward", "repeat": "1" }
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(sheet.size, [128, 64]);
+        assert_eq!(sheet.frames[0].rect, [0, 0, 30, 40]);
+        assert_eq!(sheet.frames[0].offset, [1, 2]);
+        assert_eq!(sheet.frames[0].source, [32, 48]);
+        assert_eq!(sheet.frames[1].source, [32, 48]);
+
+        let clips = sheet
+            .clips
+            .iter()
+            .map(|clip| (clip.name.as_str(), clip.first, clip.last, clip.mode))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            clips,
+            vec![
+                ("default", 0, 2, SpriteMode::Loop),
+                ("step", 1, 2, SpriteMode::PingPong),
+                ("wave", 0, 2, SpriteMode::Once),
+            ]
+        );
+
+        // the durations of the frames go to the clips showing them
+        assert_eq!(sheet.clips[1].wait_at(1), Duration::from_millis(200));
+        assert_eq!(sheet.clips[1].wait_at(2), Duration::from_millis(100));
+        assert!(sheet.warnings.is_empty());
+    }
+
+    #[test]
+    fn from_json_reads_a_texturepacker_hash() {
+        let sheet = SpriteSheet::from_json(
+            r#"{
+                "frames": {
+                    "run_10.png": { "frame": { "x": 20, "y": 0, "w": 10, "h": 10 } },
+                    "run_2.png": { "frame": { "x": 10, "y": 0, "w": 10, "h": 10 } },
+                    "run_1.png": { "frame": { "x": 0, "y": 0, "w": 10, "h": 10 } },
+                    "jump.png": { "frame": { "x": 0, "y": 10, "w": 10, "h": 20 } }
+                },
+                "animations": {
+                    "run": ["run_1.png", "run_2.png", "run_10.png"],
+                    "hop": ["jump.png", "run_10.png"],
+                    "fly": ["wing.png"]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        // without a size the sheet goes to the end of its frames
+        assert_eq!(sheet.size, [30, 30]);
+        assert_eq!(sheet.frames[0].rect, [0, 10, 10, 20]);
+        assert_eq!(sheet.frames[3].rect, [20, 0, 10, 10]);
+
+        assert_eq!(sheet.clips.len(), 1);
+        assert_eq!((sheet.clips[0].first, sheet.clips[0].last), (1, 3));
+
+        assert_eq!(
+            sheet.warnings,
+            vec![
+                "animation fly isn't a range of frames of the sheet".to_string(),
+                "animation hop isn't a range of frames of the sheet".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn from_json_refuses_what_it_cant_show() {
+        assert!(SpriteSheet::from_json(r#"{ "frames": [] }"#).is_err());
+        assert!(SpriteSheet::from_json(
+            r#"{ "frames": [{ "frame": { "x": 0, "y": 0, "w": 1, "h": 1 }, "rotated": true }] }"#
+        )
+        .is_err());
+    }
+}
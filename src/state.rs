@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     ffi::OsString,
     path::PathBuf,
     sync::{Arc, Mutex, Weak},
@@ -7,28 +8,49 @@ use std::{
 
 use crate::{
     actor::*,
+    animation::{AnimationPlayer, AnimationUpdate},
+    auto_mesh::AutoMesh,
+    bone::{Bone, Skin, SkinUpdate},
     buffer_update::{ArcDataIndex, DataBuffer, DataBufferUpdater, DataIndex, DataManager},
     camera::*,
     camera_controller::*,
     camera_uniform::{CameraUniform, CameraUniformUpdate},
-    collider::ColliderHandle,
-    deg, fs,
+    clipping::{ClipPipelines, Clipping},
+    deformer::{DeformerUpdate, RotationDeformer, WarpDeformer},
+    deg,
+    expression::{Expression, ExpressionInput, ExpressionPlayer, ExpressionUpdate},
+    fs,
     instance_uniform::{InstanceUniform, InstanceUniformUpdate},
+    keyform::{KeyformUpdate, Keyforms, Pose},
+    lip_sync::{LipSync, LipSyncMode, LipSyncUpdate, Wav},
+    mesh::{Mesh, MeshIndicesUpdate},
     model::{self, *},
-    rigid_body::RigidBodyHandle,
+    model_settings::ModelSettings,
+    motion::{MotionGroups, MotionPlayer, MotionUpdate},
+    parameter::{ParameterRestore, ParameterSave, Parameters, SavedParameters},
+    part_pose::{PartPose, PartPoseUpdate},
+    phoneme::{MouthMapping, MouthSync, MouthSyncUpdate, MouthTrack},
+    physics::{Physics, PhysicsUpdate},
+    procedural::{AutoBlink, Breath, ProceduralSettings, ProceduralUpdate},
+    psd_import::PsdImport,
+    rig::Rig,
     sprite_selector::*,
+    spine_import::SpineImport,
+    state_machine::{StateMachine, StateMachineInput, StateMachinePlayer, StateMachineUpdate},
     texture::{self},
+    transform::{GlobalTransform, GlobalTransformUpdate},
 };
 use dashmap::DashMap;
-use rapier2d::prelude::{ColliderBuilder, ColliderSet, RigidBodyBuilder, RigidBodySet};
 use smaa::SmaaMode;
-use specs::{Builder, Dispatcher, DispatcherBuilder, World, WorldExt};
+use specs::{Builder, Dispatcher, DispatcherBuilder, Entity, World, WorldExt};
+use specs_hierarchy::HierarchySystem;
 use wgpu::util::DeviceExt;
 use winit::{
     event::{DeviceEvent, KeyboardInput},
     window::Window,
 };
 
+#[allow(dead_code)] // a resource no system reads yet
 pub struct AssetsDir(pub PathBuf);
 
 pub struct State {
@@ -43,6 +65,7 @@ impl State {
         world.register::<Model>();
         world.register::<Pipeline>();
         world.register::<SpriteSelector>();
+        world.register::<Mesh>();
         world.register::<ArcDataIndex<Indices>>();
         world.register::<ArcDataIndex<ModelVertex>>();
         world.register::<ArcDataIndex<InstanceUniform>>();
@@ -60,6 +83,36 @@ impl State {
         world.register::<Translation>();
         //world.register::<TextureView>();
         world.register::<Projection>();
+        world.register::<Name>();
+        world.register::<GlobalTransform>();
+        world.register::<Parameters>();
+        world.register::<Keyforms>();
+        world.register::<Opacity>();
+        world.register::<ScreenColor>();
+        world.register::<DrawOrder>();
+        world.register::<Pose>();
+        world.register::<WarpDeformer>();
+        world.register::<RotationDeformer>();
+        world.register::<Bone>();
+        world.register::<Skin>();
+        world.register::<Clipping>();
+        world.register::<MotionPlayer>();
+        world.register::<AnimationPlayer>();
+        world.register::<StateMachinePlayer>();
+        world.register::<StateMachineInput>();
+        world.register::<ExpressionPlayer>();
+        world.register::<ExpressionInput>();
+        world.register::<SavedParameters>();
+        world.register::<AutoBlink>();
+        world.register::<Breath>();
+        world.register::<LipSync>();
+        world.register::<MouthSync>();
+        world.register::<MotionGroups>();
+        world.register::<Physics>();
+        world.register::<PartPose>();
+
+        // has to exist before any Parent is inserted so the hierarchy sees them
+        let hierarchy_system = HierarchySystem::<Parent>::new(&mut world);
 
         let size = window.inner_size();
 
@@ -235,7 +288,7 @@ impl State {
             let camera_controller = CameraController::new(0.5, 1.0);
 
             cameras_appended += 1;
-            cameras_data.push(camera_uniform.clone());
+            cameras_data.push(camera_uniform);
             cameras_indices.push(Arc::downgrade(&camera_index.0));
 
             world
@@ -504,358 +557,568 @@ impl State {
             })
         };*/
 
-        let render_pipeline = Arc::new({
-            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[
-                    &texture_bind_group_layout,
-                    &camera_bind_group_layout,
-                    //&light_bind_group_layout,
-                    //&shadow_bind_group_layout,
-                ],
-                push_constant_ranges: &[],
-            });
-            let shader = {
-                let contents = fs::load_file(assets_dir.join("shaders/test.wgsl")).unwrap();
-                device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                    label: Some("View Shader"),
-                    source: wgpu::ShaderSource::Wgsl(contents.into()),
-                })
-            };
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Render Pipeline Layout"),
+            bind_group_layouts: &[
+                &texture_bind_group_layout,
+                &camera_bind_group_layout,
+                //&light_bind_group_layout,
+                //&shadow_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let shader = {
+            let contents = fs::load_file(assets_dir.join("shaders/test.wgsl")).unwrap();
+            device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                label: Some("View Shader"),
+                source: wgpu::ShaderSource::Wgsl(contents.into()),
+            })
+        };
 
-            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                label: Some("Render Pipeline"),
-                layout: Some(&pipeline_layout),
-                vertex: wgpu::VertexState {
-                    module: &shader,
-                    entry_point: "vs_main",
-                    buffers: &[model::ModelVertex::desc(), InstanceUniform::desc()],
-                },
-                fragment: Some(wgpu::FragmentState {
-                    module: &shader,
-                    entry_point: if supports_storage_resources {
-                        "fs_main"
-                    } else {
-                        "fs_main_without_storage"
-                    },
-                    targets: &[wgpu::ColorTargetState {
-                        format: config.format,
-                        blend: Some(wgpu::BlendState {
-                            color: wgpu::BlendComponent {
-                                src_factor: wgpu::BlendFactor::SrcAlpha,
-                                dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
-                                operation: wgpu::BlendOperation::Add,
-                            },
-                            alpha: wgpu::BlendComponent {
-                                src_factor: wgpu::BlendFactor::One,
-                                dst_factor: wgpu::BlendFactor::Zero, // OneMinusSrcAlpha
-                                operation: wgpu::BlendOperation::Add,
+        // every pipeline draws the parts the same way, they only differ in what they do with the stencil
+        let create_render_pipeline =
+            |label: &str,
+             fs_entry_point: &str,
+             write_mask: wgpu::ColorWrites,
+             depth_write_enabled: bool,
+             stencil: wgpu::StencilState| {
+                Arc::new(
+                    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                        label: Some(label),
+                        layout: Some(&pipeline_layout),
+                        vertex: wgpu::VertexState {
+                            module: &shader,
+                            entry_point: "vs_main",
+                            buffers: &[model::ModelVertex::desc(), InstanceUniform::desc()],
+                        },
+                        fragment: Some(wgpu::FragmentState {
+                            module: &shader,
+                            entry_point: fs_entry_point,
+                            targets: &[wgpu::ColorTargetState {
+                                format: config.format,
+                                blend: Some(wgpu::BlendState {
+                                    color: wgpu::BlendComponent {
+                                        src_factor: wgpu::BlendFactor::SrcAlpha,
+                                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                                        operation: wgpu::BlendOperation::Add,
+                                    },
+                                    alpha: wgpu::BlendComponent {
+                                        src_factor: wgpu::BlendFactor::One,
+                                        dst_factor: wgpu::BlendFactor::Zero, // OneMinusSrcAlpha
+                                        operation: wgpu::BlendOperation::Add,
+                                    },
+                                }),
+                                write_mask,
+                            }],
+                        }),
+                        primitive: wgpu::PrimitiveState {
+                            topology: wgpu::PrimitiveTopology::TriangleList,
+                            strip_index_format: None,
+                            front_face: wgpu::FrontFace::Ccw,
+                            cull_mode: Some(wgpu::Face::Back),
+                            // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
+                            polygon_mode: wgpu::PolygonMode::Fill,
+                            // Requires Features::DEPTH_CLIP_CONTROL
+                            unclipped_depth: device
+                                .features()
+                                .contains(wgpu::Features::DEPTH_CLIP_CONTROL),
+                            // Requires Features::CONSERVATIVE_RASTERIZATION
+                            conservative: false,
+                        },
+                        depth_stencil: Some(wgpu::DepthStencilState {
+                            format: texture::Texture::DEPTH_FORMAT,
+                            depth_write_enabled,
+                            // every part sits at the same depth, the last one drawn has to win
+                            depth_compare: if depth_write_enabled {
+                                wgpu::CompareFunction::LessEqual
+                            } else {
+                                wgpu::CompareFunction::Always
                             },
+                            stencil,
+                            bias: wgpu::DepthBiasState::default(),
                         }),
-                        write_mask: wgpu::ColorWrites::ALL,
-                    }],
-                }),
-                primitive: wgpu::PrimitiveState {
-                    topology: wgpu::PrimitiveTopology::TriangleList,
-                    strip_index_format: None,
-                    front_face: wgpu::FrontFace::Ccw,
-                    cull_mode: Some(wgpu::Face::Back),
-                    // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-                    polygon_mode: wgpu::PolygonMode::Fill,
-                    // Requires Features::DEPTH_CLIP_CONTROL
-                    unclipped_depth: device
-                        .features()
-                        .contains(wgpu::Features::DEPTH_CLIP_CONTROL),
-                    // Requires Features::CONSERVATIVE_RASTERIZATION
-                    conservative: false,
-                },
-                depth_stencil: Some(wgpu::DepthStencilState {
-                    format: texture::Texture::DEPTH_FORMAT,
-                    depth_write_enabled: true,
-                    depth_compare: wgpu::CompareFunction::Less,
-                    stencil: wgpu::StencilState::default(),
-                    bias: wgpu::DepthBiasState::default(),
-                }),
-                multisample: wgpu::MultisampleState {
-                    count: 1,
-                    mask: !0,
-                    alpha_to_coverage_enabled: true, // true
-                },
-                multiview: None,
-            })
-        });
+                        multisample: wgpu::MultisampleState {
+                            count: 1,
+                            mask: !0,
+                            // with a single sample the coverage is all or nothing, a fading part would pop at half
+                            alpha_to_coverage_enabled: false,
+                        },
+                        multiview: None,
+                    }),
+                )
+            };
 
-        let render_pipelines = vec![Pipeline(render_pipeline)];
+        let fs_entry_point = if supports_storage_resources {
+            "fs_main"
+        } else {
+            "fs_main_without_storage"
+        };
 
-        let mut textures_map: DashMap<OsString, Arc<texture::Texture>> = DashMap::new();
-        let mut models_map: DashMap<
-            OsString,
-            (Model, Vec<Indices>, Vec<ModelVertex>, SpriteSelector),
-        > = DashMap::new();
+        let render_pipeline = create_render_pipeline(
+            "Render Pipeline",
+            fs_entry_point,
+            wgpu::ColorWrites::ALL,
+            true,
+            wgpu::StencilState::default(),
+        );
 
-        let models: Vec<(Model, Vec<Indices>, Vec<ModelVertex>, SpriteSelector)> = vec![
-            {
-                let (material, indices, vertices, sprite_selector) = model::Model::load(
-                    &device,
-                    &queue,
-                    &texture_bind_group_layout,
-                    None,
-                    assets_dir.clone(),
-                    &mut textures_map,
-                    &mut models_map,
-                )
-                .unwrap();
+        // masks only mark the stencil, the parts clipped by them are then only drawn where it matches
+        world.insert(ClipPipelines {
+            mask: create_render_pipeline(
+                "Mask Pipeline",
+                "fs_mask",
+                wgpu::ColorWrites::empty(),
+                false,
+                ClipPipelines::stencil(
+                    wgpu::CompareFunction::Always,
+                    wgpu::StencilOperation::Replace,
+                ),
+            ),
+            clipped: create_render_pipeline(
+                "Clipped Pipeline",
+                fs_entry_point,
+                wgpu::ColorWrites::ALL,
+                true,
+                ClipPipelines::stencil(wgpu::CompareFunction::Equal, wgpu::StencilOperation::Keep),
+            ),
+            inverted: create_render_pipeline(
+                "Inverted Clipped Pipeline",
+                fs_entry_point,
+                wgpu::ColorWrites::ALL,
+                true,
+                ClipPipelines::stencil(
+                    wgpu::CompareFunction::NotEqual,
+                    wgpu::StencilOperation::Keep,
+                ),
+            ),
+        });
 
-                (material, indices, vertices, sprite_selector)
-            },
-            {
-                let (material, indices, vertices, sprite_selector) = model::Model::load(
-                    &device,
-                    &queue,
-                    &texture_bind_group_layout,
-                    None,
-                    assets_dir.clone(),
-                    &mut textures_map,
-                    &mut models_map,
-                )
-                .unwrap();
+        let render_pipelines = [Pipeline(render_pipeline)];
 
-                (material, indices, vertices, sprite_selector)
-            },
-            /*{
-                let (material, indices, vertices, sprite_selector) = model::Model::load(
-                    &device,
-                    &queue,
-                    &texture_bind_group_layout,
-                    Some(assets_dir.join("default/materials/default.mtl")),
-                    assets_dir.clone(),
-                    &mut textures_map,
-                    &mut models_map,
-                )
-                .unwrap();
+        let textures_map: DashMap<OsString, Arc<texture::Texture>> = DashMap::new();
 
-                (material, indices, vertices, sprite_selector)
-            },*/
-        ];
+        let puppet_path = assets_dir.join("psd/mark_free_Import.psd");
 
-        {
-            let mut indices_appended = 0;
-            let mut indices_data: Vec<Indices> = Vec::new();
-            let mut indices_indices: Vec<Weak<Mutex<DataIndex<Indices>>>> = Vec::new();
-
-            let mut vertices_appended = 0;
-            let mut vertices_data: Vec<ModelVertex> = Vec::new();
-            let mut vertices_indices: Vec<Weak<Mutex<DataIndex<ModelVertex>>>> = Vec::new();
-
-            let mut instances_appended = 0;
-            let mut instances_data: Vec<InstanceUniform> = Vec::new();
-            let mut instances_indices: Vec<Weak<Mutex<DataIndex<InstanceUniform>>>> = Vec::new();
-
-            let mut rb_set = RigidBodySet::new();
-            let mut coll_set = ColliderSet::new();
-
-            for x in -2..=2 as i32 {
-                for y in -2..=2 as i32 {
-                    let position = Position::new(x as f32 * 0.25, y as f32 * 0.25);
-                    let rotation = Rotation::new(deg(0.0));
-                    let scale = Scale::new(1.0, 1.0);
-
-                    let (model, mut indices, mut vertices, sprite_selector) =
-                        models[(y + x + 4) as usize % 2].clone();
-
-                    let indices_index: ArcDataIndex<Indices> = ArcDataIndex::new(
-                        indices_appended,
-                        indices_data.len(),
-                        indices.len(),
-                        Duration::from_millis(1000),
-                    );
+        let puppet = PsdImport::load(
+            &device,
+            &queue,
+            &texture_bind_group_layout,
+            &puppet_path,
+            &AutoMesh::default(),
+            &textures_map,
+        )
+        .unwrap();
+
+        // everything in the psd hangs from this entity, moving or scaling it moves the whole puppet
+        let root = world
+            .create_entity()
+            .with(Name::new(
+                &puppet_path
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+            ))
+            .with(Position::new(0.0, 0.0))
+            .with(Rotation::new(deg(0.0)))
+            .with(Scale::new(0.06, 0.06))
+            .with(Parameters::standard())
+            .with(MotionPlayer::default())
+            .with(AnimationPlayer::default())
+            .build();
 
-                    indices_appended += 1;
-                    indices_data.append(&mut indices);
-                    indices_indices.push(Arc::downgrade(&indices_index.0));
+        // the behaviors of the puppet, it stays still without them, their triggers are on the function keys
+        match StateMachine::load(assets_dir.join("default/animations/states.json")) {
+            Ok(machine) => {
+                world
+                    .write_storage::<StateMachineInput>()
+                    .insert(root, StateMachineInput::function_keys(&machine))
+                    .unwrap();
+                world
+                    .write_storage::<StateMachinePlayer>()
+                    .insert(root, StateMachinePlayer::new(Arc::new(machine)))
+                    .unwrap();
+            }
+            Err(e) => eprintln!("{:?}", e),
+        }
 
-                    let vertices_index: ArcDataIndex<ModelVertex> = ArcDataIndex::new(
-                        vertices_appended,
-                        vertices_data.len(),
-                        vertices.len(),
-                        Duration::from_millis(1000),
-                    );
+        // blinking and breathing of the puppet, the defaults of cubism without a settings file
+        let procedural = ProceduralSettings::load(assets_dir.join("default/settings.json"))
+            .unwrap_or_else(|e| {
+                eprintln!("{:?}", e);
+                ProceduralSettings {
+                    auto_blink: Some(AutoBlink::default()),
+                    breath: Some(Breath::default()),
+                    lip_sync: LipSyncMode::default(),
+                }
+            });
 
-                    vertices_appended += 1;
-                    vertices_data.append(&mut vertices);
-                    vertices_indices.push(Arc::downgrade(&vertices_index.0));
+        if let Some(auto_blink) = procedural.auto_blink {
+            world
+                .write_storage::<AutoBlink>()
+                .insert(root, auto_blink)
+                .unwrap();
+        }
 
-                    let instance_uniform = InstanceUniform::new(&rotation.0, &position.0);
-                    let instance_index: ArcDataIndex<InstanceUniform> = ArcDataIndex::new(
-                        instances_appended,
-                        instances_data.len(),
-                        1,
-                        Duration::from_millis(1000),
-                    );
+        if let Some(breath) = procedural.breath {
+            world
+                .write_storage::<Breath>()
+                .insert(root, breath)
+                .unwrap();
+        }
 
-                    instances_appended += 1;
-                    instances_data.push(instance_uniform);
-                    instances_indices.push(Arc::downgrade(&instance_index.0));
+        // switched with the number keys in the order of their names
+        let mut expression_paths = std::fs::read_dir(assets_dir.join("default/expressions"))
+            .map(|dir| {
+                dir.filter_map(|entry| Some(entry.ok()?.path()))
+                    .filter(|path| path.to_string_lossy().ends_with(".exp3.json"))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        expression_paths.sort();
+
+        let expressions = expression_paths
+            .iter()
+            .filter_map(|path| match Expression::load(path) {
+                Ok(expression) => Some(expression),
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    None
+                }
+            })
+            .collect();
 
+        let expression_player = ExpressionPlayer::new(expressions);
+        world
+            .write_storage::<ExpressionInput>()
+            .insert(root, ExpressionInput::numbered(&expression_player))
+            .unwrap();
+        world
+            .write_storage::<ExpressionPlayer>()
+            .insert(root, expression_player)
+            .unwrap();
+
+        // mouth cues next to the psd, rhubarb or praat, shaped by the mouth mapping of the puppet
+        let track_path = ["tsv", "txt", "cues.json", "TextGrid"]
+            .iter()
+            .map(|extension| puppet_path.with_extension(extension))
+            .find(|path| path.exists());
+
+        if let Some(track_path) = &track_path {
+            let mapping_path = puppet_path.with_extension("mouth.json");
+            let mapping = match mapping_path.exists() {
+                true => MouthMapping::load(&mapping_path).unwrap_or_else(|e| {
+                    eprintln!("{:?}", e);
+                    MouthMapping::default()
+                }),
+                false => MouthMapping::default(),
+            };
+
+            match MouthTrack::load(track_path) {
+                Ok(track) => {
                     world
-                        .create_entity()
-                        .with(model)
-                        .with(render_pipelines[0].clone())
-                        .with(position)
-                        .with(rotation)
-                        .with(scale)
-                        .with(sprite_selector)
-                        .with(indices_index)
-                        .with(vertices_index)
-                        .with(instance_index)
-                        .build();
+                        .write_storage::<MouthSync>()
+                        .insert(root, MouthSync::new(Arc::new(track), Arc::new(mapping)))
+                        .unwrap();
                 }
-                for y in -2..=2 as i32 {
-                    let position = Position::new(x as f32 * 0.25, y as f32 * 0.25);
-                    let rotation = Rotation::new(deg(0.0));
-                    let scale = Scale::new(1.0, 1.0);
-
-                    let (model, mut indices, mut vertices, sprite_selector) =
-                        models[(y + x + 4) as usize % 2].clone();
-
-                    let indices_index: ArcDataIndex<Indices> = ArcDataIndex::new(
-                        indices_appended,
-                        indices_data.len(),
-                        indices.len(),
-                        Duration::from_millis(1000),
-                    );
+                Err(e) => eprintln!("{:?}", e),
+            }
+        }
 
-                    indices_appended += 1;
-                    indices_data.append(&mut indices);
-                    indices_indices.push(Arc::downgrade(&indices_index.0));
+        // a voice next to the psd moves the mouth while it plays, the cues are followed instead when there are some
+        let voice_path = puppet_path.with_extension("wav");
+        if track_path.is_none() && voice_path.exists() {
+            match Wav::load(&voice_path) {
+                Ok(voice) => {
+                    world
+                        .write_storage::<LipSync>()
+                        .insert(root, LipSync::new(Arc::new(voice), procedural.lip_sync))
+                        .unwrap();
+                }
+                Err(e) => eprintln!("{:?}", e),
+            }
+        }
 
-                    let vertices_index: ArcDataIndex<ModelVertex> = ArcDataIndex::new(
-                        vertices_appended,
-                        vertices_data.len(),
-                        vertices.len(),
-                        Duration::from_millis(1000),
-                    );
+        // a cubism package next to the psd brings its motions, expressions, physics and pose
+        let settings_path = puppet_path.with_extension("model3.json");
+        if settings_path.exists() {
+            match ModelSettings::load(&settings_path) {
+                Ok(settings) => {
+                    // nothing draws the moc3, its textures only go into the cache shared with the other models
+                    if let Err(e) = settings.load_textures(&device, &queue, &textures_map) {
+                        eprintln!("{:?}", e);
+                    }
+
+                    settings.insert(&world, root)
+                }
+                Err(e) => eprintln!("{:?}", e),
+            }
+        }
 
-                    vertices_appended += 1;
-                    vertices_data.append(&mut vertices);
-                    vertices_indices.push(Arc::downgrade(&vertices_index.0));
+        // a spine export in the assets stands next to the psd puppet, its .atlas beside it
+        let spine_path = assets_dir.join("spine/skeleton.json");
+        let spine = match spine_path.exists() {
+            true => match SpineImport::load(
+                &device,
+                &queue,
+                &texture_bind_group_layout,
+                &spine_path,
+                None,
+                &textures_map,
+            ) {
+                Ok(spine) => {
+                    for warning in spine.warnings.iter() {
+                        eprintln!("{}", warning);
+                    }
+
+                    Some(spine)
+                }
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    None
+                }
+            },
+            false => None,
+        };
 
-                    let instance_uniform = InstanceUniform::new(&rotation.0, &position.0);
-                    let instance_index: ArcDataIndex<InstanceUniform> = ArcDataIndex::new(
-                        instances_appended,
-                        instances_data.len(),
-                        1,
-                        Duration::from_millis(1000),
-                    );
+        // sprites described by a mtl in the assets stand in a row on the other side of the psd puppet
+        let mut sprite_paths = std::fs::read_dir(assets_dir.join("sprites"))
+            .map(|dir| {
+                dir.filter_map(|entry| Some(entry.ok()?.path()))
+                    .filter(|path| path.extension().is_some_and(|extension| extension == "mtl"))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        sprite_paths.sort();
+
+        let models_map: DashMap<OsString, ModelData> = DashMap::new();
+        let sprites = sprite_paths
+            .iter()
+            .filter_map(|path| {
+                match Model::load(
+                    &device,
+                    &queue,
+                    &texture_bind_group_layout,
+                    path,
+                    &assets_dir,
+                    &textures_map,
+                    &models_map,
+                ) {
+                    Ok(data) => Some((
+                        path.file_stem().unwrap_or_default().to_string_lossy(),
+                        data,
+                    )),
+                    Err(e) => {
+                        eprintln!("{:?}", e);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
 
-                    instances_appended += 1;
-                    instances_data.push(instance_uniform);
-                    instances_indices.push(Arc::downgrade(&instance_index.0));
+        let mut groups: HashMap<u32, Entity> = HashMap::new();
+        let mut group_parents: Vec<(u32, Option<u32>)> = Vec::new();
 
-                    /*let rb = RigidBodyBuilder::new_dynamic()
-                        .translation(position.0.coords)
-                        .rotation(rotation.0.angle())
-                        .build();
+        for group in puppet.groups.into_iter() {
+            let entity = world
+                .create_entity()
+                .with(Name::new(&group.name))
+                .with(group.position)
+                .with(Rotation::new(deg(0.0)))
+                .with(Scale::new(1.0, 1.0))
+                .build();
 
-                    let coll = ColliderBuilder::cuboid(
-                        (vertices[2].position[0] - vertices[0].position[0]).abs() * scale.0.x,
-                        (vertices[1].position[1] - vertices[0].position[1]).abs() * scale.0.y,
-                    )
-                    .build();
+            groups.insert(group.id, entity);
+            group_parents.push((group.id, group.parent));
+        }
 
-                    let rb_handle = rb_set.insert(rb);
-                    let coll_handle = coll_set.insert_with_parent(coll, rb_handle, &mut rb_set);*/
+        {
+            // groups are listed before their own parent, so they can only be linked once all exist
+            let mut parents = world.write_storage::<Parent>();
 
-                    world
-                        .create_entity()
-                        .with(model)
-                        .with(render_pipelines[0].clone())
-                        .with(position)
-                        .with(rotation)
-                        .with(scale)
-                        //.with(RigidBodyHandle(rb_handle))
-                        //.with(ColliderHandle(coll_handle))
-                        .with(sprite_selector)
-                        .with(indices_index)
-                        .with(vertices_index)
-                        .with(instance_index)
-                        .with(Translation::new(0.0, 0.0))
-                        .build();
-                }
+            for (id, parent) in group_parents.into_iter() {
+                let parent = parent.and_then(|id| groups.get(&id)).unwrap_or(&root);
+                parents.insert(groups[&id], Parent::new(*parent)).unwrap();
             }
+        }
 
-            /*{
-                let position = Position::new(0.0, 5.0);
-                let rotation = Rotation::new(deg(0.0));
-                let scale = Scale::new(100.0, 1.0);
-                let collider = Collider::new(&ColliderBuilder::cuboid(0.5, 0.5));
-
-                let (model, mut indices, mut vertices, sprite_selector) = models[2].clone();
-
-                let indices_index: ArcDataIndex<Indices> = ArcDataIndex::new(
-                    indices_appended,
-                    indices_data.len(),
-                    indices.len(),
+        {
+            let mut indices_data: DataManager<Indices> = DataManager::default();
+            let mut vertices_data: DataManager<ModelVertex> = DataManager::default();
+            let mut instances_data: DataManager<InstanceUniform> = DataManager::default();
+
+            // Rendering draws the parts with the same draw order in the order they are created,
+            // spawning from the bottom layer up keeps the psd stacking
+            for part in puppet.parts.into_iter() {
+                let (model, mesh, vertices, sprite_selector) = part.data;
+                let parent = part.parent.and_then(|id| groups.get(&id)).unwrap_or(&root);
+
+                let indices_index = indices_data.push(
+                    mesh.frame(sprite_selector.at).indices.clone(),
                     Duration::from_millis(1000),
                 );
-
-                indices_appended += 1;
-                indices_data.append(&mut indices);
-                indices_indices.push(Arc::downgrade(&indices_index.0));
-
-                let vertices_index: ArcDataIndex<ModelVertex> = ArcDataIndex::new(
-                    vertices_appended,
-                    vertices_data.len(),
-                    vertices.len(),
+                let vertices_index = vertices_data.push(vertices, Duration::from_millis(1000));
+                let instance_index = instances_data.push(
+                    vec![InstanceUniform::new(&Transform::identity())],
                     Duration::from_millis(1000),
                 );
 
-                vertices_appended += 1;
-                vertices_data.append(&mut vertices);
-                vertices_indices.push(Arc::downgrade(&vertices_index.0));
+                world
+                    .create_entity()
+                    .with(Name::new(&part.name))
+                    .with(model)
+                    .with(render_pipelines[0].clone())
+                    .with(part.position)
+                    .with(Rotation::new(deg(0.0)))
+                    .with(Scale::new(1.0, 1.0))
+                    .with(Parent::new(*parent))
+                    .with(Opacity::default())
+                    .with(DrawOrder::default())
+                    .with(sprite_selector)
+                    .with(mesh)
+                    .with(indices_index)
+                    .with(vertices_index)
+                    .with(instance_index)
+                    .build();
+            }
 
-                let instance_uniform = InstanceUniform::new(&rotation.0, &position.0);
-                let instance_index: ArcDataIndex<InstanceUniform> = ArcDataIndex::new(
-                    instances_appended,
-                    instances_data.len(),
-                    1,
+            for (i, (name, (model, mesh, vertices, sprite_selector))) in
+                sprites.into_iter().enumerate()
+            {
+                let indices_index = indices_data.push(
+                    mesh.frame(sprite_selector.at).indices.clone(),
+                    Duration::from_millis(1000),
+                );
+                let vertices_index = vertices_data.push(vertices, Duration::from_millis(1000));
+                let instance_index = instances_data.push(
+                    vec![InstanceUniform::new(&Transform::identity())],
                     Duration::from_millis(1000),
                 );
-
-                instances_appended += 1;
-                instances_data.push(instance_uniform);
-                instances_indices.push(Arc::downgrade(&instance_index.0));
 
                 world
                     .create_entity()
+                    .with(Name::new(&name))
                     .with(model)
                     .with(render_pipelines[0].clone())
-                    .with(position)
-                    .with(rotation)
-                    .with(scale)
-                    .with(collider)
+                    .with(Position::new(2.0 + i as Real, 0.0))
+                    .with(Rotation::new(deg(0.0)))
+                    .with(Scale::new(1.0, 1.0))
+                    .with(Opacity::default())
+                    .with(DrawOrder::default())
                     .with(sprite_selector)
+                    .with(mesh)
                     .with(indices_index)
                     .with(vertices_index)
                     .with(instance_index)
                     .build();
-            }*/
+            }
+
+            if let Some(spine) = spine {
+                let spine_root = world
+                    .create_entity()
+                    .with(Name::new(
+                        &spine_path.file_stem().unwrap_or_default().to_string_lossy(),
+                    ))
+                    .with(Position::new(-2.0, 0.0))
+                    .with(Rotation::new(deg(0.0)))
+                    .with(Scale::new(1.0, 1.0))
+                    .with(AnimationPlayer::default())
+                    .build();
+
+                // the parents of the bones come before them
+                let mut bones: Vec<Entity> = Vec::with_capacity(spine.bones.len());
+                for bone in spine.bones.into_iter() {
+                    let parent = bone.parent.map_or(spine_root, |i| bones[i]);
+
+                    bones.push(
+                        world
+                            .create_entity()
+                            .with(Name::new(&bone.name))
+                            .with(Bone)
+                            .with(bone.position)
+                            .with(bone.rotation)
+                            .with(bone.scale)
+                            .with(Parent::new(parent))
+                            .build(),
+                    );
+                }
+
+                // the slots are in draw order, like the layers of the psd
+                for part in spine.parts.into_iter() {
+                    let (model, mesh, vertices, sprite_selector) = part.data;
+
+                    let indices_index = indices_data.push(
+                        mesh.frame(sprite_selector.at).indices.clone(),
+                        Duration::from_millis(1000),
+                    );
+                    let vertices_index = vertices_data.push(vertices, Duration::from_millis(1000));
+                    let instance_index = instances_data.push(
+                        vec![InstanceUniform::new(&Transform::identity())],
+                        Duration::from_millis(1000),
+                    );
+
+                    // a skinned mesh follows its bones from the root, the other parts hang from their bone
+                    let (parent, skin, pose) = match part.skin {
+                        Some(skin) => (
+                            spine_root,
+                            Some(Skin::with_binds(
+                                skin.bones.iter().map(|i| bones[*i]).collect(),
+                                skin.binds,
+                                skin.weights,
+                            )),
+                            Some(Pose {
+                                vertices: skin.vertices,
+                                angle: 0.0,
+                                scale: 1.0,
+                            }),
+                        ),
+                        None => (bones[part.bone], None, None),
+                    };
+
+                    world
+                        .create_entity()
+                        .with(Name::new(&part.name))
+                        .with(model)
+                        .with(render_pipelines[0].clone())
+                        .with(part.position)
+                        .with(part.rotation)
+                        .with(part.scale)
+                        .with(Parent::new(parent))
+                        .with(Opacity(part.opacity))
+                        .maybe_with(part.color)
+                        .maybe_with(skin)
+                        .maybe_with(pose)
+                        .with(part.draw_order)
+                        .with(sprite_selector)
+                        .with(mesh)
+                        .with(indices_index)
+                        .with(vertices_index)
+                        .with(instance_index)
+                        .build();
+                }
+
+                if let Some(clip) = spine.clips.into_iter().next() {
+                    let mut players = world.write_storage::<AnimationPlayer>();
+                    if let Some(player) = players.get_mut(spine_root) {
+                        player.play(Arc::new(clip));
+                    }
+                }
+            }
 
             let indices_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Indices Buffer"),
-                contents: bytemuck::cast_slice(&indices_data),
+                contents: bytemuck::cast_slice(&indices_data.idle_data),
                 usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
             });
 
             let vertices_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Vertices Buffer"),
-                contents: bytemuck::cast_slice(&vertices_data),
+                contents: bytemuck::cast_slice(&vertices_data.idle_data),
                 usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
             });
 
             let instances_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Instances Buffer"),
-                contents: bytemuck::cast_slice(&instances_data),
+                contents: bytemuck::cast_slice(&instances_data.idle_data),
                 usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
             });
 
@@ -863,21 +1126,17 @@ impl State {
             world.insert(DataBuffer::<ModelVertex>::new(vertices_buffer));
             world.insert(DataBuffer::<InstanceUniform>::new(instances_buffer));
 
-            world.insert(DataManager::new(
-                indices_data,
-                indices_indices,
-                indices_appended,
-            ));
-            world.insert(DataManager::new(
-                vertices_data,
-                vertices_indices,
-                vertices_appended,
-            ));
-            world.insert(DataManager::new(
-                instances_data,
-                instances_indices,
-                instances_appended,
-            ));
+            world.insert(indices_data);
+            world.insert(vertices_data);
+            world.insert(instances_data);
+        }
+
+        // what the parameters do to the layers of the psd, written by hand next to it
+        let rig_path = puppet_path.with_extension("rig.json");
+        if rig_path.exists() {
+            if let Err(e) = Rig::load(&rig_path).and_then(|rig| rig.insert(&world, root)) {
+                eprintln!("{:?}", e);
+            }
         }
 
         /*world.insert(Collections {
@@ -924,10 +1183,11 @@ impl State {
 
         let mut dispatcher = DispatcherBuilder::new()
             .with(UpdateSize, "UpdateSize", &[])
+            .with(hierarchy_system, "HierarchySystem", &[])
             .with(ActorUpdate, "ActorUpdate", &[])
             .with(ProcessEvents, "ProcessEvents", &["UpdateSize"])
             .with(
-                CameraControllerSys::default(),
+                CameraControllerSys,
                 "CameraControllerSys",
                 &["ProcessEvents"],
             )
@@ -946,25 +1206,71 @@ impl State {
                 "SpriteSelectorUpdate",
                 &["CameraControllerSys"],
             )
+            .with(
+                SpriteClipChain::default(),
+                "SpriteClipChain",
+                &["SpriteSelectorUpdate"],
+            )
+            .with(
+                MeshIndicesUpdate::default(),
+                "MeshIndicesUpdate",
+                &["SpriteClipChain", "MouthSyncUpdate"],
+            )
             .with(
                 DataBufferUpdater::<Indices>::default(),
                 "DataBufferUpdater<Indices>",
-                &["ProcessEvents"],
+                &["MeshIndicesUpdate"],
             )
             .with(
                 ModelVertexUpdate::default(),
                 "ModelVertexUpdate",
-                &["SpriteSelectorUpdate"],
+                &["SpriteClipChain", "MouthSyncUpdate"],
+            )
+            .with(
+                ParameterRestore,
+                "ParameterRestore",
+                &["CameraControllerSys"],
+            )
+            .with(MotionUpdate, "MotionUpdate", &["ParameterRestore"])
+            .with(StateMachineUpdate, "StateMachineUpdate", &["MotionUpdate"])
+            .with(AnimationUpdate, "AnimationUpdate", &["StateMachineUpdate"])
+            .with(ParameterSave, "ParameterSave", &["AnimationUpdate"])
+            .with(ExpressionUpdate, "ExpressionUpdate", &["ParameterSave"])
+            .with(ProceduralUpdate, "ProceduralUpdate", &["ExpressionUpdate"])
+            .with(LipSyncUpdate, "LipSyncUpdate", &["ProceduralUpdate"])
+            .with(MouthSyncUpdate, "MouthSyncUpdate", &["LipSyncUpdate"])
+            .with(PhysicsUpdate, "PhysicsUpdate", &["MouthSyncUpdate"])
+            .with(PartPoseUpdate, "PartPoseUpdate", &["PhysicsUpdate"])
+            .with(
+                KeyformUpdate::default(),
+                "KeyformUpdate",
+                &["ModelVertexUpdate", "PartPoseUpdate"],
             )
+            .with(
+                DeformerUpdate::default(),
+                "DeformerUpdate",
+                &["KeyformUpdate", "GlobalTransformUpdate"],
+            )
+            .with(SkinUpdate::default(), "SkinUpdate", &["DeformerUpdate"])
             .with(
                 DataBufferUpdater::<ModelVertex>::default(),
                 "DataBufferUpdater<ModelVertex>",
-                &["ModelVertexUpdate"],
+                &["SkinUpdate"],
+            )
+            .with(
+                GlobalTransformUpdate::default(),
+                "GlobalTransformUpdate",
+                &[
+                    "HierarchySystem",
+                    "ActorUpdate",
+                    "CameraControllerSys",
+                    "AnimationUpdate",
+                ],
             )
             .with(
                 InstanceUniformUpdate::default(),
                 "InstanceUniformUpdate",
-                &["ProcessEvents"],
+                &["GlobalTransformUpdate", "KeyformUpdate"],
             )
             .with(
                 DataBufferUpdater::<InstanceUniform>::default(),
@@ -1033,7 +1339,7 @@ impl State {
             Duration::from_secs_f64(dt.as_secs_f64() * self.world.read_resource::<Time>().speed);
 
         self.world.write_resource::<Time>().delta = dt;
-        self.dispatcher.dispatch(&mut self.world);
+        self.dispatcher.dispatch(&self.world);
         self.world.maintain();
     }
 }
@@ -0,0 +1,550 @@
+use crate::{
+    actor::Parent,
+    animation::{AnimationClip, AnimationLayer, AnimationMask, AnimationPlayer, LayerBlend},
+    parameter::Parameters,
+    type_def::*,
+};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use specs::{Component, DenseVecStorage, Entities, ReadStorage, System, WriteStorage};
+use std::{path::Path, sync::Arc};
+use winit::event::{DeviceEvent, ElementState, KeyboardInput, VirtualKeyCode};
+
+// the layout of a state machine file, the clips are paths relative to it
+#[derive(Deserialize)]
+struct StateMachineFile {
+    #[serde(default = "StateMachineFile::base_layer")]
+    layer: String,
+    #[serde(default = "StateMachineFile::override_blend")]
+    blend: LayerBlend, // how the layer is added when the player does not have it yet
+    #[serde(default = "StateMachineFile::full_weight")]
+    weight: Real,
+    #[serde(default)]
+    mask: Option<AnimationMask>,
+    initial: String,
+    states: Vec<StateFile>,
+    #[serde(default)]
+    transitions: Vec<TransitionFile>,
+}
+
+impl StateMachineFile {
+    fn base_layer() -> String {
+        "base".to_string()
+    }
+
+    fn override_blend() -> LayerBlend {
+        LayerBlend::Override
+    }
+
+    fn full_weight() -> Real {
+        1.0
+    }
+}
+
+#[derive(Deserialize)]
+struct StateFile {
+    name: String,
+    clip: String,
+    #[serde(default = "StateFile::normal_speed")]
+    speed: Real,
+}
+
+impl StateFile {
+    fn normal_speed() -> Real {
+        1.0
+    }
+}
+
+#[derive(Deserialize)]
+struct TransitionFile {
+    #[serde(default)]
+    from: Option<String>, // from any state when none
+    to: String,
+    #[serde(default)]
+    conditions: Vec<Condition>,
+    #[serde(default)]
+    blend: Real,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    Greater,
+    Less,
+    GreaterOrEqual,
+    LessOrEqual,
+}
+
+impl Comparison {
+    pub fn compare(&self, a: Real, b: Real) -> bool {
+        match self {
+            Self::Greater => a > b,
+            Self::Less => a < b,
+            Self::GreaterOrEqual => a >= b,
+            Self::LessOrEqual => a <= b,
+        }
+    }
+}
+
+// every condition of a transition has to hold for it to be taken
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Parameter {
+        id: String,
+        compare: Comparison,
+        value: Real,
+    },
+    Trigger(String), // set with StateMachinePlayer::trigger, used up by the transition taking it
+    Finished,        // the clip of the state reached its end, never for a looped clip
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationState {
+    pub name: String,
+    pub clip: Arc<AnimationClip>,
+    pub speed: Real,
+}
+
+#[derive(Debug, Clone)]
+pub struct Transition {
+    pub from: Option<usize>, // index in the states, any state when none
+    pub to: usize,
+    pub conditions: Vec<Condition>,
+    pub blend: Real, // duration of the crossfade
+}
+
+// states bound to clips, played on a layer of the AnimationPlayer of the same entity
+#[derive(Debug, Clone)]
+pub struct StateMachine {
+    pub layer: String,
+    pub blend: LayerBlend,
+    pub weight: Real,
+    pub mask: Option<AnimationMask>,
+    pub initial: usize,
+    pub states: Vec<AnimationState>,
+    pub transitions: Vec<Transition>, // the first one whose conditions hold is taken
+}
+
+impl StateMachine {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file: StateMachineFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let states = file
+            .states
+            .into_iter()
+            .map(|state| {
+                Ok(AnimationState {
+                    clip: Arc::new(AnimationClip::load(dir.join(&state.clip))?),
+                    name: state.name,
+                    speed: state.speed,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let index = |name: &str| {
+            states
+                .iter()
+                .position(|state| state.name == name)
+                .ok_or_else(|| anyhow!("unknown state {} in {}", name, path.display()))
+        };
+
+        let transitions = file
+            .transitions
+            .into_iter()
+            .map(|transition| {
+                Ok(Transition {
+                    from: transition.from.as_deref().map(index).transpose()?,
+                    to: index(&transition.to)?,
+                    conditions: transition.conditions,
+                    blend: transition.blend.max(0.0),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            layer: file.layer,
+            blend: file.blend,
+            weight: file.weight,
+            mask: file.mask,
+            initial: index(&file.initial)?,
+            states,
+            transitions,
+        })
+    }
+
+    // the layer the machine plays on, for a player that does not have it yet
+    pub fn new_layer(&self) -> AnimationLayer {
+        let layer = AnimationLayer::new(&self.layer, self.blend, self.weight);
+
+        match &self.mask {
+            Some(mask) => layer.with_mask(mask.clone()),
+            None => layer,
+        }
+    }
+
+    // the names the transitions wait for, in the order they first appear
+    pub fn triggers(&self) -> Vec<&str> {
+        let mut triggers: Vec<&str> = Vec::new();
+
+        for condition in self.transitions.iter().flat_map(|t| t.conditions.iter()) {
+            if let Condition::Trigger(name) = condition {
+                if !triggers.contains(&name.as_str()) {
+                    triggers.push(name);
+                }
+            }
+        }
+
+        triggers
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StateMachinePlayer {
+    pub machine: Arc<StateMachine>,
+    pub current: Option<usize>, // entered on the next update when none
+    pub triggers: Vec<String>,
+}
+
+impl Component for StateMachinePlayer {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl StateMachinePlayer {
+    pub fn new(machine: Arc<StateMachine>) -> Self {
+        Self {
+            machine,
+            current: None,
+            triggers: Vec::new(),
+        }
+    }
+
+    // stays set until a transition uses it
+    pub fn trigger(&mut self, name: &str) {
+        if !self.triggers.iter().any(|trigger| trigger == name) {
+            self.triggers.push(name.to_string());
+        }
+    }
+}
+
+pub struct StateMachineInput {
+    pub bindings: Vec<(VirtualKeyCode, String)>,
+}
+
+impl Component for StateMachineInput {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl StateMachineInput {
+    // the function keys from F1 to F12 in the order of the triggers
+    pub fn function_keys(machine: &StateMachine) -> Self {
+        let keys = [
+            VirtualKeyCode::F1,
+            VirtualKeyCode::F2,
+            VirtualKeyCode::F3,
+            VirtualKeyCode::F4,
+            VirtualKeyCode::F5,
+            VirtualKeyCode::F6,
+            VirtualKeyCode::F7,
+            VirtualKeyCode::F8,
+            VirtualKeyCode::F9,
+            VirtualKeyCode::F10,
+            VirtualKeyCode::F11,
+            VirtualKeyCode::F12,
+        ];
+
+        Self {
+            bindings: keys
+                .into_iter()
+                .zip(machine.triggers())
+                .map(|(key, trigger)| (key, trigger.to_string()))
+                .collect(),
+        }
+    }
+
+    pub fn process_event(&self, player: &mut StateMachinePlayer, event: &DeviceEvent) -> bool {
+        let key = match event {
+            DeviceEvent::Key(KeyboardInput {
+                virtual_keycode: Some(key),
+                state: ElementState::Pressed,
+                ..
+            }) => *key,
+            _ => return false,
+        };
+
+        match self.bindings.iter().find(|(other, _)| *other == key) {
+            Some((_, name)) => {
+                player.trigger(name);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// picks the clips of the layers, AnimationUpdate plays them
+pub struct StateMachineUpdate;
+
+impl<'a> System<'a> for StateMachineUpdate {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, StateMachinePlayer>,
+        WriteStorage<'a, AnimationPlayer>,
+        ReadStorage<'a, Parameters>,
+        ReadStorage<'a, Parent>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut machines, mut players, parameters, parents): Self::SystemData,
+    ) {
+        use specs::Join;
+
+        for (entity, machine, player) in (&entities, &mut machines, &mut players).join() {
+            let state_machine = machine.machine.clone();
+
+            if player.layer(&state_machine.layer).is_none() {
+                player.add_layer(state_machine.new_layer());
+            }
+
+            let layer = match player.layer_mut(&state_machine.layer) {
+                Some(layer) => layer,
+                None => continue,
+            };
+
+            let current = match machine.current {
+                Some(current) => current,
+                None => {
+                    let state = &state_machine.states[state_machine.initial];
+                    layer.play(state.clip.clone());
+                    layer.speed = state.speed;
+                    machine.current = Some(state_machine.initial);
+                    continue;
+                }
+            };
+
+            // the parameters are on the root of the puppet the entity belongs to
+            let mut owner = Some(entity);
+            while let Some(target) = owner.filter(|target| !parameters.contains(*target)) {
+                owner = parents.get(target).map(|parent| parent.entity);
+            }
+            let parameters = owner.and_then(|owner| parameters.get(owner));
+
+            // the clip of the state is still the one on the layer when nothing else played on it
+            let finished = layer.is_finished()
+                && layer.current.as_ref().is_some_and(|playing| {
+                    Arc::ptr_eq(&playing.clip, &state_machine.states[current].clip)
+                });
+
+            let holds = |condition: &Condition| match condition {
+                Condition::Parameter { id, compare, value } => parameters
+                    .and_then(|parameters| parameters.value(id))
+                    .is_some_and(|parameter| compare.compare(parameter, *value)),
+                Condition::Trigger(name) => machine.triggers.contains(name),
+                Condition::Finished => finished,
+            };
+
+            let transition = state_machine.transitions.iter().find(|transition| {
+                let from = match transition.from {
+                    Some(from) => from == current,
+                    None => transition.to != current,
+                };
+
+                from && transition.conditions.iter().all(holds)
+            });
+
+            if let Some(transition) = transition {
+                for condition in transition.conditions.iter() {
+                    if let Condition::Trigger(name) = condition {
+                        machine.triggers.retain(|trigger| trigger != name);
+                    }
+                }
+
+                let state = &state_machine.states[transition.to];
+                layer.crossfade(state.clip.clone(), transition.blend);
+                layer.speed = state.speed;
+                machine.current = Some(transition.to);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::{Easing, Keyframe, Track, TrackTarget};
+    use specs::{Builder, RunNow, World, WorldExt};
+
+    fn state(name: &str, looped: bool) -> AnimationState {
+        AnimationState {
+            name: name.to_string(),
+            clip: Arc::new(AnimationClip {
+                name: name.to_string(),
+                duration: 1.0,
+                looped,
+                tracks: vec![Track {
+                    entity: None,
+                    target: TrackTarget::Parameter("ParamMouthOpenY".to_string()),
+                    keyframes: vec![Keyframe {
+                        time: 0.0,
+                        value: vec![0.0],
+                        easing: Easing::Linear,
+                    }],
+                }],
+            }),
+            speed: 1.0,
+        }
+    }
+
+    fn machine() -> StateMachine {
+        StateMachine {
+            layer: "base".to_string(),
+            blend: LayerBlend::Override,
+            weight: 1.0,
+            mask: None,
+            initial: 0,
+            states: vec![
+                state("idle", true),
+                state("talk", false),
+                state("wave", false),
+            ],
+            transitions: vec![
+                Transition {
+                    from: Some(0),
+                    to: 1,
+                    conditions: vec![Condition::Trigger("talk".to_string())],
+                    blend: 0.3,
+                },
+                Transition {
+                    from: None,
+                    to: 2,
+                    conditions: vec![Condition::Trigger("wave".to_string())],
+                    blend: 0.2,
+                },
+                Transition {
+                    from: Some(2),
+                    to: 0,
+                    conditions: vec![Condition::Trigger("talk".to_string())],
+                    blend: 0.0,
+                },
+            ],
+        }
+    }
+
+    fn press(key: VirtualKeyCode) -> DeviceEvent {
+        #[allow(deprecated)]
+        DeviceEvent::Key(KeyboardInput {
+            scancode: 0,
+            state: ElementState::Pressed,
+            virtual_keycode: Some(key),
+            modifiers: Default::default(),
+        })
+    }
+
+    #[test]
+    fn triggers_are_listed_once_in_order() {
+        assert_eq!(machine().triggers(), vec!["talk", "wave"]);
+    }
+
+    #[test]
+    fn function_keys_set_the_triggers() {
+        let machine = Arc::new(machine());
+        let input = StateMachineInput::function_keys(&machine);
+        let mut player = StateMachinePlayer::new(machine);
+
+        assert!(input.process_event(&mut player, &press(VirtualKeyCode::F2)));
+        assert!(input.process_event(&mut player, &press(VirtualKeyCode::F2)));
+        assert!(!input.process_event(&mut player, &press(VirtualKeyCode::F3)));
+        assert_eq!(player.triggers, vec!["wave".to_string()]);
+    }
+
+    #[test]
+    fn a_transition_uses_up_its_trigger() {
+        let mut world = World::new();
+        world.register::<StateMachinePlayer>();
+        world.register::<AnimationPlayer>();
+        world.register::<Parameters>();
+        world.register::<Parent>();
+
+        let puppet = world
+            .create_entity()
+            .with(StateMachinePlayer::new(Arc::new(machine())))
+            .with(AnimationPlayer::default())
+            .with(Parameters::standard())
+            .build();
+
+        let current = |world: &World| {
+            let machines = world.read_storage::<StateMachinePlayer>();
+            let machine = machines.get(puppet).unwrap();
+            machine.machine.states[machine.current.unwrap()]
+                .name
+                .clone()
+        };
+
+        StateMachineUpdate.run_now(&world);
+        assert_eq!(current(&world), "idle");
+
+        let trigger = |world: &World, name: &str| {
+            let mut machines = world.write_storage::<StateMachinePlayer>();
+            machines.get_mut(puppet).unwrap().trigger(name);
+        };
+
+        trigger(&world, "talk");
+        StateMachineUpdate.run_now(&world);
+        assert_eq!(current(&world), "talk");
+        assert!(world
+            .read_storage::<StateMachinePlayer>()
+            .get(puppet)
+            .unwrap()
+            .triggers
+            .is_empty());
+
+        trigger(&world, "wave");
+        StateMachineUpdate.run_now(&world);
+        assert_eq!(current(&world), "wave");
+
+        trigger(&world, "talk");
+        StateMachineUpdate.run_now(&world);
+        assert_eq!(current(&world), "idle");
+    }
+
+    #[test]
+    fn a_missing_layer_is_added_with_the_blending_of_the_machine() {
+        let mut world = World::new();
+        world.register::<StateMachinePlayer>();
+        world.register::<AnimationPlayer>();
+        world.register::<Parameters>();
+        world.register::<Parent>();
+
+        let machine = StateMachine {
+            layer: "mouth".to_string(),
+            blend: LayerBlend::Additive,
+            weight: 0.5,
+            mask: Some(AnimationMask {
+                parameters: vec!["ParamMouthOpenY".to_string()],
+                entities: Vec::new(),
+            }),
+            ..machine()
+        };
+
+        let puppet = world
+            .create_entity()
+            .with(StateMachinePlayer::new(Arc::new(machine)))
+            .with(AnimationPlayer::default())
+            .build();
+
+        StateMachineUpdate.run_now(&world);
+
+        let players = world.read_storage::<AnimationPlayer>();
+        let player = players.get(puppet).unwrap();
+        assert_eq!(player.layers.len(), 2);
+
+        let layer = player.layer("mouth").unwrap();
+        assert_eq!(layer.blend, LayerBlend::Additive);
+        assert_eq!(layer.weight, 0.5);
+        assert!(layer.mask.is_some());
+        assert_eq!(layer.current.as_ref().unwrap().clip.name, "idle");
+    }
+}
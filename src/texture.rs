@@ -30,6 +30,7 @@ impl<'a> Into<wgpu::TextureDescriptor<'a>> for TextureDescriptor {
 #[derive(Component, Debug)]
 #[storage(VecStorage)]
 pub struct Texture {
+    #[allow(dead_code)] // the view is what gets bound, this keeps the memory behind it
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
@@ -175,7 +176,8 @@ impl Texture {
 }
 
 impl Texture {
-    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+    // the stencil is used by the clipping masks
+    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth24PlusStencil8;
     /*pub const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
     pub fn create_shadow_texture(device: &wgpu::Device, label: Option<&str>) -> Self {
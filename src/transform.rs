@@ -0,0 +1,167 @@
+use crate::actor::{Parent, ParentHierarchy};
+pub use crate::type_def::*;
+use rapier::na::Isometry2;
+use specs::{
+    prelude::ComponentEvent, shred::DynamicSystemData, shrev::EventIterator, BitSet, Component,
+    DenseVecStorage, Entities, Entity, FlaggedStorage, ReadExpect, ReadStorage, ReaderId, System,
+    World, WorldExt, WriteStorage,
+};
+use specs_hierarchy::HierarchyEvent;
+
+// position, rotation and scale of an entity combined with the ones of all its parents
+#[derive(Debug)]
+pub struct GlobalTransform(pub Transform);
+
+impl Component for GlobalTransform {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl Default for GlobalTransform {
+    fn default() -> Self {
+        Self(Transform::identity())
+    }
+}
+
+impl GlobalTransform {
+    pub fn local(
+        position: &Point,
+        rotation: Option<&Rotator>,
+        scale: Option<&Vector>,
+    ) -> Transform {
+        let isometry = Isometry2::from_parts(
+            position.coords.into(),
+            rotation.copied().unwrap_or_else(Rotator::identity),
+        );
+
+        match scale {
+            Some(scale) => isometry.to_homogeneous() * Transform::new_nonuniform_scaling(scale),
+            None => isometry.to_homogeneous(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct GlobalTransformUpdate {
+    pub dirty: BitSet,
+    pub reader_id_pos: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_rot: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_scale: Option<ReaderId<ComponentEvent>>,
+    pub reader_id_hierarchy: Option<ReaderId<HierarchyEvent>>,
+}
+
+impl GlobalTransformUpdate {
+    fn event_update(&mut self, events: EventIterator<'_, ComponentEvent>) {
+        for event in events.into_iter() {
+            match event {
+                ComponentEvent::Modified(id) | ComponentEvent::Inserted(id) => {
+                    self.dirty.add(*id);
+                }
+                ComponentEvent::Removed(_id) => (),
+            }
+        }
+    }
+
+    fn set(globals: &mut WriteStorage<'_, GlobalTransform>, entity: Entity, global: Transform) {
+        match globals.get_mut(entity) {
+            Some(transform) => transform.0 = global,
+            None => {
+                globals.insert(entity, GlobalTransform(global)).unwrap();
+            }
+        }
+    }
+}
+
+impl<'a> System<'a> for GlobalTransformUpdate {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, ParentHierarchy>,
+        WriteStorage<'a, GlobalTransform>,
+        ReadStorage<'a, Parent>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Rotation>,
+        ReadStorage<'a, Scale>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, hierarchy, mut globals, parents, positions, rotations, scales): Self::SystemData,
+    ) {
+        use specs::Join;
+
+        self.dirty.clear();
+
+        let events_pos = positions
+            .channel()
+            .read(self.reader_id_pos.as_mut().unwrap());
+        let events_rot = rotations
+            .channel()
+            .read(self.reader_id_rot.as_mut().unwrap());
+        let events_scale = scales
+            .channel()
+            .read(self.reader_id_scale.as_mut().unwrap());
+
+        self.event_update(events_pos);
+        self.event_update(events_rot);
+        self.event_update(events_scale);
+
+        // an entity that changed parent (or lost it) moves with its new one
+        for event in hierarchy
+            .changed()
+            .read(self.reader_id_hierarchy.as_mut().unwrap())
+        {
+            match event {
+                HierarchyEvent::Modified(entity) | HierarchyEvent::Removed(entity) => {
+                    self.dirty.add(entity.id());
+                }
+            }
+        }
+
+        // everything below a modified entity has to follow it
+        for (entity, _) in (&entities, &self.dirty.clone()).join() {
+            self.dirty |= &hierarchy.all_children(entity);
+        }
+
+        let local = |entity| {
+            positions.get(entity).map(|position| {
+                GlobalTransform::local(
+                    &position.0,
+                    rotations.get(entity).map(|rotation| &rotation.0),
+                    scales.get(entity).map(|scale| &scale.0),
+                )
+            })
+        };
+
+        for (entity, _, _) in (&entities, &self.dirty, !&parents).join() {
+            if let Some(local) = local(entity) {
+                Self::set(&mut globals, entity, local);
+            }
+        }
+
+        // sorted so that parents are always updated before their children
+        for entity in hierarchy.all().iter() {
+            if self.dirty.contains(entity.id()) {
+                if let Some(local) = local(*entity) {
+                    let parent = parents
+                        .get(*entity)
+                        .and_then(|parent| globals.get(parent.entity))
+                        .map(|parent| parent.0);
+
+                    let global = match parent {
+                        Some(parent) => parent * local,
+                        None => local,
+                    };
+
+                    Self::set(&mut globals, *entity, global);
+                }
+            }
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(&self.accessor(), world);
+        self.reader_id_pos = Some(world.write_component::<Position>().register_reader());
+        self.reader_id_rot = Some(world.write_component::<Rotation>().register_reader());
+        self.reader_id_scale = Some(world.write_component::<Scale>().register_reader());
+        self.reader_id_hierarchy = Some(world.write_resource::<ParentHierarchy>().track());
+    }
+}
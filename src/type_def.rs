@@ -3,9 +3,8 @@ use rapier::math::{
     AngVector as MAngVector, Point as MPoint, Real as MReal, Rotation as MRotation,
     Vector as MVector,
 };
-use rapier::na::{Isometry3 as MIsometry3, Perspective3 as MPerspective};
+use rapier::na::{Isometry3 as MIsometry3, Matrix3 as MMatrix3, Perspective3 as MPerspective};
 use rapier::prelude::{ColliderHandle as MColliderHandle, RigidBodyHandle as MRigidBodyHandle};
-use rapier2d::na::Orthographic3;
 use specs::{Component, DenseVecStorage, FlaggedStorage};
 
 pub type Real = MReal;
@@ -15,6 +14,7 @@ pub type Rotator = MRotation<Real>;
 pub type AngVector = MAngVector<Real>;
 pub type Isometry = MIsometry3<Real>;
 pub type Perspective = MPerspective<Real>;
+pub type Transform = MMatrix3<Real>;
 pub type Indices = u32;
 pub type RapierColliderHandle = MColliderHandle;
 pub type RapierRigidBodyHandle = MRigidBodyHandle;
@@ -93,6 +93,21 @@ impl Component for Translation {
     type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
 }
 
+#[derive(Debug, Default, Clone)]
+pub struct Name(pub String);
+
+impl Name {
+    pub fn new(name: &str) -> Self {
+        Self {
+            0: name.to_string(),
+        }
+    }
+}
+
+impl Component for Name {
+    type Storage = DenseVecStorage<Self>;
+}
+
 #[derive(Debug, Default)]
 pub struct Color(pub Rgba); // Rgba (linear) or Srgba (non-linear (as in exponentiel))
 
@@ -123,3 +138,54 @@ impl Color {
 impl Component for Color {
     type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
 }
+
+// added over the multiply Color of a part like the screen blending of cubism, ex: the pink of a blush
+#[derive(Debug)]
+pub struct ScreenColor(pub Rgba);
+
+impl Default for ScreenColor {
+    fn default() -> Self {
+        Self {
+            0: Rgba::new(0.0, 0.0, 0.0, 1.0),
+        }
+    }
+}
+
+impl ScreenColor {
+    pub fn new_rgb(r: Real, g: Real, b: Real) -> Self {
+        Self {
+            0: Rgba::new(r, g, b, 1.0),
+        }
+    }
+}
+
+impl Component for ScreenColor {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+#[derive(Debug)]
+pub struct Opacity(pub Real);
+
+impl Default for Opacity {
+    fn default() -> Self {
+        Self { 0: 1.0 }
+    }
+}
+
+impl Component for Opacity {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+// parts with a higher draw order are drawn over the others, in whatever group they are
+#[derive(Debug)]
+pub struct DrawOrder(pub i32);
+
+impl Default for DrawOrder {
+    fn default() -> Self {
+        Self { 0: 500 } // the default draw order of live2d
+    }
+}
+
+impl Component for DrawOrder {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}